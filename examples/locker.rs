@@ -0,0 +1,57 @@
+//! Compares the cost of locking through `AdvisoryFileLock` and `AdvisoryRangeLock` and through
+//! reused `Locker`s.
+use std::fs::OpenOptions;
+use std::time::Instant;
+
+use advisory_lock::{AdvisoryFileLock, AdvisoryRangeLock, FileLockMode, Locker};
+
+const ITERATIONS: u32 = 100_000;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = std::env::temp_dir();
+    path.push("advisory_lock_locker_example");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+        AdvisoryFileLock::unlock(&file)?;
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("AdvisoryFileLock:  {:?} per lock/unlock", per_call);
+
+    let locker = Locker::new(&file, FileLockMode::Exclusive);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        locker.lock()?;
+        locker.unlock()?;
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("Locker:            {:?} per lock/unlock", per_call);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        file.lock_range(FileLockMode::Exclusive, 4096, 4096)?;
+        file.unlock_range(4096, 4096)?;
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("AdvisoryRangeLock: {:?} per lock/unlock", per_call);
+
+    let locker = Locker::range(&file, FileLockMode::Exclusive, 4096, 4096)?;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        locker.lock()?;
+        locker.unlock()?;
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("Locker::range:     {:?} per lock/unlock", per_call);
+
+    drop(file);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
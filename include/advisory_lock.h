@@ -0,0 +1,59 @@
+/*
+ * C interface of the advisory-lock crate, built with the `ffi` feature:
+ *
+ *     cargo rustc --release --features ffi --crate-type cdylib
+ *
+ * Locks taken through this interface exclude, and are excluded by, the locks taken by Rust
+ * code through the crate, and by any other program locking the same files natively.
+ */
+#ifndef ADVISORY_LOCK_H
+#define ADVISORY_LOCK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Error codes returned by every operation. */
+#define ADVISORY_LOCK_OK 0
+#define ADVISORY_LOCK_ALREADY_LOCKED 1
+#define ADVISORY_LOCK_IO 2
+#define ADVISORY_LOCK_INVALID_ARGUMENT 3
+#define ADVISORY_LOCK_INTERRUPTED 4
+#define ADVISORY_LOCK_UNSUPPORTED 5
+#define ADVISORY_LOCK_NO_LOCK_RESOURCES 6
+#define ADVISORY_LOCK_OTHER 7
+
+/* Lock modes. */
+#define ADVISORY_LOCK_SHARED 0
+#define ADVISORY_LOCK_EXCLUSIVE 1
+
+/* An open file whose lock is manipulated through this interface. */
+typedef struct advisory_lock advisory_lock;
+
+/*
+ * Open the file at `path` for reading and writing, creating it if needed, without locking it.
+ * Returns NULL on failure, and stores the error code in `*error` unless `error` is NULL.
+ * `path` must be in UTF-8 on Windows.
+ */
+advisory_lock *advisory_lock_open(const char *path, int *error);
+
+/* Acquire the lock in `mode`, waiting as long as it is held elsewhere. */
+int advisory_lock_lock(const advisory_lock *lock, int mode);
+
+/* Acquire the lock in `mode`, or return ADVISORY_LOCK_ALREADY_LOCKED if it is held elsewhere. */
+int advisory_lock_try_lock(const advisory_lock *lock, int mode);
+
+/* Release the lock. */
+int advisory_lock_unlock(const advisory_lock *lock);
+
+/* Close the file, which releases its lock, and free the handle. NULL is ignored. */
+void advisory_lock_close(advisory_lock *lock);
+
+/* Return a static description of the error code `code`. */
+const char *advisory_lock_strerror(int code);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ADVISORY_LOCK_H */
@@ -0,0 +1,342 @@
+//! Acquiring locks from async code, off the executor.
+//!
+//! Blocking on a lock would block the executor thread along with every task scheduled on it, so
+//! the wait runs on a thread meant for blocking work instead. It locks the handle of the caller
+//! with the default backend, as the synchronous API does, so the lock is the one the caller
+//! unlocks later.
+//!
+//! A blocking call can't be interrupted once the future is dropped, after which the file it
+//! borrowed may be closed. The thread polls instead: each attempt is made while holding the
+//! state of the acquisition, which the future marks as abandoned when dropped, so the handle
+//! is never used once the future is gone.
+use std::fs::File;
+use std::future::Future;
+use std::io;
+#[cfg(not(feature = "blocking"))]
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use crate::ownership::check_per_handle;
+use crate::sync::{self, Arc, Mutex, MutexGuard};
+use crate::{
+    lock_handle, sys, unlock_handle, AdvisoryFileLock, FileLockError, FileLockGuard, FileLockMode,
+};
+
+/// The delay between two attempts of a waiting thread.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    /// The thread is waiting for the lock.
+    Waiting,
+    /// The lock was acquired, but the future hasn't returned yet.
+    Acquired,
+    /// The future returned the lock to its caller.
+    Delivered,
+    /// The future was dropped before returning.
+    Abandoned,
+}
+
+/// A lock acquisition running on a blocking thread, on behalf of a future.
+#[derive(Debug)]
+struct Pending {
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    state: Mutex<State>,
+}
+
+// The handle is only used while the state is `Waiting` or `Acquired`, that is while the future
+// borrowing its file is alive.
+unsafe impl Send for Pending {}
+unsafe impl Sync for Pending {}
+
+impl Pending {
+    fn new(file: &File, file_lock_mode: FileLockMode) -> Arc<Pending> {
+        Arc::new(Pending {
+            handle: sys::handle(file),
+            file_lock_mode,
+            state: Mutex::new(State::Waiting),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        sync::lock(&self.state)
+    }
+
+    /// Wait until the lock is acquired or the future is dropped; this runs on the blocking
+    /// thread.
+    fn acquire(&self) -> Result<(), FileLockError> {
+        loop {
+            // Holding the state across the attempt keeps the future from being dropped halfway.
+            let mut state = self.state();
+            if *state == State::Abandoned {
+                return Ok(());
+            }
+            match lock_handle(self.handle, self.file_lock_mode, true) {
+                Err(FileLockError::AlreadyLocked) => {}
+                result => {
+                    if result.is_ok() {
+                        *state = State::Acquired;
+                    }
+                    return result;
+                }
+            }
+            drop(state);
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn release(&self) -> Result<(), FileLockError> {
+        unlock_handle(self.handle)
+    }
+}
+
+/// Marks the acquisition as abandoned, and releases the lock if it was acquired, unless the
+/// future returned the result first.
+struct AbandonOnDrop(Arc<Pending>);
+
+impl AbandonOnDrop {
+    fn deliver(self) {
+        *self.0.state() = State::Delivered;
+    }
+}
+
+impl Drop for AbandonOnDrop {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        match *state {
+            State::Delivered => return,
+            State::Acquired => {
+                let _ = self.0.release();
+            }
+            State::Waiting | State::Abandoned => {}
+        }
+        *state = State::Abandoned;
+    }
+}
+
+/// Lock `file`, waiting off the executor if the lock isn't available right away.
+async fn lock_off_executor(file: &File, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+    match AdvisoryFileLock::try_lock(file, file_lock_mode) {
+        Err(FileLockError::AlreadyLocked) => {}
+        result => return result,
+    }
+
+    let pending = Pending::new(file, file_lock_mode);
+    let abandon = AbandonOnDrop(Arc::clone(&pending));
+    let result = unblock(move || pending.acquire()).await;
+    abandon.deliver();
+    result
+}
+
+/// Run `wait` on a thread which may block, and resolve to its result.
+///
+/// Tokio's blocking pool is used from within a tokio runtime, and the one of the `blocking`
+/// crate, which works with any executor, everywhere else. Without the `blocking` feature, waits
+/// outside a tokio runtime get a dedicated thread.
+async fn unblock<F>(wait: F) -> Result<(), FileLockError>
+where
+    F: FnOnce() -> Result<(), FileLockError> + Send + 'static,
+{
+    #[cfg(feature = "tokio")]
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        return runtime
+            .spawn_blocking(wait)
+            .await
+            .unwrap_or_else(|err| Err(FileLockError::Io(io::Error::other(err))));
+    }
+
+    #[cfg(feature = "blocking")]
+    {
+        blocking::unblock(wait).await
+    }
+    #[cfg(not(feature = "blocking"))]
+    {
+        on_dedicated_thread(wait).await
+    }
+}
+
+/// The result of a wait running on a dedicated thread, and the task to wake when it is set.
+#[cfg(not(feature = "blocking"))]
+#[derive(Default)]
+struct Handoff {
+    result: Option<Result<(), FileLockError>>,
+    waker: Option<Waker>,
+}
+
+/// Run `wait` on a thread of its own, and resolve to its result.
+#[cfg(not(feature = "blocking"))]
+async fn on_dedicated_thread<F>(wait: F) -> Result<(), FileLockError>
+where
+    F: FnOnce() -> Result<(), FileLockError> + Send + 'static,
+{
+    let handoff = Arc::new(Mutex::new(Handoff::default()));
+    let sender = Arc::clone(&handoff);
+    thread::Builder::new()
+        .name("advisory-lock-wait".to_owned())
+        .spawn(move || {
+            let result = wait();
+            let mut handoff = sync::lock(&sender);
+            handoff.result = Some(result);
+            if let Some(waker) = handoff.waker.take() {
+                waker.wake();
+            }
+        })
+        .map_err(FileLockError::Io)?;
+
+    std::future::poll_fn(|context| {
+        let mut handoff = sync::lock(&handoff);
+        match handoff.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                handoff.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+/// An advisory lock which can be awaited without blocking the executor.
+///
+/// Waiting for the lock happens on a thread pool meant for blocking work, so the calling task
+/// yields to the others meanwhile, while locks which are available right away are acquired on
+/// the spot. Within a tokio runtime, tokio's blocking pool is used.
+///
+/// This trait is available with the `tokio` feature and with the `blocking` feature. Its
+/// futures work with any executor, such as the ones of async-std and smol; outside a tokio
+/// runtime, they wait on the pool of the `blocking` crate with the `blocking` feature, and on a
+/// dedicated thread per wait without it.
+///
+/// Example:
+/// ```
+/// # #[cfg(feature = "tokio")]
+/// # {
+/// use std::fs::File;
+/// use advisory_lock::{AsyncAdvisoryFileLock, FileLockMode};
+///
+/// # tokio::runtime::Builder::new_current_thread().build()?.block_on(async {
+/// let file = File::create("async.lock")?;
+/// let guard = file.lock_guard_async(FileLockMode::Exclusive).await?;
+/// // ... other tasks keep running while waiting for the lock ...
+/// drop(guard);
+/// # std::fs::remove_file("async.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// # })?;
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub trait AsyncAdvisoryFileLock {
+    /// Acquire the advisory file lock, waiting for it off the executor.
+    ///
+    /// If the future is dropped before completing, the lock is not acquired.
+    fn lock_async(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> impl Future<Output = Result<(), FileLockError>> + Send;
+    /// Try to acquire the advisory file lock, without waiting.
+    fn try_lock_async(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> impl Future<Output = Result<(), FileLockError>> + Send;
+    /// Acquire the advisory file lock, returning a guard which releases it when dropped.
+    ///
+    /// The guard can be held across `.await` points and moved between threads.
+    fn lock_guard_async(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> impl Future<Output = Result<FileLockGuard<'_>, FileLockError>> + Send;
+}
+
+impl AsyncAdvisoryFileLock for File {
+    async fn lock_async(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_off_executor(self, file_lock_mode).await
+    }
+
+    async fn try_lock_async(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        AdvisoryFileLock::try_lock(self, file_lock_mode)
+    }
+
+    async fn lock_guard_async(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> Result<FileLockGuard<'_>, FileLockError> {
+        check_per_handle()?;
+        self.lock_async(file_lock_mode).await?;
+        Ok(FileLockGuard::adopt(self, file_lock_mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::path::PathBuf;
+
+    fn contended(name: &str) -> (PathBuf, File, File) {
+        let mut test_file = temp_dir();
+        test_file.push(name);
+        let holder = File::create(&test_file).unwrap();
+        let waiter = File::open(&test_file).unwrap();
+        AdvisoryFileLock::lock(&holder, FileLockMode::Exclusive).unwrap();
+        (test_file, holder, waiter)
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn waits_off_the_tokio_runtime() {
+        use std::pin::pin;
+        use std::task::{Context, Waker};
+
+        let (test_file, holder, waiter) = contended("async_tokio");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        // A dropped future doesn't leave the lock behind.
+        {
+            let _context = runtime.enter();
+            let mut cancelled = pin!(waiter.lock_async(FileLockMode::Shared));
+            let mut context = Context::from_waker(Waker::noop());
+            assert!(cancelled.as_mut().poll(&mut context).is_pending());
+        }
+
+        let (guard, holder) = runtime.block_on(async {
+            assert!(waiter.try_lock_async(FileLockMode::Shared).await.is_err());
+            let releaser = tokio::task::spawn_blocking(move || {
+                thread::sleep(Duration::from_millis(50));
+                AdvisoryFileLock::unlock(&holder).unwrap();
+                holder
+            });
+            let guard = waiter.lock_guard_async(FileLockMode::Shared).await.unwrap();
+            (guard, releaser.await.unwrap())
+        });
+        assert!(AdvisoryFileLock::try_lock(&holder, FileLockMode::Exclusive).is_err());
+        drop(guard);
+        AdvisoryFileLock::try_lock(&holder, FileLockMode::Exclusive).unwrap();
+
+        drop(holder);
+        drop(waiter);
+        drop(runtime);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn waits_without_a_runtime() {
+        let (test_file, holder, waiter) = contended("async_blocking");
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            AdvisoryFileLock::unlock(&holder).unwrap();
+            holder
+        });
+        futures_lite::future::block_on(waiter.lock_async(FileLockMode::Shared)).unwrap();
+        let holder = releaser.join().unwrap();
+        assert!(AdvisoryFileLock::try_lock(&holder, FileLockMode::Exclusive).is_err());
+
+        drop(holder);
+        drop(waiter);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,470 @@
+use std::fmt;
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{emulated, sys, BackendCapabilities, FileLockError, FileLockMode};
+
+/// An enumeration of mechanisms the crate can lock files with.
+///
+/// The backend is selected at runtime for the whole process with [`set_default_backend`], for
+/// example from a configuration file through the [`FromStr`] implementation.
+///
+/// ## Crash safety
+///
+/// A process which dies while holding a lock, even by `SIGKILL` or a power loss of its machine
+/// (for local file systems), must not wedge the processes waiting for it. Every mechanism the
+/// crate locks with guarantees that its locks are released when their holder goes away:
+///
+/// | Mechanism                           | Released when the holder...                       |
+/// |-------------------------------------|---------------------------------------------------|
+/// | [`Native`] (`flock`, `LockFileEx`)  | closes the last handle sharing the lock, or exits |
+/// | [`PerProcess`] (`fcntl` records)    | closes *any* handle to the file, or exits         |
+/// | [`Fcntl`] (`fcntl` records)         | closes *any* handle to the file, or exits         |
+/// | [`Ofd`] (`fcntl` OFD records)       | closes the last handle sharing the lock, or exits |
+/// | [`Noop`]                            | nothing is ever held                              |
+/// | [`Emulated`] (sidecar files)        | unlocks it, exits, or stops renewing its lease    |
+///
+/// Note the "last handle": a lock whose handle was inherited by a child process outlives its
+/// holder until the child exits too; see [`set_inheritable`]. Mechanisms which store locks on
+/// disk rather than in the kernel must expire them on their own to meet this guarantee: the
+/// markers of emulated locks are removed by an exit hook when their holder exits normally, and
+/// are leases otherwise, which the next waiter expires once their holder is gone or stopped
+/// renewing them for 30 seconds, whether or not its process id was reused since. See
+/// [`set_emulation_dir`] for how WASI programs, which have no threads, renew their leases.
+///
+/// [`set_default_backend`]: fn.set_default_backend.html
+/// [`set_emulation_dir`]: fn.set_emulation_dir.html
+/// [`FromStr`]: https://doc.rust-lang.org/stable/std/str/trait.FromStr.html
+/// [`Emulated`]: #variant.Emulated
+/// [`Fcntl`]: #variant.Fcntl
+/// [`Native`]: #variant.Native
+/// [`Noop`]: #variant.Noop
+/// [`Ofd`]: #variant.Ofd
+/// [`PerProcess`]: struct.PerProcess.html
+/// [`set_inheritable`]: fn.set_inheritable.html
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[non_exhaustive]
+pub enum Backend {
+    /// The native mechanism of the platform: `flock` on Unix and `LockFileEx` on Windows.
+    #[default]
+    Native,
+    /// Accept every operation instantly without locking anything.
+    ///
+    /// This is meant for benchmarking baselines and single-process deployments where locking is
+    /// disabled by configuration. Other processes are **not** excluded.
+    Noop,
+    /// Classic POSIX record locks over the whole file, through `fcntl(F_SETLK)`, on Unix.
+    ///
+    /// Unlike `flock`, record locks are forwarded to the server by NFS clients (through NLM or
+    /// NFSv4), so they exclude processes on other machines sharing the file system. They are
+    /// owned by the process rather than by the handle, though:
+    ///
+    /// - locks of the same process don't exclude each other, even through different handles;
+    /// - closing *any* handle to the file releases the locks of the process on it;
+    /// - shared locks require the file to be open for reading, and exclusive locks for writing.
+    ///
+    /// Only whole-file operations are affected; range locks always use `fcntl`. The types which
+    /// promise locks owned by the handle, such as [`FileLockGuard`], refuse to lock while this is
+    /// the default backend; see [`PerHandle`].
+    ///
+    /// [`FileLockGuard`]: struct.FileLockGuard.html
+    /// [`PerHandle`]: enum.PerHandle.html
+    #[cfg(unix)]
+    Fcntl,
+    /// Open file description locks over the whole file, through `fcntl(F_OFD_SETLK)`, on
+    /// Linux.
+    ///
+    /// These are record locks too, so NFS clients forward them to the server like [`Fcntl`]
+    /// locks, but they belong to the open file description like `flock` locks: handles opened
+    /// separately exclude each other even within a process, and the lock is only released when
+    /// the last handle sharing it is closed. Shared locks still require the file to be open for
+    /// reading, and exclusive locks for writing.
+    ///
+    /// [`Fcntl`]: #variant.Fcntl
+    #[cfg(target_os = "linux")]
+    Ofd,
+    /// Marker files in a sidecar directory, created atomically with `O_EXCL` and polled for.
+    ///
+    /// This is the mechanism of platforms without file locks, such as WASI, and works on any
+    /// file system which supports `O_EXCL`. It is slower than native locks, and the locks are
+    /// *not* released when the handle is closed, only when unlocked or when the process exits.
+    /// See [`set_emulation_dir`] for where the markers are kept. Only whole-file operations are
+    /// affected.
+    ///
+    /// [`set_emulation_dir`]: fn.set_emulation_dir.html
+    Emulated,
+}
+
+impl Backend {
+    fn from_u8(value: u8) -> Backend {
+        match value {
+            1 => Backend::Noop,
+            #[cfg(unix)]
+            2 => Backend::Fcntl,
+            #[cfg(target_os = "linux")]
+            3 => Backend::Ofd,
+            4 => Backend::Emulated,
+            _ => Backend::Native,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Backend::Native => 0,
+            Backend::Noop => 1,
+            #[cfg(unix)]
+            Backend::Fcntl => 2,
+            #[cfg(target_os = "linux")]
+            Backend::Ofd => 3,
+            Backend::Emulated => 4,
+        }
+    }
+
+    /// Return what the locks of this backend provide.
+    ///
+    /// Example:
+    /// ```
+    /// use advisory_lock::Backend;
+    ///
+    /// assert!(Backend::Native.capabilities().is_cross_process());
+    /// assert!(!Backend::Noop.capabilities().is_cross_process());
+    /// ```
+    pub fn capabilities(self) -> BackendCapabilities {
+        let capabilities = BackendCapabilities::new();
+        match self {
+            Backend::Noop => capabilities.cross_process(false),
+            // Markers are polled for.
+            Backend::Emulated => capabilities.blocking(false),
+            _ => capabilities,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Native => "native",
+            Backend::Noop => "noop",
+            #[cfg(unix)]
+            Backend::Fcntl => "fcntl",
+            #[cfg(target_os = "linux")]
+            Backend::Ofd => "ofd",
+            Backend::Emulated => "emulated",
+        })
+    }
+}
+
+/// The error returned when parsing an unknown [`Backend`] name.
+///
+/// [`Backend`]: enum.Backend.html
+#[derive(Clone, Debug)]
+pub struct ParseBackendError(String);
+
+impl fmt::Display for ParseBackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown lock backend `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParseBackendError {}
+
+impl FromStr for Backend {
+    type Err = ParseBackendError;
+
+    fn from_str(s: &str) -> Result<Backend, ParseBackendError> {
+        match s {
+            "native" => Ok(Backend::Native),
+            "noop" => Ok(Backend::Noop),
+            #[cfg(unix)]
+            "fcntl" => Ok(Backend::Fcntl),
+            #[cfg(target_os = "linux")]
+            "ofd" => Ok(Backend::Ofd),
+            "emulated" => Ok(Backend::Emulated),
+            _ => Err(ParseBackendError(s.to_owned())),
+        }
+    }
+}
+
+static DEFAULT_BACKEND: AtomicU8 = AtomicU8::new(0);
+
+/// Select the backend used by every lock operation of this process.
+pub fn set_default_backend(backend: Backend) {
+    DEFAULT_BACKEND.store(backend.to_u8(), Ordering::Relaxed);
+}
+
+/// Return the backend used by every lock operation of this process.
+pub fn default_backend() -> Backend {
+    Backend::from_u8(DEFAULT_BACKEND.load(Ordering::Relaxed))
+}
+
+/// Acquires the whole-file lock of the raw handle with the mechanism of `backend`.
+pub(crate) fn lock_file(
+    backend: Backend,
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    match backend {
+        Backend::Noop => Ok(()),
+        #[cfg(unix)]
+        Backend::Fcntl => sys::lock_process(handle, file_lock_mode, immediate),
+        #[cfg(target_os = "linux")]
+        Backend::Ofd => sys::lock_description(handle, file_lock_mode, immediate),
+        Backend::Emulated => emulated::lock(handle, file_lock_mode, immediate),
+        _ => sys::lock_file(handle, file_lock_mode, immediate),
+    }
+}
+
+/// Releases the whole-file lock of the raw handle with the mechanism of `backend`.
+pub(crate) fn unlock_file(backend: Backend, handle: sys::Handle) -> Result<(), FileLockError> {
+    match backend {
+        Backend::Noop => Ok(()),
+        #[cfg(unix)]
+        Backend::Fcntl => sys::unlock_process(handle),
+        #[cfg(target_os = "linux")]
+        Backend::Ofd => sys::unlock_description(handle),
+        Backend::Emulated => emulated::unlock(handle),
+        _ => sys::unlock_file(handle),
+    }
+}
+
+/// The mechanism the lock of a file is actually held with.
+///
+/// The [`Native`] backend picks the mechanism per file: on Windows, file systems which don't
+/// support `LockFileEx` (some network redirectors and FAT variants) are locked with a kernel
+/// mutex named after the canonical path of the file instead. Such a mutex only excludes the
+/// processes of the same machine and session, and is always exclusive, even for shared locks.
+/// It also belongs to the thread which acquired it, so it must be released by that thread.
+///
+/// [`Native`]: enum.Backend.html#variant.Native
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[non_exhaustive]
+pub enum LockMechanism {
+    /// The file lock of the platform: `flock` on Unix and `LockFileEx` on Windows.
+    FileLock,
+    /// A named kernel mutex derived from the path of the file, on Windows.
+    NamedMutex,
+    /// A classic `fcntl` record lock over the whole file, with the [`Fcntl`] backend on Unix.
+    ///
+    /// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+    RecordLock,
+    /// An open file description lock over the whole file, with the [`Ofd`] backend on Linux.
+    ///
+    /// [`Ofd`]: enum.Backend.html#variant.Ofd
+    DescriptionLock,
+    /// Marker files in a sidecar directory, on WASI or with the [`Emulated`] backend.
+    ///
+    /// [`Emulated`]: enum.Backend.html#variant.Emulated
+    SidecarFile,
+}
+
+/// Return the mechanism the lock of `file` is held with.
+///
+/// Files which aren't locked report the file lock of the platform.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{lock_mechanism, AdvisoryFileLock, FileLockMode, LockMechanism};
+///
+/// let file = File::create("mechanism.lock")?;
+/// AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+/// assert_eq!(lock_mechanism(&file), LockMechanism::FileLock);
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("mechanism.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn lock_mechanism(file: &File) -> LockMechanism {
+    match default_backend() {
+        #[cfg(unix)]
+        Backend::Fcntl => LockMechanism::RecordLock,
+        #[cfg(target_os = "linux")]
+        Backend::Ofd => LockMechanism::DescriptionLock,
+        Backend::Emulated => LockMechanism::SidecarFile,
+        _ => sys::lock_mechanism(sys::handle(file)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_names_round_trip() {
+        for backend in [
+            Backend::Native,
+            Backend::Noop,
+            #[cfg(unix)]
+            Backend::Fcntl,
+            #[cfg(target_os = "linux")]
+            Backend::Ofd,
+            Backend::Emulated,
+        ] {
+            assert_eq!(backend.to_string().parse::<Backend>().unwrap(), backend);
+            assert_eq!(Backend::from_u8(backend.to_u8()), backend);
+        }
+        assert!("nope".parse::<Backend>().is_err());
+        assert_eq!(default_backend(), Backend::Native);
+    }
+
+    /// Fork a child which opens the file at `path`, locks it with `lock` and kills itself, and
+    /// wait for it.
+    #[cfg(unix)]
+    fn die_holding_lock(path: &std::path::Path, lock: fn(libc::c_int) -> bool) {
+        use std::os::unix::ffi::OsStrExt;
+
+        // Everything the child needs is allocated before forking.
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        match unsafe { libc::fork() } {
+            0 => unsafe {
+                let fd = libc::open(path.as_ptr(), libc::O_RDWR);
+                if fd < 0 || !lock(fd) {
+                    libc::_exit(1);
+                }
+                libc::kill(libc::getpid(), libc::SIGKILL);
+                libc::_exit(1);
+            },
+            -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGKILL);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn locks_are_released_when_the_holder_is_killed() {
+        use crate::{sys, FileLockGuard, FileLockMode};
+        use std::fs::{File, OpenOptions};
+
+        let mut test_file = std::env::temp_dir();
+        test_file.push("backend_killed_holder");
+        File::create(&test_file).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&test_file)
+            .unwrap();
+
+        die_holding_lock(&test_file, |fd| {
+            sys::lock_file(fd, FileLockMode::Exclusive, true).is_ok()
+        });
+        FileLockGuard::try_lock(&file, FileLockMode::Exclusive)
+            .unwrap()
+            .unlock()
+            .unwrap();
+
+        die_holding_lock(&test_file, |fd| {
+            sys::lock_process(fd, FileLockMode::Exclusive, true).is_ok()
+        });
+        FileLockGuard::try_lock_per_process(&file, FileLockMode::Exclusive)
+            .unwrap()
+            .unlock()
+            .unwrap();
+
+        // The `fcntl` backend locks the file for other processes only.
+        let handle = sys::handle(&file);
+        lock_file(Backend::Fcntl, handle, FileLockMode::Exclusive, true).unwrap();
+        sys::lock_file(handle, FileLockMode::Exclusive, true).unwrap();
+        sys::unlock_file(handle).unwrap();
+        die_holding_lock(&test_file, |fd| {
+            matches!(
+                sys::lock_process(fd, FileLockMode::Shared, true),
+                Err(crate::FileLockError::AlreadyLocked)
+            )
+        });
+        unlock_file(Backend::Fcntl, handle).unwrap();
+
+        // Open file description locks exclude other handles of the same process too.
+        #[cfg(target_os = "linux")]
+        {
+            die_holding_lock(&test_file, |fd| {
+                sys::lock_description(fd, FileLockMode::Exclusive, true).is_ok()
+            });
+            lock_file(Backend::Ofd, handle, FileLockMode::Exclusive, true).unwrap();
+            let other = OpenOptions::new().read(true).open(&test_file).unwrap();
+            assert!(matches!(
+                lock_file(
+                    Backend::Ofd,
+                    sys::handle(&other),
+                    FileLockMode::Shared,
+                    true
+                ),
+                Err(crate::FileLockError::AlreadyLocked)
+            ));
+            drop(other);
+            unlock_file(Backend::Ofd, handle).unwrap();
+        }
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn record_lock_deadlocks_are_detected() {
+        use crate::{sys, FileLockError, FileLockMode};
+        use std::fs::OpenOptions;
+
+        let open = |name: &str| {
+            let mut path = std::env::temp_dir();
+            path.push(name);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap();
+            (path, file)
+        };
+        let (path_a, file_a) = open("backend_deadlock_a");
+        let (path_b, file_b) = open("backend_deadlock_b");
+        let (a, b) = (sys::handle(&file_a), sys::handle(&file_b));
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+
+        sys::lock_process(a, FileLockMode::Exclusive, true).unwrap();
+        let child = match unsafe { libc::fork() } {
+            0 => unsafe {
+                // Hold `b`, then wait for `a`: whichever process closes the cycle is told.
+                if sys::lock_process(b, FileLockMode::Exclusive, true).is_err() {
+                    libc::_exit(1);
+                }
+                libc::write(pipe[1], [0u8].as_ptr().cast(), 1);
+                match sys::lock_process(a, FileLockMode::Exclusive, false) {
+                    Err(FileLockError::DeadlockDetected) => libc::_exit(42),
+                    _ => libc::_exit(0),
+                }
+            },
+            -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+            child => child,
+        };
+        let mut byte = 0u8;
+        assert_eq!(
+            unsafe { libc::read(pipe[0], (&mut byte as *mut u8).cast(), 1) },
+            1
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let result = sys::lock_process(b, FileLockMode::Exclusive, false);
+        sys::unlock_process(a).unwrap();
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(
+            matches!(result, Err(FileLockError::DeadlockDetected))
+                || libc::WEXITSTATUS(status) == 42
+        );
+
+        unsafe {
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+        drop((file_a, file_b));
+        std::fs::remove_file(&path_a).unwrap();
+        std::fs::remove_file(&path_b).unwrap();
+    }
+}
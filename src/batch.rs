@@ -0,0 +1,390 @@
+use std::error::Error;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::{fmt, io};
+
+use crate::{
+    lock_handle, sys, unlock_handle, AdvisoryFileLock, FileId, FileLockError, FileLockErrorKind,
+    FileLockMode,
+};
+
+/// A prepared set of files which are locked and unlocked together.
+///
+/// The files are sorted by their [`FileId`] once, when the batch is created, and every pass over
+/// the set reuses that order, so processes batching overlapping sets acquire the locks in the
+/// same order and can't deadlock each other. This is also cheaper than locking each file through
+/// the trait when the same set is locked repeatedly.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{FileLockMode, LockBatch};
+///
+/// let shards = [File::create("shard-0.txt")?, File::create("shard-1.txt")?];
+/// let batch = LockBatch::new(&shards)?;
+///
+/// batch.lock(FileLockMode::Exclusive)?;
+/// // ... update every shard ...
+/// batch.unlock()?;
+/// #
+/// # std::fs::remove_file("shard-0.txt")?;
+/// # std::fs::remove_file("shard-1.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct LockBatch<'a> {
+    handles: Vec<(FileId, sys::Handle)>,
+    files: PhantomData<&'a File>,
+}
+
+impl<'a> LockBatch<'a> {
+    /// Prepare a batch over the given files.
+    pub fn new<I>(files: I) -> io::Result<LockBatch<'a>>
+    where
+        I: IntoIterator<Item = &'a File>,
+    {
+        let mut handles = files
+            .into_iter()
+            .map(|file| {
+                let handle = sys::handle(file);
+                Ok((sys::file_id(handle)?, handle))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        handles.sort_by_key(|(file_id, _)| *file_id);
+
+        Ok(LockBatch {
+            handles,
+            files: PhantomData,
+        })
+    }
+
+    /// Return the number of files in the batch.
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Return whether the batch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Acquire the locks of every file, blocking on each in turn.
+    ///
+    /// If any acquisition fails, the locks acquired so far are released before returning.
+    pub fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, false)
+    }
+
+    /// Try to acquire the locks of every file without blocking.
+    ///
+    /// If any acquisition fails, the locks acquired so far are released before returning.
+    pub fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, true)
+    }
+
+    /// Release the locks of every file.
+    ///
+    /// Every file is unlocked even if some of them fail, and the first error is returned.
+    pub fn unlock(&self) -> Result<(), FileLockError> {
+        release(&self.handles)
+    }
+
+    fn acquire(&self, file_lock_mode: FileLockMode, immediate: bool) -> Result<(), FileLockError> {
+        for (index, (_, handle)) in self.handles.iter().enumerate() {
+            if let Err(err) = lock_handle(*handle, file_lock_mode, immediate) {
+                let _ = release(&self.handles[..index]);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn release(handles: &[(FileId, sys::Handle)]) -> Result<(), FileLockError> {
+    let mut result = Ok(());
+    for (_, handle) in handles.iter().rev() {
+        if let Err(err) = unlock_handle(*handle) {
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+/// Try to lock every file of `files` without blocking, all or nothing.
+///
+/// The files are locked in the given order. If any of them can't be locked, the locks acquired
+/// so far are released, and the error is returned together with the index of the file which
+/// failed: contention is [`FileLockError::AlreadyLocked`] as usual. Otherwise every file is
+/// locked when this returns.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{try_lock_all, AdvisoryFileLock, FileLockErrorKind, FileLockMode};
+///
+/// let (index, data) = (File::create("all-index.txt")?, File::create("all-data.txt")?);
+/// let busy = File::open("all-data.txt")?;
+/// AdvisoryFileLock::lock(&busy, FileLockMode::Shared)?;
+///
+/// let err = try_lock_all(&[&index, &data], FileLockMode::Exclusive).unwrap_err();
+/// assert_eq!((err.index(), err.kind()), (1, FileLockErrorKind::AlreadyLocked));
+/// // The index wasn't left locked.
+/// AdvisoryFileLock::try_lock(&index, FileLockMode::Exclusive)?;
+/// #
+/// # drop((index, data, busy));
+/// # std::fs::remove_file("all-index.txt")?;
+/// # std::fs::remove_file("all-data.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+pub fn try_lock_all(files: &[&File], file_lock_mode: FileLockMode) -> Result<(), LockAllError> {
+    for (index, file) in files.iter().enumerate() {
+        if let Err(error) = AdvisoryFileLock::try_lock(*file, file_lock_mode) {
+            for acquired in files[..index].iter().rev() {
+                let _ = AdvisoryFileLock::unlock(*acquired);
+            }
+            return Err(LockAllError { index, error });
+        }
+    }
+    Ok(())
+}
+
+/// Lock every file of `files`, blocking on each in turn, all or nothing.
+///
+/// The files are locked in the order of their [`FileId`] rather than in the given order, so
+/// processes locking overlapping sets, each listing the files in its own order, can't deadlock
+/// each other. If any acquisition fails, the locks acquired so far are released, and the error
+/// is returned together with the index of the file which failed, in the given slice.
+///
+/// A file may be passed several times through the same handle. Different handles to the same
+/// file would exclude each other, so the later one fails with an error of kind `InvalidInput`
+/// instead of waiting forever.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::thread;
+/// use advisory_lock::{lock_all_ordered, AdvisoryFileLock, FileLockMode};
+///
+/// # File::create("ordered-a.txt")?;
+/// # File::create("ordered-b.txt")?;
+/// let transfer = |from: &'static str, to: &'static str| {
+///     thread::spawn(move || -> std::io::Result<()> {
+///         let (from, to) = (File::open(from)?, File::open(to)?);
+///         lock_all_ordered(&[&from, &to], FileLockMode::Exclusive)?;
+///         // ... move data between the files ...
+///         AdvisoryFileLock::unlock(&from)?;
+///         AdvisoryFileLock::unlock(&to)?;
+///         Ok(())
+///     })
+/// };
+/// let there = transfer("ordered-a.txt", "ordered-b.txt");
+/// let back = transfer("ordered-b.txt", "ordered-a.txt");
+/// there.join().unwrap()?;
+/// back.join().unwrap()?;
+/// #
+/// # std::fs::remove_file("ordered-a.txt")?;
+/// # std::fs::remove_file("ordered-b.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileId`]: struct.FileId.html
+pub fn lock_all_ordered(files: &[&File], file_lock_mode: FileLockMode) -> Result<(), LockAllError> {
+    let mut order = Vec::with_capacity(files.len());
+    for (index, file) in files.iter().enumerate() {
+        let file_id = FileId::of(file).map_err(|err| LockAllError {
+            index,
+            error: FileLockError::Io(err),
+        })?;
+        order.push((file_id, index));
+    }
+    order.sort_unstable();
+
+    let mut locked: Vec<usize> = Vec::with_capacity(order.len());
+    for (position, &(file_id, index)) in order.iter().enumerate() {
+        let error = match position.checked_sub(1).map(|previous| order[previous]) {
+            Some((previous_id, previous)) if previous_id == file_id => {
+                if sys::handle(files[previous]) == sys::handle(files[index]) {
+                    continue;
+                }
+                FileLockError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the same file is passed through different handles",
+                ))
+            }
+            _ => match AdvisoryFileLock::lock(files[index], file_lock_mode) {
+                Ok(()) => {
+                    locked.push(index);
+                    continue;
+                }
+                Err(error) => error,
+            },
+        };
+        for &acquired in locked.iter().rev() {
+            let _ = AdvisoryFileLock::unlock(files[acquired]);
+        }
+        return Err(LockAllError { index, error });
+    }
+    Ok(())
+}
+
+/// The error of locking several files at once, with the index of the file which failed.
+///
+/// It is returned by [`try_lock_all`] and [`lock_all_ordered`].
+///
+/// [`try_lock_all`]: fn.try_lock_all.html
+/// [`lock_all_ordered`]: fn.lock_all_ordered.html
+#[derive(Debug)]
+pub struct LockAllError {
+    index: usize,
+    error: FileLockError,
+}
+
+impl LockAllError {
+    /// Return the index of the file which couldn't be locked, in the slice passed in.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Return the underlying error.
+    pub fn error(&self) -> &FileLockError {
+        &self.error
+    }
+
+    /// Return the kind of the underlying error.
+    pub fn kind(&self) -> FileLockErrorKind {
+        self.error.kind()
+    }
+
+    /// Unwrap the underlying error, dropping the index.
+    pub fn into_error(self) -> FileLockError {
+        self.error
+    }
+}
+
+impl fmt::Display for LockAllError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to lock file #{}: {}", self.index, self.error)
+    }
+}
+
+impl Error for LockAllError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<LockAllError> for FileLockError {
+    fn from(err: LockAllError) -> FileLockError {
+        err.error
+    }
+}
+
+/// Converts the error into an `io::Error` of the kind the underlying error converts to, whose
+/// message includes the index.
+impl From<LockAllError> for io::Error {
+    fn from(err: LockAllError) -> io::Error {
+        io::Error::new(err.error.io_kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn batch_is_all_or_nothing() {
+        let paths: Vec<_> = (0..3)
+            .map(|i| {
+                let mut path = temp_dir();
+                path.push(format!("batch_shard_{}", i));
+                path
+            })
+            .collect();
+        let files: Vec<_> = paths.iter().map(|p| File::create(p).unwrap()).collect();
+        let batch = LockBatch::new(&files).unwrap();
+        assert_eq!(batch.len(), 3);
+
+        let contender = File::open(&paths[1]).unwrap();
+        AdvisoryFileLock::lock(&contender, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            batch.try_lock(FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        for path in &paths {
+            let probe = File::open(path).unwrap();
+            if *path != paths[1] {
+                AdvisoryFileLock::try_lock(&probe, FileLockMode::Exclusive).unwrap();
+            }
+        }
+        AdvisoryFileLock::unlock(&contender).unwrap();
+
+        batch.lock(FileLockMode::Exclusive).unwrap();
+        assert!(AdvisoryFileLock::try_lock(&contender, FileLockMode::Shared).is_err());
+        batch.unlock().unwrap();
+        AdvisoryFileLock::try_lock(&contender, FileLockMode::Exclusive).unwrap();
+
+        // The same through the free function, which reports where it failed.
+        let refs: Vec<_> = files.iter().collect();
+        let err = try_lock_all(&refs, FileLockMode::Shared).unwrap_err();
+        assert_eq!(err.index(), 1);
+        assert!(matches!(err.error(), FileLockError::AlreadyLocked));
+        AdvisoryFileLock::try_lock(&File::open(&paths[0]).unwrap(), FileLockMode::Exclusive)
+            .unwrap();
+        AdvisoryFileLock::unlock(&contender).unwrap();
+        try_lock_all(&refs, FileLockMode::Shared).unwrap();
+        assert!(AdvisoryFileLock::try_lock(&contender, FileLockMode::Exclusive).is_err());
+
+        drop((files, contender));
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn ordered_locking_cannot_deadlock() {
+        let paths: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let mut path = temp_dir();
+                path.push(format!("batch_ordered_{}", name));
+                File::create(&path).unwrap();
+                path
+            })
+            .collect();
+
+        // Threads stand for processes, as separately opened handles exclude each other.
+        let workers: Vec<_> = (0..3)
+            .map(|shift| {
+                let mut paths = paths.clone();
+                paths.rotate_left(shift);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        let files: Vec<_> = paths.iter().map(|p| File::open(p).unwrap()).collect();
+                        let refs: Vec<_> = files.iter().collect();
+                        lock_all_ordered(&refs, FileLockMode::Exclusive).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let file = File::open(&paths[0]).unwrap();
+        let twin = File::open(&paths[0]).unwrap();
+        lock_all_ordered(&[&file, &file], FileLockMode::Shared).unwrap();
+        let err = lock_all_ordered(&[&twin, &file], FileLockMode::Shared).unwrap_err();
+        assert_eq!(err.kind(), FileLockErrorKind::Io);
+        AdvisoryFileLock::unlock(&file).unwrap();
+
+        drop((file, twin));
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
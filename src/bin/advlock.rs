@@ -0,0 +1,218 @@
+//! Run a command while holding an advisory lock, like the `flock(1)` utility.
+//!
+//! ```text
+//! advlock [OPTIONS] PATH COMMAND [ARGS...]
+//! ```
+//!
+//! The file at `PATH` is created if needed and locked, `COMMAND` runs with the lock held, and the
+//! lock is released when it exits. The exit status of the command is passed through.
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{self, Command, ExitStatus};
+use std::time::Duration;
+
+use advisory_lock::{Backend, FileLockError, FileLockMode, LockOptions};
+
+const USAGE: &str = "\
+Usage: advlock [OPTIONS] PATH COMMAND [ARGS...]
+
+Lock the file at PATH, creating it if needed, run COMMAND while holding the lock,
+and release the lock when it exits.
+
+Options:
+  -s, --shared                   acquire a shared lock
+  -x, --exclusive                acquire an exclusive lock (the default)
+  -n, --nonblock                 fail rather than wait if the lock is held elsewhere
+  -w, --timeout SECONDS          fail if the lock isn't acquired within SECONDS
+  -E, --conflict-exit-code CODE  exit with CODE when the lock isn't acquired (default: 1)
+  -b, --backend NAME             lock with the backend NAME (native, fcntl, ofd, emulated)
+  -h, --help                     print this help
+";
+
+/// The exit code of invalid invocations, from `sysexits.h`.
+const EX_USAGE: i32 = 64;
+
+/// The parsed command line.
+#[derive(Debug)]
+struct Args {
+    options: LockOptions,
+    conflict_exit_code: i32,
+    path: PathBuf,
+    command: Vec<OsString>,
+}
+
+#[derive(Debug)]
+enum ArgsError {
+    Help,
+    Invalid(String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::Help => f.write_str(USAGE),
+            ArgsError::Invalid(message) => write!(f, "advlock: {}\n\n{}", message, USAGE),
+        }
+    }
+}
+
+fn parse_args<I: IntoIterator<Item = OsString>>(args: I) -> Result<Args, ArgsError> {
+    let mut args = args.into_iter();
+    let mut options = LockOptions::new();
+    let mut conflict_exit_code = 1;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let flag = match arg.to_str() {
+            Some(flag) if positional.is_empty() && flag.starts_with('-') => flag.to_owned(),
+            _ => {
+                positional.push(arg);
+                // Everything after the path belongs to the command.
+                if positional.len() == 2 {
+                    positional.extend(args.by_ref());
+                }
+                continue;
+            }
+        };
+        let mut value = |name: &str| {
+            args.next()
+                .and_then(|value| value.into_string().ok())
+                .ok_or_else(|| ArgsError::Invalid(format!("{} requires a value", name)))
+        };
+        match flag.as_str() {
+            "-s" | "--shared" => options = options.mode(FileLockMode::Shared),
+            "-x" | "--exclusive" => options = options.mode(FileLockMode::Exclusive),
+            "-n" | "--nonblock" => options = options.blocking(false),
+            "-w" | "--timeout" => {
+                let seconds = value(&flag)?;
+                let timeout = seconds
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| ArgsError::Invalid(format!("invalid timeout `{}`", seconds)))?;
+                options = options.timeout(timeout);
+            }
+            "-E" | "--conflict-exit-code" => {
+                let code = value(&flag)?;
+                conflict_exit_code = code
+                    .parse()
+                    .map_err(|_| ArgsError::Invalid(format!("invalid exit code `{}`", code)))?;
+            }
+            "-b" | "--backend" => {
+                let backend = value(&flag)?
+                    .parse::<Backend>()
+                    .map_err(|err| ArgsError::Invalid(err.to_string()))?;
+                options = options.backend(backend);
+            }
+            "-h" | "--help" => return Err(ArgsError::Help),
+            "--" => positional.extend(args.by_ref()),
+            _ => return Err(ArgsError::Invalid(format!("unknown option `{}`", flag))),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let path = positional
+        .next()
+        .ok_or_else(|| ArgsError::Invalid("missing PATH".to_owned()))?;
+    let command: Vec<_> = positional.collect();
+    if command.is_empty() {
+        return Err(ArgsError::Invalid("missing COMMAND".to_owned()));
+    }
+
+    Ok(Args {
+        options,
+        conflict_exit_code,
+        path: path.into(),
+        command,
+    })
+}
+
+/// Return the exit code mirroring `status`, as shells report it.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+fn main() {
+    let args = match parse_args(std::env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(ArgsError::Help) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprint!("{}", err);
+            process::exit(EX_USAGE);
+        }
+    };
+
+    let file = match args.options.open(&args.path) {
+        Ok(file) => file,
+        Err(err @ FileLockError::AlreadyLocked) | Err(err @ FileLockError::Timeout) => {
+            eprintln!("advlock: {}: {}", args.path.display(), err);
+            process::exit(args.conflict_exit_code);
+        }
+        Err(err) => {
+            eprintln!("advlock: {}: {}", args.path.display(), err);
+            process::exit(1);
+        }
+    };
+
+    let status = Command::new(&args.command[0])
+        .args(&args.command[1..])
+        .status();
+    drop(file);
+    match status {
+        Ok(status) => process::exit(exit_code(status)),
+        Err(err) => {
+            eprintln!("advlock: {}: {}", args.command[0].to_string_lossy(), err);
+            // The codes shells use for commands which can't be run or found.
+            process::exit(if err.kind() == std::io::ErrorKind::NotFound {
+                127
+            } else {
+                126
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn arguments_are_parsed_like_flock() {
+        let args = parse(&["-s", "-w", "2.5", "-E", "3", "db.lock", "backup", "-n"]).unwrap();
+        assert_eq!(args.options.get_mode(), FileLockMode::Shared);
+        assert_eq!(args.conflict_exit_code, 3);
+        assert_eq!(args.path, PathBuf::from("db.lock"));
+        // Options after the command are the command's.
+        assert_eq!(args.command, ["backup", "-n"]);
+
+        let args = parse(&["--", "-odd.lock", "true"]).unwrap();
+        assert_eq!(args.options.get_mode(), FileLockMode::Exclusive);
+        assert_eq!(args.path, PathBuf::from("-odd.lock"));
+
+        assert!(matches!(parse(&["--help"]), Err(ArgsError::Help)));
+        assert!(matches!(parse(&["db.lock"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(
+            parse(&["-w", "soon", "db.lock", "true"]),
+            Err(ArgsError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse(&["--frobnicate", "db.lock", "true"]),
+            Err(ArgsError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+//! Chaos mode, which shakes out races by delaying lock operations at random.
+//!
+//! Once [`enable`]d, every lock, try_lock and unlock performed through the crate in this process
+//! first sleeps for a pseudo-random duration up to the configured maximum. Delays are drawn from
+//! a generator seeded by the caller, so a failing interleaving can be retried with the same seed
+//! (though thread scheduling still adds its own nondeterminism).
+//!
+//! This module is only available with the `test-util` feature.
+//!
+//! Example:
+//! ```
+//! use std::time::Duration;
+//! use advisory_lock::chaos;
+//!
+//! chaos::enable(0x5eed, Duration::from_millis(2));
+//! // ... run the code under test from several threads ...
+//! chaos::disable();
+//! ```
+use std::thread;
+use std::time::Duration;
+
+use crate::rng::Rng;
+use crate::sync::{self, AtomicBool, Mutex, MutexGuard, Ordering};
+
+sync::statics! {
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+    static STATE: Mutex<Option<(Rng, Duration)>> = Mutex::new(None);
+}
+
+/// Start delaying every lock operation by up to `max_delay`, drawing delays from `seed`.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::time::{Duration, Instant};
+/// use advisory_lock::{chaos, AdvisoryFileLock, FileLockMode};
+///
+/// let file = File::create("chaos.lock")?;
+/// let lock_and_unlock = || -> Result<Duration, Box<dyn std::error::Error>> {
+///     let start = Instant::now();
+///     for _ in 0..10 {
+///         AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+///         AdvisoryFileLock::unlock(&file)?;
+///     }
+///     Ok(start.elapsed())
+/// };
+///
+/// chaos::enable(0x5eed, Duration::from_millis(10));
+/// let shaken = lock_and_unlock()?;
+/// assert!(shaken >= Duration::from_millis(20));
+///
+/// chaos::disable();
+/// assert!(lock_and_unlock()? < shaken);
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("chaos.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn enable(seed: u64, max_delay: Duration) {
+    *state() = Some((Rng::new(seed), max_delay));
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop delaying lock operations.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+    *state() = None;
+}
+
+/// Return whether chaos mode is enabled.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+fn state() -> MutexGuard<'static, Option<(Rng, Duration)>> {
+    STATE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Sleep for a random duration if chaos mode is enabled.
+pub(crate) fn shake() {
+    if !is_enabled() {
+        return;
+    }
+
+    if let Some(delay) = next_delay(&mut state()) {
+        thread::sleep(delay);
+    }
+}
+
+/// Draw the next delay from `state`, or return `None` if chaos mode is disabled.
+fn next_delay(state: &mut Option<(Rng, Duration)>) -> Option<Duration> {
+    state.as_mut().map(|(rng, max_delay)| rng.delay(*max_delay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_seed_replays_the_same_delays() {
+        // Exercise the generator without enabling chaos for the tests running concurrently.
+        let max_delay = Duration::from_millis(2);
+        let draw = |seed| {
+            let mut state = Some((Rng::new(seed), max_delay));
+            (0..20)
+                .map(|_| next_delay(&mut state).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draw(0x5eed), draw(0x5eed));
+        assert_ne!(draw(0x5eed), draw(0x5eee));
+        assert!(draw(0x5eed).iter().all(|delay| *delay <= max_delay));
+        assert!(draw(0x5eed).iter().any(|delay| !delay.is_zero()));
+        assert_eq!(next_delay(&mut None), None);
+    }
+}
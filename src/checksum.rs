@@ -0,0 +1,82 @@
+//! CRC-32 trailers detecting torn or foreign writes to files managed by the crate.
+use crate::FileLockError;
+
+/// Marks the end of a checksummed file.
+const MAGIC: &[u8; 4] = b"ALK1";
+/// The length of the trailer appended to checksummed contents.
+const TRAILER_LEN: usize = 8;
+
+/// The lookup table of the reflected IEEE 802.3 CRC-32 polynomial.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Compute the CRC-32 (IEEE) of `bytes`.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Append the checksum trailer to `contents`.
+pub(crate) fn seal(contents: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(contents.len() + TRAILER_LEN);
+    sealed.extend_from_slice(contents);
+    sealed.extend_from_slice(&crc32(contents).to_le_bytes());
+    sealed.extend_from_slice(MAGIC);
+    sealed
+}
+
+/// Verify and strip the checksum trailer of `sealed`.
+pub(crate) fn unseal(mut sealed: Vec<u8>) -> Result<Vec<u8>, FileLockError> {
+    if sealed.len() < TRAILER_LEN || !sealed.ends_with(MAGIC) {
+        return Err(FileLockError::Corrupted);
+    }
+
+    let len = sealed.len() - TRAILER_LEN;
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&sealed[len..len + 4]);
+    if crc32(&sealed[..len]) != u32::from_le_bytes(checksum) {
+        return Err(FileLockError::Corrupted);
+    }
+
+    sealed.truncate(len);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tampering() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let sealed = seal(b"payload");
+        assert_eq!(unseal(sealed.clone()).unwrap(), b"payload");
+        assert!(matches!(
+            unseal(sealed[..sealed.len() - 1].to_vec()),
+            Err(FileLockError::Corrupted)
+        ));
+        let mut flipped = sealed;
+        flipped[0] ^= 1;
+        assert!(matches!(unseal(flipped), Err(FileLockError::Corrupted)));
+        assert!(matches!(unseal(Vec::new()), Err(FileLockError::Corrupted)));
+    }
+}
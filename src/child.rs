@@ -0,0 +1,125 @@
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command};
+
+use crate::fs::open_locked;
+use crate::ownership::check_per_handle;
+use crate::{default_backend, sys, Backend, FileLockError, FileLockMode};
+
+/// The environment variable through which [`hold_for_child`] tells the child which descriptor
+/// (on Unix) or handle (on Windows) holds the lock.
+///
+/// [`hold_for_child`]: fn.hold_for_child.html
+pub const LOCK_HANDLE_ENV: &str = "ADVISORY_LOCK_HANDLE";
+
+/// Lock the file at `path` and spawn `command` holding the lock, like the `flock(1)` utility.
+///
+/// The child inherits the handle through which the lock was acquired, and this process closes
+/// its own copy once the child is spawned, so the lock is released when the child, and any
+/// descendant it passed the handle to, exits, even if this process exits first. The handle is
+/// announced to the child in the [`LOCK_HANDLE_ENV`] variable, so it can release the lock early
+/// by closing it.
+///
+/// The file is created if needed. On Windows, other threads which spawn processes at the same
+/// time may leak the handle to their children too. The locks of the [`Fcntl`] and [`Emulated`]
+/// backends belong to this process rather than to the handle, so they fail with
+/// `ErrorKind::Unsupported`.
+///
+/// Example:
+/// ```no_run
+/// use std::process::Command;
+/// use advisory_lock::{hold_for_child, FileLockMode};
+///
+/// let mut backup = Command::new("backup-database");
+/// backup.arg("--incremental");
+/// let mut child = hold_for_child("database.lock", FileLockMode::Exclusive, backup)?;
+/// // The lock is held until the backup exits.
+/// child.wait()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`LOCK_HANDLE_ENV`]: constant.LOCK_HANDLE_ENV.html
+/// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+/// [`Emulated`]: enum.Backend.html#variant.Emulated
+pub fn hold_for_child<P: AsRef<Path>>(
+    path: P,
+    file_lock_mode: FileLockMode,
+    command: Command,
+) -> Result<Child, FileLockError> {
+    check_per_handle()?;
+    check_inheritable(default_backend())?;
+    let file = open_locked(path.as_ref(), file_lock_mode, true)?;
+    sys::spawn_inheriting(command, sys::handle(&file), LOCK_HANDLE_ENV).map_err(FileLockError::Io)
+}
+
+/// Fail if the locks of `backend` don't follow the handle into the child.
+fn check_inheritable(backend: Backend) -> Result<(), FileLockError> {
+    if backend == Backend::Emulated {
+        // The marker names this process, so it expires once this process exits.
+        return Err(FileLockError::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the locks of the Emulated backend belong to the process, not to the handle",
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(unix, windows))]
+    use crate::AdvisoryFileLock;
+    #[cfg(any(unix, windows))]
+    use std::{env::temp_dir, fs::File};
+
+    #[test]
+    fn emulated_locks_are_refused() {
+        assert!(matches!(
+            check_inheritable(Backend::Emulated),
+            Err(FileLockError::Io(err)) if err.kind() == io::ErrorKind::Unsupported
+        ));
+        check_inheritable(Backend::Native).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_child_holds_the_lock() {
+        let mut test_file = temp_dir();
+        test_file.push("child_holds");
+        let mut command = Command::new("sh");
+        command.args(["-c", "test -e /dev/fd/$ADVISORY_LOCK_HANDLE && sleep 0.2"]);
+        check_held_by(&test_file, command);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn the_child_holds_the_lock() {
+        use std::os::windows::process::CommandExt;
+
+        let mut test_file = temp_dir();
+        test_file.push("child_holds");
+        let mut command = Command::new("cmd");
+        // Pinging twice waits for about a second.
+        command
+            .raw_arg("/C (if not defined ADVISORY_LOCK_HANDLE exit 1) & ping -n 2 127.0.0.1 >NUL");
+        check_held_by(&test_file, command);
+    }
+
+    /// Spawn `command` holding the lock of `test_file`, and check that it holds it until it
+    /// exits.
+    #[cfg(any(unix, windows))]
+    fn check_held_by(test_file: &Path, command: Command) {
+        let mut child = hold_for_child(test_file, FileLockMode::Exclusive, command).unwrap();
+
+        let other = File::open(test_file).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&other, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert!(child.wait().unwrap().success());
+        AdvisoryFileLock::try_lock(&other, FileLockMode::Exclusive).unwrap();
+
+        drop(other);
+        std::fs::remove_file(test_file).unwrap();
+    }
+}
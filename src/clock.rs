@@ -0,0 +1,122 @@
+//! Time sources for timeouts, backoff and lease expiry.
+//!
+//! The acquisitions which wait a bounded time or retry take a [`Clock`] in their `_with_clock`
+//! variants, such as [`lock_timeout_with_clock`] and [`LockOptions::lock_with_clock`], so tests
+//! can substitute a [`ManualClock`] (with the `test-util` feature) and run deterministically
+//! instead of relying on real sleeps. The other variants use the [`SystemClock`].
+//!
+//! The rest of the crate reads the time of the system directly: watchdogs wait on a condition
+//! variable, and timestamps shared with other processes, such as those of lock files and rate
+//! limiters, are wall-clock times.
+//!
+//! [`lock_timeout_with_clock`]: ../trait.AdvisoryFileLock.html#method.lock_timeout_with_clock
+//! [`LockOptions::lock_with_clock`]: ../struct.LockOptions.html#method.lock_with_clock
+use std::time::{Duration, Instant};
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// Return the current instant.
+    fn now(&self) -> Instant;
+    /// Block the current thread for the given duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The clock of the operating system.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub use manual::ManualClock;
+
+#[cfg(any(test, feature = "test-util"))]
+mod manual {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    /// A clock which only moves when told to.
+    ///
+    /// Sleeping on a `ManualClock` returns immediately after advancing it by the requested
+    /// duration, so polling loops make progress without waiting. Clones share the same time.
+    ///
+    /// This type is only available with the `test-util` feature.
+    #[derive(Clone, Debug)]
+    pub struct ManualClock {
+        start: Instant,
+        elapsed: Arc<Mutex<Duration>>,
+    }
+
+    impl ManualClock {
+        /// Create a clock standing at the current instant.
+        pub fn new() -> ManualClock {
+            ManualClock {
+                start: Instant::now(),
+                elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+            }
+        }
+
+        /// Move the clock forward by the given duration.
+        pub fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap_or_else(|err| err.into_inner()) += duration;
+        }
+
+        /// Return how far the clock has moved since its creation.
+        pub fn elapsed(&self) -> Duration {
+            *self.elapsed.lock().unwrap_or_else(|err| err.into_inner())
+        }
+    }
+
+    impl Default for ManualClock {
+        fn default() -> ManualClock {
+            ManualClock::new()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(5));
+        clock.clone().sleep(Duration::from_secs(60));
+        assert_eq!(clock.now() - start, Duration::from_secs(65));
+        assert_eq!(clock.elapsed(), Duration::from_secs(65));
+    }
+}
@@ -0,0 +1,98 @@
+//! Formats in which [`TypedLockFile`] stores its value.
+//!
+//! [`Json`] is always available with the `json` feature; [`Toml`] and [`Bincode`] are enabled by
+//! the features of the same name. Other formats, including ones which don't go through serde,
+//! can be plugged in by implementing [`Codec`].
+//!
+//! [`TypedLockFile`]: ../struct.TypedLockFile.html
+//! [`Json`]: struct.Json.html
+//! [`Toml`]: struct.Toml.html
+//! [`Bincode`]: struct.Bincode.html
+//! [`Codec`]: trait.Codec.html
+use std::io;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A format in which values of type `T` are stored.
+///
+/// Errors should be reported as [`io::ErrorKind::InvalidData`].
+///
+/// [`io::ErrorKind::InvalidData`]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.InvalidData
+pub trait Codec<T> {
+    /// Serialize `value`.
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>>;
+    /// Deserialize a value from `bytes`.
+    fn decode(&self, bytes: &[u8]) -> io::Result<T>;
+}
+
+impl<T, C: Codec<T> + ?Sized> Codec<T> for &C {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        (**self).encode(value)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<T> {
+        (**self).decode(bytes)
+    }
+}
+
+/// Pretty-printed JSON, through `serde_json`.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Json;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(value).map_err(invalid_data)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(bytes).map_err(invalid_data)
+    }
+}
+
+/// TOML, through the `toml` crate.
+///
+/// TOML documents are tables, so the value must serialize to a struct or a map.
+///
+/// This codec is only available with the `toml` feature.
+#[cfg(feature = "toml")]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Toml;
+
+#[cfg(feature = "toml")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for Toml {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        toml::to_string_pretty(value)
+            .map(String::into_bytes)
+            .map_err(invalid_data)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<T> {
+        let text = std::str::from_utf8(bytes).map_err(invalid_data)?;
+        toml::from_str(text).map_err(invalid_data)
+    }
+}
+
+/// The compact binary format of the `bincode` crate, with its default options.
+///
+/// This codec is only available with the `bincode` feature.
+#[cfg(feature = "bincode")]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        bincode::serialize(value).map_err(invalid_data)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<T> {
+        bincode::deserialize(bytes).map_err(invalid_data)
+    }
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{FileLockError, FileLockErrorKind, FileLockOperation};
+
+/// A [`FileLockError`] together with the file and the operation it occurred on.
+///
+/// A bare `FileLockError` doesn't say which file was involved, which makes for unhelpful logs in
+/// programs locking many files. Attach the context with [`FileLockError::at`].
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{AdvisoryFileLock, FileLockMode, FileLockOperation};
+///
+/// let holder = File::create("context.lock")?;
+/// AdvisoryFileLock::lock(&holder, FileLockMode::Exclusive)?;
+///
+/// let file = File::open("context.lock")?;
+/// let err = AdvisoryFileLock::try_lock(&file, FileLockMode::Shared)
+///     .map_err(|err| err.at("context.lock", FileLockOperation::TryLock))
+///     .unwrap_err();
+/// assert_eq!(
+///     err.to_string(),
+///     "failed to try to lock `context.lock`: the file is already locked"
+/// );
+/// #
+/// # drop((holder, file));
+/// # std::fs::remove_file("context.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileLockError`]: enum.FileLockError.html
+/// [`FileLockError::at`]: enum.FileLockError.html#method.at
+#[derive(Debug)]
+pub struct PathLockError {
+    path: PathBuf,
+    operation: FileLockOperation,
+    error: FileLockError,
+}
+
+impl FileLockError {
+    /// Attach the path of the file and the operation which failed to the error.
+    pub fn at<P: AsRef<Path>>(self, path: P, operation: FileLockOperation) -> PathLockError {
+        PathLockError {
+            path: path.as_ref().to_path_buf(),
+            operation,
+            error: self,
+        }
+    }
+}
+
+impl PathLockError {
+    /// Return the path of the file the operation failed on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the operation which failed.
+    pub fn operation(&self) -> FileLockOperation {
+        self.operation
+    }
+
+    /// Return the underlying error.
+    pub fn error(&self) -> &FileLockError {
+        &self.error
+    }
+
+    /// Return the kind of the underlying error.
+    pub fn kind(&self) -> FileLockErrorKind {
+        self.error.kind()
+    }
+
+    /// Unwrap the underlying error, dropping the context.
+    pub fn into_error(self) -> FileLockError {
+        self.error
+    }
+}
+
+impl fmt::Display for PathLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.operation {
+            FileLockOperation::Lock => "lock",
+            FileLockOperation::TryLock => "try to lock",
+            FileLockOperation::Unlock => "unlock",
+        };
+        write!(
+            f,
+            "failed to {} `{}`: {}",
+            operation,
+            self.path.display(),
+            self.error
+        )
+    }
+}
+
+impl Error for PathLockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<PathLockError> for FileLockError {
+    fn from(err: PathLockError) -> FileLockError {
+        err.error
+    }
+}
+
+/// Converts the error into an `io::Error` of the kind the underlying error converts to, whose
+/// message includes the context.
+impl From<PathLockError> for io::Error {
+    fn from(err: PathLockError) -> io::Error {
+        io::Error::new(err.error.io_kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_is_reported() {
+        let err = FileLockError::Io(io::ErrorKind::PermissionDenied.into())
+            .at("/var/lock/app.lock", FileLockOperation::Unlock);
+        assert_eq!(err.path(), Path::new("/var/lock/app.lock"));
+        assert_eq!(err.kind(), FileLockErrorKind::Io);
+        assert!(err
+            .to_string()
+            .starts_with("failed to unlock `/var/lock/app.lock`: I/O error"));
+        assert!(err.source().is_some());
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("app.lock"));
+    }
+}
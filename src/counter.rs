@@ -0,0 +1,127 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::fs::{open_exclusive, open_locked, read_all, replace_contents};
+use crate::{FileLockError, FileLockMode};
+
+/// An integer stored in a file and updated under an exclusive lock, shared between processes.
+///
+/// The value is stored as decimal text followed by a newline. A file which doesn't exist or is
+/// empty holds zero. Each update reads the whole file, truncates it, writes the new value and
+/// syncs it to disk before releasing the lock, so an update returning successfully is never
+/// lost, and no two processes ever observe the same value returned by [`increment`].
+///
+/// Example:
+/// ```
+/// use advisory_lock::Counter;
+///
+/// let counter = Counter::new("builds.count");
+/// assert_eq!(counter.increment()?, 1);
+/// assert_eq!(counter.add(10)?, 11);
+/// assert_eq!(counter.get()?, 11);
+/// #
+/// # std::fs::remove_file("builds.count")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`increment`]: struct.Counter.html#method.increment
+#[derive(Debug, Clone)]
+pub struct Counter {
+    path: PathBuf,
+}
+
+impl Counter {
+    /// Create a counter stored in the file at `path`, which is created on the first update.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Counter {
+        Counter { path: path.into() }
+    }
+
+    /// Return the path of the file storing the counter.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the current value while holding a shared lock on the file.
+    pub fn get(&self) -> Result<u64, FileLockError> {
+        match open_locked(&self.path, FileLockMode::Shared, false) {
+            Ok(file) => parse(&read_all(&file)?),
+            Err(FileLockError::Io(err)) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Add one to the counter, returning the new value.
+    pub fn increment(&self) -> Result<u64, FileLockError> {
+        self.add(1)
+    }
+
+    /// Add `delta` to the counter, returning the new value.
+    ///
+    /// Fails with an error of kind `InvalidData`, leaving the file untouched, if the file
+    /// doesn't hold a number or the new value would overflow.
+    pub fn add(&self, delta: u64) -> Result<u64, FileLockError> {
+        let file = open_exclusive(&self.path)?;
+        let value = parse(&read_all(&file)?)?
+            .checked_add(delta)
+            .ok_or_else(|| invalid_data("counter overflow"))?;
+        replace_contents(&file, format!("{}\n", value).as_bytes(), true)?;
+        Ok(value)
+    }
+}
+
+fn parse(contents: &[u8]) -> Result<u64, FileLockError> {
+    let text =
+        std::str::from_utf8(contents).map_err(|_| invalid_data("counter is not a number"))?;
+    match text.trim() {
+        "" => Ok(0),
+        text => text
+            .parse()
+            .map_err(|_| invalid_data("counter is not a number")),
+    }
+}
+
+fn invalid_data(message: &str) -> FileLockError {
+    FileLockError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::thread;
+
+    #[test]
+    fn increments_are_never_lost() {
+        let mut test_file = temp_dir();
+        test_file.push("counter");
+        let _ = std::fs::remove_file(&test_file);
+        let counter = Counter::new(&test_file);
+        assert_eq!(counter.get().unwrap(), 0);
+
+        let mut values: Vec<u64> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..25)
+                            .map(|_| counter.increment().unwrap())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        values.sort_unstable();
+        assert_eq!(values, (1..=100).collect::<Vec<_>>());
+        assert_eq!(counter.get().unwrap(), 100);
+
+        std::fs::write(&test_file, "many").unwrap();
+        assert!(counter.increment().is_err());
+        std::fs::write(&test_file, format!("{}\n", u64::MAX)).unwrap();
+        assert!(counter.increment().is_err());
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
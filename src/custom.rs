@@ -0,0 +1,386 @@
+use std::fs::File;
+use std::time::Duration;
+
+use crate::clock::SystemClock;
+use crate::{
+    lock_handle_with, retry, sys, unlock_handle_with, AdvisoryFileLock, Backend,
+    ExponentialBackoff, FileLockError, FileLockMode,
+};
+
+/// A mechanism to lock with, for locks the crate doesn't provide itself.
+///
+/// Implement it for testing fakes or remote coordinators, and wrap them in a [`BackendLock`] to
+/// get the guards, timeouts and retries of [`AdvisoryFileLock`] for free. The mechanisms of the
+/// crate are available as backends too, through [`FileBackend`].
+///
+/// The methods follow the contract of [`AdvisoryFileLock`]: contention is reported as
+/// [`FileLockError::AlreadyLocked`], and locking a held lock converts it to the requested mode.
+///
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+/// [`BackendLock`]: struct.BackendLock.html
+/// [`FileBackend`]: struct.FileBackend.html
+/// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+pub trait LockBackend {
+    /// Acquire the lock, blocking until it is available.
+    ///
+    /// Backends which can't block declare so in their [`capabilities`], and this is never
+    /// called; it may fail with [`FileLockError::Unsupported`].
+    ///
+    /// [`capabilities`]: #method.capabilities
+    /// [`FileLockError::Unsupported`]: enum.FileLockError.html#variant.Unsupported
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError>;
+    /// Acquire the lock if it is available, or fail with [`FileLockError::AlreadyLocked`].
+    ///
+    /// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError>;
+    /// Release the lock.
+    fn unlock(&self) -> Result<(), FileLockError>;
+    /// Return what the locks of the backend provide; all of it by default.
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities::new()
+    }
+}
+
+/// What the locks of a [`LockBackend`] provide.
+///
+/// Example:
+/// ```
+/// use advisory_lock::BackendCapabilities;
+///
+/// // A lease service which only grants exclusive locks, and can't wait for them.
+/// let capabilities = BackendCapabilities::new().shared(false).blocking(false);
+/// assert!(!capabilities.is_shared());
+/// assert!(capabilities.is_cross_process());
+/// ```
+///
+/// [`LockBackend`]: trait.LockBackend.html
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct BackendCapabilities {
+    shared: bool,
+    blocking: bool,
+    cross_process: bool,
+    crash_safe: bool,
+}
+
+impl BackendCapabilities {
+    /// Return the capabilities of a backend which provides everything, like the native locks.
+    pub const fn new() -> BackendCapabilities {
+        BackendCapabilities {
+            shared: true,
+            blocking: true,
+            cross_process: true,
+            crash_safe: true,
+        }
+    }
+
+    /// Set whether shared locks can be held together. Otherwise they are taken exclusively.
+    pub const fn shared(mut self, shared: bool) -> BackendCapabilities {
+        self.shared = shared;
+        self
+    }
+
+    /// Set whether [`LockBackend::lock`] can wait for the lock. Otherwise `try_lock` is polled.
+    ///
+    /// [`LockBackend::lock`]: trait.LockBackend.html#tymethod.lock
+    pub const fn blocking(mut self, blocking: bool) -> BackendCapabilities {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Set whether the locks exclude other processes.
+    pub const fn cross_process(mut self, cross_process: bool) -> BackendCapabilities {
+        self.cross_process = cross_process;
+        self
+    }
+
+    /// Set whether the locks are released when their holder dies.
+    pub const fn crash_safe(mut self, crash_safe: bool) -> BackendCapabilities {
+        self.crash_safe = crash_safe;
+        self
+    }
+
+    /// Return whether shared locks can be held together.
+    pub const fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Return whether the backend can wait for the lock.
+    pub const fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    /// Return whether the locks exclude other processes.
+    pub const fn is_cross_process(&self) -> bool {
+        self.cross_process
+    }
+
+    /// Return whether the locks are released when their holder dies.
+    pub const fn is_crash_safe(&self) -> bool {
+        self.crash_safe
+    }
+}
+
+impl Default for BackendCapabilities {
+    fn default() -> BackendCapabilities {
+        BackendCapabilities::new()
+    }
+}
+
+/// An advisory lock implemented by a [`LockBackend`].
+///
+/// The lock adapts its requests to the [`capabilities`] of the backend: shared locks are taken
+/// exclusively from backends without shared locks, and blocking acquisitions poll `try_lock`
+/// with an exponential backoff on backends which can't block.
+///
+/// Example:
+/// ```
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::time::Duration;
+/// use advisory_lock::{
+///     AdvisoryFileLock, BackendCapabilities, BackendLock, FileLockError, FileLockMode,
+///     LockBackend,
+/// };
+///
+/// /// An in-memory fake which only knows about exclusive locks.
+/// #[derive(Default)]
+/// struct Flag(AtomicBool);
+///
+/// impl LockBackend for Flag {
+///     fn lock(&self, _: FileLockMode) -> Result<(), FileLockError> {
+///         Err(FileLockError::Unsupported)
+///     }
+///
+///     fn try_lock(&self, _: FileLockMode) -> Result<(), FileLockError> {
+///         match self.0.swap(true, Ordering::AcqRel) {
+///             true => Err(FileLockError::AlreadyLocked),
+///             false => Ok(()),
+///         }
+///     }
+///
+///     fn unlock(&self) -> Result<(), FileLockError> {
+///         self.0.store(false, Ordering::Release);
+///         Ok(())
+///     }
+///
+///     fn capabilities(&self) -> BackendCapabilities {
+///         BackendCapabilities::new().shared(false).blocking(false)
+///     }
+/// }
+///
+/// let lock = BackendLock::new(Flag::default());
+/// let guard = lock.lock_guard(FileLockMode::Exclusive)?;
+/// assert!(matches!(
+///     lock.lock_timeout(FileLockMode::Shared, Duration::from_millis(10)),
+///     Err(FileLockError::Timeout)
+/// ));
+/// drop(guard);
+/// lock.lock(FileLockMode::Shared)?;
+/// # Ok::<(), FileLockError>(())
+/// ```
+///
+/// [`LockBackend`]: trait.LockBackend.html
+/// [`capabilities`]: trait.LockBackend.html#method.capabilities
+#[derive(Debug, Default)]
+pub struct BackendLock<B> {
+    backend: B,
+}
+
+impl<B: LockBackend> BackendLock<B> {
+    /// Lock with `backend`.
+    pub fn new(backend: B) -> BackendLock<B> {
+        BackendLock { backend }
+    }
+
+    /// Return a reference to the backend.
+    pub fn get_ref(&self) -> &B {
+        &self.backend
+    }
+
+    /// Unwrap the backend, leaving its lock state as it is.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    fn mode(&self, file_lock_mode: FileLockMode) -> FileLockMode {
+        match self.backend.capabilities().is_shared() {
+            true => file_lock_mode,
+            false => FileLockMode::Exclusive,
+        }
+    }
+}
+
+impl<B: LockBackend> AdvisoryFileLock for BackendLock<B> {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        if self.backend.capabilities().is_blocking() {
+            return self.backend.lock(self.mode(file_lock_mode));
+        }
+        let mut backoff = ExponentialBackoff::new(Duration::from_millis(1), POLL_INTERVAL);
+        retry::lock_with_retry(self, file_lock_mode, &mut backoff, &SystemClock)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.backend.try_lock(self.mode(file_lock_mode))
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        self.backend.unlock()
+    }
+}
+
+/// The longest delay between two polls of a backend which can't block.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The lock of a file with one of the mechanisms of the crate, as a [`LockBackend`].
+///
+/// The file is locked with the given [`Backend`] regardless of the default backend of the
+/// process.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{AdvisoryFileLock, Backend, BackendLock, FileBackend, FileLockMode};
+///
+/// let lock = BackendLock::new(FileBackend::new(File::create("backend.lock")?, Backend::Native));
+/// lock.lock(FileLockMode::Exclusive)?;
+/// lock.unlock()?;
+/// #
+/// # drop(lock);
+/// # std::fs::remove_file("backend.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`LockBackend`]: trait.LockBackend.html
+/// [`Backend`]: enum.Backend.html
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+    backend: Backend,
+}
+
+impl FileBackend {
+    /// Lock `file` with the mechanism of `backend`.
+    pub fn new(file: File, backend: Backend) -> FileBackend {
+        FileBackend { file, backend }
+    }
+
+    /// Return the mechanism the file is locked with.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Return a reference to the file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Unwrap the file, leaving its lock state as it is.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl LockBackend for FileBackend {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_handle_with(self.backend, sys::handle(&self.file), file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_handle_with(self.backend, sys::handle(&self.file), file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        unlock_handle_with(self.backend, sys::handle(&self.file))
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{self, Mutex};
+    use std::env::temp_dir;
+
+    /// A fake which counts the operations reaching it and only grants exclusive locks.
+    #[derive(Default)]
+    struct Fake {
+        held: Mutex<Option<FileLockMode>>,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl LockBackend for Fake {
+        fn lock(&self, _: FileLockMode) -> Result<(), FileLockError> {
+            Err(FileLockError::Unsupported)
+        }
+
+        fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+            sync::lock(&self.calls).push("try_lock");
+            let mut held = sync::lock(&self.held);
+            if held.is_some() {
+                return Err(FileLockError::AlreadyLocked);
+            }
+            *held = Some(file_lock_mode);
+            Ok(())
+        }
+
+        fn unlock(&self) -> Result<(), FileLockError> {
+            sync::lock(&self.calls).push("unlock");
+            sync::lock(&self.held)
+                .take()
+                .ok_or(FileLockError::NotLocked)?;
+            Ok(())
+        }
+
+        fn capabilities(&self) -> BackendCapabilities {
+            BackendCapabilities::new().shared(false).blocking(false)
+        }
+    }
+
+    #[test]
+    fn custom_backends_get_the_provided_machinery() {
+        let lock = BackendLock::new(Fake::default());
+        let guard = lock.lock_guard(FileLockMode::Shared).unwrap();
+        assert_eq!(
+            *sync::lock(&lock.get_ref().held),
+            Some(FileLockMode::Exclusive)
+        );
+        assert!(matches!(
+            lock.lock_with_retry(FileLockMode::Shared, |attempts| {
+                Some(Duration::ZERO).filter(|_| attempts < 3)
+            }),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        guard.unlock().unwrap();
+        lock.lock(FileLockMode::Exclusive).unwrap();
+        lock.unlock().unwrap();
+        assert_eq!(
+            *sync::lock(&lock.get_ref().calls),
+            ["try_lock", "try_lock", "try_lock", "try_lock", "unlock", "try_lock", "unlock"]
+        );
+
+        // The mechanisms of the crate are backends too.
+        let mut test_file = temp_dir();
+        test_file.push("custom_file_backend");
+        let file = BackendLock::new(FileBackend::new(
+            File::create(&test_file).unwrap(),
+            Backend::Native,
+        ));
+        let other = File::open(&test_file).unwrap();
+        file.try_lock_guard(FileLockMode::Exclusive)
+            .unwrap()
+            .unlock()
+            .unwrap();
+        file.lock_timeout(FileLockMode::Exclusive, Duration::ZERO)
+            .unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&other, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        file.unlock().unwrap();
+
+        drop((file, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
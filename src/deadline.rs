@@ -0,0 +1,84 @@
+//! Blocking acquisitions bounded in time.
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::wait::poll_until;
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+pub(crate) fn lock_timeout<L>(
+    lock: &L,
+    file_lock_mode: FileLockMode,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<(), FileLockError>
+where
+    L: AdvisoryFileLock + ?Sized,
+{
+    match clock.now().checked_add(timeout) {
+        Some(deadline) => lock_until(lock, file_lock_mode, deadline, clock),
+        // A timeout too long to be represented never elapses.
+        None => lock.lock(file_lock_mode),
+    }
+}
+
+/// Retry `try_lock` until it succeeds or `deadline` passes.
+pub(crate) fn lock_until<L>(
+    lock: &L,
+    file_lock_mode: FileLockMode,
+    deadline: Instant,
+    clock: &dyn Clock,
+) -> Result<(), FileLockError>
+where
+    L: AdvisoryFileLock + ?Sized,
+{
+    poll_until(deadline, clock, || lock.try_lock(file_lock_mode).map(Some))?
+        .ok_or(FileLockError::Timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn gives_up_at_the_deadline() {
+        let mut test_file = temp_dir();
+        test_file.push("lock_timeout");
+        let holder = File::create(&test_file).unwrap();
+        let waiter = File::open(&test_file).unwrap();
+        AdvisoryFileLock::lock(&holder, FileLockMode::Exclusive).unwrap();
+
+        let clock = ManualClock::new();
+        let timeout = Duration::from_secs(5);
+        assert!(matches!(
+            waiter.lock_timeout_with_clock(FileLockMode::Shared, timeout, &clock),
+            Err(FileLockError::Timeout)
+        ));
+        assert_eq!(clock.elapsed(), timeout);
+        assert!(matches!(
+            waiter.try_lock_until_with_clock(FileLockMode::Shared, clock.now() + timeout, &clock),
+            Err(FileLockError::Timeout)
+        ));
+        assert_eq!(clock.elapsed(), timeout * 2);
+
+        let past = clock.now() - timeout;
+        assert!(matches!(
+            waiter.try_lock_until(FileLockMode::Shared, past),
+            Err(FileLockError::Timeout)
+        ));
+
+        AdvisoryFileLock::unlock(&holder).unwrap();
+        waiter
+            .lock_timeout(FileLockMode::Shared, Duration::from_secs(0))
+            .unwrap();
+        waiter
+            .lock_timeout(FileLockMode::Shared, Duration::MAX)
+            .unwrap();
+        assert!(AdvisoryFileLock::try_lock(&holder, FileLockMode::Exclusive).is_err());
+
+        drop((holder, waiter));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
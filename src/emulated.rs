@@ -0,0 +1,737 @@
+//! Advisory locks emulated with sidecar lock files, for platforms without native file locks.
+//!
+//! Every locked file gets a directory of its own under the [emulation directory], named after
+//! its [`FileId`]. Each holder of the lock creates a marker file in it, named after the mode it
+//! holds the lock in and its process id, and removes it to release the lock. The markers are
+//! only created and inspected while holding a short-lived guard file, itself created with
+//! `O_EXCL`, so that checking for conflicting holders and registering a new one is atomic.
+//! Blocking acquisitions poll until no conflicting marker remains.
+//!
+//! A marker is a lease: its modification time is renewed by a background thread of its process
+//! for as long as the lock is held, and a marker which wasn't renewed for `LEASE`, or whose
+//! process is gone, is expired by the next acquisition. The lease covers the processes which
+//! can't be looked up, such as those of WASI, and the process ids which were reused. Markers
+//! still held when their process exits normally are removed by an exit hook.
+//!
+//! This is the native mechanism on WASI, and the [`Emulated`] backend everywhere else. On the
+//! targets which have neither file descriptors nor handles, open files can't be identified at
+//! all, so the files the crate opens by path are locked through a [`PathFile`] instead, whose
+//! directory is named after the path it was opened with.
+//!
+//! [emulation directory]: fn.set_emulation_dir.html
+//! [`Emulated`]: enum.Backend.html#variant.Emulated
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::striped::Fnv1a;
+use crate::sync::{self, AtomicBool, AtomicU64, Mutex, MutexGuard, Once, Ordering};
+use crate::{process_id, sys, FileId, FileLockError, FileLockMode};
+
+sync::statics! {
+    /// The directory holding the sidecar lock files, when set explicitly.
+    static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    /// The markers held by this process, keyed by their owner.
+    ///
+    /// Since markers outlive the handles which are closed without being unlocked, each one
+    /// records the file it locks, and is dropped once its handle refers to another file.
+    static HELD: Mutex<BTreeMap<Owner, Marker>> = Mutex::new(BTreeMap::new());
+
+    /// Distinguishes the markers created by this process.
+    static NEXT_MARKER: AtomicU64 = AtomicU64::new(0);
+
+    /// Distinguishes the path files opened by this process.
+    static NEXT_PATH_FILE: AtomicU64 = AtomicU64::new(0);
+
+    /// Whether a thread renews the leases of the markers held by this process.
+    static RENEWING: AtomicBool = AtomicBool::new(false);
+}
+
+/// Registers the exit hook removing the markers of this process.
+static EXIT_HOOK: Once = Once::new();
+
+/// The interval between two attempts of a blocking acquisition.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// The age after which a guard is assumed to be left over by a holder which died holding it.
+const STALE_GUARD: Duration = Duration::from_secs(10);
+/// How long a marker which isn't renewed stays valid.
+const LEASE: Duration = Duration::from_secs(30);
+
+const GUARD: &str = "guard";
+const DEFAULT_DIR: &str = ".advisory-lock";
+
+/// What a marker was acquired through.
+#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd, Debug)]
+enum Owner {
+    /// A handle, stored as an integer: it is only compared, never dereferenced.
+    Handle(usize),
+    /// A [`PathFile`], which releases its marker itself when dropped.
+    Path(u64),
+}
+
+#[derive(Debug)]
+struct Marker {
+    path: PathBuf,
+    mode: FileLockMode,
+    file_id: FileId,
+}
+
+/// Return the markers held by this process, after removing those whose handle was closed, and
+/// possibly reused for another file, since.
+fn held() -> MutexGuard<'static, BTreeMap<Owner, Marker>> {
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    held.retain(|owner, marker| {
+        let live = match *owner {
+            Owner::Handle(handle) => {
+                sys::file_id(handle as sys::Handle).ok() == Some(marker.file_id)
+            }
+            Owner::Path(_) => true,
+        };
+        if !live {
+            let _ = remove_marker(&marker.path);
+        }
+        live
+    });
+    held
+}
+
+/// Choose the directory in which emulated locks keep their sidecar lock files.
+///
+/// The default is `.advisory-lock` in the current directory. Every program locking the same
+/// files must use the same directory, so it should be set before taking any lock, to a path
+/// all of them can reach (on WASI, within a directory preopened by every instance).
+///
+/// ## Notes
+///
+/// Emulated locks are best-effort. Unlike native locks, they aren't released when the handle
+/// is closed, so they must be unlocked explicitly. The locks still held by a process which
+/// exits normally are released as it exits. The locks of a process which was killed are
+/// ignored and cleaned up by the next acquisition, once the process is gone or, at the latest,
+/// once its lease of 30 seconds, which a background thread renews while the lock is held,
+/// lapses. WASI programs have no threads to renew their leases with: they must lock the handle
+/// again in the same mode, which renews the lease, at least every 30 seconds, or lose the lock.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{emulation_dir, set_emulation_dir};
+///
+/// set_emulation_dir("locks");
+/// assert_eq!(emulation_dir(), std::path::Path::new("locks"));
+/// # set_emulation_dir(".advisory-lock");
+/// ```
+pub fn set_emulation_dir<P: Into<PathBuf>>(dir: P) {
+    *DIR.lock().unwrap_or_else(|e| e.into_inner()) = Some(dir.into());
+}
+
+/// Return the directory in which emulated locks keep their sidecar lock files.
+pub fn emulation_dir() -> PathBuf {
+    DIR.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR))
+}
+
+/// Acquire the emulated lock of the file of `handle`.
+///
+/// Locking a handle again converts its lock to the new mode, like `flock` does.
+pub(crate) fn lock(
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    lock_in(&emulation_dir(), handle, file_lock_mode, immediate)
+}
+
+fn lock_in(
+    root: &Path,
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let file_id = sys::file_id(handle).map_err(FileLockError::Io)?;
+    acquire(
+        root,
+        Owner::Handle(handle as usize),
+        file_id,
+        file_lock_mode,
+        immediate,
+    )
+}
+
+/// Acquire the lock of the file `file_id` for `owner`, converting the lock it already holds.
+fn acquire(
+    root: &Path,
+    owner: Owner,
+    file_id: FileId,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let current = held().get(&owner).map(|m| (m.path.clone(), m.mode));
+    if let Some((path, mode)) = &current {
+        if *mode == file_lock_mode {
+            // This renews the lease, where no thread does so.
+            return renew(path).map_err(FileLockError::Io);
+        }
+    }
+
+    let dir = lock_dir(root, file_id);
+    fs::create_dir_all(&dir).map_err(FileLockError::Io)?;
+    let own = current.as_ref().map(|(path, _)| path.as_path());
+    loop {
+        if let Some(path) = try_acquire(&dir, file_lock_mode, own).map_err(FileLockError::Io)? {
+            let previous = held().insert(
+                owner,
+                Marker {
+                    path,
+                    mode: file_lock_mode,
+                    file_id,
+                },
+            );
+            keep_renewed();
+            if let Some(previous) = previous {
+                remove_marker(&previous.path).map_err(FileLockError::Io)?;
+            }
+            return Ok(());
+        }
+        if immediate {
+            return Err(FileLockError::AlreadyLocked);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Release the emulated lock of `handle`; releasing a lock which isn't held is a no-op.
+pub(crate) fn unlock(handle: sys::Handle) -> Result<(), FileLockError> {
+    release(Owner::Handle(handle as usize))
+}
+
+fn release(owner: Owner) -> Result<(), FileLockError> {
+    match held().remove(&owner) {
+        Some(marker) => remove_marker(&marker.path).map_err(FileLockError::Io),
+        None => Ok(()),
+    }
+}
+
+/// A file opened by its path, whose emulated lock is keyed by that path rather than by the file.
+///
+/// This is how the crate locks the files it opens by path on the targets where open files can't
+/// be identified. The lock belongs to this value, which can be moved freely, and is released when
+/// it is dropped. Paths are made absolute, but links aren't resolved past the parent directory,
+/// so a file and the links to it are locked separately.
+#[derive(Debug)]
+pub(crate) struct PathFile {
+    file: File,
+    root: PathBuf,
+    owner: Owner,
+    file_id: FileId,
+}
+
+// Elsewhere, files are locked through their descriptor or handle instead.
+#[cfg_attr(any(unix, windows, target_os = "wasi"), allow(dead_code))]
+impl PathFile {
+    /// Open the file at `path` with `options`, without locking it.
+    pub(crate) fn open(path: &Path, options: &OpenOptions) -> io::Result<PathFile> {
+        PathFile::open_in(&emulation_dir(), path, options)
+    }
+
+    fn open_in(root: &Path, path: &Path, options: &OpenOptions) -> io::Result<PathFile> {
+        let file = options.open(path)?;
+        Ok(PathFile {
+            file,
+            root: root.to_owned(),
+            owner: Owner::Path(NEXT_PATH_FILE.fetch_add(1, Ordering::Relaxed)),
+            file_id: path_id(path)?,
+        })
+    }
+
+    /// Lock the file at `path` in `file_lock_mode`, then open it with `options`.
+    ///
+    /// Opening the file only once it is locked makes sure that a file removed or replaced by the
+    /// previous holder isn't the one opened.
+    pub(crate) fn open_locked(
+        path: &Path,
+        options: &OpenOptions,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<PathFile, FileLockError> {
+        PathFile::open_locked_in(&emulation_dir(), path, options, file_lock_mode, immediate)
+    }
+
+    fn open_locked_in(
+        root: &Path,
+        path: &Path,
+        options: &OpenOptions,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<PathFile, FileLockError> {
+        let owner = Owner::Path(NEXT_PATH_FILE.fetch_add(1, Ordering::Relaxed));
+        let file_id = path_id(path).map_err(FileLockError::Io)?;
+        acquire(root, owner, file_id, file_lock_mode, immediate)?;
+        match options.open(path) {
+            Ok(file) => Ok(PathFile {
+                file,
+                root: root.to_owned(),
+                owner,
+                file_id,
+            }),
+            Err(err) => {
+                let _ = release(owner);
+                Err(FileLockError::Io(err))
+            }
+        }
+    }
+
+    /// Lock the file, converting the lock it already holds like `flock` does.
+    pub(crate) fn acquire(
+        &self,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<(), FileLockError> {
+        acquire(
+            &self.root,
+            self.owner,
+            self.file_id,
+            file_lock_mode,
+            immediate,
+        )
+    }
+
+    /// Release the lock of the file; releasing a lock which isn't held is a no-op.
+    pub(crate) fn release(&self) -> Result<(), FileLockError> {
+        release(self.owner)
+    }
+}
+
+impl Deref for PathFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for PathFile {
+    fn drop(&mut self) {
+        let _ = release(self.owner);
+    }
+}
+
+/// Return an identity for the file at `path`, made of its absolute path, for the files which
+/// can't be identified otherwise.
+fn path_id(path: &Path) -> io::Result<FileId> {
+    let absolute = match path.file_name() {
+        Some(name) => {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            fs::canonicalize(parent)?.join(name)
+        }
+        None => fs::canonicalize(path)?,
+    };
+    Ok(FileId {
+        device: 0,
+        index: Fnv1a::hash(absolute.to_string_lossy().as_bytes()),
+    })
+}
+
+fn lock_dir(root: &Path, file_id: FileId) -> PathBuf {
+    root.join(format!("{:x}-{:x}", file_id.device, file_id.index))
+}
+
+/// Register a holder of the lock in `dir` unless a conflicting one exists, ignoring the
+/// marker `own` of the lock being converted.
+fn try_acquire(
+    dir: &Path,
+    file_lock_mode: FileLockMode,
+    own: Option<&Path>,
+) -> io::Result<Option<PathBuf>> {
+    let _guard = Guard::acquire(dir)?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name() == Some(GUARD.as_ref()) || Some(path.as_path()) == own {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let mut fields = name.split('-');
+        let exclusive = fields.next() != Some(mode_name(FileLockMode::Shared));
+        let pid = fields.next().and_then(|pid| pid.parse::<u32>().ok());
+        if pid.is_some_and(|pid| pid != 0 && !sys::process_alive(pid)) || is_older(&path, LEASE) {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        if file_lock_mode == FileLockMode::Exclusive || exclusive {
+            return Ok(None);
+        }
+    }
+
+    loop {
+        let path = dir.join(format!(
+            "{}-{}-{}",
+            mode_name(file_lock_mode),
+            process_id(),
+            marker_token()
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(Some(path)),
+            // Another program drew the same token; draw again.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Renew the lease of a marker.
+fn renew(path: &Path) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Make sure the leases of the markers of this process are renewed, and the markers removed
+/// when it exits.
+fn keep_renewed() {
+    EXIT_HOOK.call_once(|| sys::at_exit(remove_held_markers));
+    if !RENEWING.swap(true, Ordering::SeqCst) {
+        let renewer = thread::Builder::new()
+            .name("advisory-lock-leases".to_owned())
+            .spawn(renew_leases);
+        // WASI has no threads; its programs renew their leases by locking again.
+        if renewer.is_err() {
+            RENEWING.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Renew the leases of the markers of this process until it holds none.
+fn renew_leases() {
+    loop {
+        thread::sleep(LEASE / 3);
+        // Markers are only added with the registry held, so none can be missed in between.
+        let held = held();
+        if held.is_empty() {
+            RENEWING.store(false, Ordering::SeqCst);
+            return;
+        }
+        for marker in held.values() {
+            let _ = renew(&marker.path);
+        }
+    }
+}
+
+/// Remove the markers of this process as it exits.
+extern "C" fn remove_held_markers() {
+    // A thread holding the registry as the process exits leaves the markers to expire.
+    if let Ok(held) = HELD.try_lock() {
+        for marker in held.values() {
+            let _ = remove_marker(&marker.path);
+        }
+    }
+}
+
+/// Remove a marker, which needs no guard since removing a file is atomic.
+fn remove_marker(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn mode_name(file_lock_mode: FileLockMode) -> &'static str {
+    match file_lock_mode {
+        FileLockMode::Exclusive => "exclusive",
+        FileLockMode::Shared => "shared",
+    }
+}
+
+/// Return a token to name a marker after, which is unlikely to be drawn by another program.
+fn marker_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!(
+        "{:x}-{:x}",
+        nanos,
+        NEXT_MARKER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// The guard file which serializes the inspection and update of the markers of one file.
+struct Guard(PathBuf);
+
+impl Guard {
+    fn acquire(dir: &Path) -> io::Result<Guard> {
+        let path = dir.join(GUARD);
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Guard(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    if is_older(&path, STALE_GUARD) {
+                        let _ = fs::remove_file(&path);
+                    } else {
+                        thread::yield_now();
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Return whether the file at `path` was last modified more than `age` ago.
+fn is_older(path: &Path, age: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed > age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn markers_exclude_each_other() {
+        // Leave the emulation directory of the tests running concurrently alone.
+        let root = temp_dir().join("emulated_locks");
+        let lock = |handle, mode, immediate| lock_in(&root, handle, mode, immediate);
+        let mut test_file = temp_dir();
+        test_file.push("emulated_markers");
+        let first = File::create(&test_file).unwrap();
+        let second = File::open(&test_file).unwrap();
+        let (first, second) = (sys::handle(&first), sys::handle(&second));
+
+        lock(first, FileLockMode::Shared, true).unwrap();
+        lock(second, FileLockMode::Shared, true).unwrap();
+        assert!(matches!(
+            lock(first, FileLockMode::Exclusive, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        unlock(second).unwrap();
+        lock(first, FileLockMode::Exclusive, true).unwrap();
+        assert!(matches!(
+            lock(second, FileLockMode::Shared, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        unlock(first).unwrap();
+        unlock(first).unwrap();
+        lock(second, FileLockMode::Exclusive, true).unwrap();
+        unlock(second).unwrap();
+
+        // The marker of a process which is gone doesn't exclude anyone.
+        let dir = lock_dir(&root, sys::file_id(first).unwrap());
+        File::create(dir.join(format!("exclusive-{}-0", u32::MAX))).unwrap();
+        lock(first, FileLockMode::Exclusive, true).unwrap();
+        unlock(first).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reused_handles_are_not_mistaken_for_holders() {
+        use std::os::unix::io::AsRawFd;
+
+        let root = temp_dir().join("emulated_locks");
+        let (mut old_path, mut new_path) = (temp_dir(), temp_dir());
+        old_path.push("emulated_reused_old");
+        new_path.push("emulated_reused_new");
+        let old = File::create(&old_path).unwrap();
+        let new = File::create(&new_path).unwrap();
+        let other = File::open(&new_path).unwrap();
+        let old_dir = lock_dir(&root, FileId::of(&old).unwrap());
+
+        lock_in(&root, sys::handle(&old), FileLockMode::Exclusive, true).unwrap();
+        // Close the locked handle and reuse its number for another file.
+        assert_ne!(unsafe { libc::dup2(new.as_raw_fd(), old.as_raw_fd()) }, -1);
+        lock_in(&root, sys::handle(&old), FileLockMode::Exclusive, true).unwrap();
+        assert!(matches!(
+            lock_in(&root, sys::handle(&other), FileLockMode::Shared, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert_eq!(fs::read_dir(&old_dir).unwrap().count(), 0);
+        unlock(sys::handle(&old)).unwrap();
+
+        drop((old, new, other));
+        std::fs::remove_file(&old_path).unwrap();
+        std::fs::remove_file(&new_path).unwrap();
+    }
+
+    #[test]
+    fn path_files_are_locked_through_their_path() {
+        let root = temp_dir().join("emulated_locks");
+        let mut test_file = temp_dir();
+        test_file.push("emulated_path_file");
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+        let open_locked =
+            |path: &Path, mode| PathFile::open_locked_in(&root, path, &options, mode, true);
+
+        // The lock belongs to the `PathFile` wherever it is moved, and is found through any
+        // spelling of the path.
+        let first = Box::new(open_locked(&test_file, FileLockMode::Exclusive).unwrap());
+        let respelled = temp_dir().join(".").join("emulated_path_file");
+        assert!(matches!(
+            open_locked(&respelled, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        let second = PathFile::open_in(&root, &test_file, &options).unwrap();
+        assert!(matches!(
+            second.acquire(FileLockMode::Shared, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop(first);
+        second.acquire(FileLockMode::Shared, true).unwrap();
+        let third = open_locked(&respelled, FileLockMode::Shared).unwrap();
+        assert!(matches!(
+            second.acquire(FileLockMode::Exclusive, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop(third);
+        second.acquire(FileLockMode::Exclusive, true).unwrap();
+        second.release().unwrap();
+        second.release().unwrap();
+
+        // A file which can't be opened is left unlocked.
+        std::fs::remove_file(&test_file).unwrap();
+        let dir = lock_dir(&root, path_id(&test_file).unwrap());
+        assert!(matches!(
+            PathFile::open_locked_in(
+                &root,
+                &test_file,
+                OpenOptions::new().read(true),
+                FileLockMode::Exclusive,
+                true
+            ),
+            Err(FileLockError::Io(err)) if err.kind() == io::ErrorKind::NotFound
+        ));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    /// The variable telling the child spawned by `holders_which_exit_release_their_locks` how
+    /// to exit while holding the lock of the file it names.
+    #[cfg(any(unix, windows))]
+    const HOLDER_ENV: &str = "ADVISORY_LOCK_EMULATED_HOLDER";
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn holder() {
+        let Some(how) = std::env::var_os(HOLDER_ENV) else {
+            return;
+        };
+        let test_file = std::env::var_os("ADVISORY_LOCK_EMULATED_FILE").unwrap();
+        let file = File::open(test_file).unwrap();
+        let root = temp_dir().join("emulated_locks");
+        lock_in(&root, sys::handle(&file), FileLockMode::Exclusive, true).unwrap();
+        if how == "kill" {
+            #[cfg(unix)]
+            unsafe {
+                libc::kill(libc::getpid(), libc::SIGKILL)
+            };
+            // Like a kill, this skips the exit hooks.
+            std::process::abort();
+        }
+        std::process::exit(0);
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn holders_which_exit_release_their_locks() {
+        let root = temp_dir().join("emulated_locks");
+        let mut test_file = temp_dir();
+        test_file.push("emulated_exited_holder");
+        let file = File::create(&test_file).unwrap();
+        let dir = lock_dir(&root, FileId::of(&file).unwrap());
+        let hold_and_exit = |how| {
+            std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "emulated::tests::holder",
+                    "--exact",
+                    "--test-threads=1",
+                    "-q",
+                ])
+                .env(HOLDER_ENV, how)
+                .env("ADVISORY_LOCK_EMULATED_FILE", &test_file)
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .unwrap()
+        };
+
+        // A holder which exits normally removes its marker.
+        assert!(hold_and_exit("exit").success());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // The marker of a killed holder is expired, as its process is gone.
+        assert!(!hold_and_exit("kill").success());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        lock_in(&root, sys::handle(&file), FileLockMode::Exclusive, true).unwrap();
+        unlock(sys::handle(&file)).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn lapsed_leases_expire() {
+        let root = temp_dir().join("emulated_locks");
+        let mut test_file = temp_dir();
+        test_file.push("emulated_lapsed_lease");
+        let file = File::create(&test_file).unwrap();
+        let handle = sys::handle(&file);
+        let dir = lock_dir(&root, FileId::of(&file).unwrap());
+        fs::create_dir_all(&dir).unwrap();
+
+        // A marker left by a process whose id was reused by a running process, this one.
+        let marker = dir.join(format!("exclusive-{}-0", process_id()));
+        File::create(&marker).unwrap();
+        assert!(matches!(
+            lock_in(&root, handle, FileLockMode::Shared, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        let lapsed = SystemTime::now() - LEASE - Duration::from_secs(1);
+        OpenOptions::new()
+            .write(true)
+            .open(&marker)
+            .unwrap()
+            .set_modified(lapsed)
+            .unwrap();
+        lock_in(&root, handle, FileLockMode::Shared, true).unwrap();
+        assert!(!marker.exists());
+
+        // Locking again in the same mode renews the lease.
+        let own = held()[&Owner::Handle(handle as usize)].path.clone();
+        OpenOptions::new()
+            .write(true)
+            .open(&own)
+            .unwrap()
+            .set_modified(lapsed)
+            .unwrap();
+        lock_in(&root, handle, FileLockMode::Shared, true).unwrap();
+        assert!(!is_older(&own, LEASE));
+        unlock(handle).unwrap();
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
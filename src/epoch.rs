@@ -0,0 +1,193 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::fs::{open_locked, read_all};
+use crate::sync::{self, Mutex};
+use crate::{FileLockError, FileLockMode};
+
+/// How recent a modification must be for its timestamp to be distrusted.
+///
+/// A write which lands within the timestamp resolution of the file system (up to two seconds on
+/// FAT) after the cached read, without changing the length, leaves the epoch untouched. Files
+/// modified that recently are read again until the window has passed, like Git does for "racily
+/// clean" entries of its index.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// The cheap validity check of a cached read, taken from the metadata of the file.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct Epoch {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// The inode and change time, which catch files replaced by a rename.
+    #[cfg(unix)]
+    inode: (u64, u64, i64, i64),
+}
+
+impl Epoch {
+    fn of(metadata: &Metadata) -> Epoch {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        Epoch {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: (
+                metadata.dev(),
+                metadata.ino(),
+                metadata.ctime(),
+                metadata.ctime_nsec(),
+            ),
+        }
+    }
+
+    /// Return whether a read made at `read_at` can be trusted to stay valid while the epoch
+    /// doesn't change.
+    fn is_settled(&self, read_at: SystemTime) -> bool {
+        self.modified
+            .and_then(|modified| read_at.duration_since(modified).ok())
+            .is_some_and(|age| age >= RACY_WINDOW)
+    }
+}
+
+#[derive(Debug)]
+struct Cached<T> {
+    epoch: Epoch,
+    read_at: SystemTime,
+    value: Arc<T>,
+}
+
+/// A cache of the parsed contents of a file which is read far more often than it is written.
+///
+/// Reading a file safely takes a shared lock, which is a handful of system calls, plus parsing.
+/// The cache only does that when the file changed since the last read, as told by a `stat` of
+/// the path: its modification time, its length and, on Unix, its inode. A configuration file read
+/// on every request thus costs one `stat` per request instead.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{write_locked, EpochCache, FileLockError};
+///
+/// write_locked("epoch.conf", "threads = 4")?;
+/// let config = EpochCache::new("epoch.conf", |bytes| {
+///     String::from_utf8(bytes).map_err(|_| FileLockError::Corrupted)
+/// });
+/// assert_eq!(*config.get()?, "threads = 4");
+///
+/// write_locked("epoch.conf", "threads = 16")?;
+/// assert_eq!(*config.get()?, "threads = 16");
+/// #
+/// # std::fs::remove_file("epoch.conf")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct EpochCache<T> {
+    path: PathBuf,
+    parse: Box<dyn Fn(Vec<u8>) -> Result<T, FileLockError> + Send + Sync>,
+    cached: Mutex<Option<Cached<T>>>,
+}
+
+impl<T> EpochCache<T> {
+    /// Create a cache of the file at `path`, whose contents are parsed with `parse`.
+    ///
+    /// Nothing is read until the first call to [`get`].
+    ///
+    /// [`get`]: #method.get
+    pub fn new<P, F>(path: P, parse: F) -> EpochCache<T>
+    where
+        P: AsRef<Path>,
+        F: Fn(Vec<u8>) -> Result<T, FileLockError> + Send + Sync + 'static,
+    {
+        EpochCache {
+            path: path.as_ref().to_path_buf(),
+            parse: Box::new(parse),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Return the path of the cached file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the parsed contents of the file, reading them again under a shared lock only if
+    /// the file changed since they were cached.
+    pub fn get(&self) -> Result<Arc<T>, FileLockError> {
+        let metadata = std::fs::metadata(&self.path).map_err(FileLockError::Io)?;
+        let epoch = Epoch::of(&metadata);
+        if let Some(cached) = &*sync::lock(&self.cached) {
+            if cached.epoch == epoch && cached.epoch.is_settled(cached.read_at) {
+                return Ok(Arc::clone(&cached.value));
+            }
+        }
+
+        let file = open_locked(&self.path, FileLockMode::Shared, false)?;
+        let read_at = SystemTime::now();
+        let epoch = Epoch::of(&file.metadata().map_err(FileLockError::Io)?);
+        let value = Arc::new((self.parse)(read_all(&file)?)?);
+        *sync::lock(&self.cached) = Some(Cached {
+            epoch,
+            read_at,
+            value: Arc::clone(&value),
+        });
+        Ok(value)
+    }
+
+    /// Forget the cached contents, so the next [`get`] reads the file.
+    ///
+    /// [`get`]: #method.get
+    pub fn invalidate(&self) {
+        *sync::lock(&self.cached) = None;
+    }
+}
+
+impl<T> std::fmt::Debug for EpochCache<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EpochCache")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::LockRecorder;
+    use crate::write_locked;
+    use std::env::temp_dir;
+
+    #[test]
+    fn reads_only_when_the_epoch_changes() {
+        let mut test_file = temp_dir();
+        test_file.push("epoch_cache");
+        write_locked(&test_file, b"1").unwrap();
+        let cache = EpochCache::new(&test_file, |bytes| Ok(bytes.len()));
+
+        // Backdate the file so its timestamp is trusted.
+        let backdate = |path: &Path| {
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(SystemTime::now() - RACY_WINDOW * 2)
+                .unwrap();
+        };
+        backdate(&test_file);
+        let recorder = LockRecorder::install();
+        let probe = std::fs::File::open(&test_file).unwrap();
+        let first = cache.get().unwrap();
+        let locks = recorder.events_for(&probe).unwrap().len();
+        assert!(Arc::ptr_eq(&first, &cache.get().unwrap()));
+        assert_eq!(recorder.events_for(&probe).unwrap().len(), locks);
+
+        write_locked(&test_file, b"22").unwrap();
+        assert_eq!(*cache.get().unwrap(), 2);
+        // Recently modified files are read every time.
+        assert!(!Arc::ptr_eq(&cache.get().unwrap(), &cache.get().unwrap()));
+        backdate(&test_file);
+        assert!(Arc::ptr_eq(&cache.get().unwrap(), &cache.get().unwrap()));
+
+        cache.invalidate();
+        assert_eq!(*cache.get().unwrap(), 2);
+        drop(probe);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fs::{open_locked_with, PathFile};
+use crate::sync::{self, AtomicU64, Ordering};
+use crate::{
+    default_backend, process_id, sys, AdvisoryFileLock, Backend, FileLockError, FileLockMode,
+};
+
+/// How often waiters which aren't at the head of the queue look at it again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How old an unlocked ticket must be to be considered abandoned, rather than not locked yet.
+const STALE_GRACE: Duration = Duration::from_secs(1);
+
+sync::statics! {
+    static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+}
+
+/// The priority class of a [`FairLock`] waiter.
+///
+/// [`FairLock`]: struct.FairLock.html
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Priority {
+    /// Administrative or interactive work, served before every other class.
+    High,
+    /// The default class.
+    #[default]
+    Normal,
+    /// Background work, served when no one else is waiting.
+    Low,
+}
+
+impl Priority {
+    fn rank(self) -> u8 {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// An exclusive lock which serves waiting processes in order, by priority class.
+///
+/// Plain file locks make no promise about which waiter gets the lock next, so a busy lock can
+/// starve some of them. A `FairLock` queues the waiters with tickets: the one at the head of the
+/// queue takes the lock next, as soon as it is released. Tickets are ordered by priority class,
+/// then by age, so an urgent administrative process jumps ahead of routine batch jobs. To keep a
+/// steady stream of urgent waiters from starving the others, a ticket which waited longer than
+/// the [starvation limit](#method.starvation_limit) is promoted to the highest class.
+///
+/// The tickets are files in a `.queue` directory next to the lock file, each locked by its
+/// waiter, so the tickets of crashed waiters are recognized and removed. Every process must
+/// acquire the lock through a `FairLock` for the order to hold.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{FairLock, Priority};
+///
+/// let lock = FairLock::new("deploy.lock");
+/// let guard = lock.lock(Priority::High)?;
+/// // ... deploy ...
+/// drop(guard);
+/// #
+/// # std::fs::remove_file("deploy.lock")?;
+/// # std::fs::remove_dir_all("deploy.lock.queue")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct FairLock {
+    path: PathBuf,
+    queue: PathBuf,
+    starvation_limit: Duration,
+}
+
+/// The lock of a [`FairLock`], released when dropped.
+///
+/// [`FairLock`]: struct.FairLock.html
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct FairGuard {
+    file: PathFile,
+}
+
+impl FairGuard {
+    /// Return the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Deref for FairGuard {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+/// A waiter's place in the queue, as encoded in the name of its ticket.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+struct Ticket {
+    created: u128,
+    pid: u32,
+    sequence: u64,
+    rank: u8,
+}
+
+impl Ticket {
+    fn parse(name: &str) -> Option<Ticket> {
+        let mut fields = name.strip_suffix(".ticket")?.split('-');
+        let ticket = Ticket {
+            created: fields.next()?.parse().ok()?,
+            pid: fields.next()?.parse().ok()?,
+            sequence: fields.next()?.parse().ok()?,
+            rank: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(ticket)
+    }
+
+    fn file_name(&self) -> String {
+        format!(
+            "{:020}-{}-{}-{}.ticket",
+            self.created, self.pid, self.sequence, self.rank
+        )
+    }
+
+    fn age(&self, now: u128) -> Duration {
+        let nanos = now.saturating_sub(self.created);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// The order in which tickets are served.
+    fn key(&self, now: u128, starvation_limit: Duration) -> (u8, u128, u32, u64) {
+        let rank = if self.age(now) >= starvation_limit {
+            0
+        } else {
+            self.rank
+        };
+        (rank, self.created, self.pid, self.sequence)
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos())
+}
+
+impl FairLock {
+    /// Create a fair lock over the file at `path`, which is created as needed.
+    pub fn new<P: AsRef<Path>>(path: P) -> FairLock {
+        let path = path.as_ref().to_path_buf();
+        let mut queue = path.clone().into_os_string();
+        queue.push(".queue");
+        FairLock {
+            path,
+            queue: queue.into(),
+            starvation_limit: Duration::from_secs(10),
+        }
+    }
+
+    /// Promote the waiters which waited for longer than `limit` to the highest priority class.
+    ///
+    /// The default is ten seconds.
+    pub fn starvation_limit(mut self, limit: Duration) -> FairLock {
+        self.starvation_limit = limit;
+        self
+    }
+
+    /// Return the path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue up with the given priority, and acquire the lock exclusively once at the head of the
+    /// queue.
+    pub fn lock(&self, priority: Priority) -> Result<FairGuard, FileLockError> {
+        std::fs::create_dir_all(&self.queue).map_err(FileLockError::Io)?;
+        let ticket = Ticket {
+            created: now(),
+            pid: process_id(),
+            sequence: NEXT_TICKET.fetch_add(1, Ordering::Relaxed),
+            rank: priority.rank(),
+        };
+        let ticket_path = self.queue.join(ticket.file_name());
+        let ticket_file = File::create(&ticket_path).map_err(FileLockError::Io)?;
+        AdvisoryFileLock::lock(&ticket_file, FileLockMode::Exclusive)?;
+
+        let result = self.wait_for_turn(&ticket);
+        // Once the lock is held, or given up on, the next waiter is at the head of the queue.
+        drop(ticket_file);
+        let _ = std::fs::remove_file(&ticket_path);
+        result.map(|file| FairGuard { file })
+    }
+
+    /// Poll the queue until `ticket` is at its head and the lock is free.
+    ///
+    /// The head doesn't block on the lock, since a waiter of a higher class may still queue up
+    /// before it is released.
+    fn wait_for_turn(&self, ticket: &Ticket) -> Result<PathFile, FileLockError> {
+        loop {
+            let now = now();
+            let mut head = ticket.key(now, self.starvation_limit);
+            let entries = std::fs::read_dir(&self.queue).map_err(FileLockError::Io)?;
+            for entry in entries.filter_map(Result::ok) {
+                let other = match entry.file_name().to_str().and_then(Ticket::parse) {
+                    Some(other) if other != *ticket => other,
+                    _ => continue,
+                };
+                if !self.is_abandoned(&other, now, &entry.path()) {
+                    head = head.min(other.key(now, self.starvation_limit));
+                }
+            }
+            if head == ticket.key(now, self.starvation_limit) {
+                match open_locked_with(&self.path, FileLockMode::Exclusive, true, true) {
+                    Err(FileLockError::AlreadyLocked) => {}
+                    result => return result,
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Return whether the waiter of `ticket` is gone, removing the ticket if so.
+    fn is_abandoned(&self, ticket: &Ticket, now: u128, path: &Path) -> bool {
+        if ticket.age(now) < STALE_GRACE {
+            return false;
+        }
+        let abandoned = if ticket.pid == process_id() {
+            // The tickets of this process belong to its waiting threads, which remove them
+            // themselves. Probing them wouldn't tell either: the locks of the `Fcntl` backend
+            // belong to the process, and closing the probe would even release them.
+            false
+        } else if default_backend() == Backend::Noop {
+            // Nothing is ever locked, so only the process of the waiter tells.
+            !sys::process_alive(ticket.pid)
+        } else {
+            File::open(path)
+                .is_ok_and(|file| AdvisoryFileLock::try_lock(&file, FileLockMode::Shared).is_ok())
+        };
+        if abandoned {
+            let _ = std::fs::remove_file(path);
+        }
+        abandoned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::sync::{Arc, Mutex};
+
+    /// Queue waiters of the given classes, one by one, behind a held lock, and return the order
+    /// in which they got it.
+    fn serve(lock: &FairLock, priorities: &[Priority]) -> Vec<Priority> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let holder = lock.lock(Priority::Normal).unwrap();
+        thread::scope(|scope| {
+            for (queued, &priority) in priorities.iter().enumerate() {
+                let order = Arc::clone(&order);
+                scope.spawn(move || {
+                    let _guard = lock.lock(priority).unwrap();
+                    order.lock().unwrap().push(priority);
+                });
+                while std::fs::read_dir(&lock.queue).unwrap().count() <= queued {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            drop(holder);
+        });
+        Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn higher_classes_jump_the_queue() {
+        let mut test_file = temp_dir();
+        test_file.push("fair_priorities");
+        let lock = FairLock::new(&test_file);
+        assert_eq!(
+            serve(&lock, &[Priority::Low, Priority::Normal, Priority::High]),
+            [Priority::High, Priority::Normal, Priority::Low]
+        );
+
+        // Starved waiters are served in arrival order.
+        let lock = lock.starvation_limit(Duration::from_millis(0));
+        assert_eq!(
+            serve(&lock, &[Priority::Low, Priority::High]),
+            [Priority::Low, Priority::High]
+        );
+
+        std::fs::remove_dir_all(&lock.queue).unwrap();
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn only_the_tickets_of_other_processes_are_abandoned() {
+        let mut test_file = temp_dir();
+        test_file.push("fair_abandoned");
+        let lock = FairLock::new(&test_file);
+        std::fs::create_dir_all(&lock.queue).unwrap();
+        let now = now();
+        let stale = |pid| {
+            let ticket = Ticket {
+                created: now - STALE_GRACE.as_nanos(),
+                pid,
+                sequence: 0,
+                rank: Priority::Normal.rank(),
+            };
+            let path = lock.queue.join(ticket.file_name());
+            File::create(&path).unwrap();
+            (ticket, path)
+        };
+
+        // An unlocked ticket of this process may still have a waiter, whose locks the probe
+        // can't see with the `Fcntl` backend.
+        let (ticket, path) = stale(process_id());
+        assert!(!lock.is_abandoned(&ticket, now, &path));
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+
+        let (ticket, path) = stale(process_id().wrapping_add(1));
+        assert!(lock.is_abandoned(&ticket, now, &path));
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&lock.queue).unwrap();
+    }
+}
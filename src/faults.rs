@@ -0,0 +1,149 @@
+//! Fault injection for exercising error-handling paths in tests.
+//!
+//! Faults are queued per operation and consumed by the next matching operation issued from
+//! the **current thread**, so tests running in parallel don't observe each other's faults.
+//! A failing fault short-circuits the operation before the underlying system call is made.
+//!
+//! This module is only available with the `test-util` feature.
+//!
+//! Example:
+//! ```
+//! use std::fs::File;
+//! use std::io::ErrorKind;
+//! use advisory_lock::{faults, AdvisoryFileLock, FileLockError, FileLockMode};
+//!
+//! let file = File::create("faults.txt")?;
+//! AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+//!
+//! faults::fail_next_unlock(ErrorKind::Other);
+//! assert!(matches!(AdvisoryFileLock::unlock(&file), Err(FileLockError::Io(_))));
+//! AdvisoryFileLock::unlock(&file)?;
+//! #
+//! # std::fs::remove_file("faults.txt")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+use crate::{FileLockError, FileLockOperation};
+
+/// A fault which can be injected into an operation.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Fail the operation with an I/O error of the given kind.
+    Error(ErrorKind),
+    /// Fail the operation as if the file were locked by another process.
+    AlreadyLocked,
+    /// Sleep for the given duration, then perform the operation as usual.
+    Delay(Duration),
+}
+
+thread_local! {
+    static FAULTS: RefCell<VecDeque<(FileLockOperation, Fault)>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// Queue a fault for the next `operation` issued from the current thread.
+pub fn inject(operation: FileLockOperation, fault: Fault) {
+    FAULTS.with(|faults| faults.borrow_mut().push_back((operation, fault)));
+}
+
+/// Make the next blocking lock fail with an I/O error of the given kind.
+pub fn fail_next_lock(kind: ErrorKind) {
+    inject(FileLockOperation::Lock, Fault::Error(kind));
+}
+
+/// Make the next non-blocking lock fail with an I/O error of the given kind.
+pub fn fail_next_try_lock(kind: ErrorKind) {
+    inject(FileLockOperation::TryLock, Fault::Error(kind));
+}
+
+/// Make the next unlock fail with an I/O error of the given kind.
+pub fn fail_next_unlock(kind: ErrorKind) {
+    inject(FileLockOperation::Unlock, Fault::Error(kind));
+}
+
+/// Delay the next `operation` by the given duration.
+pub fn delay_next(operation: FileLockOperation, duration: Duration) {
+    inject(operation, Fault::Delay(duration));
+}
+
+/// Discard every fault queued on the current thread.
+pub fn clear() {
+    FAULTS.with(|faults| faults.borrow_mut().clear());
+}
+
+/// Apply the first fault queued for `operation`, if any.
+pub(crate) fn intercept(operation: FileLockOperation) -> Result<(), FileLockError> {
+    let fault = FAULTS.with(|faults| {
+        let mut faults = faults.borrow_mut();
+        let index = faults.iter().position(|(op, _)| *op == operation)?;
+        faults.remove(index).map(|(_, fault)| fault)
+    });
+
+    match fault {
+        None => Ok(()),
+        Some(Fault::Error(kind)) => Err(FileLockError::Io(io::Error::new(kind, "injected fault"))),
+        Some(Fault::AlreadyLocked) => Err(FileLockError::AlreadyLocked),
+        Some(Fault::Delay(duration)) => {
+            thread::sleep(duration);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockMode};
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::time::Instant;
+
+    #[test]
+    fn faults_are_consumed_in_order() {
+        let mut test_file = temp_dir();
+        test_file.push("faults_consumed");
+        let file = File::create(&test_file).unwrap();
+
+        fail_next_try_lock(ErrorKind::PermissionDenied);
+        inject(FileLockOperation::TryLock, Fault::AlreadyLocked);
+        match AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive) {
+            Err(FileLockError::Io(err)) => assert_eq!(err.kind(), ErrorKind::PermissionDenied),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive).unwrap();
+
+        fail_next_unlock(ErrorKind::Other);
+        assert!(AdvisoryFileLock::unlock(&file).is_err());
+        AdvisoryFileLock::unlock(&file).unwrap();
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn delay_and_clear() {
+        let mut test_file = temp_dir();
+        test_file.push("faults_delay");
+        let file = File::create(&test_file).unwrap();
+
+        delay_next(FileLockOperation::Lock, Duration::from_millis(20));
+        let start = Instant::now();
+        AdvisoryFileLock::lock(&file, FileLockMode::Shared).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        fail_next_unlock(ErrorKind::Other);
+        clear();
+        AdvisoryFileLock::unlock(&file).unwrap();
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
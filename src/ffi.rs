@@ -0,0 +1,244 @@
+//! A C interface to the crate, so that C and C++ code can take part in the same locking.
+//!
+//! A lock is an opaque `advisory_lock *` handle to an open file, created by
+//! [`advisory_lock_open`] and freed by [`advisory_lock_close`], which releases its lock. Every
+//! operation returns one of the `ADVISORY_LOCK_*` codes declared, with the functions, in
+//! `include/advisory_lock.h`.
+//!
+//! This module is only available with the `ffi` feature. Build a shared library with:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! [`advisory_lock_open`]: fn.advisory_lock_open.html
+//! [`advisory_lock_close`]: fn.advisory_lock_close.html
+use std::ffi::{c_char, c_int, CStr};
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+use crate::{AdvisoryFileLock, FileLockError, FileLockErrorKind, FileLockMode};
+
+/// The operation succeeded.
+pub const ADVISORY_LOCK_OK: c_int = 0;
+/// The lock is held elsewhere.
+pub const ADVISORY_LOCK_ALREADY_LOCKED: c_int = 1;
+/// An I/O error occurred; `errno` or `GetLastError` may tell more.
+pub const ADVISORY_LOCK_IO: c_int = 2;
+/// A pointer was null, a path wasn't valid, or a mode was unknown.
+pub const ADVISORY_LOCK_INVALID_ARGUMENT: c_int = 3;
+/// The operation was interrupted by a signal.
+pub const ADVISORY_LOCK_INTERRUPTED: c_int = 4;
+/// The file or its file system doesn't support locking.
+pub const ADVISORY_LOCK_UNSUPPORTED: c_int = 5;
+/// The system ran out of lock resources.
+pub const ADVISORY_LOCK_NO_LOCK_RESOURCES: c_int = 6;
+/// Any other error.
+pub const ADVISORY_LOCK_OTHER: c_int = 7;
+
+/// Request a shared lock.
+pub const ADVISORY_LOCK_SHARED: c_int = 0;
+/// Request an exclusive lock.
+pub const ADVISORY_LOCK_EXCLUSIVE: c_int = 1;
+
+/// An open file whose lock is manipulated through the C interface.
+#[allow(non_camel_case_types)]
+pub struct advisory_lock {
+    file: File,
+}
+
+fn error_code(err: &FileLockError) -> c_int {
+    match err.kind() {
+        FileLockErrorKind::AlreadyLocked => ADVISORY_LOCK_ALREADY_LOCKED,
+        FileLockErrorKind::Io => ADVISORY_LOCK_IO,
+        FileLockErrorKind::InvalidHandle => ADVISORY_LOCK_INVALID_ARGUMENT,
+        FileLockErrorKind::Interrupted => ADVISORY_LOCK_INTERRUPTED,
+        FileLockErrorKind::Unsupported | FileLockErrorKind::UnsupportedFileType => {
+            ADVISORY_LOCK_UNSUPPORTED
+        }
+        FileLockErrorKind::NoLockResources => ADVISORY_LOCK_NO_LOCK_RESOURCES,
+        _ => ADVISORY_LOCK_OTHER,
+    }
+}
+
+fn result_code(result: Result<(), FileLockError>) -> c_int {
+    match result {
+        Ok(()) => ADVISORY_LOCK_OK,
+        Err(err) => error_code(&err),
+    }
+}
+
+fn parse_mode(mode: c_int) -> Option<FileLockMode> {
+    match mode {
+        ADVISORY_LOCK_SHARED => Some(FileLockMode::Shared),
+        ADVISORY_LOCK_EXCLUSIVE => Some(FileLockMode::Exclusive),
+        _ => None,
+    }
+}
+
+fn parse_path(path: &CStr) -> Option<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(std::ffi::OsStr::from_bytes(path.to_bytes()).into())
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_str().ok().map(PathBuf::from)
+    }
+}
+
+/// Open the file at `path` for reading and writing, creating it if needed, without locking it.
+///
+/// Returns null on failure, and stores the error code in `*error` unless `error` is null.
+///
+/// # Safety
+///
+/// `path` must be null or a valid NUL-terminated string, in UTF-8 on Windows, and `error` must
+/// be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn advisory_lock_open(
+    path: *const c_char,
+    error: *mut c_int,
+) -> *mut advisory_lock {
+    let result = match (!path.is_null()).then(|| parse_path(CStr::from_ptr(path))) {
+        Some(Some(path)) => OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|err| error_code(&FileLockError::from_io(err))),
+        _ => Err(ADVISORY_LOCK_INVALID_ARGUMENT),
+    };
+    let (lock, code) = match result {
+        Ok(file) => (
+            Box::into_raw(Box::new(advisory_lock { file })),
+            ADVISORY_LOCK_OK,
+        ),
+        Err(code) => (std::ptr::null_mut(), code),
+    };
+    if !error.is_null() {
+        *error = code;
+    }
+    lock
+}
+
+/// Acquire the lock of `lock` in `mode`, waiting as long as it is held elsewhere.
+///
+/// # Safety
+///
+/// `lock` must be null or a handle returned by `advisory_lock_open` and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn advisory_lock_lock(lock: *const advisory_lock, mode: c_int) -> c_int {
+    match (lock.as_ref(), parse_mode(mode)) {
+        (Some(lock), Some(mode)) => result_code(AdvisoryFileLock::lock(&lock.file, mode)),
+        _ => ADVISORY_LOCK_INVALID_ARGUMENT,
+    }
+}
+
+/// Acquire the lock of `lock` in `mode`, or return `ADVISORY_LOCK_ALREADY_LOCKED` right away
+/// if it is held elsewhere.
+///
+/// # Safety
+///
+/// `lock` must be null or a handle returned by `advisory_lock_open` and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn advisory_lock_try_lock(lock: *const advisory_lock, mode: c_int) -> c_int {
+    match (lock.as_ref(), parse_mode(mode)) {
+        (Some(lock), Some(mode)) => result_code(AdvisoryFileLock::try_lock(&lock.file, mode)),
+        _ => ADVISORY_LOCK_INVALID_ARGUMENT,
+    }
+}
+
+/// Release the lock of `lock`.
+///
+/// # Safety
+///
+/// `lock` must be null or a handle returned by `advisory_lock_open` and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn advisory_lock_unlock(lock: *const advisory_lock) -> c_int {
+    match lock.as_ref() {
+        Some(lock) => result_code(AdvisoryFileLock::unlock(&lock.file)),
+        None => ADVISORY_LOCK_INVALID_ARGUMENT,
+    }
+}
+
+/// Close the file of `lock`, which releases its lock, and free the handle. Null is ignored.
+///
+/// # Safety
+///
+/// `lock` must be null or a handle returned by `advisory_lock_open` and not closed yet; it
+/// must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn advisory_lock_close(lock: *mut advisory_lock) {
+    if !lock.is_null() {
+        drop(Box::from_raw(lock));
+    }
+}
+
+/// Return a static, NUL-terminated description of the error code `code`.
+#[no_mangle]
+pub extern "C" fn advisory_lock_strerror(code: c_int) -> *const c_char {
+    let message: &'static [u8] = match code {
+        ADVISORY_LOCK_OK => b"success\0",
+        ADVISORY_LOCK_ALREADY_LOCKED => b"the file is already locked\0",
+        ADVISORY_LOCK_IO => b"I/O error\0",
+        ADVISORY_LOCK_INVALID_ARGUMENT => b"invalid argument\0",
+        ADVISORY_LOCK_INTERRUPTED => b"interrupted\0",
+        ADVISORY_LOCK_UNSUPPORTED => b"locking is not supported\0",
+        ADVISORY_LOCK_NO_LOCK_RESOURCES => b"no lock resources available\0",
+        _ => b"unknown error\0",
+    };
+    message.as_ptr().cast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::ffi::CString;
+
+    #[test]
+    fn c_interface_locks_files() {
+        let mut test_file = temp_dir();
+        test_file.push("ffi_locks");
+        let path = CString::new(test_file.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let mut error = -1;
+            let first = advisory_lock_open(path.as_ptr(), &mut error);
+            assert_eq!(error, ADVISORY_LOCK_OK);
+            let second = advisory_lock_open(path.as_ptr(), std::ptr::null_mut());
+            assert!(!first.is_null() && !second.is_null());
+
+            assert_eq!(
+                advisory_lock_lock(first, ADVISORY_LOCK_EXCLUSIVE),
+                ADVISORY_LOCK_OK
+            );
+            assert_eq!(
+                advisory_lock_try_lock(second, ADVISORY_LOCK_SHARED),
+                ADVISORY_LOCK_ALREADY_LOCKED
+            );
+            assert_eq!(
+                advisory_lock_try_lock(second, 2),
+                ADVISORY_LOCK_INVALID_ARGUMENT
+            );
+            assert_eq!(advisory_lock_unlock(first), ADVISORY_LOCK_OK);
+            assert_eq!(
+                advisory_lock_try_lock(second, ADVISORY_LOCK_SHARED),
+                ADVISORY_LOCK_OK
+            );
+            advisory_lock_close(second);
+            advisory_lock_close(first);
+
+            assert!(advisory_lock_open(std::ptr::null(), &mut error).is_null());
+            assert_eq!(error, ADVISORY_LOCK_INVALID_ARGUMENT);
+            assert_eq!(advisory_lock_unlock(std::ptr::null()), error);
+            let message = CStr::from_ptr(advisory_lock_strerror(ADVISORY_LOCK_ALREADY_LOCKED));
+            assert_eq!(message.to_str().unwrap(), "the file is already locked");
+        }
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
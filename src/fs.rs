@@ -0,0 +1,550 @@
+//! Convenience functions reading and writing whole files under the appropriate lock.
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+pub(crate) use crate::emulated::PathFile;
+use crate::{checksum, process_id, AdvisoryFileLock, FileId, FileLockError, FileLockMode};
+
+/// A file the crate opened by its path, to be locked with [`AdvisoryFileLock`].
+///
+/// This is a plain `File` wherever files can be locked through their descriptor or handle. On
+/// the targets which have neither, the file is locked through the path it was opened with, and
+/// the lock is released when it is dropped; see `emulated::PathFile`.
+#[cfg(any(unix, windows, target_os = "wasi"))]
+pub(crate) type PathFile = File;
+
+/// Read the entire contents of a file while holding a shared lock on it.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{read_locked, write_locked};
+///
+/// write_locked("read_locked.txt", b"hello")?;
+/// assert_eq!(read_locked("read_locked.txt")?, b"hello");
+/// #
+/// # std::fs::remove_file("read_locked.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn read_locked<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, FileLockError> {
+    let file = open_locked(path.as_ref(), FileLockMode::Shared, false)?;
+    read_all(&file)
+}
+
+/// Read the entire contents of a file into a string while holding a shared lock on it.
+pub fn read_to_string_locked<P: AsRef<Path>>(path: P) -> Result<String, FileLockError> {
+    let file = open_locked(path.as_ref(), FileLockMode::Shared, false)?;
+    let mut contents = String::new();
+    (&file)
+        .read_to_string(&mut contents)
+        .map_err(FileLockError::Io)?;
+    Ok(contents)
+}
+
+/// Replace the contents of a file, creating it if needed, while holding an exclusive lock on it.
+///
+/// The file is only truncated once the lock is held, so readers using [`read_locked`] never
+/// observe it empty or partially written.
+///
+/// [`read_locked`]: fn.read_locked.html
+pub fn write_locked<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> Result<(), FileLockError> {
+    let file = open_exclusive(path.as_ref())?;
+    replace_contents(&file, contents.as_ref(), false)
+}
+
+/// Like [`write_locked`], but also syncs the file to disk before releasing the lock.
+///
+/// [`write_locked`]: fn.write_locked.html
+pub fn write_locked_durably<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> Result<(), FileLockError> {
+    let file = open_exclusive(path.as_ref())?;
+    replace_contents(&file, contents.as_ref(), true)
+}
+
+/// Read the contents of a file written by [`write_locked_checked`] while holding a shared lock on
+/// it, verifying their checksum.
+///
+/// Returns [`FileLockError::Corrupted`] if the checksum doesn't match, e.g. because a writer
+/// crashed halfway or a process which doesn't cooperate modified the file.
+///
+/// [`write_locked_checked`]: fn.write_locked_checked.html
+/// [`FileLockError::Corrupted`]: enum.FileLockError.html#variant.Corrupted
+pub fn read_locked_checked<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, FileLockError> {
+    checksum::unseal(read_locked(path)?)
+}
+
+/// Like [`write_locked_durably`], but also appends a checksum of `contents` to the file, to be
+/// verified by [`read_locked_checked`].
+///
+/// Example:
+/// ```
+/// use advisory_lock::{read_locked_checked, write_locked_checked, FileLockError};
+///
+/// write_locked_checked("checked.txt", b"hello")?;
+/// assert_eq!(read_locked_checked("checked.txt")?, b"hello");
+///
+/// std::fs::write("checked.txt", b"torn write")?;
+/// assert!(matches!(read_locked_checked("checked.txt"), Err(FileLockError::Corrupted)));
+/// #
+/// # std::fs::remove_file("checked.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`write_locked_durably`]: fn.write_locked_durably.html
+/// [`read_locked_checked`]: fn.read_locked_checked.html
+pub fn write_locked_checked<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> Result<(), FileLockError> {
+    write_locked_durably(path, checksum::seal(contents.as_ref()))
+}
+
+/// Replace the contents of a file through a temporary file, while holding an exclusive lock on it.
+///
+/// `f` receives the current contents (empty if the file doesn't exist) and returns the new ones,
+/// which are written to a temporary file in the same directory, synced, and renamed over the
+/// original, after which the directory itself is synced. A crash at any point leaves either the
+/// old or the new contents in place, never a mix of both.
+///
+/// Renaming replaces the file, so processes waiting for the lock of the old one must notice
+/// it was replaced: [`read_locked`], [`write_locked`] and the other whole-file helpers do, by
+/// checking that the path still refers to the file they locked and retrying otherwise. Don't
+/// mix this function with locks taken on long-lived handles of the same path.
+///
+/// The new file gets the permissions of the old one. On Windows, a file can only be replaced
+/// while other handles have it open if they allow it to be deleted, as the handles opened by the
+/// standard library do, and if the system supports POSIX rename semantics (Windows 10 version
+/// 1607 and later); otherwise the rename fails with an error of kind `PermissionDenied`.
+///
+/// [`read_locked`]: fn.read_locked.html
+/// [`write_locked`]: fn.write_locked.html
+///
+/// Example:
+/// ```
+/// use advisory_lock::{read_locked, replace_atomically};
+///
+/// replace_atomically("replace.txt", |old| [old, b"line\n"].concat())?;
+/// replace_atomically("replace.txt", |old| [old, b"line\n"].concat())?;
+/// assert_eq!(read_locked("replace.txt")?, b"line\nline\n");
+/// #
+/// # std::fs::remove_file("replace.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn replace_atomically<P, F>(path: P, f: F) -> Result<(), FileLockError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&[u8]) -> Vec<u8>,
+{
+    let path = path.as_ref();
+    let file = open_exclusive(path)?;
+    let contents = f(&read_all(&file)?);
+    replace_file(path, &contents).map_err(FileLockError::Io)
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over `path`.
+pub(crate) fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_file_with(path, |temp| temp.write_all(contents))
+}
+
+/// Fill a temporary file next to `path` with `write` and rename it over `path`, keeping the
+/// permissions of `path`.
+fn replace_file_with<T>(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<T>,
+) -> io::Result<T> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(
+        ".{}.{}.tmp",
+        process_id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = (|| -> io::Result<T> {
+        let mut temp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        // The temporary file is created with the default permissions, which may be wider.
+        match std::fs::metadata(path) {
+            Ok(metadata) => temp.set_permissions(metadata.permissions())?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let written = write(&mut temp)?;
+        temp.sync_all()?;
+        drop(temp);
+        std::fs::rename(&temp_path, path)?;
+        Ok(written)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    let written = result?;
+
+    sync_parent(path)?;
+    Ok(written)
+}
+
+/// Sync the directory containing `path`, making a rename inside it durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be synced on Windows, where renames are durable once they return.
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Copy a file to `dest` while holding a shared lock on it, returning the number of bytes copied.
+///
+/// Cooperating writers are excluded for the duration of the copy, so the snapshot is consistent.
+/// The copy is written to a temporary file next to `dest` and renamed into place once synced,
+/// so `dest` either keeps its previous contents or holds the complete snapshot. Where the
+/// platform supports it, the data is copied (or reflinked) by the kernel.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{read_locked, snapshot_to, write_locked};
+///
+/// write_locked("database.db", b"records")?;
+/// snapshot_to("database.db", "database.db.bak")?;
+/// assert_eq!(read_locked("database.db.bak")?, b"records");
+/// #
+/// # std::fs::remove_file("database.db")?;
+/// # std::fs::remove_file("database.db.bak")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn snapshot_to<P: AsRef<Path>, Q: AsRef<Path>>(path: P, dest: Q) -> Result<u64, FileLockError> {
+    let file = open_locked(path.as_ref(), FileLockMode::Shared, false)?;
+    replace_file_with(dest.as_ref(), |temp| io::copy(&mut &file, temp)).map_err(FileLockError::Io)
+}
+
+/// Lock `file`, run `f` and release the lock, even if `f` panics.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::io::Write;
+/// use advisory_lock::{with_file_lock, FileLockMode};
+///
+/// let file = File::create("with_file_lock.txt")?;
+/// with_file_lock(&file, FileLockMode::Exclusive, |mut file| {
+///     file.write_all(b"written under the lock")
+/// })??;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("with_file_lock.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn with_file_lock<F, R>(
+    file: &File,
+    file_lock_mode: FileLockMode,
+    f: F,
+) -> Result<R, FileLockError>
+where
+    F: FnOnce(&File) -> R,
+{
+    let guard = AdvisoryFileLock::lock_guard(file, file_lock_mode)?;
+    let result = f(file);
+    guard.unlock()?;
+    Ok(result)
+}
+
+/// Open the file at `path`, lock it, run `f` on it and close it, releasing the lock, even if `f`
+/// panics.
+///
+/// Exclusive locks create the file if needed and open it for reading and writing; shared locks
+/// open it for reading only.
+///
+/// Example:
+/// ```
+/// use std::io::{Read, Write};
+/// use advisory_lock::{with_path_lock, FileLockMode};
+///
+/// with_path_lock("with_path_lock.txt", FileLockMode::Exclusive, |mut file| {
+///     file.write_all(b"hello")
+/// })??;
+/// let contents = with_path_lock("with_path_lock.txt", FileLockMode::Shared, |mut file| {
+///     let mut contents = String::new();
+///     file.read_to_string(&mut contents).map(|_| contents)
+/// })??;
+/// assert_eq!(contents, "hello");
+/// #
+/// # std::fs::remove_file("with_path_lock.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn with_path_lock<P, F, R>(
+    path: P,
+    file_lock_mode: FileLockMode,
+    f: F,
+) -> Result<R, FileLockError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&File) -> R,
+{
+    let create = file_lock_mode == FileLockMode::Exclusive;
+    let file = open_locked(path.as_ref(), file_lock_mode, create)?;
+    Ok(f(&file))
+}
+
+/// Open or create the file at `path` for reading and writing and lock it exclusively.
+pub(crate) fn open_exclusive(path: &Path) -> Result<File, FileLockError> {
+    open_locked(path, FileLockMode::Exclusive, true)
+}
+
+/// Open the file at `path` and lock it, making sure it wasn't replaced while waiting for the lock.
+pub(crate) fn open_locked(
+    path: &Path,
+    file_lock_mode: FileLockMode,
+    create: bool,
+) -> Result<File, FileLockError> {
+    open_locked_by(path, create, |file| {
+        AdvisoryFileLock::lock(file, file_lock_mode)
+    })
+}
+
+/// Like [`open_locked`], but fails with `AlreadyLocked` instead of blocking if `immediate`.
+#[cfg(any(unix, windows, target_os = "wasi"))]
+pub(crate) fn open_locked_with(
+    path: &Path,
+    file_lock_mode: FileLockMode,
+    create: bool,
+    immediate: bool,
+) -> Result<PathFile, FileLockError> {
+    open_locked_by(path, create, |file| {
+        if immediate {
+            AdvisoryFileLock::try_lock(file, file_lock_mode)
+        } else {
+            AdvisoryFileLock::lock(file, file_lock_mode)
+        }
+    })
+}
+
+/// Like [`open_locked`], but fails with `AlreadyLocked` instead of blocking if `immediate`.
+///
+/// Unlike the other functions opening locked files, this works on the targets without file
+/// descriptors or handles too: there, the path is locked before the file is opened.
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+pub(crate) fn open_locked_with(
+    path: &Path,
+    file_lock_mode: FileLockMode,
+    create: bool,
+    immediate: bool,
+) -> Result<PathFile, FileLockError> {
+    PathFile::open_locked(path, &open_options(create), file_lock_mode, immediate)
+}
+
+/// Like [`open_locked`], but locks the file with `lock`.
+pub(crate) fn open_locked_by(
+    path: &Path,
+    create: bool,
+    lock: impl Fn(&File) -> Result<(), FileLockError>,
+) -> Result<File, FileLockError> {
+    loop {
+        let file = open_options(create).open(path).map_err(FileLockError::Io)?;
+        lock(&file)?;
+
+        let locked = FileId::of(&file).map_err(FileLockError::Io)?;
+        match FileId::of_path(path) {
+            Ok(current) if current == locked => return Ok(file),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(FileLockError::Io(err)),
+        }
+    }
+}
+
+/// Open the file at `path` with `options`, to be locked later.
+pub(crate) fn open_unlocked(path: &Path, options: &OpenOptions) -> Result<PathFile, FileLockError> {
+    #[cfg(any(unix, windows, target_os = "wasi"))]
+    let file = options.open(path);
+    #[cfg(not(any(unix, windows, target_os = "wasi")))]
+    let file = PathFile::open(path, options);
+    file.map_err(FileLockError::Io)
+}
+
+/// The options opening a file to lock, for reading, and for writing too if it may be `create`d.
+fn open_options(create: bool) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(create)
+        .create(create)
+        .truncate(false);
+    options
+}
+
+/// Remove the file at `path` through which `file`, a `File` or a [`PathFile`], is locked,
+/// releasing the lock only after.
+///
+/// The standard library opens files sharing deletion, so on Windows too, they can be removed
+/// while open: the file is either gone right away, or, on older systems, can't be opened anymore
+/// until its last handle is closed. Either way, no one can lock it in between. Removal fails on
+/// Windows if another program opened the file without sharing deletion.
+pub(crate) fn remove_locked<F>(path: &Path, file: F) -> Result<(), FileLockError> {
+    let result = std::fs::remove_file(path).map_err(FileLockError::Io);
+    drop(file);
+    result
+}
+
+/// Read the rest of `file`.
+pub(crate) fn read_all(mut file: &File) -> Result<Vec<u8>, FileLockError> {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).map_err(FileLockError::Io)?;
+    Ok(bytes)
+}
+
+/// Replace the whole contents of `file`, optionally syncing it to disk.
+pub(crate) fn replace_contents(
+    mut file: &File,
+    contents: &[u8],
+    sync: bool,
+) -> Result<(), FileLockError> {
+    file.set_len(0)
+        .and_then(|()| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(contents))
+        .and_then(|()| if sync { file.sync_all() } else { Ok(()) })
+        .map_err(FileLockError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn scoped_locks_survive_panics() {
+        let mut test_file = temp_dir();
+        test_file.push("fs_scoped_panic");
+        let file = File::create(&test_file).unwrap();
+        let outsider = File::open(&test_file).unwrap();
+
+        let panicked = std::panic::catch_unwind(|| {
+            with_file_lock(&file, FileLockMode::Exclusive, |_| panic!("in the closure"))
+        });
+        assert!(panicked.is_err());
+        AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).unwrap();
+        AdvisoryFileLock::unlock(&outsider).unwrap();
+
+        let held = with_path_lock(&test_file, FileLockMode::Shared, |_| {
+            AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).is_err()
+        });
+        assert!(held.unwrap());
+        AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).unwrap();
+
+        drop((file, outsider));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn write_waits_for_readers() {
+        let mut test_file = temp_dir();
+        test_file.push("fs_write_waits");
+        write_locked_durably(&test_file, "first version").unwrap();
+        assert_eq!(read_to_string_locked(&test_file).unwrap(), "first version");
+
+        let reader = File::open(&test_file).unwrap();
+        AdvisoryFileLock::lock(&reader, FileLockMode::Shared).unwrap();
+        let writer = {
+            let path = test_file.clone();
+            std::thread::spawn(move || write_locked(path, "second").unwrap())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(read_all(&reader).unwrap(), b"first version");
+        AdvisoryFileLock::unlock(&reader).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(read_locked(&test_file).unwrap(), b"second");
+        drop(reader);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn waiters_follow_replacements() {
+        let mut test_file = temp_dir();
+        test_file.push("fs_waiters_follow");
+        write_locked(&test_file, "0").unwrap();
+
+        let holder = open_exclusive(&test_file).unwrap();
+        let waiter = {
+            let path = test_file.clone();
+            std::thread::spawn(move || read_to_string_locked(path).unwrap())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        replace_file(&test_file, b"1").unwrap();
+        drop(holder);
+        assert_eq!(waiter.join().unwrap(), "1");
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let path = test_file.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        replace_atomically(&path, |old| {
+                            let n: u32 = std::str::from_utf8(old).unwrap().parse().unwrap();
+                            (n + 1).to_string().into_bytes()
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(read_to_string_locked(&test_file).unwrap(), "41");
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn files_are_removed_before_being_unlocked() {
+        let mut test_file = temp_dir();
+        test_file.push("fs_remove_locked");
+        let holder = open_exclusive(&test_file).unwrap();
+        // A process which opened the file before its removal, and waits for its lock.
+        let waiter = File::open(&test_file).unwrap();
+
+        remove_locked(&test_file, holder).unwrap();
+        drop(waiter);
+        assert!(!test_file.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replacements_keep_the_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut test_file = temp_dir();
+        test_file.push("fs_replace_permissions");
+        write_locked(&test_file, "secret").unwrap();
+        let private = std::fs::Permissions::from_mode(0o600);
+        std::fs::set_permissions(&test_file, private).unwrap();
+
+        replace_atomically(&test_file, |old| [old, b" and more"].concat()).unwrap();
+        let mode = std::fs::metadata(&test_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(read_locked(&test_file).unwrap(), b"secret and more");
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::fs::remove_locked;
+use crate::{sys, AdvisoryFileLock, FileId, FileLockError, FileLockMode};
+
+/// Which files [`gc_lock_files`] considers, and how old they must be to be removed.
+///
+/// [`gc_lock_files`]: fn.gc_lock_files.html
+#[derive(Clone, Debug)]
+pub struct GcPolicy {
+    extensions: Vec<OsString>,
+    min_age: Duration,
+}
+
+impl Default for GcPolicy {
+    /// Consider `*.lock` and `*.pid` files which weren't modified for a minute.
+    fn default() -> GcPolicy {
+        GcPolicy {
+            extensions: vec!["lock".into(), "pid".into()],
+            min_age: Duration::from_secs(60),
+        }
+    }
+}
+
+impl GcPolicy {
+    /// Create the default policy.
+    pub fn new() -> GcPolicy {
+        GcPolicy::default()
+    }
+
+    /// Consider the files with the given extensions, instead of `lock` and `pid`.
+    pub fn extensions<I, S>(mut self, extensions: I) -> GcPolicy
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only remove files which weren't modified for at least `min_age`.
+    ///
+    /// This leaves time to the process which created a lock file to lock it, so it isn't
+    /// mistaken for an orphan in between.
+    pub fn min_age(mut self, min_age: Duration) -> GcPolicy {
+        self.min_age = min_age;
+        self
+    }
+}
+
+/// Remove the orphaned lock files directly inside `dir`, returning their paths.
+///
+/// A file is an orphan if nobody holds its lock, and, if it starts with a process id like a pid
+/// file does, that process is gone. Each file is removed while its lock is held, so a process
+/// which opened it in the meantime ends up locking a file which no longer exists; the crate's own
+/// path-based APIs detect this and open the file anew, but code which opens and locks a path by
+/// hand must check it too (e.g. by comparing [`FileId::of`] and [`FileId::of_path`]).
+///
+/// Files which can't be inspected, e.g. because they vanished or aren't readable, are skipped,
+/// as are files which can't be removed, e.g. on Windows, because another program opened them
+/// without sharing deletion.
+///
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use advisory_lock::{gc_lock_files, GcPolicy};
+///
+/// std::fs::create_dir_all("locks")?;
+/// std::fs::write("locks/orphan.lock", b"")?;
+/// let removed = gc_lock_files("locks", &GcPolicy::new().min_age(Duration::from_secs(0)))?;
+/// assert_eq!(removed.len(), 1);
+/// #
+/// # std::fs::remove_dir_all("locks")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileId::of`]: struct.FileId.html#method.of
+/// [`FileId::of_path`]: struct.FileId.html#method.of_path
+pub fn gc_lock_files<P: AsRef<Path>>(
+    dir: P,
+    policy: &GcPolicy,
+) -> Result<Vec<PathBuf>, FileLockError> {
+    let mut removed = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(FileLockError::Io)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let considered = path
+            .extension()
+            .is_some_and(|extension| policy.extensions.iter().any(|e| e == extension));
+        if considered && remove_if_orphaned(&path, policy.min_age) {
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+fn remove_if_orphaned(path: &Path, min_age: Duration) -> bool {
+    let old_enough = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age >= min_age);
+    if !old_enough {
+        return false;
+    }
+
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    if AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive).is_err() {
+        return false;
+    }
+    // The path may have been replaced before the lock was acquired.
+    let same_file = match (FileId::of(&file), FileId::of_path(path)) {
+        (Ok(locked), Ok(current)) => locked == current,
+        _ => false,
+    };
+    if !same_file || owner_alive(&mut file) {
+        return false;
+    }
+
+    remove_locked(path, file).is_ok()
+}
+
+/// Return whether `file` starts with the id of a running process.
+fn owner_alive(file: &mut File) -> bool {
+    let mut contents = String::new();
+    if file.take(64).read_to_string(&mut contents).is_err() {
+        return false;
+    }
+    contents
+        .lines()
+        .next()
+        .and_then(|line| line.trim().parse::<u32>().ok())
+        .is_some_and(sys::process_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn only_orphans_are_collected() {
+        let mut dir = temp_dir();
+        dir.push("gc_lock_files");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("orphan.lock"), b"").unwrap();
+        std::fs::write(dir.join("held.lock"), b"").unwrap();
+        std::fs::write(dir.join("alive.pid"), std::process::id().to_string()).unwrap();
+        // Well above the largest process id of any supported system.
+        std::fs::write(dir.join("dead.pid"), b"2147483000\n").unwrap();
+        std::fs::write(dir.join("unrelated.txt"), b"").unwrap();
+        let held = File::open(dir.join("held.lock")).unwrap();
+        AdvisoryFileLock::lock(&held, FileLockMode::Shared).unwrap();
+
+        let policy = GcPolicy::new().min_age(Duration::from_secs(0));
+        let mut removed = gc_lock_files(&dir, &policy).unwrap();
+        removed.sort();
+        assert_eq!(removed, vec![dir.join("dead.pid"), dir.join("orphan.lock")]);
+        assert!(gc_lock_files(&dir, &GcPolicy::new()).unwrap().is_empty());
+
+        drop(held);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//! Targets which have neither file descriptors nor handles.
+//!
+//! Open files can't be identified there, so a lock taken through a `File` can't tell whether
+//! another handle refers to the same file: those operations fail with
+//! [`FileLockError::Unsupported`]. The files the crate opens by path itself are locked through
+//! [`PathFile`] instead, which emulates the lock on their path with sidecar files and releases it
+//! when dropped.
+//!
+//! [`PathFile`]: ../emulated/struct.PathFile.html
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::process::{Child, Command};
+
+use crate::emulated::PathFile;
+use crate::{
+    lock_handle, unlock_handle, AdvisoryFileLock, FileId, FileLockError, FileLockMode,
+    LockMechanism,
+};
+
+/// The address of the locked `File`, which is only compared, never dereferenced.
+pub(crate) type Handle = *const File;
+
+/// The types which expose a file to lock, on targets without file descriptors or handles.
+///
+/// This stands for `AsFd` and `AsHandle`, and is implemented for every type which borrows a
+/// `File`.
+///
+/// Open files can't be identified on these targets, so locking them through this trait fails
+/// with [`FileLockError::Unsupported`], as do the functions which lock a `File` or return one
+/// locked, such as [`with_path_lock`] or [`lock_when_exclusive_available`]. The types which open
+/// their file by path themselves emulate their lock on that path instead, with the [emulation
+/// directory]'s sidecar files and polling: [`Lockfile`], [`PidFile`], [`NamedLock`],
+/// [`TreeLock`] and [`run_once`]. Their locks are released when they are dropped, and a file
+/// reached through several paths, such as links, is locked separately through each.
+///
+/// [`FileLockError::Unsupported`]: enum.FileLockError.html#variant.Unsupported
+/// [`with_path_lock`]: fn.with_path_lock.html
+/// [`lock_when_exclusive_available`]: fn.lock_when_exclusive_available.html
+/// [emulation directory]: fn.set_emulation_dir.html
+/// [`Lockfile`]: struct.Lockfile.html
+/// [`PidFile`]: struct.PidFile.html
+/// [`NamedLock`]: struct.NamedLock.html
+/// [`TreeLock`]: struct.TreeLock.html
+/// [`run_once`]: fn.run_once.html
+pub trait AsFile {
+    /// Borrow the file.
+    fn as_file(&self) -> &File;
+}
+
+impl<T: Borrow<File> + ?Sized> AsFile for T {
+    fn as_file(&self) -> &File {
+        self.borrow()
+    }
+}
+
+pub(crate) use AsFile as AsHandle;
+
+pub(crate) fn handle<T: AsHandle + ?Sized>(file: &T) -> Handle {
+    file.as_file()
+}
+
+impl<T: AsHandle + ?Sized> AdvisoryFileLock for T {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_handle(handle(self), file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_handle(handle(self), file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        unlock_handle(handle(self))
+    }
+}
+
+impl AdvisoryFileLock for PathFile {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        self.release()
+    }
+}
+
+#[derive(Copy, Clone)]
+pub(crate) struct PreparedLock;
+
+impl PreparedLock {
+    pub(crate) fn new(_: FileLockMode) -> PreparedLock {
+        PreparedLock
+    }
+}
+
+pub(crate) fn os_error(code: i32) -> FileLockError {
+    FileLockError::Io(Error::from_raw_os_error(code))
+}
+
+pub(crate) fn lock_file(_: Handle, _: FileLockMode, _: bool) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn lock_prepared(_: Handle, _: &PreparedLock, _: bool) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn unlock_prepared(_: Handle, _: &PreparedLock) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn unlock_file(_: Handle) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn lock_range(
+    _: Handle,
+    _: FileLockMode,
+    _: bool,
+    _: u64,
+    _: u64,
+) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn unlock_range(_: Handle, _: u64, _: u64) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) struct PreparedRange;
+
+impl PreparedRange {
+    pub(crate) fn new(_: FileLockMode, _: u64, _: u64) -> Result<PreparedRange, FileLockError> {
+        Ok(PreparedRange)
+    }
+}
+
+pub(crate) fn lock_range_prepared(
+    _: Handle,
+    _: &PreparedRange,
+    _: bool,
+) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn unlock_range_prepared(_: Handle, _: &PreparedRange) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn lock_mechanism(_: Handle) -> LockMechanism {
+    LockMechanism::FileLock
+}
+
+pub(crate) fn file_id(_: Handle) -> Result<FileId, Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+/// Paths aren't tracked for open files here.
+#[cfg(feature = "diagnostics")]
+pub(crate) fn path(_: Handle) -> Result<std::path::PathBuf, Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+pub(crate) fn is_lockable(_: Handle) -> Result<bool, Error> {
+    Ok(true)
+}
+
+/// Processes can't be looked up, so every process is assumed to be running.
+pub(crate) fn process_alive(_: u32) -> bool {
+    true
+}
+
+pub(crate) fn at_exit(_: extern "C" fn()) {}
+
+pub(crate) fn set_inheritable(_: Handle, _: bool) -> Result<(), Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+pub(crate) fn spawn_inheriting(_: Command, _: Handle, _: &str) -> Result<Child, Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+pub(crate) fn is_inheritable(_: Handle) -> Result<bool, Error> {
+    Ok(false)
+}
+
+pub(crate) fn same_description(a: Handle, b: Handle) -> Result<bool, Error> {
+    Ok(a == b)
+}
@@ -0,0 +1,538 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+#[cfg(unix)]
+use crate::ownership::PerProcess;
+use crate::ownership::{LockOwnership, PerHandle};
+use crate::{process_id, AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// An RAII guard which releases the advisory lock of a file when dropped.
+///
+/// Errors which occur while unlocking on drop are ignored; call [`unlock`] to handle them. The
+/// guard dereferences to the locked file.
+///
+/// The second type parameter tells who owns the lock. Guards over the locks of
+/// [`AdvisoryFileLock`] are [`PerHandle`] and can be sent to other threads; guards over
+/// per-process locks are [`PerProcess`] and can't, because such locks don't exclude threads.
+///
+/// ## Fork safety
+///
+/// A guard copied into a child process by `fork` is *inherited*: the child's copy of the handle
+/// shares the lock of the parent's, so unlocking it would release the parent's lock too.
+/// Inherited guards are detected by comparing process ids, and never unlock the file; see
+/// [`is_inherited`] and [`reacquire`].
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::io::{BufRead, Write};
+/// use advisory_lock::{FileLockGuard, FileLockMode};
+///
+/// let file = File::create("guard.txt")?;
+/// let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive)?;
+/// let mut writer = guard.buf_writer();
+/// writeln!(writer, "first line")?;
+/// writer.flush()?;
+/// drop(writer);
+/// guard.unlock()?;
+///
+/// let file = File::open("guard.txt")?;
+/// let guard = FileLockGuard::lock(&file, FileLockMode::Shared)?;
+/// assert_eq!(guard.buf_reader().lines().next().unwrap()?, "first line");
+/// #
+/// # drop(guard);
+/// # std::fs::remove_file("guard.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`unlock`]: #method.unlock
+/// [`is_inherited`]: #method.is_inherited
+/// [`reacquire`]: #method.reacquire
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+/// [`PerHandle`]: enum.PerHandle.html
+/// [`PerProcess`]: struct.PerProcess.html
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct FileLockGuard<'a, O: LockOwnership = PerHandle> {
+    file: &'a File,
+    file_lock_mode: FileLockMode,
+    pid: u32,
+    ownership: PhantomData<O>,
+}
+
+impl<'a> FileLockGuard<'a> {
+    /// Acquire the lock of `file`, blocking until it succeeds or errors.
+    pub fn lock(file: &'a File, file_lock_mode: FileLockMode) -> Result<Self, FileLockError> {
+        FileLockGuard::acquire(file, file_lock_mode, false)
+    }
+
+    /// Try to acquire the lock of `file`, returning immediately.
+    pub fn try_lock(file: &'a File, file_lock_mode: FileLockMode) -> Result<Self, FileLockError> {
+        FileLockGuard::acquire(file, file_lock_mode, true)
+    }
+
+    /// Guard the lock of `file`, which was already acquired in `file_lock_mode`.
+    #[cfg_attr(not(any(feature = "tokio", feature = "blocking")), allow(dead_code))]
+    pub(crate) fn adopt(file: &'a File, file_lock_mode: FileLockMode) -> Self {
+        FileLockGuard {
+            file,
+            file_lock_mode,
+            pid: process_id(),
+            ownership: PhantomData,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<'a> FileLockGuard<'a, PerProcess> {
+    /// Acquire a per-process record lock over the whole of `file`, blocking until it succeeds or
+    /// errors.
+    ///
+    /// See [`PerProcess`] for the hazards of such locks. Shared locks require `file` to be open
+    /// for reading, and exclusive locks for writing; an error of kind `InvalidInput` is returned
+    /// otherwise. They don't conflict with the locks of
+    /// [`AdvisoryFileLock`] on every system, so every process must agree on the kind of lock used
+    /// for a file.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::File;
+    /// use advisory_lock::{FileLockGuard, FileLockMode};
+    ///
+    /// let file = File::create("per-process.lock")?;
+    /// let guard = FileLockGuard::lock_per_process(&file, FileLockMode::Exclusive)?;
+    /// // Opening and closing another handle to the file here would silently release the lock.
+    /// guard.unlock()?;
+    /// #
+    /// # std::fs::remove_file("per-process.lock")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// The guard can't leave its thread:
+    /// ```compile_fail
+    /// # use std::fs::File;
+    /// # use advisory_lock::{FileLockGuard, FileLockMode};
+    /// # let file = File::create("per-process-send.lock").unwrap();
+    /// let guard = FileLockGuard::lock_per_process(&file, FileLockMode::Exclusive).unwrap();
+    /// std::thread::scope(|scope| {
+    ///     scope.spawn(move || drop(guard));
+    /// });
+    /// ```
+    ///
+    /// [`PerProcess`]: struct.PerProcess.html
+    /// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+    pub fn lock_per_process(
+        file: &'a File,
+        file_lock_mode: FileLockMode,
+    ) -> Result<Self, FileLockError> {
+        FileLockGuard::acquire(file, file_lock_mode, false)
+    }
+
+    /// Try to acquire a per-process record lock over the whole of `file`, returning immediately.
+    ///
+    /// See [`lock_per_process`] for details.
+    ///
+    /// [`lock_per_process`]: #method.lock_per_process
+    pub fn try_lock_per_process(
+        file: &'a File,
+        file_lock_mode: FileLockMode,
+    ) -> Result<Self, FileLockError> {
+        FileLockGuard::acquire(file, file_lock_mode, true)
+    }
+}
+
+impl<'a, O: LockOwnership> FileLockGuard<'a, O> {
+    fn acquire(
+        file: &'a File,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<Self, FileLockError> {
+        O::lock(file, file_lock_mode, immediate)?;
+        Ok(FileLockGuard {
+            file,
+            file_lock_mode,
+            pid: process_id(),
+            ownership: PhantomData,
+        })
+    }
+
+    /// Return the locked file.
+    pub fn file(&self) -> &'a File {
+        self.file
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Return a buffered reader over the locked file.
+    ///
+    /// The reader borrows the guard, so the lock can't be released while it is in use.
+    pub fn buf_reader(&self) -> BufReader<&File> {
+        BufReader::new(self.file)
+    }
+
+    /// Return a buffered writer over the locked file.
+    ///
+    /// The writer borrows the guard, so it is flushed when dropped, before the lock can be
+    /// released. Flush it explicitly to handle write errors.
+    ///
+    /// The writer can't outlive the guard:
+    /// ```compile_fail
+    /// # use std::fs::File;
+    /// # use std::io::Write;
+    /// # use advisory_lock::{FileLockGuard, FileLockMode};
+    /// # let file = File::create("buf-writer.lock").unwrap();
+    /// let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive).unwrap();
+    /// let mut writer = guard.buf_writer();
+    /// guard.unlock().unwrap();
+    /// writer.write_all(b"written after unlocking").unwrap();
+    /// ```
+    pub fn buf_writer(&self) -> BufWriter<&File> {
+        BufWriter::new(self.file)
+    }
+
+    /// Return whether the guard was inherited from the process which acquired the lock.
+    ///
+    /// Whether an inherited guard still protects anything depends on the locking mechanism:
+    /// `flock` locks are shared with the parent as long as either process keeps the handle
+    /// open, while per-process `fcntl` locks are not inherited at all.
+    pub fn is_inherited(&self) -> bool {
+        self.pid != process_id()
+    }
+
+    /// Acquire the lock again through the guarded handle, and adopt the guard in this process.
+    ///
+    /// This makes an inherited guard trusted again, blocking until the lock is acquired. The
+    /// handle is still shared with the parent though, so where the lock belongs to the handle
+    /// (`flock`, `LockFileEx`), both processes hold it; open the file anew for a lock of the
+    /// child's own.
+    pub fn reacquire(&mut self) -> Result<(), FileLockError> {
+        O::lock(self.file, self.file_lock_mode, false)?;
+        self.pid = process_id();
+        Ok(())
+    }
+
+    /// Release the lock, returning any error which occurs.
+    ///
+    /// The lock of an inherited guard is left alone.
+    pub fn unlock(self) -> Result<(), FileLockError> {
+        let (file, inherited) = (self.file, self.is_inherited());
+        std::mem::forget(self);
+        if inherited {
+            return Ok(());
+        }
+        O::unlock(file)
+    }
+}
+
+impl<O: LockOwnership> Deref for FileLockGuard<'_, O> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.file
+    }
+}
+
+impl<O: LockOwnership> Drop for FileLockGuard<'_, O> {
+    fn drop(&mut self) {
+        if !self.is_inherited() {
+            let _ = O::unlock(self.file);
+        }
+    }
+}
+
+/// An RAII guard over the lock of any [`AdvisoryFileLock`], which releases it when dropped.
+///
+/// It is returned by [`AdvisoryFileLock::lock_guard`] and
+/// [`AdvisoryFileLock::try_lock_guard`], and dereferences to the held lock. Errors which occur
+/// while unlocking on drop are ignored; call [`unlock`] to handle them.
+///
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+/// [`AdvisoryFileLock::lock_guard`]: trait.AdvisoryFileLock.html#method.lock_guard
+/// [`AdvisoryFileLock::try_lock_guard`]: trait.AdvisoryFileLock.html#method.try_lock_guard
+/// [`unlock`]: #method.unlock
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct LockGuard<'a, L: AdvisoryFileLock> {
+    lock: &'a L,
+    file_lock_mode: FileLockMode,
+}
+
+impl<'a, L: AdvisoryFileLock> LockGuard<'a, L> {
+    pub(crate) fn new(lock: &'a L, file_lock_mode: FileLockMode) -> LockGuard<'a, L> {
+        LockGuard {
+            lock,
+            file_lock_mode,
+        }
+    }
+
+    /// Return the held lock.
+    pub fn get_ref(&self) -> &'a L {
+        self.lock
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Release the lock, returning any error which occurs.
+    pub fn unlock(self) -> Result<(), FileLockError> {
+        let lock = self.lock;
+        std::mem::forget(self);
+        lock.unlock()
+    }
+}
+
+impl<L: AdvisoryFileLock> Deref for LockGuard<'_, L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        self.lock
+    }
+}
+
+impl<L: AdvisoryFileLock> Drop for LockGuard<'_, L> {
+    fn drop(&mut self) {
+        let _ = self.lock.unlock();
+    }
+}
+
+/// An RAII guard which owns a lock, and releases it when dropped.
+///
+/// It is returned by [`AdvisoryFileLock::lock_owned`] and
+/// [`AdvisoryFileLock::try_lock_owned`]. Unlike [`LockGuard`], it doesn't borrow anything, so it
+/// can be moved into spawned threads or async tasks. It dereferences, mutably too, to the held
+/// lock. Errors which occur while unlocking on drop are ignored; call [`unlock`] to handle them.
+///
+/// [`AdvisoryFileLock::lock_owned`]: trait.AdvisoryFileLock.html#method.lock_owned
+/// [`AdvisoryFileLock::try_lock_owned`]: trait.AdvisoryFileLock.html#method.try_lock_owned
+/// [`LockGuard`]: struct.LockGuard.html
+/// [`unlock`]: #method.unlock
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct OwnedFileLockGuard<L: AdvisoryFileLock = File> {
+    /// Only `None` once unlocked.
+    lock: Option<L>,
+    file_lock_mode: FileLockMode,
+}
+
+impl<L: AdvisoryFileLock> OwnedFileLockGuard<L> {
+    pub(crate) fn new(lock: L, file_lock_mode: FileLockMode) -> OwnedFileLockGuard<L> {
+        OwnedFileLockGuard {
+            lock: Some(lock),
+            file_lock_mode,
+        }
+    }
+
+    /// Return a reference to the held lock.
+    pub fn get_ref(&self) -> &L {
+        self.lock.as_ref().expect("the lock is held")
+    }
+
+    /// Return a mutable reference to the held lock.
+    pub fn get_mut(&mut self) -> &mut L {
+        self.lock.as_mut().expect("the lock is held")
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Release the lock and return it, or the error which occurred while releasing it.
+    pub fn unlock(mut self) -> Result<L, FileLockError> {
+        let lock = self.lock.take().expect("the lock is held");
+        lock.unlock()?;
+        Ok(lock)
+    }
+}
+
+impl<L: AdvisoryFileLock> Deref for OwnedFileLockGuard<L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        self.get_ref()
+    }
+}
+
+impl<L: AdvisoryFileLock> DerefMut for OwnedFileLockGuard<L> {
+    fn deref_mut(&mut self) -> &mut L {
+        self.get_mut()
+    }
+}
+
+impl<L: AdvisoryFileLock> Drop for OwnedFileLockGuard<L> {
+    fn drop(&mut self) {
+        if let Some(lock) = &self.lock {
+            let _ = lock.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::io::{Read, Seek, Write};
+
+    #[test]
+    fn guard_releases_on_drop() {
+        let mut test_file = temp_dir();
+        test_file.push("guard_releases");
+        let file = File::create(&test_file).unwrap();
+        let other = File::open(&test_file).unwrap();
+
+        {
+            let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive).unwrap();
+            assert_eq!(guard.mode(), FileLockMode::Exclusive);
+            guard.buf_writer().write_all(b"buffered").unwrap();
+            assert!(FileLockGuard::try_lock(&other, FileLockMode::Shared).is_err());
+        }
+
+        let guard = FileLockGuard::try_lock(&other, FileLockMode::Shared).unwrap();
+        let mut contents = String::new();
+        guard.buf_reader().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "buffered");
+        assert_eq!(guard.metadata().unwrap().len(), 8);
+        guard.unlock().unwrap();
+
+        drop((file, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn trait_guards_release_on_drop() {
+        let mut test_file = temp_dir();
+        test_file.push("guard_trait");
+        let file = crate::TrackedLock::new(File::create(&test_file).unwrap());
+        let other = File::open(&test_file).unwrap();
+
+        let guard = file.lock_guard(FileLockMode::Exclusive).unwrap();
+        assert_eq!(file.state(), Some(FileLockMode::Exclusive));
+        assert!(other.try_lock_guard(FileLockMode::Shared).is_err());
+        drop(guard);
+        assert_eq!(file.state(), None);
+
+        let guard = other.try_lock_guard(FileLockMode::Shared).unwrap();
+        assert_eq!(guard.mode(), FileLockMode::Shared);
+        guard.unlock().unwrap();
+        file.try_lock_guard(FileLockMode::Exclusive)
+            .unwrap()
+            .unlock()
+            .unwrap();
+
+        drop((file, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn owned_guards_move_across_threads() {
+        let mut test_file = temp_dir();
+        test_file.push("guard_owned");
+        let other = File::create(&test_file).unwrap();
+
+        let mut guard = File::open(&test_file)
+            .unwrap()
+            .lock_owned(FileLockMode::Shared)
+            .unwrap();
+        assert!(guard.get_mut().metadata().is_ok());
+        guard.seek(std::io::SeekFrom::End(0)).unwrap();
+        let guard = std::thread::spawn(move || guard).join().unwrap();
+        assert!(AdvisoryFileLock::try_lock(&other, FileLockMode::Exclusive).is_err());
+        let file = guard.unlock().unwrap();
+        AdvisoryFileLock::try_lock(&other, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            file.try_lock_owned(FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+
+        drop(other);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inherited_guards_leave_the_lock_alone() {
+        let mut test_file = temp_dir();
+        test_file.push("guard_inherited");
+        let file = File::create(&test_file).unwrap();
+        let other = File::open(&test_file).unwrap();
+        let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive).unwrap();
+
+        // The child only calls async-signal-safe functions before exiting.
+        match unsafe { libc::fork() } {
+            0 => {
+                let inherited = guard.is_inherited();
+                drop(guard);
+                unsafe { libc::_exit(if inherited { 0 } else { 1 }) };
+            }
+            -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+
+        assert!(!guard.is_inherited());
+        assert!(matches!(
+            crate::AdvisoryFileLock::try_lock(&other, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        guard.unlock().unwrap();
+
+        drop((file, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn per_process_locks_exclude_other_processes() {
+        let mut test_file = temp_dir();
+        test_file.push("guard_per_process");
+        std::fs::write(&test_file, b"").unwrap();
+        let open = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&test_file)
+                .unwrap()
+        };
+        let (file, same_process, other) = (open(), open(), open());
+        let other_handle = crate::sys::handle(&other);
+
+        let guard = FileLockGuard::lock_per_process(&file, FileLockMode::Shared).unwrap();
+        // The process owns the lock, so another of its handles doesn't conflict with it.
+        let upgraded = FileLockGuard::try_lock_per_process(&same_process, FileLockMode::Exclusive);
+        assert!(upgraded.is_ok());
+        std::mem::forget(upgraded);
+
+        match unsafe { libc::fork() } {
+            0 => {
+                let result = crate::sys::lock_process(other_handle, FileLockMode::Shared, true);
+                let contended = matches!(result, Err(FileLockError::AlreadyLocked));
+                unsafe { libc::_exit(if contended { 0 } else { 1 }) };
+            }
+            -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+
+        guard.unlock().unwrap();
+        let read_only = File::open(&test_file).unwrap();
+        match FileLockGuard::try_lock_per_process(&read_only, FileLockMode::Exclusive) {
+            Err(FileLockError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop((file, same_process, other, read_only));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
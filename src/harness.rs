@@ -0,0 +1,416 @@
+//! Helper processes for testing contention between processes.
+//!
+//! Locks held by the same process don't always exclude each other (see [`PerProcess`]), so
+//! cross-process behavior is best tested against a real second process. A [`Helper`] is such a
+//! process: it locks and unlocks files on command, and reports the outcome of each command back
+//! to the test.
+//!
+//! Helpers run the current test binary again, restricted to a test which calls
+//! [`helper_main`]. When the test suite runs normally, that test returns right away; when it is
+//! spawned as a helper, it serves commands until the [`Helper`] is dropped. Binaries can serve
+//! as helpers too, by calling [`helper_main`] first thing in `main`, and be spawned with
+//! [`Helper::spawn`].
+//!
+//! This module is only available with the `test-util` feature.
+//!
+//! Example:
+//! ```no_run
+//! use advisory_lock::harness::{self, Helper};
+//! use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode};
+//!
+//! #[test]
+//! fn helper() {
+//!     harness::helper_main();
+//! }
+//!
+//! #[test]
+//! fn writers_exclude_other_processes() {
+//!     let mut helper = Helper::spawn_test("helper").unwrap();
+//!     helper.lock("shared.db", FileLockMode::Exclusive).unwrap();
+//!
+//!     let file = std::fs::File::open("shared.db").unwrap();
+//!     assert!(matches!(
+//!         AdvisoryFileLock::try_lock(&file, FileLockMode::Shared),
+//!         Err(FileLockError::AlreadyLocked)
+//!     ));
+//!     helper.unlock("shared.db").unwrap();
+//! }
+//! ```
+//!
+//! [`PerProcess`]: ../struct.PerProcess.html
+//! [`Helper`]: struct.Helper.html
+//! [`Helper::spawn`]: struct.Helper.html#method.spawn
+//! [`helper_main`]: fn.helper_main.html
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// The environment variable which tells a process it was spawned as a helper.
+const HELPER_ENV: &str = "ADVISORY_LOCK_HELPER";
+
+/// The prefix of the replies of a helper, which tells them apart from the output of the test
+/// harness running it.
+const REPLY_PREFIX: &str = "advisory-lock-helper:";
+
+/// Serve the commands of the [`Helper`] which spawned this process, then exit.
+///
+/// Returns right away if this process wasn't spawned as a helper.
+///
+/// [`Helper`]: struct.Helper.html
+pub fn helper_main() {
+    if std::env::var_os(HELPER_ENV).is_none() {
+        return;
+    }
+
+    let mut files = HashMap::new();
+    let stdout = io::stdout();
+    for line in io::stdin().lock().lines() {
+        let reply = match line {
+            Ok(line) => serve(&mut files, &line),
+            Err(_) => break,
+        };
+        let mut stdout = stdout.lock();
+        let _ = writeln!(stdout, "{} {}", REPLY_PREFIX, reply);
+        let _ = stdout.flush();
+    }
+    process::exit(0);
+}
+
+/// Run one command, returning the reply to send back.
+fn serve(files: &mut HashMap<PathBuf, File>, line: &str) -> String {
+    match run(files, line) {
+        Ok(()) => "ok".to_owned(),
+        Err(err) => format!("err {:?} {}", err.kind(), err),
+    }
+}
+
+fn run(files: &mut HashMap<PathBuf, File>, line: &str) -> Result<(), FileLockError> {
+    // Paths may contain spaces, so they are always the last field.
+    let mut words = line.splitn(2, ' ');
+    match (words.next(), words.next()) {
+        (Some("lock"), Some(arguments)) => {
+            let (mode, path) = mode_and_path(line, arguments)?;
+            AdvisoryFileLock::lock(open(files, path)?, mode)
+        }
+        (Some("try_lock"), Some(arguments)) => {
+            let (mode, path) = mode_and_path(line, arguments)?;
+            AdvisoryFileLock::try_lock(open(files, path)?, mode)
+        }
+        (Some("unlock"), Some(path)) => AdvisoryFileLock::unlock(open(files, path)?),
+        _ => Err(unknown_command(line)),
+    }
+}
+
+fn mode_and_path<'a>(
+    line: &str,
+    arguments: &'a str,
+) -> Result<(FileLockMode, &'a str), FileLockError> {
+    let mut words = arguments.splitn(2, ' ');
+    match (words.next(), words.next()) {
+        (Some(mode), Some(path)) => Ok((parse_mode(mode)?, path)),
+        _ => Err(unknown_command(line)),
+    }
+}
+
+fn unknown_command(line: &str) -> FileLockError {
+    FileLockError::Io(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unknown command `{}`", line),
+    ))
+}
+
+fn open<'a>(files: &'a mut HashMap<PathBuf, File>, path: &str) -> Result<&'a File, FileLockError> {
+    let path = PathBuf::from(path);
+    if !files.contains_key(&path) {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(FileLockError::Io)?;
+        files.insert(path.clone(), file);
+    }
+    Ok(&files[&path])
+}
+
+fn mode_name(file_lock_mode: FileLockMode) -> &'static str {
+    match file_lock_mode {
+        FileLockMode::Exclusive => "exclusive",
+        FileLockMode::Shared => "shared",
+    }
+}
+
+fn parse_mode(name: &str) -> Result<FileLockMode, FileLockError> {
+    match name {
+        "exclusive" => Ok(FileLockMode::Exclusive),
+        "shared" => Ok(FileLockMode::Shared),
+        _ => Err(FileLockError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown lock mode `{}`", name),
+        ))),
+    }
+}
+
+/// Decode a reply of a helper.
+fn parse_reply(reply: &str) -> Result<(), FileLockError> {
+    if reply == "ok" {
+        return Ok(());
+    }
+
+    let mut words = reply.splitn(3, ' ');
+    let (kind, message) = match (words.next(), words.next(), words.next()) {
+        (Some("err"), Some(kind), message) => (kind, message.unwrap_or("")),
+        _ => {
+            return Err(FileLockError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed reply `{}`", reply),
+            )))
+        }
+    };
+    Err(match kind {
+        "AlreadyLocked" => FileLockError::AlreadyLocked,
+        "Corrupted" => FileLockError::Corrupted,
+        "Conflict" => FileLockError::Conflict,
+        "DuplicatedHandle" => FileLockError::DuplicatedHandle,
+        "UnsupportedFileType" => FileLockError::UnsupportedFileType,
+        "Timeout" => FileLockError::Timeout,
+        "Interrupted" => FileLockError::Interrupted,
+        "NotLocked" => FileLockError::NotLocked,
+        "NoLockResources" => FileLockError::NoLockResources,
+        "InvalidHandle" => FileLockError::InvalidHandle,
+        "Unsupported" => FileLockError::Unsupported,
+        "Poisoned" => FileLockError::Poisoned,
+        "DeadlockDetected" => FileLockError::DeadlockDetected,
+        "WouldSelfDeadlock" => FileLockError::WouldSelfDeadlock,
+        _ => FileLockError::Io(io::Error::other(message.to_owned())),
+    })
+}
+
+/// A helper process which locks and unlocks files on command.
+///
+/// Each file is opened (and created if needed) by the helper the first time a command names it,
+/// and stays open until the helper exits, so the helper holds at most one lock per path. The
+/// helper is killed when dropped, which releases every lock it holds.
+#[derive(Debug)]
+pub struct Helper {
+    child: Child,
+    stdin: ChildStdin,
+    replies: Receiver<String>,
+}
+
+impl Helper {
+    /// Spawn the current test binary as a helper, running only the test `name`, which must call
+    /// [`helper_main`].
+    ///
+    /// The name is matched exactly, so tests of the crate itself need their full path, such as
+    /// `tests::helper`.
+    ///
+    /// [`helper_main`]: fn.helper_main.html
+    pub fn spawn_test(name: &str) -> io::Result<Helper> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args([name, "--exact", "--nocapture", "--test-threads=1", "-q"]);
+        Helper::spawn(command)
+    }
+
+    /// Spawn `command` as a helper; the program must call [`helper_main`].
+    ///
+    /// [`helper_main`]: fn.helper_main.html
+    pub fn spawn(mut command: Command) -> io::Result<Helper> {
+        let mut child = command
+            .env(HELPER_ENV, "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        // Replies are read on a thread of their own so they can be waited for with a timeout.
+        let (sender, replies) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if let Some(reply) = line.strip_prefix(REPLY_PREFIX) {
+                    if sender.send(reply.trim_start().to_owned()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Helper {
+            child,
+            stdin,
+            replies,
+        })
+    }
+
+    /// Return the process id of the helper.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Make the helper acquire the lock of the file at `path`, waiting until it did.
+    pub fn lock<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> Result<(), FileLockError> {
+        self.start_lock(path, file_lock_mode)
+            .map_err(FileLockError::Io)?;
+        self.wait_reply()
+    }
+
+    /// Make the helper try to acquire the lock of the file at `path`.
+    pub fn try_lock<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> Result<(), FileLockError> {
+        let command = format!(
+            "try_lock {} {}",
+            mode_name(file_lock_mode),
+            path.as_ref().display()
+        );
+        self.send(&command).map_err(FileLockError::Io)?;
+        self.wait_reply()
+    }
+
+    /// Make the helper release the lock of the file at `path`.
+    pub fn unlock<P: AsRef<Path>>(&mut self, path: P) -> Result<(), FileLockError> {
+        let command = format!("unlock {}", path.as_ref().display());
+        self.send(&command).map_err(FileLockError::Io)?;
+        self.wait_reply()
+    }
+
+    /// Make the helper start acquiring the lock of the file at `path`, without waiting for it.
+    ///
+    /// The outcome is then collected with [`wait_reply`] or [`reply_within`], which lets tests
+    /// check that the helper is blocked while the lock is held elsewhere.
+    ///
+    /// [`wait_reply`]: #method.wait_reply
+    /// [`reply_within`]: #method.reply_within
+    pub fn start_lock<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> io::Result<()> {
+        let command = format!(
+            "lock {} {}",
+            mode_name(file_lock_mode),
+            path.as_ref().display()
+        );
+        self.send(&command)
+    }
+
+    /// Wait for the outcome of the oldest command which hasn't been collected yet.
+    pub fn wait_reply(&mut self) -> Result<(), FileLockError> {
+        match self.replies.recv() {
+            Ok(reply) => parse_reply(&reply),
+            Err(_) => Err(self.exited()),
+        }
+    }
+
+    /// Wait up to `timeout` for the outcome of the oldest command which hasn't been collected
+    /// yet, returning `None` if the helper is still busy with it.
+    pub fn reply_within(&mut self, timeout: Duration) -> Option<Result<(), FileLockError>> {
+        match self.replies.recv_timeout(timeout) {
+            Ok(reply) => Some(parse_reply(&reply)),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Err(self.exited())),
+        }
+    }
+
+    /// Kill the helper, which releases every lock it holds, and wait for it to exit.
+    pub fn kill(mut self) -> io::Result<()> {
+        self.child.kill()?;
+        self.child.wait().map(drop)
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<()> {
+        writeln!(self.stdin, "{}", command)?;
+        self.stdin.flush()
+    }
+
+    fn exited(&mut self) -> FileLockError {
+        let status = self.child.wait();
+        FileLockError::Io(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            format!("the helper exited: {:?}", status),
+        ))
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn helper() {
+        helper_main();
+    }
+
+    #[test]
+    fn helpers_contend_with_this_process() {
+        let mut test_file = temp_dir();
+        test_file.push("harness_contention");
+        let file = File::create(&test_file).unwrap();
+        let mut helper = Helper::spawn_test("harness::tests::helper").unwrap();
+
+        AdvisoryFileLock::lock(&file, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            helper.try_lock(&test_file, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        helper.start_lock(&test_file, FileLockMode::Shared).unwrap();
+        assert!(helper.reply_within(Duration::from_millis(100)).is_none());
+        AdvisoryFileLock::unlock(&file).unwrap();
+        helper.wait_reply().unwrap();
+
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        helper.unlock(&test_file).unwrap();
+        AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive).unwrap();
+
+        helper.kill().unwrap();
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn paths_may_contain_spaces() {
+        let mut test_file = temp_dir();
+        test_file.push("harness with spaces");
+        let file = File::create(&test_file).unwrap();
+        let mut helper = Helper::spawn_test("harness::tests::helper").unwrap();
+
+        helper.lock(&test_file, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&file, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        helper.unlock(&test_file).unwrap();
+        AdvisoryFileLock::try_lock(&file, FileLockMode::Shared).unwrap();
+
+        helper.kill().unwrap();
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::convert::TryFrom;
+use std::fs::File;
+
+use crate::{sys, FileLockError, FileLockMode};
+
+/// A lock which conflicts with an acquisition, as reported by [`lock_holder`].
+///
+/// [`lock_holder`]: fn.lock_holder.html
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LockHolder {
+    pid: Option<u32>,
+    mode: FileLockMode,
+    offset: u64,
+    len: Option<u64>,
+}
+
+impl LockHolder {
+    /// Return the id of the process holding the lock, if the lock is owned by a process.
+    ///
+    /// Open file description locks, such as the range locks of [`AdvisoryRangeLock`] on Linux,
+    /// belong to an open file which may be shared by several processes, so they have none.
+    ///
+    /// [`AdvisoryRangeLock`]: trait.AdvisoryRangeLock.html
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.mode
+    }
+
+    /// Return the offset of the first byte of the locked range.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Return the length of the locked range, or `None` if it extends to the end of the file,
+    /// however far it grows.
+    pub fn length(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+/// Return a lock which keeps `file` from being locked in `file_lock_mode`, if there is one.
+///
+/// This is meant for reporting who holds a lock after an acquisition failed, as in "locked by
+/// process 1234", and doesn't acquire anything itself. The lock may of course be released, or
+/// another one acquired, right after the query.
+///
+/// Record locks, such as those of the [`Fcntl`] backend and of [`AdvisoryRangeLock`], are
+/// found with `fcntl(F_GETLK)`, which doesn't report the record locks of the calling process.
+/// On Linux, the `flock` locks of the [`Native`] backend are found in `/proc/locks`, including
+/// the ones held by this process, even through `file` itself. Other systems report them only
+/// when `F_GETLK` does.
+///
+/// This function is only available on Unix.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{lock_holder, AdvisoryFileLock, FileLockError, FileLockMode};
+///
+/// let file = File::create("holder.lock")?;
+/// match AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive) {
+///     Err(FileLockError::AlreadyLocked) => match lock_holder(&file, FileLockMode::Exclusive)? {
+///         Some(holder) => eprintln!("locked by process {:?}", holder.pid()),
+///         None => eprintln!("locked"),
+///     },
+///     result => result?,
+/// }
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("holder.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+/// [`Native`]: enum.Backend.html#variant.Native
+/// [`AdvisoryRangeLock`]: trait.AdvisoryRangeLock.html
+pub fn lock_holder(
+    file: &File,
+    file_lock_mode: FileLockMode,
+) -> Result<Option<LockHolder>, FileLockError> {
+    let handle = sys::handle(file);
+    if let Some((pid, mode, offset, len)) = sys::record_holder(handle, file_lock_mode, 0, 0)? {
+        return Ok(Some(LockHolder {
+            pid: u32::try_from(pid).ok().filter(|pid| *pid > 0),
+            mode,
+            offset,
+            len: Some(len).filter(|len| *len > 0),
+        }));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some((pid, mode)) = sys::flock_holder(handle, file_lock_mode)? {
+        return Ok(Some(LockHolder {
+            pid: Some(pid),
+            mode,
+            offset: 0,
+            len: None,
+        }));
+    }
+    Ok(None)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, AdvisoryRangeLock};
+    use std::env::temp_dir;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn reports_the_holder() {
+        let mut test_file = temp_dir();
+        test_file.push("lock_holder");
+        let holder = File::create(&test_file).unwrap();
+        let prober = OpenOptions::new().read(true).open(&test_file).unwrap();
+        assert_eq!(lock_holder(&prober, FileLockMode::Exclusive).unwrap(), None);
+
+        AdvisoryFileLock::lock(&holder, FileLockMode::Shared).unwrap();
+        assert_eq!(lock_holder(&prober, FileLockMode::Shared).unwrap(), None);
+        let found = lock_holder(&prober, FileLockMode::Exclusive)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.pid(), Some(std::process::id()));
+        assert_eq!(
+            (found.mode(), found.offset(), found.length()),
+            (FileLockMode::Shared, 0, None)
+        );
+        AdvisoryFileLock::unlock(&holder).unwrap();
+
+        holder.lock_range(FileLockMode::Exclusive, 10, 5).unwrap();
+        let found = lock_holder(&prober, FileLockMode::Shared).unwrap().unwrap();
+        assert_eq!(found.pid(), None);
+        assert_eq!((found.offset(), found.length()), (10, Some(5)));
+        holder.unlock_range(10, 5).unwrap();
+
+        drop((holder, prober));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
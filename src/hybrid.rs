@@ -0,0 +1,306 @@
+use std::fs::{File, OpenOptions};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::TryLockError;
+
+use crate::sync::{self, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// A file lock paired with an in-process readers-writer lock, which excludes threads and
+/// processes alike.
+///
+/// File locks belong to the handle, so the threads sharing one handle don't exclude each other,
+/// and the first of several readers to unlock releases the lock for all of them. A
+/// `ProcessAndThreadLock` takes an [`RwLock`] first, to settle which threads of this process
+/// hold the lock, then the file lock on behalf of all of them: the writer locks the file
+/// exclusively, the first reader locks it shared and the last one to leave unlocks it.
+///
+/// Use it instead of [`set_thread_aware`] to get the same guarantees for one file without
+/// changing how every other lock of the process behaves.
+///
+/// Example:
+/// ```
+/// use std::io::Write;
+/// use advisory_lock::{FileLockMode, ProcessAndThreadLock};
+///
+/// let lock = ProcessAndThreadLock::open("server.state")?;
+/// std::thread::scope(|scope| {
+///     for worker in 0..4 {
+///         let lock = &lock;
+///         scope.spawn(move || {
+///             let mut guard = lock.lock(FileLockMode::Exclusive).unwrap();
+///             writeln!(&*guard, "worker {}", worker).unwrap();
+///         });
+///     }
+/// });
+/// #
+/// # drop(lock);
+/// # std::fs::remove_file("server.state")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`RwLock`]: https://doc.rust-lang.org/stable/std/sync/struct.RwLock.html
+/// [`set_thread_aware`]: fn.set_thread_aware.html
+#[derive(Debug)]
+pub struct ProcessAndThreadLock {
+    file: File,
+    threads: RwLock<()>,
+    readers: Mutex<Readers>,
+    /// Signalled when a reader is done acquiring the file lock for the others.
+    acquired: Condvar,
+}
+
+/// The threads of this process sharing the file lock.
+#[derive(Debug, Default)]
+struct Readers {
+    count: usize,
+    /// Whether a reader is acquiring the shared file lock.
+    acquiring: bool,
+}
+
+/// An RAII guard over a [`ProcessAndThreadLock`], which releases it when dropped.
+///
+/// It dereferences to the locked file.
+///
+/// [`ProcessAndThreadLock`]: struct.ProcessAndThreadLock.html
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct ProcessAndThreadGuard<'a> {
+    lock: &'a ProcessAndThreadLock,
+    file_lock_mode: FileLockMode,
+    released: bool,
+    // Dropped after the file lock is released.
+    _thread: ThreadGuard<'a>,
+}
+
+#[allow(dead_code)] // The guards are only held, to release the `RwLock` when dropped.
+#[derive(Debug)]
+enum ThreadGuard<'a> {
+    Read(RwLockReadGuard<'a, ()>),
+    Write(RwLockWriteGuard<'a, ()>),
+}
+
+impl ProcessAndThreadLock {
+    /// Open the file at `path` for reading and writing, creating it if needed, without locking
+    /// it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ProcessAndThreadLock, FileLockError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(FileLockError::Io)?;
+        Ok(ProcessAndThreadLock::new(file))
+    }
+
+    /// Lock `file`, which must not be locked yet.
+    pub fn new(file: File) -> ProcessAndThreadLock {
+        ProcessAndThreadLock {
+            file,
+            threads: RwLock::new(()),
+            readers: Mutex::new(Readers::default()),
+            acquired: Condvar::new(),
+        }
+    }
+
+    /// Return the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Acquire the lock, blocking until every other thread and process lets go of it.
+    pub fn lock(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> Result<ProcessAndThreadGuard<'_>, FileLockError> {
+        self.acquire(file_lock_mode, false)
+    }
+
+    /// Try to acquire the lock, returning immediately.
+    pub fn try_lock(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> Result<ProcessAndThreadGuard<'_>, FileLockError> {
+        self.acquire(file_lock_mode, true)
+    }
+
+    fn acquire(
+        &self,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<ProcessAndThreadGuard<'_>, FileLockError> {
+        // The `RwLock` guards no data, so poisoning is meaningless.
+        let thread = match (file_lock_mode, immediate) {
+            (FileLockMode::Shared, false) => {
+                ThreadGuard::Read(self.threads.read().unwrap_or_else(|err| err.into_inner()))
+            }
+            (FileLockMode::Exclusive, false) => {
+                ThreadGuard::Write(self.threads.write().unwrap_or_else(|err| err.into_inner()))
+            }
+            (FileLockMode::Shared, true) => match self.threads.try_read() {
+                Ok(guard) => ThreadGuard::Read(guard),
+                Err(TryLockError::Poisoned(err)) => ThreadGuard::Read(err.into_inner()),
+                Err(TryLockError::WouldBlock) => return Err(FileLockError::AlreadyLocked),
+            },
+            (FileLockMode::Exclusive, true) => match self.threads.try_write() {
+                Ok(guard) => ThreadGuard::Write(guard),
+                Err(TryLockError::Poisoned(err)) => ThreadGuard::Write(err.into_inner()),
+                Err(TryLockError::WouldBlock) => return Err(FileLockError::AlreadyLocked),
+            },
+        };
+
+        match file_lock_mode {
+            FileLockMode::Exclusive => self.lock_file(file_lock_mode, immediate)?,
+            FileLockMode::Shared => self.join_readers(immediate)?,
+        }
+        Ok(ProcessAndThreadGuard {
+            lock: self,
+            file_lock_mode,
+            released: false,
+            _thread: thread,
+        })
+    }
+
+    /// Count this thread among the readers, locking the file if it is the first one.
+    fn join_readers(&self, immediate: bool) -> Result<(), FileLockError> {
+        let mut readers = sync::lock(&self.readers);
+        loop {
+            if readers.count > 0 {
+                readers.count += 1;
+                return Ok(());
+            }
+            if !readers.acquiring {
+                break;
+            }
+            // Another reader is waiting for a writer of another process.
+            if immediate {
+                return Err(FileLockError::AlreadyLocked);
+            }
+            readers = self
+                .acquired
+                .wait(readers)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+
+        readers.acquiring = true;
+        drop(readers);
+        let result = self.lock_file(FileLockMode::Shared, immediate);
+        let mut readers = sync::lock(&self.readers);
+        readers.acquiring = false;
+        if result.is_ok() {
+            readers.count += 1;
+        }
+        self.acquired.notify_all();
+        result
+    }
+
+    fn lock_file(
+        &self,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<(), FileLockError> {
+        if immediate {
+            AdvisoryFileLock::try_lock(&self.file, file_lock_mode)
+        } else {
+            AdvisoryFileLock::lock(&self.file, file_lock_mode)
+        }
+    }
+
+    /// Release the file lock held in `file_lock_mode`, unless other readers still hold it.
+    fn release(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        if file_lock_mode == FileLockMode::Shared {
+            let mut readers = sync::lock(&self.readers);
+            readers.count -= 1;
+            if readers.count > 0 {
+                return Ok(());
+            }
+        }
+        AdvisoryFileLock::unlock(&self.file)
+    }
+}
+
+impl ProcessAndThreadGuard<'_> {
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Release the lock, returning any error which occurs.
+    pub fn unlock(mut self) -> Result<(), FileLockError> {
+        self.released = true;
+        self.lock.release(self.file_lock_mode)
+    }
+}
+
+impl Deref for ProcessAndThreadGuard<'_> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock.file
+    }
+}
+
+impl Drop for ProcessAndThreadGuard<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.lock.release(self.file_lock_mode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn threads_and_processes_are_excluded() {
+        let mut test_file = temp_dir();
+        test_file.push("hybrid_lock");
+        let lock = ProcessAndThreadLock::open(&test_file).unwrap();
+        // Stands for another process.
+        let outsider = File::open(&test_file).unwrap();
+
+        std::thread::scope(|scope| {
+            let first = lock.lock(FileLockMode::Shared).unwrap();
+            scope
+                .spawn(|| {
+                    let second = lock.try_lock(FileLockMode::Shared).unwrap();
+                    assert!(matches!(
+                        lock.try_lock(FileLockMode::Exclusive),
+                        Err(FileLockError::AlreadyLocked)
+                    ));
+                    drop(second);
+                })
+                .join()
+                .unwrap();
+            // The file lock is held until the last reader leaves.
+            assert!(matches!(
+                AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive),
+                Err(FileLockError::AlreadyLocked)
+            ));
+            first.unlock().unwrap();
+        });
+        AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            lock.try_lock(FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        AdvisoryFileLock::unlock(&outsider).unwrap();
+
+        let guard = lock.lock(FileLockMode::Exclusive).unwrap();
+        std::thread::scope(|scope| {
+            let contender = scope.spawn(|| lock.try_lock(FileLockMode::Shared).map(drop));
+            assert!(matches!(
+                contender.join().unwrap(),
+                Err(FileLockError::AlreadyLocked)
+            ));
+        });
+        assert_eq!(guard.mode(), FileLockMode::Exclusive);
+        drop(guard);
+
+        drop((lock, outsider));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
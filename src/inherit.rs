@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io;
+
+use crate::sys;
+
+/// Set whether `file` is inherited by child processes.
+///
+/// A child which inherits the handle of a locked file keeps the lock alive until it exits, even
+/// if the parent exits or unlocks its own copy first (for `flock` and `LockFileEx`, the lock
+/// belongs to the handle, which the child shares). Files opened by the standard library, and
+/// thus by this crate, are already closed on `exec` (`O_CLOEXEC`) on Unix and not inheritable on
+/// Windows; this helper is for handles obtained elsewhere, e.g. from a C library or the parent
+/// process.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{is_inheritable, set_inheritable};
+///
+/// let file = File::create("inherit.lock")?;
+/// assert!(!is_inheritable(&file)?);
+/// set_inheritable(&file, true)?;
+/// assert!(is_inheritable(&file)?);
+/// set_inheritable(&file, false)?;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("inherit.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn set_inheritable(file: &File, inheritable: bool) -> io::Result<()> {
+    sys::set_inheritable(sys::handle(file), inheritable)
+}
+
+/// Return whether `file` is inherited by child processes.
+///
+/// On Unix, this is whether the descriptor survives `exec`; every descriptor is copied by `fork`.
+pub fn is_inheritable(file: &File) -> io::Result<bool> {
+    sys::is_inheritable(sys::handle(file))
+}
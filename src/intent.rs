@@ -0,0 +1,230 @@
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fs::replace_contents;
+use crate::{process_id, sys, AdvisoryFileLock, FileLockError, FileLockMode};
+
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// A registry of the processes which intend to lock a file exclusively soon.
+///
+/// A heavyweight exclusive operation, such as a migration, waits for every reader to finish, and
+/// readers arriving meanwhile keep it waiting. Instead of blocking on the lock right away, the
+/// writer can [`announce`] its intent; readers which check for [`pending`] intents before
+/// starting new work then drain voluntarily, and the writer takes the lock once they are gone.
+/// Nothing is enforced: readers which don't check still get the lock.
+///
+/// Intents are stored in a small file beside the lock file, edited under a brief lock of its
+/// own. The intents of processes which exited without withdrawing them are ignored.
+///
+/// Example:
+/// ```
+/// use advisory_lock::IntentRegistry;
+///
+/// let intents = IntentRegistry::beside("catalog.lock");
+///
+/// // In the writer:
+/// let intent = intents.announce("schema migration")?;
+///
+/// // In the readers, before starting new work:
+/// if intents.has_pending()? {
+///     // ... back off and let the migration through ...
+/// }
+/// # drop(intent);
+/// # std::fs::remove_file(intents.path())?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`announce`]: #method.announce
+/// [`pending`]: #method.pending
+#[derive(Clone, Debug)]
+pub struct IntentRegistry {
+    path: PathBuf,
+}
+
+/// An intent announced in an [`IntentRegistry`], which is withdrawn when dropped.
+///
+/// [`IntentRegistry`]: struct.IntentRegistry.html
+#[must_use = "if unused the intent is immediately withdrawn"]
+#[derive(Debug)]
+pub struct Intent<'r> {
+    registry: &'r IntentRegistry,
+    token: u64,
+}
+
+/// An intent of another process, or of this one, to lock the file exclusively.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PendingIntent {
+    /// The id of the process which announced the intent.
+    pub pid: u32,
+    /// When the intent was announced, to the millisecond.
+    pub since: SystemTime,
+    /// What the process intends to do.
+    pub label: String,
+}
+
+/// An intent as stored in the registry, one per line.
+struct Record {
+    pid: u32,
+    token: u64,
+    millis: u64,
+    label: String,
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Record> {
+        let mut fields = line.splitn(4, ' ');
+        Some(Record {
+            pid: fields.next()?.parse().ok()?,
+            token: fields.next()?.parse().ok()?,
+            millis: fields.next()?.parse().ok()?,
+            label: fields.next().unwrap_or_default().to_owned(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {}\n",
+            self.pid, self.token, self.millis, self.label
+        )
+    }
+
+    fn is_mine(&self, token: u64) -> bool {
+        self.pid == process_id() && self.token == token
+    }
+}
+
+impl IntentRegistry {
+    /// Create the registry of the lock file at `lock_path`, stored at `<lock_path>.intents`.
+    pub fn beside<P: AsRef<Path>>(lock_path: P) -> IntentRegistry {
+        let mut path = lock_path.as_ref().as_os_str().to_os_string();
+        path.push(".intents");
+        IntentRegistry { path: path.into() }
+    }
+
+    /// Return the path of the registry file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Announce that this process intends to lock the file exclusively soon.
+    pub fn announce<S: Into<String>>(&self, label: S) -> Result<Intent<'_>, FileLockError> {
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let record = Record {
+            pid: process_id(),
+            token,
+            millis,
+            label: label.into().replace(['\n', '\r'], " "),
+        };
+        self.edit(|records| records.push(record))?;
+        Ok(Intent {
+            registry: self,
+            token,
+        })
+    }
+
+    /// Return the intents announced by running processes, oldest first.
+    pub fn pending(&self) -> Result<Vec<PendingIntent>, FileLockError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(FileLockError::Io(err)),
+        };
+        AdvisoryFileLock::lock(&file, FileLockMode::Shared)?;
+        Ok(read_records(&file)?
+            .into_iter()
+            .filter(|record| sys::process_alive(record.pid))
+            .map(|record| PendingIntent {
+                pid: record.pid,
+                since: UNIX_EPOCH + Duration::from_millis(record.millis),
+                label: record.label,
+            })
+            .collect())
+    }
+
+    /// Return whether any running process intends to lock the file exclusively.
+    pub fn has_pending(&self) -> Result<bool, FileLockError> {
+        Ok(!self.pending()?.is_empty())
+    }
+
+    /// Rewrite the registry under its lock, dropping the records of exited processes.
+    fn edit(&self, f: impl FnOnce(&mut Vec<Record>)) -> Result<(), FileLockError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .map_err(FileLockError::Io)?;
+        AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+        let mut records = read_records(&file)?;
+        records.retain(|record| sys::process_alive(record.pid));
+        f(&mut records);
+        let contents: String = records.iter().map(Record::to_line).collect();
+        replace_contents(&file, contents.as_bytes(), false)
+    }
+}
+
+fn read_records(mut file: &File) -> Result<Vec<Record>, FileLockError> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(FileLockError::Io)?;
+    Ok(contents.lines().filter_map(Record::parse).collect())
+}
+
+impl Intent<'_> {
+    /// Withdraw the intent, returning any error which occurs.
+    pub fn withdraw(self) -> Result<(), FileLockError> {
+        let (registry, token) = (self.registry, self.token);
+        std::mem::forget(self);
+        registry.edit(|records| records.retain(|record| !record.is_mine(token)))
+    }
+}
+
+impl Drop for Intent<'_> {
+    fn drop(&mut self) {
+        let token = self.token;
+        let _ = self
+            .registry
+            .edit(|records| records.retain(|record| !record.is_mine(token)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn intents_are_visible_until_withdrawn() {
+        let mut test_file = temp_dir();
+        test_file.push("intent_registry");
+        let intents = IntentRegistry::beside(&test_file);
+        // An intent left behind by a process which is long gone.
+        std::fs::write(intents.path(), "2147483000 0 0 crashed writer\n").unwrap();
+        assert!(!intents.has_pending().unwrap());
+
+        let first = intents.announce("migration").unwrap();
+        let second = intents.announce("compaction").unwrap();
+        let pending = intents.pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].label, "migration");
+        assert_eq!(pending[0].pid, process_id());
+
+        first.withdraw().unwrap();
+        assert_eq!(intents.pending().unwrap()[0].label, "compaction");
+        drop(second);
+        assert!(!intents.has_pending().unwrap());
+        assert!(!std::fs::read_to_string(intents.path())
+            .unwrap()
+            .contains("crashed"));
+
+        std::fs::remove_file(intents.path()).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! What blocking acquisitions do when a signal interrupts them.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RETRY_ON_INTERRUPT: AtomicBool = AtomicBool::new(true);
+
+/// Choose whether blocking acquisitions interrupted by a signal are retried.
+///
+/// On Unix, a signal delivered to a thread blocked in [`AdvisoryFileLock::lock`] interrupts the
+/// system call with `EINTR` when its handler was installed without `SA_RESTART`. By default the
+/// acquisition is then retried transparently. Disable retrying to get
+/// [`FileLockError::Interrupted`] instead, e.g. for a daemon which must notice its shutdown signal
+/// while waiting for a lock. Windows acquisitions aren't interrupted by signals.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{retry_on_interrupt, set_retry_on_interrupt};
+///
+/// set_retry_on_interrupt(false);
+/// assert!(!retry_on_interrupt());
+/// # set_retry_on_interrupt(true);
+/// ```
+///
+/// [`AdvisoryFileLock::lock`]: trait.AdvisoryFileLock.html#tymethod.lock
+/// [`FileLockError::Interrupted`]: enum.FileLockError.html#variant.Interrupted
+pub fn set_retry_on_interrupt(enabled: bool) {
+    RETRY_ON_INTERRUPT.store(enabled, Ordering::Relaxed);
+}
+
+/// Return whether interrupted blocking acquisitions are retried.
+pub fn retry_on_interrupt() -> bool {
+    RETRY_ON_INTERRUPT.load(Ordering::Relaxed)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    extern "C" fn ignore(_: libc::c_int) {}
+
+    /// Run `acquire` on a thread, sending it signals until it returns.
+    fn interrupted<T: Send + 'static>(acquire: impl FnOnce() -> T + Send + 'static) -> T {
+        let done = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let result = acquire();
+                done.store(true, Ordering::SeqCst);
+                result
+            })
+        };
+        while !done.load(Ordering::SeqCst) {
+            unsafe { libc::pthread_kill(waiter.as_pthread_t(), libc::SIGUSR1) };
+            thread::sleep(Duration::from_millis(20));
+        }
+        waiter.join().unwrap()
+    }
+
+    #[test]
+    fn interrupts_are_retried_or_reported() {
+        // Without `SA_RESTART`, the signal interrupts a blocking `flock`.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+        let mut test_file = temp_dir();
+        test_file.push("interrupt_retry");
+        let holder = File::create(&test_file).unwrap();
+        AdvisoryFileLock::lock(&holder, FileLockMode::Exclusive).unwrap();
+
+        set_retry_on_interrupt(false);
+        let waiter = File::open(&test_file).unwrap();
+        let (result, waiter) = interrupted(move || {
+            let result = AdvisoryFileLock::lock(&waiter, FileLockMode::Shared);
+            (result, waiter)
+        });
+        assert!(matches!(result, Err(FileLockError::Interrupted)));
+        set_retry_on_interrupt(true);
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            AdvisoryFileLock::unlock(&holder).unwrap();
+        });
+        interrupted(move || AdvisoryFileLock::lock(&waiter, FileLockMode::Shared)).unwrap();
+        releaser.join().unwrap();
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fs::replace_contents;
+use crate::{process_id, AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// One acquisition recorded in a [`Journal`].
+///
+/// [`Journal`]: struct.Journal.html
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct JournalEntry {
+    /// When the lock was acquired, to the millisecond.
+    pub time: SystemTime,
+    /// The id of the process which acquired the lock.
+    pub pid: u32,
+    /// The mode the lock was acquired in.
+    pub mode: FileLockMode,
+    /// Who acquired the lock: the name of the program, unless given otherwise.
+    pub holder: String,
+}
+
+impl JournalEntry {
+    fn to_line(&self) -> String {
+        let millis = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let mode = match self.mode {
+            FileLockMode::Exclusive => "exclusive",
+            FileLockMode::Shared => "shared",
+        };
+        // Line breaks would split the entry in two.
+        let holder = self.holder.replace(['\n', '\r'], " ");
+        format!("{} {} {} {}\n", millis, self.pid, mode, holder)
+    }
+
+    fn parse(line: &str) -> Option<JournalEntry> {
+        let mut fields = line.splitn(4, ' ');
+        let millis = fields.next()?.parse().ok()?;
+        let pid = fields.next()?.parse().ok()?;
+        let mode = match fields.next()? {
+            "exclusive" => FileLockMode::Exclusive,
+            "shared" => FileLockMode::Shared,
+            _ => return None,
+        };
+        Some(JournalEntry {
+            time: UNIX_EPOCH + Duration::from_millis(millis),
+            pid,
+            mode,
+            holder: fields.next().unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+/// A bounded journal of lock acquisitions, kept in a file beside the lock file.
+///
+/// Holders record their acquisitions as they get the lock, which leaves a timeline of the last
+/// holders on disk for post-incident forensics, without any logging infrastructure. The journal
+/// keeps the most recent entries only, up to its [capacity](#method.capacity).
+///
+/// The journal file has a lock of its own, taken briefly while it is written, so holders of a
+/// shared lock can record their acquisitions concurrently. Entries are one line of text each,
+/// e.g. `1700000000000 4242 exclusive backup-job`.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{FileLockGuard, FileLockMode, Journal};
+///
+/// let file = File::create("journaled.lock")?;
+/// let journal = Journal::beside("journaled.lock");
+/// let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive)?;
+/// journal.record(FileLockMode::Exclusive)?;
+/// drop(guard);
+///
+/// let last = journal.entries()?.pop().unwrap();
+/// assert_eq!(last.pid, std::process::id());
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("journaled.lock")?;
+/// # std::fs::remove_file("journaled.lock.journal")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct Journal {
+    path: PathBuf,
+    capacity: usize,
+    holder: String,
+}
+
+impl Journal {
+    /// Create the journal of the lock file at `lock_path`, stored at `<lock_path>.journal`.
+    pub fn beside<P: AsRef<Path>>(lock_path: P) -> Journal {
+        let mut path = lock_path.as_ref().as_os_str().to_os_string();
+        path.push(".journal");
+        Journal::at(path)
+    }
+
+    /// Create a journal stored at `path`.
+    pub fn at<P: Into<PathBuf>>(path: P) -> Journal {
+        let holder = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        Journal {
+            path: path.into(),
+            capacity: 64,
+            holder,
+        }
+    }
+
+    /// Keep at most `capacity` entries, dropping the oldest ones. The default is 64.
+    pub fn capacity(mut self, capacity: usize) -> Journal {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Record the acquisitions under the given name, instead of the name of the program.
+    pub fn holder<S: Into<String>>(mut self, holder: S) -> Journal {
+        self.holder = holder.into();
+        self
+    }
+
+    /// Return the path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record that this process just acquired the lock in `file_lock_mode`.
+    ///
+    /// Call it while holding the lock, so the journal reflects the order of the holders.
+    pub fn record(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        let entry = JournalEntry {
+            time: SystemTime::now(),
+            pid: process_id(),
+            mode: file_lock_mode,
+            holder: self.holder.clone(),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .map_err(FileLockError::Io)?;
+        AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+        let mut lines = read_lines(&file)?;
+        let len: usize = lines.iter().map(String::len).sum();
+        // A torn last line, left by a crashed writer, is overwritten.
+        let torn = file.metadata().map_err(FileLockError::Io)?.len() != len as u64;
+        lines.push(entry.to_line());
+        if lines.len() > self.capacity || torn {
+            let kept = lines.split_off(lines.len().saturating_sub(self.capacity));
+            replace_contents(&file, kept.concat().as_bytes(), false)
+        } else {
+            file.seek(SeekFrom::End(0))
+                .and_then(|_| file.write_all(lines[lines.len() - 1].as_bytes()))
+                .map_err(FileLockError::Io)
+        }
+    }
+
+    /// Return the recorded acquisitions, oldest first.
+    ///
+    /// Lines which can't be parsed, e.g. because a writer crashed halfway through one, are
+    /// skipped. A journal which doesn't exist yet is empty.
+    pub fn entries(&self) -> Result<Vec<JournalEntry>, FileLockError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(FileLockError::Io(err)),
+        };
+        AdvisoryFileLock::lock(&file, FileLockMode::Shared)?;
+        Ok(read_lines(&file)?
+            .iter()
+            .filter_map(|line| JournalEntry::parse(line.trim_end_matches('\n')))
+            .collect())
+    }
+}
+
+/// Read the complete lines of `file`, keeping their line breaks.
+fn read_lines(file: &File) -> Result<Vec<String>, FileLockError> {
+    let mut reader = BufReader::new(file);
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(FileLockError::Io)? == 0 {
+            return Ok(lines);
+        }
+        if line.ends_with('\n') {
+            lines.push(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn journal_keeps_the_latest_entries() {
+        let mut test_file = temp_dir();
+        test_file.push("journal_bounded");
+        let journal = Journal::beside(&test_file)
+            .capacity(3)
+            .holder("test\nsuite");
+        let _ = std::fs::remove_file(journal.path());
+        assert!(journal.entries().unwrap().is_empty());
+
+        for _ in 0..4 {
+            journal.record(FileLockMode::Shared).unwrap();
+        }
+        journal.record(FileLockMode::Exclusive).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].mode, FileLockMode::Exclusive);
+        assert_eq!(entries[2].holder, "test suite");
+        assert_eq!(entries[2].pid, process_id());
+        assert!(entries[0].time <= entries[2].time);
+
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::fs::replace_file;
+use crate::{process_id, sys, AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// A leader election among the processes pointing at the same file.
+///
+/// Whoever holds the exclusive lock of the file is the leader. Each time leadership is taken,
+/// the leader's term, one more than the previous term, and its process id are recorded in a
+/// sidecar file named after the lock file with a `.leader` suffix, so the other processes can
+/// tell who leads. The sidecar is replaced atomically, and the lock file itself is never
+/// written, so it can be read even on Windows while it is locked.
+///
+/// A leader which steps down clears its process id from the record, and a leader which crashed
+/// is recognized by its process being gone, so [`leader`] only reports a running leader. Its
+/// lock is released by the system either way, letting a follower take over.
+///
+/// Example:
+/// ```
+/// use advisory_lock::LeaderLock;
+///
+/// let election = LeaderLock::new("cron.lock")?;
+/// if let Some(leadership) = election.try_lead()? {
+///     assert_eq!(election.leader()?.unwrap().pid(), std::process::id());
+///     // ... only the leader runs the job ...
+///     println!("leading in term {}", leadership.term());
+/// }
+/// assert!(election.leader()?.is_none());
+/// # std::fs::remove_file("cron.lock")?;
+/// # std::fs::remove_file("cron.lock.leader")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`leader`]: struct.LeaderLock.html#method.leader
+#[derive(Debug)]
+pub struct LeaderLock {
+    path: PathBuf,
+    record: PathBuf,
+    file: File,
+}
+
+/// The leader recorded by a [`LeaderLock`].
+///
+/// [`LeaderLock`]: struct.LeaderLock.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Leader {
+    term: u64,
+    pid: u32,
+}
+
+impl Leader {
+    /// Return the term of the leader, which grows each time leadership is taken.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Return the id of the leading process.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+/// Leadership of a [`LeaderLock`], which is given up when dropped.
+///
+/// [`LeaderLock`]: struct.LeaderLock.html
+#[derive(Debug)]
+pub struct Leadership<'a> {
+    election: &'a LeaderLock,
+    term: u64,
+}
+
+impl LeaderLock {
+    /// Open the election of the lock file at `path`, creating the file if needed.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<LeaderLock, FileLockError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(FileLockError::Io)?;
+        let mut record = OsString::from(path.as_os_str());
+        record.push(".leader");
+        Ok(LeaderLock {
+            path: path.to_owned(),
+            record: record.into(),
+            file,
+        })
+    }
+
+    /// Return the path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Become the leader, blocking as long as another process leads.
+    pub fn lead(&self) -> Result<Leadership<'_>, FileLockError> {
+        AdvisoryFileLock::lock(&self.file, FileLockMode::Exclusive)?;
+        self.take_office()
+    }
+
+    /// Become the leader if no other process leads, or return `None` right away.
+    pub fn try_lead(&self) -> Result<Option<Leadership<'_>>, FileLockError> {
+        match AdvisoryFileLock::try_lock(&self.file, FileLockMode::Exclusive) {
+            Ok(()) => self.take_office().map(Some),
+            Err(FileLockError::AlreadyLocked) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Follow the leader until this process becomes the leader, checking every `interval`.
+    ///
+    /// `on_change` is called with the current leader, or `None` while there is none, when
+    /// following starts and whenever the leader changes.
+    pub fn follow<F>(
+        &self,
+        interval: Duration,
+        mut on_change: F,
+    ) -> Result<Leadership<'_>, FileLockError>
+    where
+        F: FnMut(Option<Leader>),
+    {
+        let mut observed = None;
+        loop {
+            if let Some(leadership) = self.try_lead()? {
+                return Ok(leadership);
+            }
+            let leader = self.leader()?;
+            if observed != Some(leader) {
+                on_change(leader);
+                observed = Some(leader);
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    /// Return the leader recorded for the election, or `None` if there is no running leader.
+    ///
+    /// A leader is recorded by its process id, so a crashed leader whose id was reused by an
+    /// unrelated process is still reported until the next leader takes over.
+    pub fn leader(&self) -> Result<Option<Leader>, FileLockError> {
+        Ok(self
+            .read_record()?
+            .filter(|leader| leader.pid != 0 && sys::process_alive(leader.pid)))
+    }
+
+    fn read_record(&self) -> Result<Option<Leader>, FileLockError> {
+        let contents = match std::fs::read_to_string(&self.record) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(FileLockError::Io(err)),
+        };
+        let mut lines = contents.lines();
+        match (
+            lines.next().and_then(|term| term.parse().ok()),
+            lines.next().and_then(|pid| pid.parse().ok()),
+        ) {
+            (Some(term), Some(pid)) => Ok(Some(Leader { term, pid })),
+            _ => Err(FileLockError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid leader record",
+            ))),
+        }
+    }
+
+    fn write_record(&self, leader: Leader) -> Result<(), FileLockError> {
+        let contents = format!("{}\n{}\n", leader.term, leader.pid);
+        replace_file(&self.record, contents.as_bytes()).map_err(FileLockError::Io)
+    }
+
+    /// Record the next term with this process as its leader, while holding the lock.
+    fn take_office(&self) -> Result<Leadership<'_>, FileLockError> {
+        let recorded = self.read_record().and_then(|previous| {
+            let term = previous.map_or(0, |leader| leader.term) + 1;
+            self.write_record(Leader {
+                term,
+                pid: process_id(),
+            })?;
+            Ok(term)
+        });
+        match recorded {
+            Ok(term) => Ok(Leadership {
+                election: self,
+                term,
+            }),
+            Err(err) => {
+                let _ = AdvisoryFileLock::unlock(&self.file);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Leadership<'_> {
+    /// Return the term of this leadership.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Step down, reporting errors which dropping the leadership would ignore.
+    pub fn resign(self) -> Result<(), FileLockError> {
+        let result = self.step_down();
+        std::mem::forget(self);
+        result
+    }
+
+    fn step_down(&self) -> Result<(), FileLockError> {
+        let cleared = self.election.write_record(Leader {
+            term: self.term,
+            pid: 0,
+        });
+        let unlocked = AdvisoryFileLock::unlock(&self.election.file);
+        cleared.and(unlocked)
+    }
+}
+
+impl Drop for Leadership<'_> {
+    fn drop(&mut self) {
+        let _ = self.step_down();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn leadership_passes_to_followers() {
+        let mut test_file = temp_dir();
+        test_file.push("leader_election");
+        let _ = std::fs::remove_file(test_file.with_extension("leader"));
+        let first = LeaderLock::new(&test_file).unwrap();
+        let second = LeaderLock::new(&test_file).unwrap();
+
+        let leadership = first.try_lead().unwrap().unwrap();
+        assert!(second.try_lead().unwrap().is_none());
+        let leader = second.leader().unwrap().unwrap();
+        assert_eq!((leader.term(), leader.pid()), (1, process_id()));
+
+        let mut changes = Vec::new();
+        thread::scope(|scope| {
+            let follower = scope.spawn(|| {
+                let leadership = second
+                    .follow(Duration::from_millis(10), |leader| changes.push(leader))
+                    .unwrap();
+                leadership.term()
+            });
+            thread::sleep(Duration::from_millis(50));
+            leadership.resign().unwrap();
+            assert_eq!(follower.join().unwrap(), 2);
+        });
+        assert_eq!(changes[0], Some(leader));
+        assert!(second.leader().unwrap().is_none());
+
+        std::fs::remove_file(test_file.with_extension("leader")).unwrap();
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
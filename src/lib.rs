@@ -8,6 +8,9 @@
 //! The main entity of the crate is [`AdvisoryFileLock`] which is effectively
 //! a [`RwLock`] but for [`File`].
 //!
+//! Since Rust 1.89 [`File`] has inherent `lock`, `try_lock` and `unlock` methods which take
+//! precedence over the trait methods, so the examples below call them with fully-qualified syntax.
+//!
 //! Example:
 //! ```
 //! use std::fs::File;
@@ -16,30 +19,30 @@
 //! #
 //! // Create the file and obtain its exclusive advisory lock
 //! let exclusive_file = File::create("foo.txt").unwrap();
-//! exclusive_file.lock(FileLockMode::Exclusive)?;
+//! AdvisoryFileLock::lock(&exclusive_file, FileLockMode::Exclusive)?;
 //!
 //! let shared_file = File::open("foo.txt")?;
 //!
 //! // Try to acquire the lock in non-blocking way
-//! assert!(matches!(shared_file.try_lock(FileLockMode::Shared), Err(FileLockError::AlreadyLocked)));
+//! assert!(matches!(AdvisoryFileLock::try_lock(&shared_file, FileLockMode::Shared), Err(FileLockError::AlreadyLocked)));
 //!
-//! exclusive_file.unlock()?;
+//! AdvisoryFileLock::unlock(&exclusive_file)?;
 //!
-//! shared_file.try_lock(FileLockMode::Shared).expect("Works, because the exclusive lock was released");
+//! AdvisoryFileLock::try_lock(&shared_file, FileLockMode::Shared).expect("Works, because the exclusive lock was released");
 //!
 //! let shared_file_2 = File::open("foo.txt")?;
 //!
-//! shared_file_2.lock(FileLockMode::Shared).expect("Should be fine to have multiple shared locks");
+//! AdvisoryFileLock::lock(&shared_file_2, FileLockMode::Shared).expect("Should be fine to have multiple shared locks");
 //!
 //! // Nope, now we have to wait until all shared locks are released...
-//! assert!(matches!(exclusive_file.try_lock(FileLockMode::Exclusive), Err(FileLockError::AlreadyLocked)));
+//! assert!(matches!(AdvisoryFileLock::try_lock(&exclusive_file, FileLockMode::Exclusive), Err(FileLockError::AlreadyLocked)));
 //!
 //! // We can unlock them explicitly and handle the potential error
-//! shared_file.unlock()?;
+//! AdvisoryFileLock::unlock(&shared_file)?;
 //! // Or drop the lock, such that we `log::error!()` if it happens and discard it
 //! drop(shared_file_2);
 //!
-//! exclusive_file.lock(FileLockMode::Exclusive).expect("All other locks should have been released");
+//! AdvisoryFileLock::lock(&exclusive_file, FileLockMode::Exclusive).expect("All other locks should have been released");
 //! #
 //! # std::fs::remove_file("foo.txt")?;
 //! # Ok::<(), Box<dyn std::error::Error>>(())
@@ -48,21 +51,242 @@
 //! [`AdvisoryFileLock`]: struct.AdvisoryFileLock.html
 //! [`RwLock`]: https://doc.rust-lang.org/stable/std/sync/struct.RwLock.html
 //! [`File`]: https://doc.rust-lang.org/stable/std/fs/struct.File.html
+// `File` holds nothing to close on some of the targets without file descriptors or handles.
+#![cfg_attr(
+    not(any(unix, windows, target_os = "wasi")),
+    allow(clippy::drop_non_drop)
+)]
+use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
+#[cfg(any(feature = "tokio", feature = "blocking"))]
+pub use crate::async_lock::AsyncAdvisoryFileLock;
+pub use crate::backend::{
+    default_backend, lock_mechanism, set_default_backend, Backend, LockMechanism, ParseBackendError,
+};
+pub use crate::batch::{lock_all_ordered, try_lock_all, LockAllError, LockBatch};
+pub use crate::child::{hold_for_child, LOCK_HANDLE_ENV};
+pub use crate::context::PathLockError;
+pub use crate::counter::Counter;
+pub use crate::custom::{BackendCapabilities, BackendLock, FileBackend, LockBackend};
+pub use crate::emulated::{emulation_dir, set_emulation_dir};
+pub use crate::epoch::EpochCache;
+pub use crate::fair::{FairGuard, FairLock, Priority};
+pub use crate::fs::{
+    read_locked, read_locked_checked, read_to_string_locked, replace_atomically, snapshot_to,
+    with_file_lock, with_path_lock, write_locked, write_locked_checked, write_locked_durably,
+};
+pub use crate::gc::{gc_lock_files, GcPolicy};
+pub use crate::guard::{FileLockGuard, LockGuard, OwnedFileLockGuard};
+#[cfg(unix)]
+pub use crate::holder::{lock_holder, LockHolder};
+pub use crate::hybrid::{ProcessAndThreadGuard, ProcessAndThreadLock};
+pub use crate::inherit::{is_inheritable, set_inheritable};
+pub use crate::intent::{Intent, IntentRegistry, PendingIntent};
+pub use crate::interrupt::{retry_on_interrupt, set_retry_on_interrupt};
+pub use crate::journal::{Journal, JournalEntry};
+pub use crate::leader::{Leader, LeaderLock, Leadership};
+pub use crate::locked::{Exclusive, LockKind, LockedFile, LockedReader, LockedWriter, Shared};
+pub use crate::locker::Locker;
+pub use crate::lockfile::{Lockfile, LockfileHolder, LockfileStatus};
+pub use crate::named::{named_lock_dir, set_named_lock_dir, NamedLock};
+pub use crate::once::{run_once, try_run_once};
+pub use crate::open::{LockOnOpen, OpenOptionsLockExt};
+pub use crate::optimistic::{compare_and_write, modify_if, read_versioned, Version};
+pub use crate::options::LockOptions;
+#[cfg(unix)]
+pub use crate::ownership::PerProcess;
+pub use crate::ownership::{LockOwnership, PerHandle};
+pub use crate::pidfile::PidFile;
+pub use crate::poison::{PoisonGuard, PoisonInfo, PoisonLock};
+pub use crate::pool::FilePool;
+pub use crate::range::AdvisoryRangeLock;
+pub use crate::rate::FileRateLimiter;
+pub use crate::reentrancy::{self_deadlock_policy, set_self_deadlock_policy, SelfDeadlockPolicy};
+pub use crate::region::{lock_region, set_lock_region, LockRegion};
+#[cfg(feature = "diagnostics")]
+pub use crate::registry::{dump_held_locks, held_locks, HeldLock};
+pub use crate::retry::{ExponentialBackoff, FixedInterval, RetryPolicy};
+pub use crate::strict::{set_strict_mode, strict_mode};
+pub use crate::striped::StripedLock;
+pub use crate::threads::{set_thread_aware, thread_aware};
+pub use crate::tracked::TrackedLock;
+#[cfg(unix)]
+pub use crate::transfer::TransferableLock;
+pub use crate::tree::{EntryGuard, TreeGuard, TreeLock};
+pub use crate::txn::FileTxn;
+#[cfg(feature = "json")]
+pub use crate::typed::TypedLockFile;
+pub use crate::validate::{file_type_check, set_file_type_check};
+pub use crate::wait::{
+    count_readers, is_locked, lock_when_exclusive_available, wait_until_exclusive_available,
+};
+pub use crate::watchdog::{HoldAction, Watchdog};
+#[cfg(feature = "json")]
+pub use crate::watcher::ConfigWatcher;
+
+#[cfg(any(feature = "tokio", feature = "blocking"))]
+mod async_lock;
+mod backend;
+mod batch;
+#[cfg(any(test, feature = "test-util"))]
+pub mod chaos;
+mod checksum;
+mod child;
+pub mod clock;
+#[cfg(feature = "json")]
+pub mod codec;
+mod context;
+mod counter;
+mod custom;
+mod deadline;
+mod emulated;
+mod epoch;
+mod fair;
+#[cfg(any(test, feature = "test-util"))]
+pub mod faults;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod fs;
+mod gc;
+mod guard;
+#[cfg(any(test, feature = "test-util"))]
+pub mod harness;
+#[cfg(unix)]
+mod holder;
+mod hybrid;
+mod inherit;
+mod intent;
+mod interrupt;
+mod journal;
+mod leader;
+mod locked;
+mod locker;
+mod lockfile;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+mod named;
+#[cfg(windows)]
+mod named_mutex;
+mod once;
+mod open;
+mod optimistic;
+mod options;
+mod ownership;
+pub mod panic_hook;
+mod pidfile;
+mod poison;
+mod pool;
+mod range;
+mod rate;
+#[cfg(any(test, feature = "test-util"))]
+pub mod recorder;
+mod reentrancy;
+mod region;
+#[cfg(feature = "diagnostics")]
+mod registry;
+mod retry;
+mod rng;
+#[cfg(unix)]
+mod semaphore;
+mod spin;
+mod strict;
+mod striped;
+mod sync;
+mod threads;
+mod tracked;
+#[cfg(unix)]
+mod transfer;
+mod tree;
+mod txn;
+#[cfg(feature = "json")]
+mod typed;
+mod validate;
+mod wait;
+mod watchdog;
+#[cfg(feature = "json")]
+mod watcher;
+
 #[cfg(windows)]
 mod windows;
+#[cfg(windows)]
+use windows as sys;
 
 #[cfg(unix)]
 mod unix;
+#[cfg(unix)]
+use unix as sys;
+
+#[cfg(target_os = "wasi")]
+mod wasi;
+#[cfg(target_os = "wasi")]
+use wasi as sys;
+
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+mod generic;
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+use generic as sys;
+#[cfg(not(any(unix, windows, target_os = "wasi")))]
+pub use generic::AsFile;
 
 /// An enumeration of possible errors which can occur while trying to acquire a lock.
+///
+/// Operating system errors which callers commonly need to tell apart have a variant of their
+/// own; the others are reported as [`Io`]. More variants may be added in the future.
+///
+/// [`Io`]: #variant.Io
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum FileLockError {
     /// The file is already locked by other process.
     AlreadyLocked,
     /// The error occurred during I/O operations.
     Io(io::Error),
+    /// The contents of the file failed checksum verification.
+    Corrupted,
+    /// The file was modified since the version the operation was based on.
+    Conflict,
+    /// The lock is held through another handle to the same open file, which the operation was
+    /// refused on in strict mode.
+    DuplicatedHandle,
+    /// The file is a pipe, a socket, a device or another kind of file which whole-file locks
+    /// aren't meaningful on.
+    UnsupportedFileType,
+    /// The lock wasn't acquired before the timeout elapsed.
+    Timeout,
+    /// A blocking call was interrupted by a signal before the lock was acquired (`EINTR`).
+    Interrupted,
+    /// The lock to release isn't held (`ERROR_NOT_LOCKED`). Unlocking an unlocked file only
+    /// fails on Windows; elsewhere it does nothing.
+    NotLocked,
+    /// The system ran out of resources to record the lock (`ENOLCK`).
+    NoLockResources,
+    /// The handle isn't open, or not open in a way which allows locking (`EBADF`,
+    /// `ERROR_INVALID_HANDLE`).
+    InvalidHandle,
+    /// The file system doesn't support the kind of lock requested (`EOPNOTSUPP`,
+    /// `ERROR_NOT_SUPPORTED`).
+    Unsupported,
+    /// A holder of the lock panicked while holding it exclusively, so the data it protects may
+    /// be inconsistent; see [`PoisonLock`].
+    ///
+    /// [`PoisonLock`]: struct.PoisonLock.html
+    Poisoned,
+    /// Waiting for the lock would deadlock with another process waiting for a lock held by this
+    /// one (`EDEADLK`).
+    ///
+    /// Only record locks are checked for deadlocks, by the kernels which implement the check,
+    /// such as with the [`Fcntl`] backend on Linux. Release the locks held and retry.
+    ///
+    /// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+    DeadlockDetected,
+    /// The calling thread would wait forever for a lock it holds itself through another handle;
+    /// see [`set_self_deadlock_policy`].
+    ///
+    /// [`set_self_deadlock_policy`]: fn.set_self_deadlock_policy.html
+    WouldSelfDeadlock,
 }
 
 impl fmt::Display for FileLockError {
@@ -70,11 +294,176 @@ impl fmt::Display for FileLockError {
         match self {
             FileLockError::AlreadyLocked => f.write_str("the file is already locked"),
             FileLockError::Io(err) => write!(f, "I/O error: {}", err),
+            FileLockError::Corrupted => f.write_str("the file contents are corrupted"),
+            FileLockError::Conflict => f.write_str("the file was modified concurrently"),
+            FileLockError::DuplicatedHandle => {
+                f.write_str("the lock is held through a duplicate of this handle")
+            }
+            FileLockError::UnsupportedFileType => {
+                f.write_str("the file is not a regular file or directory")
+            }
+            FileLockError::Timeout => f.write_str("timed out waiting for the lock"),
+            FileLockError::Interrupted => f.write_str("interrupted while waiting for the lock"),
+            FileLockError::NotLocked => f.write_str("the file is not locked"),
+            FileLockError::NoLockResources => f.write_str("no resources left to record the lock"),
+            FileLockError::InvalidHandle => f.write_str("the file handle is invalid"),
+            FileLockError::Unsupported => {
+                f.write_str("the file system does not support this kind of lock")
+            }
+            FileLockError::Poisoned => f.write_str("a holder of the lock panicked"),
+            FileLockError::DeadlockDetected => {
+                f.write_str("waiting for the lock would cause a deadlock")
+            }
+            FileLockError::WouldSelfDeadlock => {
+                f.write_str("the lock is held by this thread through another handle")
+            }
         }
     }
 }
 
-impl std::error::Error for FileLockError {}
+impl std::error::Error for FileLockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileLockError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Converts the error into an `io::Error` of the closest kind, e.g. `WouldBlock` for
+/// `AlreadyLocked`.
+///
+/// `Io` errors are unwrapped; the others are wrapped, so they can be recovered with
+/// [`io::Error::get_ref`] and `downcast_ref`, or by converting the `io::Error` back.
+///
+/// Example:
+/// ```
+/// use std::io;
+/// use advisory_lock::{FileLockError, FileLockErrorKind};
+///
+/// let err = io::Error::from(FileLockError::AlreadyLocked);
+/// assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+/// assert_eq!(FileLockError::from(err).kind(), FileLockErrorKind::AlreadyLocked);
+/// ```
+///
+/// [`io::Error::get_ref`]: https://doc.rust-lang.org/stable/std/io/struct.Error.html#method.get_ref
+impl From<FileLockError> for io::Error {
+    fn from(err: FileLockError) -> io::Error {
+        match err {
+            FileLockError::Io(err) => err,
+            err => io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
+/// Recovers the `FileLockError` wrapped by the conversion into `io::Error`, and wraps any other
+/// error as `Io`.
+impl From<io::Error> for FileLockError {
+    fn from(err: io::Error) -> FileLockError {
+        if err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<FileLockError>())
+        {
+            let inner = err.into_inner().expect("the error has an inner error");
+            return *inner
+                .downcast()
+                .expect("the inner error is a FileLockError");
+        }
+        FileLockError::Io(err)
+    }
+}
+
+/// The kind of a [`FileLockError`], without its payload.
+///
+/// [`FileLockError`]: enum.FileLockError.html
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[non_exhaustive]
+pub enum FileLockErrorKind {
+    /// See [`FileLockError::AlreadyLocked`](enum.FileLockError.html#variant.AlreadyLocked).
+    AlreadyLocked,
+    /// See [`FileLockError::Io`](enum.FileLockError.html#variant.Io).
+    Io,
+    /// See [`FileLockError::Corrupted`](enum.FileLockError.html#variant.Corrupted).
+    Corrupted,
+    /// See [`FileLockError::Conflict`](enum.FileLockError.html#variant.Conflict).
+    Conflict,
+    /// See [`FileLockError::DuplicatedHandle`](enum.FileLockError.html#variant.DuplicatedHandle).
+    DuplicatedHandle,
+    /// See
+    /// [`FileLockError::UnsupportedFileType`](enum.FileLockError.html#variant.UnsupportedFileType).
+    UnsupportedFileType,
+    /// See [`FileLockError::Timeout`](enum.FileLockError.html#variant.Timeout).
+    Timeout,
+    /// See [`FileLockError::Interrupted`](enum.FileLockError.html#variant.Interrupted).
+    Interrupted,
+    /// See [`FileLockError::NotLocked`](enum.FileLockError.html#variant.NotLocked).
+    NotLocked,
+    /// See [`FileLockError::NoLockResources`](enum.FileLockError.html#variant.NoLockResources).
+    NoLockResources,
+    /// See [`FileLockError::InvalidHandle`](enum.FileLockError.html#variant.InvalidHandle).
+    InvalidHandle,
+    /// See [`FileLockError::Unsupported`](enum.FileLockError.html#variant.Unsupported).
+    Unsupported,
+    /// See [`FileLockError::Poisoned`](enum.FileLockError.html#variant.Poisoned).
+    Poisoned,
+    /// See [`FileLockError::DeadlockDetected`](enum.FileLockError.html#variant.DeadlockDetected).
+    DeadlockDetected,
+    /// See
+    /// [`FileLockError::WouldSelfDeadlock`](enum.FileLockError.html#variant.WouldSelfDeadlock).
+    WouldSelfDeadlock,
+}
+
+impl FileLockError {
+    /// Return the kind of the error.
+    pub fn kind(&self) -> FileLockErrorKind {
+        match self {
+            FileLockError::AlreadyLocked => FileLockErrorKind::AlreadyLocked,
+            FileLockError::Io(_) => FileLockErrorKind::Io,
+            FileLockError::Corrupted => FileLockErrorKind::Corrupted,
+            FileLockError::Conflict => FileLockErrorKind::Conflict,
+            FileLockError::DuplicatedHandle => FileLockErrorKind::DuplicatedHandle,
+            FileLockError::UnsupportedFileType => FileLockErrorKind::UnsupportedFileType,
+            FileLockError::Timeout => FileLockErrorKind::Timeout,
+            FileLockError::Interrupted => FileLockErrorKind::Interrupted,
+            FileLockError::NotLocked => FileLockErrorKind::NotLocked,
+            FileLockError::NoLockResources => FileLockErrorKind::NoLockResources,
+            FileLockError::InvalidHandle => FileLockErrorKind::InvalidHandle,
+            FileLockError::Unsupported => FileLockErrorKind::Unsupported,
+            FileLockError::Poisoned => FileLockErrorKind::Poisoned,
+            FileLockError::DeadlockDetected => FileLockErrorKind::DeadlockDetected,
+            FileLockError::WouldSelfDeadlock => FileLockErrorKind::WouldSelfDeadlock,
+        }
+    }
+
+    /// Return the kind of `io::Error` the error converts to.
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            FileLockError::Io(err) => err.kind(),
+            FileLockError::AlreadyLocked => io::ErrorKind::WouldBlock,
+            FileLockError::Corrupted => io::ErrorKind::InvalidData,
+            FileLockError::Conflict | FileLockError::DuplicatedHandle => io::ErrorKind::Other,
+            FileLockError::UnsupportedFileType | FileLockError::Unsupported => {
+                io::ErrorKind::Unsupported
+            }
+            FileLockError::Timeout => io::ErrorKind::TimedOut,
+            FileLockError::Interrupted => io::ErrorKind::Interrupted,
+            FileLockError::NotLocked => io::ErrorKind::Other,
+            FileLockError::NoLockResources => io::ErrorKind::OutOfMemory,
+            FileLockError::InvalidHandle => io::ErrorKind::InvalidInput,
+            FileLockError::Poisoned
+            | FileLockError::DeadlockDetected
+            | FileLockError::WouldSelfDeadlock => io::ErrorKind::Other,
+        }
+    }
+
+    /// Map an error of the operating system to its variant, if it has one.
+    pub(crate) fn from_io(err: io::Error) -> FileLockError {
+        match err.raw_os_error() {
+            Some(code) => sys::os_error(code as _),
+            None => FileLockError::Io(err),
+        }
+    }
+}
 
 /// An enumeration of types which represents how to acquire an advisory lock.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -85,6 +474,53 @@ pub enum FileLockMode {
     Shared,
 }
 
+/// An enumeration of operations which can be performed on an advisory lock.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum FileLockOperation {
+    /// A blocking acquisition, i.e. [`AdvisoryFileLock::lock`].
+    Lock,
+    /// A non-blocking acquisition, i.e. [`AdvisoryFileLock::try_lock`].
+    TryLock,
+    /// A release, i.e. [`AdvisoryFileLock::unlock`].
+    Unlock,
+}
+
+/// The identity of a file on its file system, independent of the handle used to open it.
+///
+/// This is the device and inode number on Unix, and the volume serial number and file index on
+/// Windows. Two handles refer to the same file if and only if their `FileId`s are equal.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct FileId {
+    device: u64,
+    index: u64,
+}
+
+impl FileId {
+    /// Return the identity of the file opened as `file`.
+    pub fn of(file: &File) -> io::Result<FileId> {
+        sys::file_id(sys::handle(file))
+    }
+
+    /// Return the identity of the file at `path`.
+    ///
+    /// On Windows the file has to be opened to query its identity, which requires read access.
+    pub fn of_path<P: AsRef<Path>>(path: P) -> io::Result<FileId> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = std::fs::metadata(path)?;
+            Ok(FileId {
+                device: metadata.dev(),
+                index: metadata.ino(),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            FileId::of(&File::open(path)?)
+        }
+    }
+}
+
 /// An advisory lock for files.
 ///
 /// An advisory lock provides a mutual-exclusion mechanism among processes which explicitly
@@ -95,10 +531,43 @@ pub enum FileLockMode {
 /// - Shared or exclusive modes.
 /// - All operations are thread-safe.
 ///
+/// It is implemented for every type which implements [`AsFd`] on Unix or [`AsHandle`] on
+/// Windows: `File`, references and smart pointers to it, the I/O-safety types, and any newtype
+/// exposing its handle. Lock a raw descriptor by borrowing it with `BorrowedFd::borrow_raw` or
+/// `BorrowedHandle::borrow_raw`, which works the same on both platforms:
+///
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{AdvisoryFileLock, FileLockMode};
+/// #[cfg(unix)]
+/// use std::os::unix::io::{AsRawFd as AsRawHandle, BorrowedFd as BorrowedHandle};
+/// #[cfg(windows)]
+/// use std::os::windows::io::{AsRawHandle, BorrowedHandle};
+///
+/// let file = File::create("raw_handle.lock")?;
+/// # #[cfg(unix)]
+/// let raw = file.as_raw_fd();
+/// # #[cfg(windows)]
+/// let raw = file.as_raw_handle();
+/// // Safety: `file` keeps the handle open for as long as it is borrowed.
+/// let borrowed = unsafe { BorrowedHandle::borrow_raw(raw) };
+/// AdvisoryFileLock::lock(&borrowed, FileLockMode::Exclusive)?;
+/// AdvisoryFileLock::unlock(&borrowed)?;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("raw_handle.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
 /// ## Notes
 ///
 /// `AdvisoryFileLock` has following limitations:
-/// - Locks are allowed only on files, but not directories.
+/// - Locks are allowed only on regular files, and on directories on Unix; see
+///   [`set_file_type_check`].
+///
+/// [`set_file_type_check`]: fn.set_file_type_check.html
+/// [`AsFd`]: https://doc.rust-lang.org/stable/std/os/fd/trait.AsFd.html
+/// [`AsHandle`]: https://doc.rust-lang.org/stable/std/os/windows/io/trait.AsHandle.html
 pub trait AdvisoryFileLock {
     /// Acquire the advisory file lock.
     ///
@@ -110,6 +579,362 @@ pub trait AdvisoryFileLock {
     fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError>;
     /// Unlock this advisory file lock.
     fn unlock(&self) -> Result<(), FileLockError>;
+    /// Acquire the advisory file lock, briefly spinning on `try_lock` before blocking.
+    ///
+    /// This cuts the latency of acquiring locks which are typically held for a very short time.
+    /// The number of attempts is bounded and adapts to how often spinning succeeded recently.
+    fn lock_adaptive(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        spin::lock_adaptive(self, file_lock_mode)
+    }
+    /// Acquire the advisory file lock, blocking for at most `timeout`.
+    ///
+    /// The lock is retried with `try_lock` until it succeeds, and [`FileLockError::Timeout`] is
+    /// returned if it is still held by someone else once `timeout` elapsed. A zero `timeout`
+    /// makes a single attempt.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::File;
+    /// use std::time::Duration;
+    /// use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode};
+    ///
+    /// let file = File::create("lock_timeout.txt")?;
+    /// match file.lock_timeout(FileLockMode::Exclusive, Duration::from_secs(1)) {
+    ///     Ok(()) => { /* ... */ }
+    ///     Err(FileLockError::Timeout) => eprintln!("lock_timeout.txt is busy"),
+    ///     Err(err) => return Err(err.into()),
+    /// }
+    /// #
+    /// # drop(file);
+    /// # std::fs::remove_file("lock_timeout.txt")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`FileLockError::Timeout`]: enum.FileLockError.html#variant.Timeout
+    fn lock_timeout(
+        &self,
+        file_lock_mode: FileLockMode,
+        timeout: Duration,
+    ) -> Result<(), FileLockError> {
+        self.lock_timeout_with_clock(file_lock_mode, timeout, &clock::SystemClock)
+    }
+    /// Acquire the advisory file lock like [`lock_timeout`], measuring the timeout and sleeping
+    /// between attempts with `clock`.
+    ///
+    /// [`lock_timeout`]: #method.lock_timeout
+    fn lock_timeout_with_clock(
+        &self,
+        file_lock_mode: FileLockMode,
+        timeout: Duration,
+        clock: &dyn clock::Clock,
+    ) -> Result<(), FileLockError> {
+        deadline::lock_timeout(self, file_lock_mode, timeout, clock)
+    }
+    /// Acquire the advisory file lock, blocking until `deadline` at the latest.
+    ///
+    /// This is [`lock_timeout`] with an absolute deadline, which several acquisitions can share
+    /// without recomputing the time left for each. If the deadline has already passed, a single
+    /// attempt is made.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::File;
+    /// use std::time::{Duration, Instant};
+    /// use advisory_lock::{AdvisoryFileLock, FileLockMode};
+    ///
+    /// let files = [File::create("until-0.txt")?, File::create("until-1.txt")?];
+    /// let deadline = Instant::now() + Duration::from_secs(1);
+    /// for file in &files {
+    ///     file.try_lock_until(FileLockMode::Exclusive, deadline)?;
+    /// }
+    /// #
+    /// # drop(files);
+    /// # std::fs::remove_file("until-0.txt")?;
+    /// # std::fs::remove_file("until-1.txt")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`lock_timeout`]: #method.lock_timeout
+    fn try_lock_until(
+        &self,
+        file_lock_mode: FileLockMode,
+        deadline: Instant,
+    ) -> Result<(), FileLockError> {
+        self.try_lock_until_with_clock(file_lock_mode, deadline, &clock::SystemClock)
+    }
+    /// Acquire the advisory file lock like [`try_lock_until`], reading the time and sleeping
+    /// between attempts with `clock`.
+    ///
+    /// [`try_lock_until`]: #method.try_lock_until
+    fn try_lock_until_with_clock(
+        &self,
+        file_lock_mode: FileLockMode,
+        deadline: Instant,
+        clock: &dyn clock::Clock,
+    ) -> Result<(), FileLockError> {
+        crate::deadline::lock_until(self, file_lock_mode, deadline, clock)
+    }
+    /// Acquire the advisory file lock, retrying as long as `policy` allows while it is held
+    /// elsewhere.
+    ///
+    /// The policy decides how long to sleep between attempts, such as the growing, randomized
+    /// delays of [`ExponentialBackoff`] which keep contending processes from hammering the file
+    /// system. [`FileLockError::AlreadyLocked`] is returned once the policy gives up.
+    ///
+    /// [`ExponentialBackoff`]: struct.ExponentialBackoff.html
+    /// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+    fn lock_with_retry<P: RetryPolicy>(
+        &self,
+        file_lock_mode: FileLockMode,
+        policy: P,
+    ) -> Result<(), FileLockError>
+    where
+        Self: Sized,
+    {
+        self.lock_with_retry_with_clock(file_lock_mode, policy, &clock::SystemClock)
+    }
+    /// Acquire the advisory file lock like [`lock_with_retry`], sleeping between attempts with
+    /// `clock`.
+    ///
+    /// [`lock_with_retry`]: #method.lock_with_retry
+    fn lock_with_retry_with_clock<P: RetryPolicy>(
+        &self,
+        file_lock_mode: FileLockMode,
+        mut policy: P,
+        clock: &dyn clock::Clock,
+    ) -> Result<(), FileLockError>
+    where
+        Self: Sized,
+    {
+        retry::lock_with_retry(self, file_lock_mode, &mut policy, clock)
+    }
+    /// Acquire the advisory file lock, returning a guard which releases it when dropped.
+    ///
+    /// The guard borrows the lock, so it can't be used after the lock is released. For files,
+    /// [`FileLockGuard`] offers more, such as buffered I/O and fork detection.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::File;
+    /// use advisory_lock::{AdvisoryFileLock, FileLockMode};
+    ///
+    /// let file = File::create("lock_guard.txt")?;
+    /// {
+    ///     let _guard = file.lock_guard(FileLockMode::Exclusive)?;
+    ///     // ... an early return here still releases the lock ...
+    /// }
+    /// let _guard = file.try_lock_guard(FileLockMode::Exclusive)?;
+    /// #
+    /// # drop(_guard);
+    /// # std::fs::remove_file("lock_guard.txt")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`FileLockGuard`]: struct.FileLockGuard.html
+    fn lock_guard(&self, file_lock_mode: FileLockMode) -> Result<LockGuard<'_, Self>, FileLockError>
+    where
+        Self: Sized,
+    {
+        self.lock(file_lock_mode)?;
+        Ok(LockGuard::new(self, file_lock_mode))
+    }
+    /// Try to acquire the advisory file lock, returning a guard which releases it when dropped.
+    ///
+    /// See [`lock_guard`].
+    ///
+    /// [`lock_guard`]: #method.lock_guard
+    fn try_lock_guard(
+        &self,
+        file_lock_mode: FileLockMode,
+    ) -> Result<LockGuard<'_, Self>, FileLockError>
+    where
+        Self: Sized,
+    {
+        self.try_lock(file_lock_mode)?;
+        Ok(LockGuard::new(self, file_lock_mode))
+    }
+    /// Acquire the advisory file lock, returning a guard which owns the lock and releases it
+    /// when dropped.
+    ///
+    /// The guard is `'static` if the lock is, so it can be moved into spawned threads or tasks.
+    /// If the lock can't be acquired, it is dropped.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::File;
+    /// use std::io::Write;
+    /// use advisory_lock::{AdvisoryFileLock, FileLockMode};
+    ///
+    /// let guard = File::create("lock_owned.txt")?.lock_owned(FileLockMode::Exclusive)?;
+    /// std::thread::spawn(move || {
+    ///     guard.get_ref().write_all(b"written in the background").unwrap();
+    /// })
+    /// .join()
+    /// .unwrap();
+    /// #
+    /// # std::fs::remove_file("lock_owned.txt")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    fn lock_owned(
+        self,
+        file_lock_mode: FileLockMode,
+    ) -> Result<OwnedFileLockGuard<Self>, FileLockError>
+    where
+        Self: Sized,
+    {
+        ownership::check_per_handle()?;
+        self.lock(file_lock_mode)?;
+        Ok(OwnedFileLockGuard::new(self, file_lock_mode))
+    }
+    /// Try to acquire the advisory file lock, returning a guard which owns the lock and releases
+    /// it when dropped.
+    ///
+    /// See [`lock_owned`].
+    ///
+    /// [`lock_owned`]: #method.lock_owned
+    fn try_lock_owned(
+        self,
+        file_lock_mode: FileLockMode,
+    ) -> Result<OwnedFileLockGuard<Self>, FileLockError>
+    where
+        Self: Sized,
+    {
+        ownership::check_per_handle()?;
+        self.try_lock(file_lock_mode)?;
+        Ok(OwnedFileLockGuard::new(self, file_lock_mode))
+    }
+}
+
+/// Returns the id of this process, or 0 on targets without processes, where `process::id` panics.
+pub(crate) fn process_id() -> u32 {
+    if cfg!(any(unix, windows)) {
+        std::process::id()
+    } else {
+        0
+    }
+}
+
+/// Acquires the lock on the raw handle.
+pub(crate) fn lock_handle(
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    lock_handle_with(default_backend(), handle, file_lock_mode, immediate)
+}
+
+/// Acquires the lock on the raw handle with the mechanism of `backend`.
+pub(crate) fn lock_handle_with(
+    backend: Backend,
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let operation = if immediate {
+        FileLockOperation::TryLock
+    } else {
+        FileLockOperation::Lock
+    };
+    whole_file_operation(handle, operation, Some(file_lock_mode), || {
+        backend::lock_file(backend, handle, file_lock_mode, immediate)
+    })
+}
+
+/// Releases the lock on the raw handle.
+pub(crate) fn unlock_handle(handle: sys::Handle) -> Result<(), FileLockError> {
+    unlock_handle_with(default_backend(), handle)
+}
+
+/// Releases the lock on the raw handle with the mechanism of `backend`.
+pub(crate) fn unlock_handle_with(
+    backend: Backend,
+    handle: sys::Handle,
+) -> Result<(), FileLockError> {
+    whole_file_operation(handle, FileLockOperation::Unlock, None, || {
+        backend::unlock_file(backend, handle)
+    })
+}
+
+/// Acquires the lock on a byte range of the raw handle.
+pub(crate) fn lock_range_handle(
+    handle: sys::Handle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    let operation = if immediate {
+        FileLockOperation::TryLock
+    } else {
+        FileLockOperation::Lock
+    };
+    run_operation(handle, operation, Some(file_lock_mode), || {
+        sys::lock_range(handle, file_lock_mode, immediate, offset, len)
+    })
+}
+
+/// Releases the lock on a byte range of the raw handle.
+pub(crate) fn unlock_range_handle(
+    handle: sys::Handle,
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    run_operation(handle, FileLockOperation::Unlock, None, || {
+        sys::unlock_range(handle, offset, len)
+    })
+}
+
+/// Performs the whole-file lock `operation` through `syscall`, enforcing the process-wide modes.
+pub(crate) fn whole_file_operation(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+    syscall: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    if operation != FileLockOperation::Unlock {
+        validate::check_file_type(handle)?;
+    }
+    reentrancy::checked(handle, operation, file_lock_mode, || {
+        strict::checked(handle, operation, || {
+            threads::coordinated(handle, operation, file_lock_mode, || {
+                run_operation(handle, operation, file_lock_mode, syscall)
+            })
+        })
+    })?;
+    #[cfg(feature = "diagnostics")]
+    registry::record(handle, operation, file_lock_mode);
+    Ok(())
+}
+
+/// Performs `operation` through `syscall`, running the hooks shared by every platform.
+pub(crate) fn run_operation(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+    syscall: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let syscall = || match default_backend() {
+        Backend::Noop => Ok(()),
+        _ => syscall(),
+    };
+
+    #[cfg(any(test, feature = "test-util"))]
+    {
+        chaos::shake();
+        let result = faults::intercept(operation).and_then(|()| syscall());
+        recorder::record(
+            operation,
+            file_lock_mode,
+            || sys::file_id(handle).ok(),
+            result.is_ok(),
+        );
+        result
+    }
+    #[cfg(not(any(test, feature = "test-util")))]
+    {
+        let _ = (handle, operation, file_lock_mode);
+        syscall()
+    }
 }
 
 #[cfg(test)]
@@ -125,9 +950,9 @@ mod tests {
         File::create(&test_file).unwrap();
         {
             let f1 = File::open(&test_file).unwrap();
-            f1.lock(FileLockMode::Shared).unwrap();
+            AdvisoryFileLock::lock(&f1, FileLockMode::Shared).unwrap();
             let f2 = File::open(&test_file).unwrap();
-            f2.lock(FileLockMode::Shared).unwrap();
+            AdvisoryFileLock::lock(&f2, FileLockMode::Shared).unwrap();
         }
         std::fs::remove_file(&test_file).unwrap();
     }
@@ -139,9 +964,9 @@ mod tests {
         File::create(&test_file).unwrap();
         {
             let f1 = File::open(&test_file).unwrap();
-            f1.lock(FileLockMode::Exclusive).unwrap();
+            AdvisoryFileLock::lock(&f1, FileLockMode::Exclusive).unwrap();
             let f2 = File::open(&test_file).unwrap();
-            assert!(f2.try_lock(FileLockMode::Exclusive).is_err());
+            assert!(AdvisoryFileLock::try_lock(&f2, FileLockMode::Exclusive).is_err());
         }
         std::fs::remove_file(&test_file).unwrap();
     }
@@ -153,10 +978,10 @@ mod tests {
         File::create(&test_file).unwrap();
         {
             let f1 = File::open(&test_file).unwrap();
-            f1.lock(FileLockMode::Shared).unwrap();
+            AdvisoryFileLock::lock(&f1, FileLockMode::Shared).unwrap();
             let f2 = File::open(&test_file).unwrap();
             assert!(matches!(
-                f2.try_lock(FileLockMode::Exclusive),
+                AdvisoryFileLock::try_lock(&f2, FileLockMode::Exclusive),
                 Err(FileLockError::AlreadyLocked)
             ));
         }
@@ -170,10 +995,128 @@ mod tests {
         File::create(&test_file).unwrap();
         {
             let f1 = File::open(&test_file).unwrap();
-            f1.lock(FileLockMode::Exclusive).unwrap();
+            AdvisoryFileLock::lock(&f1, FileLockMode::Exclusive).unwrap();
             let f2 = File::open(&test_file).unwrap();
-            assert!(f2.try_lock(FileLockMode::Shared).is_err());
+            assert!(AdvisoryFileLock::try_lock(&f2, FileLockMode::Shared).is_err());
+        }
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_errors_have_variants() {
+        assert!(matches!(
+            lock_handle(-1, FileLockMode::Shared, true),
+            Err(FileLockError::InvalidHandle)
+        ));
+        assert!(matches!(
+            FileLockError::from_io(io::Error::from_raw_os_error(libc::ENOLCK)),
+            FileLockError::NoLockResources
+        ));
+        assert!(matches!(
+            FileLockError::from_io(io::Error::from_raw_os_error(libc::EDEADLK)),
+            FileLockError::DeadlockDetected
+        ));
+        assert!(matches!(
+            FileLockError::from_io(io::Error::from(io::ErrorKind::Other)),
+            FileLockError::Io(_)
+        ));
+    }
+
+    #[test]
+    fn contention_is_already_locked_everywhere() {
+        use crate::AdvisoryRangeLock;
+        use std::fs::OpenOptions;
+
+        let mut test_file = temp_dir();
+        test_file.push("contention_everywhere");
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&test_file)
+                .unwrap()
+        };
+        let (holder, contender) = (open(), open());
+        let modes = [FileLockMode::Shared, FileLockMode::Exclusive];
+
+        for &held in &modes {
+            for &wanted in &modes {
+                if held == FileLockMode::Shared && wanted == FileLockMode::Shared {
+                    continue;
+                }
+                AdvisoryFileLock::lock(&holder, held).unwrap();
+                assert!(matches!(
+                    AdvisoryFileLock::try_lock(&contender, wanted),
+                    Err(FileLockError::AlreadyLocked)
+                ));
+                AdvisoryFileLock::unlock(&holder).unwrap();
+
+                // Record locks of the same process only conflict where they belong to the handle.
+                if cfg!(any(target_os = "linux", windows)) {
+                    holder.lock_range(held, 0, 16).unwrap();
+                    assert!(matches!(
+                        contender.try_lock_range(wanted, 8, 16),
+                        Err(FileLockError::AlreadyLocked)
+                    ));
+                    holder.unlock_range(0, 16).unwrap();
+                }
+            }
+        }
+
+        drop((holder, contender));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn errors_round_trip_through_io() {
+        let err = io::Error::from(FileLockError::Timeout);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(FileLockError::from(err).kind(), FileLockErrorKind::Timeout);
+
+        let err = io::Error::from(FileLockError::Io(io::ErrorKind::NotFound.into()));
+        assert!(err.get_ref().is_none());
+        match FileLockError::from(err) {
+            FileLockError::Io(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn handle_wrappers_are_lockable() {
+        struct Wrapper(File);
+
+        #[cfg(unix)]
+        impl std::os::unix::io::AsFd for Wrapper {
+            fn as_fd(&self) -> std::os::unix::io::BorrowedFd<'_> {
+                self.0.as_fd()
+            }
+        }
+
+        #[cfg(windows)]
+        impl std::os::windows::io::AsHandle for Wrapper {
+            fn as_handle(&self) -> std::os::windows::io::BorrowedHandle<'_> {
+                self.0.as_handle()
+            }
         }
+
+        let mut test_file = temp_dir();
+        test_file.push("handle_wrappers");
+        let wrapper = Wrapper(File::create(&test_file).unwrap());
+        let shared = std::sync::Arc::new(File::open(&test_file).unwrap());
+
+        AdvisoryFileLock::lock(&wrapper, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&shared, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        AdvisoryFileLock::unlock(&wrapper).unwrap();
+        AdvisoryFileLock::try_lock(&shared, FileLockMode::Shared).unwrap();
+
+        drop((wrapper, shared));
         std::fs::remove_file(&test_file).unwrap();
     }
 }
@@ -0,0 +1,332 @@
+use std::fs::{File, Metadata};
+use std::io::{self, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+use crate::fs::open_locked;
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The mode of a [`LockedFile`], known at compile time: [`Shared`] or [`Exclusive`].
+///
+/// This trait is sealed.
+///
+/// [`LockedFile`]: struct.LockedFile.html
+/// [`Shared`]: enum.Shared.html
+/// [`Exclusive`]: enum.Exclusive.html
+pub trait LockKind: sealed::Sealed {
+    /// The mode the lock is held in.
+    const MODE: FileLockMode;
+}
+
+/// A shared lock, marking a [`LockedFile`] which can be read from only.
+///
+/// [`LockedFile`]: struct.LockedFile.html
+#[derive(Debug)]
+pub enum Shared {}
+
+/// An exclusive lock, marking a [`LockedFile`] which can be written to.
+///
+/// [`LockedFile`]: struct.LockedFile.html
+#[derive(Debug)]
+pub enum Exclusive {}
+
+impl sealed::Sealed for Shared {}
+impl sealed::Sealed for Exclusive {}
+
+impl LockKind for Shared {
+    const MODE: FileLockMode = FileLockMode::Shared;
+}
+
+impl LockKind for Exclusive {
+    const MODE: FileLockMode = FileLockMode::Exclusive;
+}
+
+/// A file held under a shared lock for its whole lifetime, which can be read from and seeked.
+///
+/// Example:
+/// ```
+/// use std::io::{self, Read};
+/// use advisory_lock::{LockedReader, LockedWriter};
+///
+/// fn checksum(mut input: impl Read) -> io::Result<u32> {
+///     let mut bytes = Vec::new();
+///     input.read_to_end(&mut bytes)?;
+///     Ok(bytes.iter().map(|&b| u32::from(b)).sum())
+/// }
+///
+/// let mut writer = LockedWriter::open("stream.txt")?;
+/// io::copy(&mut &b"abc"[..], &mut writer)?;
+/// drop(writer);
+///
+/// assert_eq!(checksum(LockedReader::open("stream.txt")?)?, 294);
+/// #
+/// # std::fs::remove_file("stream.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub type LockedReader = LockedFile<Shared>;
+
+/// A file held under an exclusive lock for its whole lifetime, which can be written to, read
+/// from and seeked.
+///
+/// See [`LockedReader`] for an example.
+///
+/// [`LockedReader`]: type.LockedReader.html
+pub type LockedWriter = LockedFile<Exclusive>;
+
+/// A file owned together with its lock, whose mode is part of its type.
+///
+/// Every `LockedFile` can be read from and seeked, but only a `LockedFile<Exclusive>` can be
+/// written to, so writing to a file while holding a shared lock on it doesn't compile. The lock
+/// is released when the `LockedFile` is dropped or [`unlock`]ed.
+///
+/// A `LockedFile<Exclusive>` dereferences to the locked `File`. A `LockedFile<Shared>` doesn't,
+/// since `&File` implements `Write` too.
+///
+/// Example:
+/// ```
+/// use std::io::{Read, Write};
+/// use advisory_lock::{Exclusive, LockedFile, Shared};
+///
+/// let mut writer = LockedFile::<Exclusive>::open("typed_state.txt")?;
+/// writer.write_all(b"hello")?;
+/// drop(writer);
+///
+/// let mut reader = LockedFile::<Shared>::open("typed_state.txt")?;
+/// let mut contents = String::new();
+/// reader.read_to_string(&mut contents)?;
+/// assert_eq!(contents, "hello");
+/// #
+/// # drop(reader);
+/// # std::fs::remove_file("typed_state.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// Writing under a shared lock is rejected:
+/// ```compile_fail
+/// use std::io::Write;
+/// use advisory_lock::{LockedFile, Shared};
+///
+/// let mut reader = LockedFile::<Shared>::open("typed_state.txt")?;
+/// reader.write_all(b"oops")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`unlock`]: #method.unlock
+#[derive(Debug)]
+pub struct LockedFile<M: LockKind> {
+    file: Option<File>,
+    mode: PhantomData<fn() -> M>,
+}
+
+impl<M: LockKind> LockedFile<M> {
+    /// Lock `file`, blocking until the lock is acquired.
+    pub fn lock(file: File) -> Result<LockedFile<M>, FileLockError> {
+        AdvisoryFileLock::lock(&file, M::MODE)?;
+        Ok(LockedFile::adopt(file))
+    }
+
+    /// Try to lock `file`, returning immediately.
+    pub fn try_lock(file: File) -> Result<LockedFile<M>, FileLockError> {
+        AdvisoryFileLock::try_lock(&file, M::MODE)?;
+        Ok(LockedFile::adopt(file))
+    }
+
+    /// Open the file at `path` and lock it, blocking until the lock is acquired.
+    ///
+    /// Exclusive locks create the file if needed and open it for reading and writing; shared
+    /// locks open it for reading only.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LockedFile<M>, FileLockError> {
+        let create = M::MODE == FileLockMode::Exclusive;
+        open_locked(path.as_ref(), M::MODE, create).map(LockedFile::adopt)
+    }
+
+    fn adopt(file: File) -> LockedFile<M> {
+        LockedFile {
+            file: Some(file),
+            mode: PhantomData,
+        }
+    }
+
+    fn file(&self) -> &File {
+        self.file
+            .as_ref()
+            .expect("the file is owned until unlocked")
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        M::MODE
+    }
+
+    /// Query the metadata of the file.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.file().metadata()
+    }
+
+    /// Release the lock, returning the file.
+    pub fn unlock(mut self) -> Result<File, FileLockError> {
+        let file = self.file.take().expect("the file is owned until unlocked");
+        AdvisoryFileLock::unlock(&file)?;
+        Ok(file)
+    }
+}
+
+impl LockedFile<Exclusive> {
+    /// Truncate or extend the file to `size` bytes.
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file().set_len(size)
+    }
+
+    /// Sync the contents and metadata of the file to disk.
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.file().sync_all()
+    }
+
+    /// Sync the contents of the file to disk.
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.file().sync_data()
+    }
+}
+
+impl Deref for LockedFile<Exclusive> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        self.file()
+    }
+}
+
+impl DerefMut for LockedFile<Exclusive> {
+    fn deref_mut(&mut self) -> &mut File {
+        self.file
+            .as_mut()
+            .expect("the file is owned until unlocked")
+    }
+}
+
+impl<M: LockKind> Read for LockedFile<M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file().read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.file().read_vectored(bufs)
+    }
+}
+
+impl<M: LockKind> Seek for LockedFile<M> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file().seek(pos)
+    }
+}
+
+impl<M: LockKind> Read for &LockedFile<M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file().read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.file().read_vectored(bufs)
+    }
+}
+
+impl<M: LockKind> Seek for &LockedFile<M> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file().seek(pos)
+    }
+}
+
+impl Write for &LockedFile<Exclusive> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.file().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl Write for LockedFile<Exclusive> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.file().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl<M: LockKind> Drop for LockedFile<M> {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = AdvisoryFileLock::unlock(&file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn modes_follow_the_type() {
+        let mut test_file = temp_dir();
+        test_file.push("locked_file_modes");
+
+        let mut writer = LockedFile::<Exclusive>::open(&test_file).unwrap();
+        writer.write_all(b"exclusive").unwrap();
+        assert_eq!(writer.mode(), FileLockMode::Exclusive);
+        assert!(matches!(
+            LockedFile::<Shared>::try_lock(File::open(&test_file).unwrap()),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        let file = writer.unlock().unwrap();
+
+        let mut first = LockedFile::<Shared>::try_lock(file).unwrap();
+        let mut second = LockedFile::<Shared>::open(&test_file).unwrap();
+        let mut contents = String::new();
+        first.seek(SeekFrom::Start(0)).unwrap();
+        first.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "exclusive");
+        assert_eq!(second.metadata().unwrap().len(), 9);
+        second.read_to_string(&mut contents).unwrap();
+
+        drop((first, second));
+        LockedFile::<Exclusive>::try_lock(File::open(&test_file).unwrap()).unwrap();
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn streams_hold_the_lock() {
+        let mut test_file = temp_dir();
+        test_file.push("locked_file_streams");
+
+        let writer = LockedWriter::open(&test_file).unwrap();
+        std::io::copy(&mut &b"streamed"[..], &mut &writer).unwrap();
+        assert!(LockedReader::try_lock(File::open(&test_file).unwrap()).is_err());
+        drop(writer);
+
+        let reader = LockedReader::open(&test_file).unwrap();
+        let mut contents = Vec::new();
+        std::io::copy(&mut &reader, &mut contents).unwrap();
+        assert_eq!(contents, b"streamed");
+        assert!(LockedWriter::try_lock(File::open(&test_file).unwrap()).is_err());
+
+        drop(reader);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(windows)]
+use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+
+use crate::fs::{open_locked_with, remove_locked, replace_contents, PathFile};
+use crate::{process_id, sys, AdvisoryFileLock, FileId, FileLockError, FileLockMode};
+
+/// A lock file recording its holder, which is removed when released.
+///
+/// The file is created if needed and locked exclusively, then its contents are replaced with the
+/// id of the holding process and the time the lock was acquired, one per line. The process id
+/// comes first, like in a pid file, so [`gc_lock_files`] recognizes lock files whose holder is
+/// gone.
+///
+/// When the `Lockfile` is released or dropped, the file is removed while its lock is still held.
+/// A process waiting for the lock then ends up locking a file which no longer exists, which
+/// `Lockfile` detects, opening the path anew. On Windows, removal fails if another program
+/// opened the file without sharing deletion.
+///
+/// Where open files can't be identified, the lock is [emulated] on the path of the lock file,
+/// which is only opened once locked. [`break_stale`] isn't supported there.
+///
+/// Example:
+/// ```
+/// use advisory_lock::Lockfile;
+///
+/// let lockfile = Lockfile::acquire("app.lock")?;
+/// assert_eq!(lockfile.pid(), std::process::id());
+/// // ... only one instance of the application gets here ...
+/// lockfile.release()?;
+/// assert!(!std::path::Path::new("app.lock").exists());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`gc_lock_files`]: fn.gc_lock_files.html
+/// [emulated]: fn.set_emulation_dir.html
+/// [`break_stale`]: #method.break_stale
+#[derive(Debug)]
+pub struct Lockfile {
+    path: PathBuf,
+    file: Option<PathFile>,
+    pid: u32,
+    acquired: SystemTime,
+}
+
+impl Lockfile {
+    /// Create and lock the lock file at `path`, blocking until the lock is acquired.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Lockfile, FileLockError> {
+        Lockfile::open(path.as_ref(), false)
+    }
+
+    /// Create and lock the lock file at `path`, failing with [`FileLockError::AlreadyLocked`]
+    /// if it is held by someone else.
+    ///
+    /// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+    pub fn try_acquire<P: AsRef<Path>>(path: P) -> Result<Lockfile, FileLockError> {
+        Lockfile::open(path.as_ref(), true)
+    }
+
+    fn open(path: &Path, immediate: bool) -> Result<Lockfile, FileLockError> {
+        let file = open_locked_with(path, FileLockMode::Exclusive, true, immediate)?;
+        let pid = process_id();
+        let acquired = SystemTime::now();
+        let millis = acquired
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let lockfile = Lockfile {
+            path: path.to_owned(),
+            file: Some(file),
+            pid,
+            acquired,
+        };
+        replace_contents(
+            lockfile.file(),
+            format!("{}\n{}\n", pid, millis).as_bytes(),
+            false,
+        )?;
+        Ok(lockfile)
+    }
+
+    /// Return the path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the locked file.
+    pub fn file(&self) -> &File {
+        self.file
+            .as_ref()
+            .expect("the lock file is open until dropped")
+    }
+
+    /// Return the id of the holding process, as recorded in the file.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Return the time the lock was acquired, as recorded in the file.
+    pub fn acquired(&self) -> SystemTime {
+        self.acquired
+    }
+
+    /// Remove the lock file and release its lock.
+    pub fn release(mut self) -> Result<(), FileLockError> {
+        self.remove()
+    }
+
+    /// Report the holder recorded in the lock file at `path`, and whether it is still running.
+    ///
+    /// A lock file outliving its holder, e.g. after a crash, is [`Stale`]; since the lock itself
+    /// was released with the holder, another process can acquire it as usual, but tools may want
+    /// to report the crash or clean the file up with [`break_stale`].
+    ///
+    /// ## Notes
+    ///
+    /// The process id is checked on this machine: a lock file on a network file system whose
+    /// holder runs elsewhere, or in another pid namespace, may be reported stale while it isn't.
+    /// Process ids are also reused, so a holder which died long ago may be reported alive.
+    ///
+    /// Example:
+    /// ```
+    /// use advisory_lock::{Lockfile, LockfileStatus};
+    ///
+    /// let lockfile = Lockfile::acquire("status.lock")?;
+    /// match Lockfile::inspect("status.lock")? {
+    ///     LockfileStatus::Alive(holder) => println!("locked by process {}", holder.pid()),
+    ///     LockfileStatus::Stale(holder) => println!("process {} crashed", holder.pid()),
+    ///     _ => println!("not locked"),
+    /// }
+    /// #
+    /// # drop(lockfile);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`Stale`]: enum.LockfileStatus.html#variant.Stale
+    /// [`break_stale`]: #method.break_stale
+    pub fn inspect<P: AsRef<Path>>(path: P) -> Result<LockfileStatus, FileLockError> {
+        match File::open(path.as_ref()) {
+            Ok(file) => LockfileStatus::read(&file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(LockfileStatus::Missing),
+            Err(err) => Err(FileLockError::Io(err)),
+        }
+    }
+
+    /// Remove the lock file at `path` if it is [`Stale`], returning whether it was.
+    ///
+    /// The file is locked while it is inspected and removed, so a lock file which was acquired
+    /// anew in the meantime is left alone.
+    ///
+    /// [`Stale`]: enum.LockfileStatus.html#variant.Stale
+    pub fn break_stale<P: AsRef<Path>>(path: P) -> Result<bool, FileLockError> {
+        let path = path.as_ref();
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(FileLockError::Io(err)),
+        };
+        match AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive) {
+            Err(FileLockError::AlreadyLocked) => return Ok(false),
+            result => result?,
+        }
+        let same_file = FileId::of(&file).ok() == FileId::of_path(path).ok();
+        // Read through the locked handle, which the lock never keeps from reading.
+        if !same_file || !matches!(LockfileStatus::read(&file)?, LockfileStatus::Stale(_)) {
+            return Ok(false);
+        }
+
+        remove_locked(path, file)?;
+        Ok(true)
+    }
+
+    fn remove(&mut self) -> Result<(), FileLockError> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        remove_locked(&self.path, file)
+    }
+}
+
+/// The state of a lock file, as reported by [`Lockfile::inspect`].
+///
+/// [`Lockfile::inspect`]: struct.Lockfile.html#method.inspect
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum LockfileStatus {
+    /// There is no lock file.
+    Missing,
+    /// The lock file doesn't record a holder, e.g. because it is being created.
+    Unrecorded,
+    /// The lock file records a process which is still running.
+    Alive(LockfileHolder),
+    /// The lock file records a process which is gone.
+    Stale(LockfileHolder),
+    /// The lock file is locked, but its record can't be read. This happens on Windows when
+    /// locks cover the whole file; see [`LockRegion`].
+    ///
+    /// [`LockRegion`]: enum.LockRegion.html
+    Held,
+}
+
+impl LockfileStatus {
+    fn read(file: &File) -> Result<LockfileStatus, FileLockError> {
+        let mut contents = String::new();
+        match file.take(128).read_to_string(&mut contents) {
+            Ok(_) => Ok(LockfileStatus::of(&contents)),
+            #[cfg(windows)]
+            Err(err) if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) => {
+                Ok(LockfileStatus::Held)
+            }
+            Err(err) => Err(FileLockError::Io(err)),
+        }
+    }
+
+    pub(crate) fn of(contents: &str) -> LockfileStatus {
+        let mut lines = contents.lines();
+        let pid = match lines
+            .next()
+            .and_then(|line| line.trim().parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => return LockfileStatus::Unrecorded,
+        };
+        let acquired = lines
+            .next()
+            .and_then(|line| line.trim().parse::<u64>().ok())
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+        let holder = LockfileHolder { pid, acquired };
+        if sys::process_alive(pid) {
+            LockfileStatus::Alive(holder)
+        } else {
+            LockfileStatus::Stale(holder)
+        }
+    }
+}
+
+/// The holder recorded in a lock file.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LockfileHolder {
+    pid: u32,
+    acquired: Option<SystemTime>,
+}
+
+impl LockfileHolder {
+    /// Return the id of the holding process.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Return the time the lock was acquired, if it was recorded.
+    pub fn acquired(&self) -> Option<SystemTime> {
+        self.acquired
+    }
+}
+
+impl Drop for Lockfile {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn records_and_removes_the_holder() {
+        let mut test_file = temp_dir();
+        test_file.push("lockfile_holder.lock");
+
+        let lockfile = Lockfile::acquire(&test_file).unwrap();
+        assert!(matches!(
+            Lockfile::try_acquire(&test_file),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        if cfg!(unix) {
+            let contents = std::fs::read_to_string(&test_file).unwrap();
+            let mut lines = contents.lines();
+            assert_eq!(lines.next(), Some(process_id().to_string().as_str()));
+            assert!(lines.next().unwrap().parse::<u128>().is_ok());
+        }
+
+        let waiter = {
+            let path = test_file.clone();
+            std::thread::spawn(move || Lockfile::acquire(path).unwrap())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(lockfile);
+        let lockfile = waiter.join().unwrap();
+        assert_eq!(
+            crate::FileId::of(lockfile.file()).unwrap(),
+            crate::FileId::of_path(&test_file).unwrap()
+        );
+
+        match Lockfile::inspect(&test_file).unwrap() {
+            LockfileStatus::Alive(holder) => assert_eq!(holder.pid(), process_id()),
+            LockfileStatus::Held if cfg!(windows) => {}
+            status => panic!("unexpected status: {:?}", status),
+        }
+        assert!(!Lockfile::break_stale(&test_file).unwrap());
+        lockfile.release().unwrap();
+        assert!(!test_file.exists());
+        assert_eq!(
+            Lockfile::inspect(&test_file).unwrap(),
+            LockfileStatus::Missing
+        );
+
+        // A holder which crashed.
+        std::fs::write(&test_file, "2147483000\n1700000000000\n").unwrap();
+        match Lockfile::inspect(&test_file).unwrap() {
+            LockfileStatus::Stale(holder) => assert_eq!(
+                holder.acquired(),
+                Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            ),
+            status => panic!("unexpected status: {:?}", status),
+        }
+        assert!(Lockfile::break_stale(&test_file).unwrap());
+        assert!(!test_file.exists());
+    }
+}
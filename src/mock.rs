@@ -0,0 +1,280 @@
+//! An in-memory lock table for testing code which uses advisory locks.
+//!
+//! A [`MockLockTable`] stands for a file system, and each [`MockLock`] opened from it stands for
+//! a handle to a file of that file system. The locks behave like `flock` locks, held by the
+//! handle: two handles to the same name exclude each other even within a thread, relocking a
+//! handle converts its lock, and dropping a handle releases it. Nothing touches the real file
+//! system, so tests of contention are deterministic and can run in parallel, each with a table
+//! of its own.
+//!
+//! Faults queued with the [`faults`] module apply to mock operations too.
+//!
+//! This module is only available with the `test-util` feature.
+//!
+//! Example:
+//! ```
+//! use advisory_lock::mock::MockLockTable;
+//! use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode};
+//!
+//! let table = MockLockTable::new();
+//! let writer = table.open("config.toml");
+//! let reader = table.open("config.toml");
+//!
+//! writer.lock(FileLockMode::Exclusive)?;
+//! assert!(matches!(
+//!     reader.try_lock(FileLockMode::Shared),
+//!     Err(FileLockError::AlreadyLocked)
+//! ));
+//! writer.unlock()?;
+//! reader.try_lock(FileLockMode::Shared)?;
+//! assert_eq!(table.mode("config.toml"), Some(FileLockMode::Shared));
+//! # Ok::<(), FileLockError>(())
+//! ```
+//!
+//! [`MockLockTable`]: struct.MockLockTable.html
+//! [`MockLock`]: struct.MockLock.html
+//! [`faults`]: ../faults/index.html
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::faults;
+use crate::sync::{self, Arc, AtomicU64, Condvar, Mutex, Ordering};
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode, FileLockOperation};
+
+/// A process-local table of locks, keyed by name.
+///
+/// Clones share the same table.
+#[derive(Clone, Default)]
+pub struct MockLockTable {
+    table: Arc<Table>,
+}
+
+#[derive(Default)]
+struct Table {
+    entries: Mutex<HashMap<String, Entry>>,
+    released: Condvar,
+    next_id: AtomicU64,
+}
+
+/// The holders of the lock of one name.
+#[derive(Default)]
+struct Entry {
+    exclusive: Option<u64>,
+    shared: Vec<u64>,
+}
+
+impl Entry {
+    fn conflicts(&self, id: u64, file_lock_mode: FileLockMode) -> bool {
+        let exclusive = self.exclusive.is_some_and(|holder| holder != id);
+        match file_lock_mode {
+            FileLockMode::Shared => exclusive,
+            FileLockMode::Exclusive => exclusive || self.shared.iter().any(|&h| h != id),
+        }
+    }
+
+    fn release(&mut self, id: u64) -> bool {
+        let held = self.mode(id).is_some();
+        if self.exclusive == Some(id) {
+            self.exclusive = None;
+        }
+        self.shared.retain(|&holder| holder != id);
+        held
+    }
+
+    fn mode(&self, id: u64) -> Option<FileLockMode> {
+        if self.exclusive == Some(id) {
+            Some(FileLockMode::Exclusive)
+        } else if self.shared.contains(&id) {
+            Some(FileLockMode::Shared)
+        } else {
+            None
+        }
+    }
+}
+
+impl MockLockTable {
+    /// Create an empty table.
+    pub fn new() -> MockLockTable {
+        MockLockTable::default()
+    }
+
+    /// Open a new handle to the lock of `name`.
+    pub fn open<S: Into<String>>(&self, name: S) -> MockLock {
+        MockLock {
+            table: Arc::clone(&self.table),
+            name: name.into(),
+            id: self.table.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Return the mode the lock of `name` is held in, or `None` if it is not held.
+    pub fn mode(&self, name: &str) -> Option<FileLockMode> {
+        let entries = sync::lock(&self.table.entries);
+        let entry = entries.get(name)?;
+        if entry.exclusive.is_some() {
+            Some(FileLockMode::Exclusive)
+        } else if !entry.shared.is_empty() {
+            Some(FileLockMode::Shared)
+        } else {
+            None
+        }
+    }
+
+    /// Return the number of handles holding the lock of `name`.
+    pub fn holders(&self, name: &str) -> usize {
+        sync::lock(&self.table.entries)
+            .get(name)
+            .map_or(0, |entry| {
+                entry.exclusive.iter().count() + entry.shared.len()
+            })
+    }
+}
+
+impl fmt::Debug for MockLockTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockLockTable").finish_non_exhaustive()
+    }
+}
+
+/// A handle to a lock of a [`MockLockTable`], which releases its lock when dropped.
+///
+/// Converting the lock of a handle is atomic, and a conversion which fails keeps the lock in
+/// the mode it was held in.
+///
+/// [`MockLockTable`]: struct.MockLockTable.html
+pub struct MockLock {
+    table: Arc<Table>,
+    name: String,
+    id: u64,
+}
+
+impl MockLock {
+    /// Return the name of the lock.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the mode this handle holds the lock in, or `None` if it doesn't hold it.
+    pub fn mode(&self) -> Option<FileLockMode> {
+        sync::lock(&self.table.entries)
+            .get(&self.name)
+            .and_then(|entry| entry.mode(self.id))
+    }
+
+    fn acquire(&self, file_lock_mode: FileLockMode, immediate: bool) -> Result<(), FileLockError> {
+        let operation = if immediate {
+            FileLockOperation::TryLock
+        } else {
+            FileLockOperation::Lock
+        };
+        faults::intercept(operation)?;
+
+        let mut entries = sync::lock(&self.table.entries);
+        loop {
+            let entry = entries.entry(self.name.clone()).or_default();
+            if !entry.conflicts(self.id, file_lock_mode) {
+                let downgraded = entry.release(self.id);
+                match file_lock_mode {
+                    FileLockMode::Exclusive => entry.exclusive = Some(self.id),
+                    FileLockMode::Shared => entry.shared.push(self.id),
+                }
+                if downgraded && file_lock_mode == FileLockMode::Shared {
+                    self.table.released.notify_all();
+                }
+                return Ok(());
+            }
+            if immediate {
+                return Err(FileLockError::AlreadyLocked);
+            }
+            entries = self
+                .table
+                .released
+                .wait(entries)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    fn release(&self) {
+        let mut entries = sync::lock(&self.table.entries);
+        if let Some(entry) = entries.get_mut(&self.name) {
+            if entry.release(self.id) {
+                self.table.released.notify_all();
+            }
+        }
+    }
+}
+
+impl AdvisoryFileLock for MockLock {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        faults::intercept(FileLockOperation::Unlock)?;
+        self.release();
+        Ok(())
+    }
+}
+
+impl Drop for MockLock {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl fmt::Debug for MockLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockLock")
+            .field("name", &self.name)
+            .field("mode", &self.mode())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn mock_locks_exclude_each_other() {
+        let table = MockLockTable::new();
+        let first = table.open("a");
+        let second = table.open("a");
+        let other = table.open("b");
+
+        first.lock(FileLockMode::Shared).unwrap();
+        second.try_lock(FileLockMode::Shared).unwrap();
+        other.try_lock(FileLockMode::Exclusive).unwrap();
+        assert_eq!(table.holders("a"), 2);
+        assert!(matches!(
+            first.try_lock(FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert_eq!(first.mode(), Some(FileLockMode::Shared));
+
+        drop(second);
+        first.try_lock(FileLockMode::Exclusive).unwrap();
+        assert_eq!(table.mode("a"), Some(FileLockMode::Exclusive));
+
+        let waiter = table.open("a");
+        let blocked = thread::spawn(move || {
+            waiter.lock(FileLockMode::Shared).unwrap();
+            waiter
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+        first.lock(FileLockMode::Shared).unwrap();
+        let waiter = blocked.join().unwrap();
+        assert_eq!(table.holders("a"), 2);
+
+        first.unlock().unwrap();
+        waiter.unlock().unwrap();
+        assert_eq!(table.mode("a"), None);
+    }
+}
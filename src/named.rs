@@ -0,0 +1,508 @@
+use std::fmt::Write;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(any(unix, windows))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::fs::{open_unlocked, PathFile};
+#[cfg(windows)]
+use crate::named_mutex::NamedMutex;
+#[cfg(unix)]
+use crate::semaphore::NamedSemaphore;
+#[cfg(any(unix, windows))]
+use crate::striped::Fnv1a;
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// The directory holding the files of named locks, when set explicitly.
+static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// A lock identified by a name rather than a path, shared by every process of the user.
+///
+/// The name is mapped to a file in the [named lock directory], which is created along with the
+/// file as needed. Any name which isn't empty is valid: characters other than ASCII letters,
+/// digits, `-`, `_` and `.` are percent-encoded, so `my-app/db-migration` is locked through the
+/// file `my-app%2Fdb-migration.lock`. On case-insensitive file systems, names differing only in
+/// case share their lock.
+///
+/// The lock belongs to the `NamedLock`, like the lock of a [`File`], and is released when it is
+/// dropped. `NamedLock` implements [`AdvisoryFileLock`], so locks can be taken with timeouts,
+/// retries or guards as usual.
+///
+/// ## Windows
+///
+/// Unless a named lock directory was set, named locks are named mutexes on Windows, created with
+/// `CreateMutexW` in the `Local\` namespace of the session, or the `Global\` one with
+/// [`global`]. There are no files to create or clean up, and a lock whose thread exits is
+/// released. Mutexes are always exclusive, though, whatever the requested mode, and they belong
+/// to a thread: a mutex must be unlocked by the thread which locked it, and the same thread
+/// locking it through another `NamedLock` doesn't wait. Dropping a `NamedLock` on another thread
+/// than the one holding its lock only releases it when that thread exits.
+///
+/// ## POSIX semaphores
+///
+/// On Unix, [`semaphore`] opens a named lock backed by a POSIX named semaphore instead of a
+/// file, for environments where lock files are awkward, e.g. with a read-only temporary
+/// directory. See its documentation for the caveats.
+///
+/// ## Targets without file descriptors or handles
+///
+/// Where open files can't be identified, named locks still work: their lock is [emulated] on the
+/// path of their file.
+///
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use advisory_lock::{AdvisoryFileLock, FileLockMode, NamedLock};
+///
+/// let lock = NamedLock::new("my-app/db-migration")?;
+/// lock.lock_timeout(FileLockMode::Exclusive, Duration::from_secs(5))?;
+/// // ... only one process of the user migrates the database ...
+/// lock.unlock()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [named lock directory]: fn.named_lock_dir.html
+/// [emulated]: fn.set_emulation_dir.html
+/// [`File`]: https://doc.rust-lang.org/stable/std/fs/struct.File.html
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+/// [`global`]: #method.global
+/// [`semaphore`]: #method.semaphore
+#[derive(Debug)]
+pub struct NamedLock {
+    name: String,
+    inner: Inner,
+}
+
+#[derive(Debug)]
+enum Inner {
+    File {
+        path: PathBuf,
+        file: PathFile,
+    },
+    #[cfg(windows)]
+    Mutex {
+        mutex: NamedMutex,
+        held: AtomicBool,
+    },
+    #[cfg(unix)]
+    Semaphore {
+        semaphore: NamedSemaphore,
+        held: AtomicBool,
+    },
+}
+
+impl NamedLock {
+    /// Open the lock named `name` in the [named lock directory], without acquiring it.
+    ///
+    /// [named lock directory]: fn.named_lock_dir.html
+    pub fn new<S: Into<String>>(name: S) -> Result<NamedLock, FileLockError> {
+        #[cfg(windows)]
+        {
+            if DIR.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+                return NamedLock::mutex("Local", name.into());
+            }
+        }
+        let dir = named_lock_dir();
+        create_dir(&dir)?;
+        NamedLock::open(&dir, name.into())
+    }
+
+    /// Open the lock named `name` shared by every session of the machine, backed by a mutex in
+    /// the `Global\` namespace.
+    ///
+    /// Creating global mutexes requires the `SeCreateGlobalPrivilege` privilege outside of
+    /// session zero, which services run in.
+    #[cfg(windows)]
+    pub fn global<S: Into<String>>(name: S) -> Result<NamedLock, FileLockError> {
+        NamedLock::mutex("Global", name.into())
+    }
+
+    #[cfg(windows)]
+    fn mutex(namespace: &str, name: String) -> Result<NamedLock, FileLockError> {
+        check_name(&name)?;
+        // Mutex names are limited to `MAX_PATH` characters.
+        let mut encoded = file_name(&name);
+        if encoded.len() > 200 {
+            encoded = format!("{:016x}", Fnv1a::hash(name.as_bytes()));
+        }
+        let mutex = NamedMutex::open(&format!("{}\\advisory-lock-named-{}", namespace, encoded))
+            .map_err(FileLockError::Io)?;
+        Ok(NamedLock {
+            name,
+            inner: Inner::Mutex {
+                mutex,
+                held: AtomicBool::new(false),
+            },
+        })
+    }
+
+    /// Open the lock named `name` in `dir` instead of the named lock directory, creating the
+    /// directory if needed.
+    pub fn in_dir<P: AsRef<Path>, S: Into<String>>(
+        dir: P,
+        name: S,
+    ) -> Result<NamedLock, FileLockError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(FileLockError::Io)?;
+        NamedLock::open(dir, name.into())
+    }
+
+    /// Open the lock named `name` backed by a POSIX named semaphore, without acquiring it.
+    ///
+    /// The semaphore is created with `sem_open` as needed, named after a hash of `name`, and
+    /// shared by every process of the machine allowed to open it, which is only the user by
+    /// default. It is always exclusive, whatever the requested mode.
+    ///
+    /// ## Notes
+    ///
+    /// Semaphores aren't locks: the system doesn't release one when its holder exits, so a
+    /// process which crashes holding it leaves it held until [`remove_semaphore`] is called or
+    /// the machine restarts. Dropping a `NamedLock` which holds it releases it, though.
+    ///
+    /// Example:
+    /// ```
+    /// use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode, NamedLock};
+    ///
+    /// let lock = NamedLock::semaphore("my-app/db-migration")?;
+    /// let other = NamedLock::semaphore("my-app/db-migration")?;
+    /// lock.lock(FileLockMode::Exclusive)?;
+    /// assert!(matches!(other.try_lock(FileLockMode::Shared), Err(FileLockError::AlreadyLocked)));
+    /// lock.unlock()?;
+    /// #
+    /// # NamedLock::remove_semaphore("my-app/db-migration")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`remove_semaphore`]: #method.remove_semaphore
+    #[cfg(unix)]
+    pub fn semaphore<S: Into<String>>(name: S) -> Result<NamedLock, FileLockError> {
+        let name = name.into();
+        check_name(&name)?;
+        let semaphore = NamedSemaphore::open(&semaphore_name(&name)).map_err(FileLockError::Io)?;
+        Ok(NamedLock {
+            name,
+            inner: Inner::Semaphore {
+                semaphore,
+                held: AtomicBool::new(false),
+            },
+        })
+    }
+
+    /// Remove the semaphore of the lock named `name`, returning whether it existed.
+    ///
+    /// This recovers a semaphore left held by a process which crashed. Processes which have it
+    /// open keep using the removed semaphore, so only call this when none does.
+    #[cfg(unix)]
+    pub fn remove_semaphore(name: &str) -> Result<bool, FileLockError> {
+        NamedSemaphore::unlink(&semaphore_name(name)).map_err(FileLockError::Io)
+    }
+
+    fn open(dir: &Path, name: String) -> Result<NamedLock, FileLockError> {
+        check_name(&name)?;
+        let path = dir.join(file_name(&name));
+        let file = open_unlocked(
+            &path,
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false),
+        )?;
+        Ok(NamedLock {
+            name,
+            inner: Inner::File { path, file },
+        })
+    }
+
+    /// Return the name of the lock.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the path of the file the lock is taken on, or `None` if it is a named mutex or a
+    /// semaphore.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.inner {
+            Inner::File { path, .. } => Some(path),
+            #[cfg(windows)]
+            Inner::Mutex { .. } => None,
+            #[cfg(unix)]
+            Inner::Semaphore { .. } => None,
+        }
+    }
+
+    fn acquire(&self, file_lock_mode: FileLockMode, immediate: bool) -> Result<(), FileLockError> {
+        match &self.inner {
+            Inner::File { file, .. } if immediate => {
+                AdvisoryFileLock::try_lock(file, file_lock_mode)
+            }
+            Inner::File { file, .. } => AdvisoryFileLock::lock(file, file_lock_mode),
+            // Relocking is a no-op rather than a recursive acquisition, as for files.
+            #[cfg(windows)]
+            Inner::Mutex { mutex, held } => {
+                if !held.load(Ordering::Acquire) {
+                    mutex.acquire(immediate)?;
+                    held.store(true, Ordering::Release);
+                }
+                Ok(())
+            }
+            #[cfg(unix)]
+            Inner::Semaphore { semaphore, held } => {
+                if !held.load(Ordering::Acquire) {
+                    semaphore.acquire(immediate)?;
+                    held.store(true, Ordering::Release);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl AdvisoryFileLock for NamedLock {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        match &self.inner {
+            Inner::File { file, .. } => AdvisoryFileLock::unlock(file),
+            #[cfg(windows)]
+            Inner::Mutex { mutex, held } => {
+                if !held.load(Ordering::Acquire) {
+                    return Err(FileLockError::NotLocked);
+                }
+                mutex.release()?;
+                held.store(false, Ordering::Release);
+                Ok(())
+            }
+            #[cfg(unix)]
+            Inner::Semaphore { semaphore, held } => {
+                if !held.swap(false, Ordering::AcqRel) {
+                    return Err(FileLockError::NotLocked);
+                }
+                semaphore.release()
+            }
+        }
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl Drop for NamedLock {
+    fn drop(&mut self) {
+        match &self.inner {
+            #[cfg(windows)]
+            Inner::Mutex { mutex, held } if held.load(Ordering::Acquire) => {
+                let _ = mutex.release();
+            }
+            #[cfg(unix)]
+            Inner::Semaphore { semaphore, held } if held.load(Ordering::Acquire) => {
+                let _ = semaphore.release();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FileLockError> {
+    if name.is_empty() {
+        return Err(FileLockError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the name of a lock must not be empty",
+        )));
+    }
+    Ok(())
+}
+
+/// Return the name of the file of the lock named `name`.
+fn file_name(name: &str) -> String {
+    let mut file_name = String::with_capacity(name.len() + 5);
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                file_name.push(char::from(byte))
+            }
+            _ => {
+                let _ = write!(file_name, "%{:02X}", byte);
+            }
+        }
+    }
+    file_name.push_str(".lock");
+    file_name
+}
+
+/// Return the name of the semaphore of the lock named `name`.
+///
+/// Semaphore names are limited to 31 characters on macOS, so `name` is hashed.
+#[cfg(unix)]
+fn semaphore_name(name: &str) -> String {
+    format!("/advlock-{:016x}", Fnv1a::hash(name.as_bytes()))
+}
+
+/// Choose the directory holding the files of named locks, for every [`NamedLock::new`] of this
+/// process.
+///
+/// Every program sharing named locks must use the same directory. On Windows, setting it makes
+/// named locks files in it, rather than named mutexes.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{named_lock_dir, set_named_lock_dir};
+///
+/// # let default = named_lock_dir();
+/// set_named_lock_dir("/var/lock/my-app");
+/// assert_eq!(named_lock_dir(), std::path::Path::new("/var/lock/my-app"));
+/// # set_named_lock_dir(default);
+/// ```
+///
+/// [`NamedLock::new`]: struct.NamedLock.html#method.new
+pub fn set_named_lock_dir<P: Into<PathBuf>>(dir: P) {
+    *DIR.lock().unwrap_or_else(|e| e.into_inner()) = Some(dir.into());
+}
+
+/// Return the directory holding the files of named locks.
+///
+/// The default is private to the user: `advisory-lock` in `$XDG_RUNTIME_DIR` if it is set,
+/// or else `advisory-lock-<uid>` in the temporary directory, on Unix, and `advisory-lock` in
+/// the temporary directory of the user elsewhere. On Windows, it is only used once set.
+pub fn named_lock_dir() -> PathBuf {
+    if let Some(dir) = DIR.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+        return dir;
+    }
+    #[cfg(unix)]
+    {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(runtime_dir) if !runtime_dir.is_empty() => {
+                Path::new(&runtime_dir).join("advisory-lock")
+            }
+            _ => {
+                let uid = unsafe { libc::getuid() };
+                std::env::temp_dir().join(format!("advisory-lock-{}", uid))
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        std::env::temp_dir().join("advisory-lock")
+    }
+}
+
+/// Create the named lock directory, private to the user.
+///
+/// The temporary directory is shared with other users, who could have created the directory
+/// first, so it must be owned by this user.
+#[cfg(unix)]
+fn create_dir(dir: &Path) -> Result<(), FileLockError> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    let created = std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir);
+    let metadata = created
+        .and_then(|()| std::fs::symlink_metadata(dir))
+        .map_err(FileLockError::Io)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } {
+        return Err(FileLockError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by the user", dir.display()),
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_dir(dir: &Path) -> Result<(), FileLockError> {
+    std::fs::create_dir_all(dir).map_err(FileLockError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn names_map_to_lock_files() {
+        assert_eq!(
+            file_name("my-app/db-migration"),
+            "my-app%2Fdb-migration.lock"
+        );
+        assert_eq!(file_name("..\\ü"), "..%5C%C3%BC.lock");
+
+        let mut dir = temp_dir();
+        dir.push("named_locks");
+        let lock = NamedLock::in_dir(&dir, "my-app/db-migration").unwrap();
+        let other = NamedLock::in_dir(&dir, "my-app/db-migration").unwrap();
+        assert_eq!(lock.name(), "my-app/db-migration");
+        assert_eq!(
+            lock.path(),
+            Some(dir.join("my-app%2Fdb-migration.lock").as_path())
+        );
+
+        lock.lock(FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            other.try_lock(FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop(lock);
+        other.try_lock(FileLockMode::Exclusive).unwrap();
+        assert!(NamedLock::in_dir(&dir, "").is_err());
+
+        drop(other);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn named_locks_are_mutexes_on_windows() {
+        let lock = NamedLock::new("advisory-lock-tests/mutex").unwrap();
+        let other = NamedLock::new("advisory-lock-tests/mutex").unwrap();
+        assert_eq!(lock.path(), None);
+
+        lock.lock(FileLockMode::Shared).unwrap();
+        lock.lock(FileLockMode::Exclusive).unwrap();
+        // Mutexes belong to threads, so the contender has to be another thread.
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                assert!(matches!(
+                    other.try_lock(FileLockMode::Shared),
+                    Err(FileLockError::AlreadyLocked)
+                ));
+            });
+        });
+        lock.unlock().unwrap();
+        assert!(matches!(lock.unlock(), Err(FileLockError::NotLocked)));
+        other.try_lock(FileLockMode::Exclusive).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn semaphores_exclude_each_other() {
+        let name = format!("advisory-lock-tests/{}", std::process::id());
+        let lock = NamedLock::semaphore(name.as_str()).unwrap();
+        let other = NamedLock::semaphore(name.as_str()).unwrap();
+        assert_eq!(lock.path(), None);
+
+        lock.lock(FileLockMode::Shared).unwrap();
+        lock.lock(FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            other.try_lock(FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        lock.unlock().unwrap();
+        assert!(matches!(lock.unlock(), Err(FileLockError::NotLocked)));
+        other.try_lock(FileLockMode::Exclusive).unwrap();
+        // Dropping the holder gives the token back.
+        drop(other);
+        lock.try_lock(FileLockMode::Exclusive).unwrap();
+
+        drop(lock);
+        assert!(NamedLock::remove_semaphore(&name).unwrap());
+        assert!(!NamedLock::remove_semaphore(&name).unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+//! Named mutexes, which back `NamedLock` on Windows, and the fallback for Windows file systems
+//! which don't support `LockFileEx`.
+use std::collections::BTreeMap;
+use std::io;
+use std::os::windows::io::RawHandle;
+
+use winapi::{
+    shared::{minwindef::FALSE, winerror::WAIT_TIMEOUT},
+    um::{
+        handleapi::CloseHandle,
+        synchapi::{CreateMutexW, ReleaseMutex, WaitForSingleObject},
+        winbase::{INFINITE, WAIT_ABANDONED, WAIT_OBJECT_0},
+        winnt::HANDLE,
+    },
+};
+
+use crate::striped::Fnv1a;
+use crate::sync::{self, Mutex, MutexGuard};
+use crate::{sys, FileLockError};
+
+sync::statics! {
+    /// The mutexes held by this process, keyed by the file handle they were acquired for.
+    ///
+    /// File handles are stored as integers: they are only compared, never dereferenced.
+    static HELD: Mutex<BTreeMap<usize, NamedMutex>> = Mutex::new(BTreeMap::new());
+}
+
+fn held() -> MutexGuard<'static, BTreeMap<usize, NamedMutex>> {
+    HELD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A handle to a named mutex, which is closed when dropped.
+///
+/// Mutexes belong to the thread which acquired them: only it can release them, and acquiring
+/// them again from it succeeds right away, counting one more acquisition to release. A mutex
+/// whose thread exits holding it is released, as *abandoned*.
+#[derive(Debug)]
+pub(crate) struct NamedMutex {
+    /// Stored as an integer, as it is only passed to the operating system.
+    handle: usize,
+}
+
+impl NamedMutex {
+    /// Open the mutex called `name`, creating it if needed, without acquiring it.
+    pub(crate) fn open(name: &str) -> io::Result<NamedMutex> {
+        let name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+        let handle = unsafe { CreateMutexW(std::ptr::null_mut(), FALSE, name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NamedMutex {
+            handle: handle as usize,
+        })
+    }
+
+    /// Acquire the mutex, waiting for it unless `immediate`.
+    pub(crate) fn acquire(&self, immediate: bool) -> Result<(), FileLockError> {
+        let timeout = if immediate { 0 } else { INFINITE };
+        match unsafe { WaitForSingleObject(self.handle as HANDLE, timeout) } {
+            // An abandoned mutex was held by a thread which exited: its lock is released, as that
+            // of a file would be.
+            WAIT_OBJECT_0 | WAIT_ABANDONED => Ok(()),
+            WAIT_TIMEOUT => Err(FileLockError::AlreadyLocked),
+            _ => Err(FileLockError::Io(io::Error::last_os_error())),
+        }
+    }
+
+    /// Release the mutex, which must be held by the current thread.
+    pub(crate) fn release(&self) -> Result<(), FileLockError> {
+        if unsafe { ReleaseMutex(self.handle as HANDLE) } == FALSE {
+            return Err(FileLockError::Io(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NamedMutex {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle as HANDLE) };
+    }
+}
+
+/// Return whether the lock of `raw_handle` is held through a named mutex.
+pub(crate) fn holds(raw_handle: RawHandle) -> bool {
+    held().contains_key(&(raw_handle as usize))
+}
+
+/// Acquire the mutex named after the file of `raw_handle`.
+///
+/// The mutex is exclusive whatever the requested mode, and already holding it through the same
+/// handle is a no-op, like relocking a file.
+pub(crate) fn lock(raw_handle: RawHandle, immediate: bool) -> Result<(), FileLockError> {
+    if holds(raw_handle) {
+        return Ok(());
+    }
+
+    let mutex = mutex_name(raw_handle)
+        .and_then(|name| NamedMutex::open(&name))
+        .map_err(FileLockError::Io)?;
+    mutex.acquire(immediate)?;
+    held().insert(raw_handle as usize, mutex);
+    Ok(())
+}
+
+/// Release the mutex held for `raw_handle`, or return `None` if there is none.
+///
+/// The mutex is only forgotten once released, so it stays tracked if the release fails, for
+/// instance because it is attempted from another thread than the one which acquired it.
+pub(crate) fn unlock(raw_handle: RawHandle) -> Option<Result<(), FileLockError>> {
+    let mut held = held();
+    let result = held.get(&(raw_handle as usize))?.release();
+    if result.is_ok() {
+        held.remove(&(raw_handle as usize));
+    }
+    Some(result)
+}
+
+/// Derive the name of the mutex from the canonical path of the file.
+///
+/// Names can't contain backslashes and are limited in length, so the path is hashed.
+fn mutex_name(raw_handle: RawHandle) -> io::Result<String> {
+    // The file systems this is used on are case-insensitive.
+    let path = sys::path(raw_handle)?.to_string_lossy().to_lowercase();
+    Ok(format!(
+        "Local\\advisory-lock-{:016x}",
+        Fnv1a::hash(path.as_bytes())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::os::windows::io::AsRawHandle;
+
+    #[test]
+    fn mutexes_exclude_other_handles() {
+        let mut test_file = temp_dir();
+        test_file.push("named_mutex_fallback");
+        let first = File::create(&test_file).unwrap();
+        let second = File::open(&test_file).unwrap();
+        assert_eq!(
+            mutex_name(first.as_raw_handle()).unwrap(),
+            mutex_name(second.as_raw_handle()).unwrap()
+        );
+
+        lock(first.as_raw_handle(), true).unwrap();
+        assert!(holds(first.as_raw_handle()));
+        lock(first.as_raw_handle(), true).unwrap();
+        // Mutexes are recursive, so the contender has to be another thread.
+        let handle = second.as_raw_handle() as usize;
+        std::thread::spawn(move || {
+            assert!(matches!(
+                lock(handle as RawHandle, true),
+                Err(FileLockError::AlreadyLocked)
+            ));
+        })
+        .join()
+        .unwrap();
+        // Only the thread which acquired the mutex can release it.
+        let handle = first.as_raw_handle() as usize;
+        std::thread::spawn(move || assert!(unlock(handle as RawHandle).unwrap().is_err()))
+            .join()
+            .unwrap();
+        assert!(holds(first.as_raw_handle()));
+        unlock(first.as_raw_handle()).unwrap().unwrap();
+        assert!(unlock(first.as_raw_handle()).is_none());
+
+        drop((first, second));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+//! Running a job exactly once across processes.
+use std::path::Path;
+
+use crate::fs::{open_locked_with, read_all, replace_contents};
+use crate::{FileLockError, FileLockMode};
+
+/// The contents of the marker file once the job has completed.
+const COMPLETED: &[u8] = b"completed\n";
+
+/// Run `f` unless it already completed, in this process or any other, and mark it completed.
+///
+/// The marker file at `path` is created if needed and locked exclusively while `f` runs, so of
+/// several processes starting at once, one runs `f` and the others wait for it to complete,
+/// then return `Ok(None)`. Once `f` returns, the file records its completion and is synced, so
+/// later calls return `Ok(None)` right away. If `f` panics or its process dies, nothing is
+/// recorded and the next process to get the lock runs `f` instead.
+///
+/// Remove the marker file to run `f` again.
+///
+/// Example:
+/// ```
+/// use advisory_lock::run_once;
+///
+/// assert_eq!(run_once("migrated", || 42)?, Some(42));
+/// assert_eq!(run_once("migrated", || 42)?, None);
+/// #
+/// # std::fs::remove_file("migrated")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn run_once<P, F, T>(path: P, f: F) -> Result<Option<T>, FileLockError>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> T,
+{
+    run(path.as_ref(), f, false)
+}
+
+/// Like [`run_once`], but returns `Ok(None)` right away instead of waiting if another process
+/// is running `f`.
+///
+/// [`run_once`]: fn.run_once.html
+pub fn try_run_once<P, F, T>(path: P, f: F) -> Result<Option<T>, FileLockError>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> T,
+{
+    match run(path.as_ref(), f, true) {
+        Err(FileLockError::AlreadyLocked) => Ok(None),
+        result => result,
+    }
+}
+
+fn run<T>(path: &Path, f: impl FnOnce() -> T, immediate: bool) -> Result<Option<T>, FileLockError> {
+    let file = open_locked_with(path, FileLockMode::Exclusive, true, immediate)?;
+    if read_all(&file)? == COMPLETED {
+        return Ok(None);
+    }
+    let value = f();
+    replace_contents(&file, COMPLETED, true)?;
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_run_once() {
+        let mut test_file = temp_dir();
+        test_file.push("run_once");
+        let _ = std::fs::remove_file(&test_file);
+        let runs = AtomicUsize::new(0);
+        let barrier = Barrier::new(4);
+
+        let ran = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        run_once(&test_file, || {
+                            assert_eq!(try_run_once(&test_file, || ()).unwrap(), None);
+                            thread::sleep(Duration::from_millis(20));
+                            runs.fetch_add(1, Ordering::SeqCst)
+                        })
+                        .unwrap()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .filter_map(|worker| worker.join().unwrap())
+                .count()
+        });
+        assert_eq!((ran, runs.load(Ordering::SeqCst)), (1, 1));
+        assert_eq!(try_run_once(&test_file, || ()).unwrap(), None);
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
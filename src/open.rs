@@ -0,0 +1,209 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+use crate::{
+    default_backend, self_deadlock_policy, strict_mode, sys, thread_aware, validate, Backend,
+    SelfDeadlockPolicy,
+};
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// An extension of [`OpenOptions`] to lock files as they are opened.
+///
+/// [`OpenOptions`]: https://doc.rust-lang.org/stable/std/fs/struct.OpenOptions.html
+pub trait OpenOptionsLockExt {
+    /// Lock the file in `file_lock_mode` once it is opened.
+    ///
+    /// The returned [`LockOnOpen`] opens the file with these options and locks it, blocking until
+    /// the lock is acquired. If the lock can't be acquired, the file is closed before the error is
+    /// returned.
+    ///
+    /// On macOS and the BSDs, the file is opened and locked in a single system call, with
+    /// `O_SHLOCK` or `O_EXLOCK`, so a file created by the call can't be locked by anyone else
+    /// first. This replaces the custom flags set through `OpenOptionsExt::custom_flags`; set them
+    /// with [`LockOnOpen::custom_flags`] instead. Files are locked in two steps when the
+    /// [`Fcntl`] backend, strict mode, thread-aware mode or a [`SelfDeadlockPolicy`] other than
+    /// `Ignore` is enabled, or when the file system doesn't support these flags.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::OpenOptions;
+    /// use std::io::Write;
+    /// use advisory_lock::{FileLockMode, OpenOptionsLockExt};
+    ///
+    /// let mut file = OpenOptions::new()
+    ///     .write(true)
+    ///     .create(true)
+    ///     .truncate(false)
+    ///     .lock_on_open(FileLockMode::Exclusive)
+    ///     .open("lock_on_open.txt")?;
+    /// file.write_all(b"written under the lock")?;
+    /// #
+    /// # drop(file);
+    /// # std::fs::remove_file("lock_on_open.txt")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// [`LockOnOpen`]: struct.LockOnOpen.html
+    /// [`LockOnOpen::custom_flags`]: struct.LockOnOpen.html#method.custom_flags
+    /// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+    /// [`SelfDeadlockPolicy`]: enum.SelfDeadlockPolicy.html
+    fn lock_on_open(&self, file_lock_mode: FileLockMode) -> LockOnOpen<'_>;
+}
+
+impl OpenOptionsLockExt for OpenOptions {
+    fn lock_on_open(&self, file_lock_mode: FileLockMode) -> LockOnOpen<'_> {
+        LockOnOpen {
+            options: self,
+            file_lock_mode,
+            immediate: false,
+            custom_flags: 0,
+        }
+    }
+}
+
+/// Options opening a file and locking it in one call, created by
+/// [`OpenOptionsLockExt::lock_on_open`].
+///
+/// [`OpenOptionsLockExt::lock_on_open`]: trait.OpenOptionsLockExt.html#tymethod.lock_on_open
+#[derive(Debug)]
+pub struct LockOnOpen<'a> {
+    options: &'a OpenOptions,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    custom_flags: i32,
+}
+
+impl LockOnOpen<'_> {
+    /// Fail with [`FileLockError::AlreadyLocked`] instead of blocking if the file is locked
+    /// elsewhere.
+    ///
+    /// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+    pub fn immediate(mut self) -> Self {
+        self.immediate = true;
+        self
+    }
+
+    /// Pass `flags` to the `open` call, like `OpenOptionsExt::custom_flags`, on Unix.
+    #[cfg(unix)]
+    pub fn custom_flags(mut self, flags: i32) -> Self {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// Open the file at `path` and lock it.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File, FileLockError> {
+        let path = path.as_ref();
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        ))]
+        if default_backend() == Backend::Native
+            && !strict_mode()
+            && !thread_aware()
+            && self_deadlock_policy() == SelfDeadlockPolicy::Ignore
+        {
+            let (mode, immediate) = (self.file_lock_mode, self.immediate);
+            match sys::open_locked(self.options, path, self.custom_flags, mode, immediate) {
+                Err(FileLockError::Unsupported) => {}
+                // The lock is taken by `open`, so the checks and the bookkeeping surrounding other
+                // acquisitions follow it; the file is closed, and unlocked, if they fail.
+                Ok(file) => {
+                    let handle = sys::handle(&file);
+                    validate::check_file_type(handle)?;
+                    #[cfg(feature = "diagnostics")]
+                    {
+                        let operation = match immediate {
+                            true => crate::FileLockOperation::TryLock,
+                            false => crate::FileLockOperation::Lock,
+                        };
+                        crate::registry::record(handle, operation, Some(mode));
+                    }
+                    return Ok(file);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        #[cfg(unix)]
+        let file = {
+            use std::os::unix::fs::OpenOptionsExt;
+            let mut options = self.options.clone();
+            if self.custom_flags != 0 {
+                options.custom_flags(self.custom_flags);
+            }
+            options.open(path)
+        };
+        #[cfg(not(unix))]
+        let file = self.options.open(path);
+        let file = file.map_err(FileLockError::Io)?;
+        if self.immediate {
+            AdvisoryFileLock::try_lock(&file, self.file_lock_mode)?;
+        } else {
+            AdvisoryFileLock::lock(&file, self.file_lock_mode)?;
+        }
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn opens_locked() {
+        let mut test_file = temp_dir();
+        test_file.push("lock_on_open");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+
+        let file = options
+            .lock_on_open(FileLockMode::Exclusive)
+            .open(&test_file)
+            .unwrap();
+        let mut read = OpenOptions::new();
+        read.read(true);
+        let reader = read.lock_on_open(FileLockMode::Shared);
+        assert!(matches!(
+            reader.immediate().open(&test_file),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            options
+                .lock_on_open(FileLockMode::Shared)
+                .open(test_file.join("missing")),
+            Err(FileLockError::Io(_))
+        ));
+
+        drop(file);
+        read.lock_on_open(FileLockMode::Shared)
+            .immediate()
+            .open(&test_file)
+            .unwrap();
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn devices_are_rejected() {
+        let mut read = OpenOptions::new();
+        read.read(true);
+        assert!(matches!(
+            read.lock_on_open(FileLockMode::Shared).open("/dev/null"),
+            Err(FileLockError::UnsupportedFileType)
+        ));
+    }
+}
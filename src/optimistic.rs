@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::checksum::crc32;
+use crate::fs::{open_exclusive, open_locked, read_all, replace_contents};
+use crate::{FileLockError, FileLockMode};
+
+/// A token identifying one version of a file's contents.
+///
+/// It combines the modification time, the length and a hash of the contents, so a change is
+/// detected even when it happens within the resolution of the file system's timestamps.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Version {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u32,
+}
+
+impl Version {
+    fn of(file: &File, contents: &[u8]) -> Result<Version, FileLockError> {
+        let metadata = file.metadata().map_err(FileLockError::Io)?;
+        Ok(Version {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: crc32(contents),
+        })
+    }
+}
+
+/// Read the contents of a file under a shared lock, together with their [`Version`].
+///
+/// Pass the version to [`compare_and_write`] to write the file only if nobody changed it in the
+/// meantime, without holding any lock while computing the new contents.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{compare_and_write, read_versioned, write_locked, FileLockError};
+///
+/// write_locked("versioned.txt", b"1")?;
+/// let (contents, version) = read_versioned("versioned.txt")?;
+/// let (_, stale) = read_versioned("versioned.txt")?;
+///
+/// compare_and_write("versioned.txt", &version, [&contents[..], b"2"].concat())?;
+/// assert!(matches!(
+///     compare_and_write("versioned.txt", &stale, b"lost update"),
+///     Err(FileLockError::Conflict)
+/// ));
+/// #
+/// # std::fs::remove_file("versioned.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`Version`]: struct.Version.html
+/// [`compare_and_write`]: fn.compare_and_write.html
+pub fn read_versioned<P: AsRef<Path>>(path: P) -> Result<(Vec<u8>, Version), FileLockError> {
+    let file = open_locked(path.as_ref(), FileLockMode::Shared, false)?;
+    let contents = read_all(&file)?;
+    let version = Version::of(&file, &contents)?;
+    Ok((contents, version))
+}
+
+/// Replace the contents of a file under an exclusive lock if they are still at `version`.
+///
+/// Returns the version of the new contents, or [`FileLockError::Conflict`] without writing
+/// anything if the file changed since `version` was read.
+///
+/// [`FileLockError::Conflict`]: enum.FileLockError.html#variant.Conflict
+pub fn compare_and_write<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    version: &Version,
+    contents: C,
+) -> Result<Version, FileLockError> {
+    let file = open_exclusive(path.as_ref())?;
+    let current = read_all(&file)?;
+    if Version::of(&file, &current)? != *version {
+        return Err(FileLockError::Conflict);
+    }
+
+    let contents = contents.as_ref();
+    replace_contents(&file, contents, true)?;
+    Version::of(&file, contents)
+}
+
+/// Write a file under an exclusive lock, but only if `check` says it is needed.
+///
+/// `check` first runs under a shared lock, so concurrent callers which find nothing to do don't
+/// exclude each other. Only if it returns `true` is the lock escalated to an exclusive one, and
+/// since a lock can't be upgraded atomically (another process may get the exclusive lock in
+/// between), `check` runs again before `write` does. This is double-checked locking, across
+/// processes. Both closures receive the file positioned at its start.
+///
+/// Returns whether `write` ran.
+///
+/// Example:
+/// ```
+/// use std::io::{Read, Write};
+/// use advisory_lock::{modify_if, write_locked};
+///
+/// write_locked("migrations.txt", b"")?;
+/// let pending = |mut file: &std::fs::File| {
+///     let mut contents = String::new();
+///     file.read_to_string(&mut contents).is_ok() && !contents.contains("v2")
+/// };
+/// assert!(modify_if("migrations.txt", pending, |file| writeln!(file, "v2"))?);
+/// assert!(!modify_if("migrations.txt", pending, |file| writeln!(file, "v2"))?);
+/// #
+/// # std::fs::remove_file("migrations.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn modify_if<P, C, W>(path: P, check: C, write: W) -> Result<bool, FileLockError>
+where
+    P: AsRef<Path>,
+    C: Fn(&File) -> bool,
+    W: FnOnce(&mut File) -> io::Result<()>,
+{
+    let path = path.as_ref();
+    let checked = |mut file: &File| -> Result<bool, FileLockError> {
+        file.seek(SeekFrom::Start(0)).map_err(FileLockError::Io)?;
+        Ok(check(file))
+    };
+
+    let file = open_locked(path, FileLockMode::Shared, false)?;
+    if !checked(&file)? {
+        return Ok(false);
+    }
+    drop(file);
+
+    let mut file = open_exclusive(path)?;
+    if !checked(&file)? {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(0)).map_err(FileLockError::Io)?;
+    write(&mut file).map_err(FileLockError::Io)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_locked;
+    use std::env::temp_dir;
+
+    #[test]
+    fn same_length_changes_conflict() {
+        let mut test_file = temp_dir();
+        test_file.push("optimistic_same_length");
+        write_locked(&test_file, "aaaa").unwrap();
+
+        let (_, version) = read_versioned(&test_file).unwrap();
+        write_locked(&test_file, "bbbb").unwrap();
+        assert!(matches!(
+            compare_and_write(&test_file, &version, "cccc"),
+            Err(FileLockError::Conflict)
+        ));
+
+        let (contents, version) = read_versioned(&test_file).unwrap();
+        assert_eq!(contents, b"bbbb");
+        let next = compare_and_write(&test_file, &version, "cccc").unwrap();
+        assert_eq!(
+            read_versioned(&test_file).unwrap(),
+            (b"cccc".to_vec(), next)
+        );
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn modify_if_checks_again_under_the_exclusive_lock() {
+        use std::cell::Cell;
+        use std::io::Write;
+
+        let mut test_file = temp_dir();
+        test_file.push("optimistic_modify_if");
+        write_locked(&test_file, "").unwrap();
+
+        // Another process "fixes" the file between the probe and the exclusive lock.
+        let checks = Cell::new(0);
+        let check = |_: &File| {
+            checks.set(checks.get() + 1);
+            checks.get() == 1
+        };
+        let written = modify_if(&test_file, check, |_| panic!("not needed anymore")).unwrap();
+        assert!(!written);
+        assert_eq!(checks.get(), 2);
+
+        let empty = |file: &File| file.metadata().unwrap().len() == 0;
+        assert!(modify_if(&test_file, empty, |file| file.write_all(b"done")).unwrap());
+        assert!(!modify_if(&test_file, empty, |_| panic!("already done")).unwrap());
+        assert_eq!(crate::read_locked(&test_file).unwrap(), b"done");
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::wait::poll_until;
+use crate::{
+    default_backend, lock_handle_with, sys, unlock_handle_with, Backend, FileLockError,
+    FileLockMode,
+};
+
+/// Options deciding how a file is locked: with which mechanism, in which mode, and how long to
+/// wait for it.
+///
+/// By default, files are locked exclusively with the [`default_backend`] of the process, waiting
+/// as long as needed. The backend chosen here applies to the locks taken through these options
+/// only; if the process-wide backend is [`Noop`], locking is disabled altogether though.
+///
+/// A file must be unlocked with the backend it was locked with, so it should be unlocked through
+/// the same options.
+///
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use advisory_lock::{Backend, FileLockMode, LockOptions};
+///
+/// let options = LockOptions::new()
+///     .mode(FileLockMode::Exclusive)
+///     .backend("native".parse::<Backend>()?)
+///     .timeout(Duration::from_secs(5));
+/// let file = options.open("options.lock")?;
+/// // ... the lock is held ...
+/// options.unlock(&file)?;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("options.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`default_backend`]: fn.default_backend.html
+/// [`Noop`]: enum.Backend.html#variant.Noop
+#[derive(Copy, Clone, Debug)]
+pub struct LockOptions {
+    file_lock_mode: FileLockMode,
+    backend: Option<Backend>,
+    blocking: bool,
+    timeout: Option<Duration>,
+}
+
+impl Default for LockOptions {
+    fn default() -> LockOptions {
+        LockOptions::new()
+    }
+}
+
+impl LockOptions {
+    /// Create options locking exclusively with the default backend, waiting indefinitely.
+    pub fn new() -> LockOptions {
+        LockOptions {
+            file_lock_mode: FileLockMode::Exclusive,
+            backend: None,
+            blocking: true,
+            timeout: None,
+        }
+    }
+
+    /// Set the mode locks are acquired in.
+    pub fn mode(mut self, file_lock_mode: FileLockMode) -> LockOptions {
+        self.file_lock_mode = file_lock_mode;
+        self
+    }
+
+    /// Set the mechanism files are locked with, instead of the default backend of the process.
+    pub fn backend(mut self, backend: Backend) -> LockOptions {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Set whether to wait for locks held elsewhere; if not, [`FileLockError::AlreadyLocked`]
+    /// is returned right away.
+    ///
+    /// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+    pub fn blocking(mut self, blocking: bool) -> LockOptions {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Wait at most `timeout` for locks held elsewhere, then return [`FileLockError::Timeout`].
+    ///
+    /// This has no effect on non-blocking options.
+    ///
+    /// [`FileLockError::Timeout`]: enum.FileLockError.html#variant.Timeout
+    pub fn timeout(mut self, timeout: Duration) -> LockOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Return the mode locks are acquired in.
+    pub fn get_mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Return the mechanism files are locked with.
+    pub fn get_backend(&self) -> Backend {
+        self.backend.unwrap_or_else(default_backend)
+    }
+
+    /// Lock `file` according to these options.
+    pub fn lock(&self, file: &File) -> Result<(), FileLockError> {
+        self.lock_with_clock(file, &SystemClock)
+    }
+
+    /// Lock `file` according to these options, measuring the timeout and sleeping between
+    /// attempts with `clock`.
+    pub fn lock_with_clock(&self, file: &File, clock: &dyn Clock) -> Result<(), FileLockError> {
+        let (backend, handle) = (self.get_backend(), sys::handle(file));
+        // A timeout too long to be represented never elapses.
+        let deadline = self
+            .timeout
+            .and_then(|timeout| clock.now().checked_add(timeout));
+        match deadline {
+            Some(deadline) if self.blocking => poll_until(deadline, clock, || {
+                lock_handle_with(backend, handle, self.file_lock_mode, true).map(Some)
+            })?
+            .ok_or(FileLockError::Timeout),
+            _ => lock_handle_with(backend, handle, self.file_lock_mode, !self.blocking),
+        }
+    }
+
+    /// Open the file at `path` for reading and writing, creating it if needed, and lock it
+    /// according to these options.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File, FileLockError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(FileLockError::Io)?;
+        self.lock(&file)?;
+        Ok(file)
+    }
+
+    /// Release the lock of `file`, which was locked through these options.
+    pub fn unlock(&self, file: &File) -> Result<(), FileLockError> {
+        unlock_handle_with(self.get_backend(), sys::handle(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::AdvisoryFileLock;
+    use std::env::temp_dir;
+
+    #[test]
+    fn options_decide_how_to_wait() {
+        let mut test_file = temp_dir();
+        test_file.push("lock_options");
+        let holder = LockOptions::new().open(&test_file).unwrap();
+        let waiter = File::open(&test_file).unwrap();
+
+        let shared = LockOptions::new().mode(FileLockMode::Shared);
+        assert!(matches!(
+            shared.blocking(false).lock(&waiter),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        let clock = ManualClock::new();
+        let timeout = Duration::from_secs(3);
+        assert!(matches!(
+            shared.timeout(timeout).lock_with_clock(&waiter, &clock),
+            Err(FileLockError::Timeout)
+        ));
+        assert_eq!(clock.elapsed(), timeout);
+
+        LockOptions::new().unlock(&holder).unwrap();
+        shared
+            .backend(Backend::Native)
+            .timeout(timeout)
+            .lock(&waiter)
+            .unwrap();
+        assert!(AdvisoryFileLock::try_lock(&holder, FileLockMode::Exclusive).is_err());
+        shared.unlock(&waiter).unwrap();
+        shared.timeout(Duration::MAX).lock(&waiter).unwrap();
+        shared.unlock(&waiter).unwrap();
+        assert_eq!(shared.get_mode(), FileLockMode::Shared);
+
+        drop((holder, waiter));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs::File;
+#[cfg(unix)]
+use std::marker::PhantomData;
+
+use crate::{FileLockError, FileLockMode};
+
+/// Who owns a lock, which decides what a [`FileLockGuard`] over it may safely do.
+///
+/// This trait is sealed; it is implemented by [`PerHandle`] and, on Unix, [`PerProcess`].
+///
+/// [`FileLockGuard`]: struct.FileLockGuard.html
+/// [`PerHandle`]: enum.PerHandle.html
+/// [`PerProcess`]: struct.PerProcess.html
+pub trait LockOwnership: sealed::Sealed {}
+
+mod sealed {
+    use super::*;
+
+    pub trait Sealed {
+        fn lock(
+            file: &File,
+            file_lock_mode: FileLockMode,
+            immediate: bool,
+        ) -> Result<(), FileLockError>;
+        fn unlock(file: &File) -> Result<(), FileLockError>;
+    }
+}
+
+/// Locks owned by the handle they were acquired through: `flock` on Unix and `LockFileEx` on
+/// Windows, which back [`AdvisoryFileLock`].
+///
+/// These locks exclude every other handle, including ones of the same process, and are only
+/// released through their own handle (or a duplicate of it), so guards over them can be moved
+/// and shared between threads freely.
+///
+/// The locks of the [`Fcntl`] backend belong to the process instead, so while it is the
+/// default backend, acquiring a per-handle lock fails with an [`Unsupported`] I/O error. This
+/// applies to every type relying on these guarantees: [`FileLockGuard`], the guards of
+/// [`lock_owned`], [`TransferableLock`] and [`hold_for_child`].
+///
+/// ```
+/// use std::fs::File;
+/// use std::io::ErrorKind;
+/// use advisory_lock::{set_default_backend, Backend, FileLockError, FileLockGuard, FileLockMode};
+///
+/// # #[cfg(unix)]
+/// # {
+/// set_default_backend(Backend::Fcntl);
+/// let file = File::create("per-handle.lock")?;
+/// match FileLockGuard::lock(&file, FileLockMode::Exclusive) {
+///     Err(FileLockError::Io(err)) => assert_eq!(err.kind(), ErrorKind::Unsupported),
+///     _ => unreachable!(),
+/// }
+/// let guard = FileLockGuard::lock_per_process(&file, FileLockMode::Exclusive)?;
+/// # guard.unlock()?;
+/// # std::fs::remove_file("per-handle.lock")?;
+/// # }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+/// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+/// [`Unsupported`]: https://doc.rust-lang.org/stable/std/io/enum.ErrorKind.html#variant.Unsupported
+/// [`FileLockGuard`]: struct.FileLockGuard.html
+/// [`lock_owned`]: trait.AdvisoryFileLock.html#method.lock_owned
+/// [`TransferableLock`]: struct.TransferableLock.html
+/// [`hold_for_child`]: fn.hold_for_child.html
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PerHandle {}
+
+/// Fail if the locks of the default backend belong to the process rather than to the handle,
+/// which would void the guarantees of [`PerHandle`] locks.
+pub(crate) fn check_per_handle() -> Result<(), FileLockError> {
+    #[cfg(unix)]
+    if crate::default_backend() == crate::Backend::Fcntl {
+        return Err(FileLockError::Io(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the locks of the Fcntl backend belong to the process, not to the handle",
+        )));
+    }
+    Ok(())
+}
+
+impl LockOwnership for PerHandle {}
+
+impl sealed::Sealed for PerHandle {
+    fn lock(
+        file: &File,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<(), FileLockError> {
+        check_per_handle()?;
+        crate::lock_handle(crate::sys::handle(file), file_lock_mode, immediate)
+    }
+
+    fn unlock(file: &File) -> Result<(), FileLockError> {
+        crate::unlock_handle(crate::sys::handle(file))
+    }
+}
+
+/// Locks owned by the process: classic POSIX record locks, taken with `fcntl(F_SETLK)`.
+///
+/// These are what NFS and some other network file systems support, but they come with two
+/// hazards: they don't exclude the threads of the process from each other, and closing *any*
+/// handle to the file in the process releases them, even one opened long after the lock was
+/// acquired. Guards over them are therefore neither [`Send`] nor [`Sync`], keeping them on the
+/// thread which acquired them, and are only created through constructors whose names spell out
+/// the ownership, e.g. [`FileLockGuard::lock_per_process`].
+///
+/// This type is only available on Unix.
+///
+/// [`Send`]: https://doc.rust-lang.org/stable/std/marker/trait.Send.html
+/// [`Sync`]: https://doc.rust-lang.org/stable/std/marker/trait.Sync.html
+/// [`FileLockGuard::lock_per_process`]: struct.FileLockGuard.html#method.lock_per_process
+#[cfg(unix)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PerProcess(PhantomData<*const ()>);
+
+#[cfg(unix)]
+impl LockOwnership for PerProcess {}
+
+#[cfg(unix)]
+impl sealed::Sealed for PerProcess {
+    fn lock(
+        file: &File,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<(), FileLockError> {
+        let handle = crate::sys::handle(file);
+        let operation = if immediate {
+            crate::FileLockOperation::TryLock
+        } else {
+            crate::FileLockOperation::Lock
+        };
+        crate::run_operation(handle, operation, Some(file_lock_mode), || {
+            crate::sys::lock_process(handle, file_lock_mode, immediate)
+        })
+    }
+
+    fn unlock(file: &File) -> Result<(), FileLockError> {
+        let handle = crate::sys::handle(file);
+        crate::run_operation(handle, crate::FileLockOperation::Unlock, None, || {
+            crate::sys::unlock_process(handle)
+        })
+    }
+}
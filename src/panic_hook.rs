@@ -0,0 +1,207 @@
+//! A panic hook which deals with the locks held by a panicking thread.
+//!
+//! A lock held by a thread which panics is released when its guard is dropped during unwinding,
+//! or by the operating system when the process aborts; either way, other processes then see the
+//! protected file as it was left, possibly halfway through an update, and can't tell. Locks
+//! [`register`]ed here are handled by the hook as soon as the panic starts, before unwinding, and
+//! even with `panic = "abort"`: their holder is reported on standard error, and they can be
+//! released and have a poison marker written next to them.
+//!
+//! The hook is installed by [`install`], on top of the hook which was in place, which still runs
+//! afterwards.
+//!
+//! Example:
+//! ```
+//! use std::fs::File;
+//! use advisory_lock::panic_hook::{self, PanicAction};
+//! use advisory_lock::{FileLockGuard, FileLockMode};
+//!
+//! panic_hook::install();
+//! let file = File::create("hooked.db")?;
+//! let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive)?;
+//! let registration = panic_hook::register(
+//!     &file,
+//!     "hooked.db",
+//!     PanicAction::Poison("hooked.db.poisoned".into()),
+//! );
+//! // ... update the file; a panic here leaves `hooked.db.poisoned` behind ...
+//! drop(registration);
+//! drop(guard);
+//! #
+//! # drop(file);
+//! # std::fs::remove_file("hooked.db")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [`register`]: fn.register.html
+//! [`install`]: fn.install.html
+use crate::sync::thread::{self, ThreadId};
+use crate::sync::{self, AtomicU64, Mutex, MutexGuard, Once, Ordering};
+use crate::{sys, unlock_handle};
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+/// What the panic hook does with a registered lock when its thread panics.
+#[derive(Clone, Debug)]
+pub enum PanicAction {
+    /// Report the lock on standard error, leaving it held until it is released as usual.
+    Annotate,
+    /// Report the lock and release it right away.
+    Release,
+    /// Report the lock, write the panic message to a marker file at the given path, and release
+    /// the lock.
+    ///
+    /// The marker is written before the lock is released, so the next process to acquire it can
+    /// check for the marker and recover the file.
+    Poison(PathBuf),
+}
+
+/// A lock registered with the panic hook, which is unregistered when dropped.
+///
+/// Drop the registration before releasing the lock, so a later panic doesn't act on a lock
+/// which was already released.
+#[must_use = "if unused the lock is immediately unregistered"]
+#[derive(Debug)]
+pub struct Registration<'a> {
+    id: u64,
+    file: PhantomData<&'a File>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        entries().retain(|entry| entry.id != self.id);
+    }
+}
+
+struct Entry {
+    id: u64,
+    thread: ThreadId,
+    handle: sys::Handle,
+    label: String,
+    action: PanicAction,
+}
+
+// The handle is only passed to the operating system, and outlived by the file it belongs to.
+unsafe impl Send for Entry {}
+
+sync::statics! {
+    static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+}
+
+fn entries() -> MutexGuard<'static, Vec<Entry>> {
+    ENTRIES.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Install the panic hook, on top of the current one.
+///
+/// Installing it more than once has no effect.
+pub fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            on_panic(&info.to_string());
+            previous(info);
+        }));
+    });
+}
+
+/// Register the lock held through `file` by the current thread, to be handled by the panic hook
+/// with `action` if the thread panics.
+///
+/// `label` identifies the lock in reports, e.g. the path of the file. Only panics of the current
+/// thread are considered, and only once the hook is [`install`]ed.
+///
+/// [`install`]: fn.install.html
+pub fn register<'a, L: Into<String>>(
+    file: &'a File,
+    label: L,
+    action: PanicAction,
+) -> Registration<'a> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    entries().push(Entry {
+        id,
+        thread: thread::current().id(),
+        handle: sys::handle(file),
+        label: label.into(),
+        action,
+    });
+    Registration {
+        id,
+        file: PhantomData,
+    }
+}
+
+fn on_panic(message: &str) {
+    let current = thread::current();
+    let mut entries = entries();
+    for entry in entries
+        .iter_mut()
+        .filter(|entry| entry.thread == current.id())
+    {
+        eprintln!(
+            "advisory-lock: thread '{}' panicked while holding the lock of {}",
+            current.name().unwrap_or("<unnamed>"),
+            entry.label
+        );
+        match &entry.action {
+            PanicAction::Annotate => continue,
+            PanicAction::Release => {}
+            PanicAction::Poison(marker) => {
+                if let Err(err) = std::fs::write(marker, message) {
+                    eprintln!(
+                        "advisory-lock: failed to write poison marker {}: {}",
+                        marker.display(),
+                        err
+                    );
+                }
+            }
+        }
+        let _ = unlock_handle(entry.handle);
+        // The lock is gone; don't release it again if the thread panics again while unwinding.
+        entry.action = PanicAction::Annotate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockMode};
+    use std::env::temp_dir;
+
+    #[test]
+    fn panicking_threads_poison_their_locks() {
+        let mut test_file = temp_dir();
+        test_file.push("panic_hook_poison");
+        let marker = test_file.with_extension("poisoned");
+        let _ = std::fs::remove_file(&marker);
+        let file = File::create(&test_file).unwrap();
+        let other = File::open(&test_file).unwrap();
+
+        install();
+        let result = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    AdvisoryFileLock::lock(&file, FileLockMode::Exclusive).unwrap();
+                    let _registration =
+                        register(&file, "test file", PanicAction::Poison(marker.clone()));
+                    // No guard releases the lock while unwinding; only the hook can.
+                    panic!("halfway through an update");
+                })
+                .join()
+        });
+        assert!(result.is_err());
+
+        assert!(std::fs::read_to_string(&marker)
+            .unwrap()
+            .contains("halfway through an update"));
+        AdvisoryFileLock::try_lock(&other, FileLockMode::Exclusive).unwrap();
+        assert!(entries().iter().all(|entry| entry.label != "test file"));
+
+        drop((file, other));
+        std::fs::remove_file(&test_file).unwrap();
+        std::fs::remove_file(&marker).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[cfg(windows)]
+use crate::fs::open_locked_by;
+#[cfg(not(windows))]
+use crate::fs::open_locked_with;
+use crate::fs::{read_all, remove_locked, replace_contents, PathFile};
+#[cfg(windows)]
+use crate::region::lock_handle_in;
+#[cfg(windows)]
+use crate::LockRegion;
+use crate::{process_id, FileLockError, FileLockMode, Lockfile, LockfileStatus};
+
+/// A daemon's pid file, locked for as long as the daemon runs and removed when it shuts down.
+///
+/// The file is created if needed and locked exclusively, failing with
+/// [`FileLockError::AlreadyLocked`] if another instance holds it; then its contents are replaced
+/// with the id of this process and a newline, as init scripts and `kill $(cat daemon.pid)`
+/// expect, and synced to disk.
+///
+/// A daemon which shuts down cleanly removes the file, so a pid file found unlocked but still
+/// recording a process was left behind by a daemon which crashed: it is taken over, and
+/// [`stale_pid`] reports the id it recorded. The lock was released by the system when the old
+/// daemon died, so this is reliable even when process ids are reused.
+///
+/// Create the pid file once the daemon has forked into the background, so it records the
+/// process which keeps running.
+///
+/// On Windows, whose locks keep other processes from reading the locked bytes, the pid file is
+/// locked through its [last byte] whatever region [`set_lock_region`] selected, so the id stays
+/// readable while the daemon runs.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{FileLockError, PidFile};
+///
+/// let pid_file = PidFile::create("daemon.pid")?;
+/// if let Some(pid) = pid_file.stale_pid() {
+///     eprintln!("process {} didn't shut down cleanly", pid);
+/// }
+/// assert!(matches!(PidFile::create("daemon.pid"), Err(FileLockError::AlreadyLocked)));
+/// // ... serve until asked to stop ...
+/// pid_file.remove()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+/// [`stale_pid`]: #method.stale_pid
+/// [last byte]: enum.LockRegion.html#variant.LastByte
+/// [`set_lock_region`]: fn.set_lock_region.html
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: Option<PathFile>,
+    pid: u32,
+    stale_pid: Option<u32>,
+}
+
+impl PidFile {
+    /// Create and lock the pid file at `path` and record this process in it.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<PidFile, FileLockError> {
+        let path = path.as_ref();
+        #[cfg(windows)]
+        let file = open_locked_by(path, true, |file| {
+            let handle = crate::sys::handle(file);
+            lock_handle_in(LockRegion::LastByte, handle, FileLockMode::Exclusive, true)
+        })?;
+        #[cfg(not(windows))]
+        let file = open_locked_with(path, FileLockMode::Exclusive, true, true)?;
+        let stale_pid = match LockfileStatus::of(&String::from_utf8_lossy(&read_all(&file)?)) {
+            LockfileStatus::Alive(holder) | LockfileStatus::Stale(holder) => Some(holder.pid()),
+            _ => None,
+        };
+        let pid = process_id();
+        let pid_file = PidFile {
+            path: path.to_owned(),
+            file: Some(file),
+            pid,
+            stale_pid,
+        };
+        replace_contents(pid_file.file(), format!("{}\n", pid).as_bytes(), true)?;
+        Ok(pid_file)
+    }
+
+    /// Return the path of the pid file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the locked file.
+    pub fn file(&self) -> &File {
+        self.file
+            .as_ref()
+            .expect("the pid file is open until dropped")
+    }
+
+    /// Return the id of this process, as recorded in the file.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Return the id recorded by a daemon which crashed, leaving the pid file behind, if this
+    /// pid file took over from one.
+    pub fn stale_pid(&self) -> Option<u32> {
+        self.stale_pid
+    }
+
+    /// Report the daemon recorded in the pid file at `path`, and whether it is still running.
+    ///
+    /// This is [`Lockfile::inspect`], as pid files and lock files both record their holder on
+    /// their first line, and the same caveats apply.
+    ///
+    /// [`Lockfile::inspect`]: struct.Lockfile.html#method.inspect
+    pub fn inspect<P: AsRef<Path>>(path: P) -> Result<LockfileStatus, FileLockError> {
+        Lockfile::inspect(path)
+    }
+
+    /// Remove the pid file and release its lock, on clean shutdown.
+    pub fn remove(mut self) -> Result<(), FileLockError> {
+        self.remove_file()
+    }
+
+    fn remove_file(&mut self) -> Result<(), FileLockError> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        remove_locked(&self.path, file)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = self.remove_file();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn pid_files_take_over_from_crashed_daemons() {
+        let mut test_file = temp_dir();
+        test_file.push("pidfile_daemon.pid");
+        let _ = std::fs::remove_file(&test_file);
+
+        let pid_file = PidFile::create(&test_file).unwrap();
+        assert_eq!(pid_file.stale_pid(), None);
+        assert!(matches!(
+            PidFile::create(&test_file),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        // Other processes read the id through handles of their own.
+        assert_eq!(
+            std::fs::read_to_string(&test_file).unwrap(),
+            format!("{}\n", process_id())
+        );
+        pid_file.remove().unwrap();
+        assert_eq!(
+            PidFile::inspect(&test_file).unwrap(),
+            LockfileStatus::Missing
+        );
+
+        // A daemon which crashed.
+        std::fs::write(&test_file, "2147483000\n").unwrap();
+        assert!(matches!(
+            PidFile::inspect(&test_file).unwrap(),
+            LockfileStatus::Stale(_)
+        ));
+        let pid_file = PidFile::create(&test_file).unwrap();
+        assert_eq!(pid_file.stale_pid(), Some(2147483000));
+        drop(pid_file);
+        assert!(!test_file.exists());
+    }
+}
@@ -0,0 +1,402 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fs::replace_file;
+use crate::{process_id, AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// A file lock which is poisoned when its holder panics, like `std::sync::Mutex`, but across
+/// processes.
+///
+/// When a [`PoisonGuard`] holding the lock exclusively is dropped while its thread panics, a
+/// marker file named after the locked file with a `.poisoned` suffix is written before the lock
+/// is released, recording the process, the time and the thread. As long as the marker exists,
+/// acquiring the lock, in any process, fails with [`FileLockError::Poisoned`] once the lock is
+/// held, and releases it again, so the protected data isn't used until it has been recovered.
+/// Shared guards only read, and never poison the lock.
+///
+/// [`lock_or_recover`] clears the poison after running a recovery routine, and so does removing
+/// the marker by other means. A process which crashes or is killed doesn't unwind, so it
+/// doesn't poison the lock; see the [`panic_hook`] module to handle aborting panics.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{FileLockError, FileLockMode, PoisonLock};
+///
+/// let lock = PoisonLock::open("ledger.db")?;
+/// let result = std::thread::scope(|scope| {
+///     scope
+///         .spawn(|| {
+///             let _guard = lock.lock(FileLockMode::Exclusive).unwrap();
+///             panic!("halfway through an update");
+///         })
+///         .join()
+/// });
+/// assert!(result.is_err());
+/// assert!(matches!(lock.lock(FileLockMode::Shared), Err(FileLockError::Poisoned)));
+/// #
+/// # std::fs::remove_file("ledger.db.poisoned")?;
+/// # std::fs::remove_file("ledger.db")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`PoisonGuard`]: struct.PoisonGuard.html
+/// [`FileLockError::Poisoned`]: enum.FileLockError.html#variant.Poisoned
+/// [`lock_or_recover`]: #method.lock_or_recover
+/// [`panic_hook`]: panic_hook/index.html
+#[derive(Debug)]
+pub struct PoisonLock {
+    path: PathBuf,
+    marker: PathBuf,
+    file: File,
+}
+
+/// An RAII guard over a [`PoisonLock`], which poisons it if dropped during a panic while held
+/// exclusively, and releases it.
+///
+/// It dereferences to the locked file.
+///
+/// [`PoisonLock`]: struct.PoisonLock.html
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct PoisonGuard<'a> {
+    lock: &'a PoisonLock,
+    file_lock_mode: FileLockMode,
+}
+
+impl PoisonLock {
+    /// Open the file at `path` for reading and writing, creating it if needed, without locking
+    /// it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PoisonLock, FileLockError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(FileLockError::Io)?;
+        let mut marker = OsString::from(path.as_os_str());
+        marker.push(".poisoned");
+        Ok(PoisonLock {
+            path: path.to_owned(),
+            marker: marker.into(),
+            file,
+        })
+    }
+
+    /// Return the path of the locked file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the path of the poison marker.
+    pub fn marker_path(&self) -> &Path {
+        &self.marker
+    }
+
+    /// Acquire the lock, blocking until it succeeds or errors.
+    pub fn lock(&self, file_lock_mode: FileLockMode) -> Result<PoisonGuard<'_>, FileLockError> {
+        AdvisoryFileLock::lock(&self.file, file_lock_mode)?;
+        self.check(file_lock_mode)
+    }
+
+    /// Try to acquire the lock, returning immediately.
+    pub fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<PoisonGuard<'_>, FileLockError> {
+        AdvisoryFileLock::try_lock(&self.file, file_lock_mode)?;
+        self.check(file_lock_mode)
+    }
+
+    /// Acquire the lock, recovering it first with `recover` if it is poisoned.
+    ///
+    /// If the lock is poisoned, it is acquired exclusively, and `recover` is called with what
+    /// the marker records of the poisoning, to repair or validate the protected data. If it
+    /// succeeds, the marker is removed and the lock is acquired in `file_lock_mode`; otherwise
+    /// the lock is released, still poisoned, and the error is returned. A panic in `recover`
+    /// poisons the lock anew.
+    ///
+    /// Example:
+    /// ```
+    /// use advisory_lock::{FileLockMode, PoisonLock};
+    ///
+    /// let lock = PoisonLock::open("recovered.db")?;
+    /// std::fs::write(lock.marker_path(), "")?;
+    /// let guard = lock.lock_or_recover(FileLockMode::Exclusive, |info| {
+    ///     eprintln!("recovering from a panic of process {:?}", info.pid());
+    ///     // ... restore the last good version of the data ...
+    ///     Ok(())
+    /// })?;
+    /// assert!(!lock.is_poisoned()?);
+    /// #
+    /// # drop(guard);
+    /// # std::fs::remove_file("recovered.db")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn lock_or_recover<F>(
+        &self,
+        file_lock_mode: FileLockMode,
+        recover: F,
+    ) -> Result<PoisonGuard<'_>, FileLockError>
+    where
+        F: FnOnce(&PoisonInfo) -> Result<(), FileLockError>,
+    {
+        match self.lock(file_lock_mode) {
+            Err(FileLockError::Poisoned) => {}
+            result => return result,
+        }
+        AdvisoryFileLock::lock(&self.file, FileLockMode::Exclusive)?;
+        let guard = PoisonGuard {
+            lock: self,
+            file_lock_mode: FileLockMode::Exclusive,
+        };
+        // Another process may have recovered the lock in the meantime.
+        if let Some(info) = self.poison_info()? {
+            recover(&info)?;
+            std::fs::remove_file(&self.marker).map_err(FileLockError::Io)?;
+        }
+        match file_lock_mode {
+            FileLockMode::Exclusive => Ok(guard),
+            FileLockMode::Shared => {
+                guard.unlock()?;
+                self.lock(FileLockMode::Shared)
+            }
+        }
+    }
+
+    /// Return what the marker records of the poisoning, or `None` if the lock isn't poisoned.
+    pub fn poison_info(&self) -> Result<Option<PoisonInfo>, FileLockError> {
+        let contents = match std::fs::read_to_string(&self.marker) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(FileLockError::Io(err)),
+        };
+        Ok(Some(PoisonInfo::of(&contents)))
+    }
+
+    /// Return whether the lock is poisoned.
+    ///
+    /// This doesn't lock the file, so the answer may be outdated by the time it is returned.
+    pub fn is_poisoned(&self) -> Result<bool, FileLockError> {
+        match std::fs::symlink_metadata(&self.marker) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(FileLockError::Io(err)),
+        }
+    }
+
+    /// Guard the lock which was just acquired, unless it is poisoned.
+    fn check(&self, file_lock_mode: FileLockMode) -> Result<PoisonGuard<'_>, FileLockError> {
+        match self.is_poisoned() {
+            Ok(false) => Ok(PoisonGuard {
+                lock: self,
+                file_lock_mode,
+            }),
+            Ok(true) => {
+                let _ = AdvisoryFileLock::unlock(&self.file);
+                Err(FileLockError::Poisoned)
+            }
+            Err(err) => {
+                let _ = AdvisoryFileLock::unlock(&self.file);
+                Err(err)
+            }
+        }
+    }
+
+    fn poison(&self) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let contents = format!(
+            "{}\n{}\n{}\n",
+            process_id(),
+            millis,
+            thread::current().name().unwrap_or("<unnamed>")
+        );
+        replace_file(&self.marker, contents.as_bytes())
+    }
+}
+
+/// What the marker of a poisoned [`PoisonLock`] records.
+///
+/// Markers written by other means, e.g. by the [`panic_hook`] module, may not record anything.
+///
+/// [`PoisonLock`]: struct.PoisonLock.html
+/// [`panic_hook`]: panic_hook/index.html
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PoisonInfo {
+    pid: Option<u32>,
+    poisoned: Option<SystemTime>,
+    thread: Option<String>,
+}
+
+impl PoisonInfo {
+    fn of(contents: &str) -> PoisonInfo {
+        let mut lines = contents.lines();
+        let pid = lines.next().and_then(|line| line.parse().ok());
+        let poisoned = pid
+            .and(lines.next())
+            .and_then(|line| line.parse().ok())
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+        let thread = poisoned.and(lines.next()).map(str::to_owned);
+        PoisonInfo {
+            pid,
+            poisoned,
+            thread,
+        }
+    }
+
+    /// Return the id of the process which poisoned the lock.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Return the time the lock was poisoned.
+    pub fn poisoned(&self) -> Option<SystemTime> {
+        self.poisoned
+    }
+
+    /// Return the name of the thread which poisoned the lock, `<unnamed>` if it had none.
+    pub fn thread(&self) -> Option<&str> {
+        self.thread.as_deref()
+    }
+}
+
+impl PoisonGuard<'_> {
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Release the lock, returning any error which occurs.
+    pub fn unlock(self) -> Result<(), FileLockError> {
+        let lock = self.lock;
+        std::mem::forget(self);
+        AdvisoryFileLock::unlock(&lock.file)
+    }
+}
+
+impl Deref for PoisonGuard<'_> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock.file
+    }
+}
+
+impl Drop for PoisonGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() && self.file_lock_mode == FileLockMode::Exclusive {
+            if let Err(err) = self.lock.poison() {
+                eprintln!(
+                    "advisory-lock: failed to write poison marker {}: {}",
+                    self.lock.marker.display(),
+                    err
+                );
+            }
+        }
+        let _ = AdvisoryFileLock::unlock(&self.lock.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn panicking_writers_poison_the_lock() {
+        let mut test_file = temp_dir();
+        test_file.push("poison_lock");
+        let lock = PoisonLock::open(&test_file).unwrap();
+        let other = PoisonLock::open(&test_file).unwrap();
+        let _ = std::fs::remove_file(lock.marker_path());
+
+        // Readers which panic don't poison the lock.
+        let result = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _guard = lock.lock(FileLockMode::Shared).unwrap();
+                    panic!("while reading");
+                })
+                .join()
+        });
+        assert!(result.is_err());
+        let guard = other.try_lock(FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            lock.try_lock(FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        guard.unlock().unwrap();
+
+        let result = thread::scope(|scope| {
+            thread::Builder::new()
+                .name("writer".to_owned())
+                .spawn_scoped(scope, || {
+                    let _guard = lock.lock(FileLockMode::Exclusive).unwrap();
+                    panic!("while writing");
+                })
+                .unwrap()
+                .join()
+        });
+        assert!(result.is_err());
+        assert!(other.is_poisoned().unwrap());
+        assert!(matches!(
+            other.try_lock(FileLockMode::Shared),
+            Err(FileLockError::Poisoned)
+        ));
+        // The poisoned lock was released again.
+        AdvisoryFileLock::try_lock(&other.file, FileLockMode::Exclusive).unwrap();
+        AdvisoryFileLock::unlock(&other.file).unwrap();
+        let marker = std::fs::read_to_string(other.marker_path()).unwrap();
+        assert_eq!(marker.lines().nth(2), Some("writer"));
+
+        std::fs::remove_file(other.marker_path()).unwrap();
+        other
+            .try_lock(FileLockMode::Exclusive)
+            .unwrap()
+            .unlock()
+            .unwrap();
+        drop((lock, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn recovery_clears_the_poison() {
+        let mut test_file = temp_dir();
+        test_file.push("poison_recovery");
+        let lock = PoisonLock::open(&test_file).unwrap();
+        std::fs::write(lock.marker_path(), "1234\n1700000000000\nworker\n").unwrap();
+
+        let recovered = lock.lock_or_recover(FileLockMode::Shared, |info| {
+            assert_eq!(info.pid(), Some(1234));
+            assert_eq!(
+                info.poisoned(),
+                Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            );
+            assert_eq!(info.thread(), Some("worker"));
+            Err(FileLockError::Corrupted)
+        });
+        assert!(matches!(recovered, Err(FileLockError::Corrupted)));
+        drop(recovered);
+        assert!(lock.is_poisoned().unwrap());
+
+        let guard = lock
+            .lock_or_recover(FileLockMode::Shared, |_| Ok(()))
+            .unwrap();
+        assert_eq!(guard.mode(), FileLockMode::Shared);
+        assert_eq!(lock.poison_info().unwrap(), None);
+        drop(guard);
+        // Without poison, the recovery routine isn't called.
+        lock.lock_or_recover(FileLockMode::Exclusive, |_| unreachable!())
+            .unwrap()
+            .unlock()
+            .unwrap();
+
+        drop(lock);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use crate::sync::{self, Mutex};
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// A pool of open lock files, keyed by file identity.
+///
+/// Repeatedly locking a file by path normally costs an `open` and a `close` per acquisition.
+/// The pool keeps one handle per file open instead, and hands it out to every caller asking for
+/// that file, under any path which resolves to it.
+///
+/// ## Notes
+///
+/// Since the handle is shared, locks taken through the pool are held by the *process*: two
+/// threads locking the same file exclusively through the pool both succeed. Pair the pool with
+/// an in-process lock if threads need to exclude each other too.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{FileLockMode, FilePool};
+///
+/// let pool = FilePool::global();
+/// for _ in 0..100 {
+///     pool.lock_path("pool.lock", FileLockMode::Exclusive)?;
+///     // ... do the work ...
+///     pool.unlock_path("pool.lock")?;
+/// }
+/// #
+/// # pool.evict("pool.lock")?;
+/// # std::fs::remove_file("pool.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct FilePool {
+    files: Mutex<HashMap<PoolKey, Arc<File>>>,
+}
+
+/// The identity of a file as far as the pool is concerned.
+///
+/// On Unix, the device and inode number can be read from the path without opening the file.
+/// Windows only exposes its file index through an open handle, so the canonical path is used.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+enum PoolKey {
+    #[cfg(unix)]
+    Inode(u64, u64),
+    #[cfg(not(unix))]
+    Path(std::path::PathBuf),
+}
+
+impl PoolKey {
+    fn of(path: &Path) -> io::Result<PoolKey> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = std::fs::metadata(path)?;
+            Ok(PoolKey::Inode(metadata.dev(), metadata.ino()))
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(PoolKey::Path(std::fs::canonicalize(path)?))
+        }
+    }
+}
+
+impl FilePool {
+    /// Create an empty pool.
+    pub fn new() -> FilePool {
+        FilePool::default()
+    }
+
+    /// Return the pool shared by the whole process.
+    pub fn global() -> &'static FilePool {
+        static GLOBAL: OnceLock<FilePool> = OnceLock::new();
+        GLOBAL.get_or_init(FilePool::new)
+    }
+
+    /// Return the pooled handle of the file at `path`, opening (and creating) it if needed.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<Arc<File>> {
+        let path = path.as_ref();
+        if let Ok(key) = PoolKey::of(path) {
+            if let Some(file) = sync::lock(&self.files).get(&key) {
+                return Ok(Arc::clone(file));
+            }
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let key = PoolKey::of(path)?;
+        let mut files = sync::lock(&self.files);
+        // Another thread may have opened the file in the meantime; keep the first handle.
+        Ok(Arc::clone(
+            files.entry(key).or_insert_with(|| Arc::new(file)),
+        ))
+    }
+
+    /// Acquire the lock of the file at `path` through the pooled handle.
+    pub fn lock_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> Result<Arc<File>, FileLockError> {
+        let file = self.open(path).map_err(FileLockError::Io)?;
+        AdvisoryFileLock::lock(&*file, file_lock_mode)?;
+        Ok(file)
+    }
+
+    /// Try to acquire the lock of the file at `path` through the pooled handle.
+    pub fn try_lock_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> Result<Arc<File>, FileLockError> {
+        let file = self.open(path).map_err(FileLockError::Io)?;
+        AdvisoryFileLock::try_lock(&*file, file_lock_mode)?;
+        Ok(file)
+    }
+
+    /// Release the lock of the file at `path` held through the pooled handle.
+    pub fn unlock_path<P: AsRef<Path>>(&self, path: P) -> Result<(), FileLockError> {
+        let file = self.open(path).map_err(FileLockError::Io)?;
+        AdvisoryFileLock::unlock(&*file)
+    }
+
+    /// Remove the file at `path` from the pool.
+    ///
+    /// The handle is closed, releasing its lock, once every caller dropped its clone.
+    pub fn evict<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let key = PoolKey::of(path.as_ref())?;
+        sync::lock(&self.files).remove(&key);
+        Ok(())
+    }
+
+    /// Remove every file from the pool.
+    pub fn clear(&self) {
+        sync::lock(&self.files).clear();
+    }
+
+    /// Return the number of files in the pool.
+    pub fn len(&self) -> usize {
+        sync::lock(&self.files).len()
+    }
+
+    /// Return whether the pool is empty.
+    pub fn is_empty(&self) -> bool {
+        sync::lock(&self.files).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn pool_reuses_handles() {
+        let mut test_file = temp_dir();
+        test_file.push("pool_reuses");
+        let pool = FilePool::new();
+
+        let first = pool.lock_path(&test_file, FileLockMode::Exclusive).unwrap();
+        let mut other_path = test_file.parent().unwrap().join(".");
+        other_path.push("pool_reuses");
+        let second = pool.open(&other_path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(pool.len(), 1);
+
+        let outsider = File::open(&test_file).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&outsider, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        pool.unlock_path(&test_file).unwrap();
+        AdvisoryFileLock::try_lock(&outsider, FileLockMode::Shared).unwrap();
+
+        pool.evict(&test_file).unwrap();
+        assert!(pool.is_empty());
+
+        drop((first, second, outsider));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::io;
+
+use crate::{lock_range_handle, sys, unlock_range_handle, FileLockError, FileLockMode};
+
+/// An advisory lock over byte ranges of a file, for coordinating on regions of a file rather
+/// than on the file as a whole.
+///
+/// Ranges are locked with `fcntl` on Unix and with `LockFileEx` on Windows. They may extend past
+/// the end of the file, and several non-overlapping ranges can be held at once. Like
+/// [`AdvisoryFileLock`], it is implemented for every type exposing its handle through `AsFd` or
+/// `AsHandle`.
+///
+/// ## Notes
+///
+/// - Range locks don't interact with the whole-file locks of [`AdvisoryFileLock`] on most
+///   platforms.
+/// - On Linux, they are open file description locks, which exclude other handles of the same
+///   process. On other Unix systems they are classic record locks, which are owned by the
+///   process: they don't exclude other handles of the same process, and are all released when
+///   *any* descriptor of the file in this process is closed.
+/// - A range must be released with the same offset and length it was locked with to be portable;
+///   Windows doesn't split or merge locked ranges.
+/// - Locking a range in shared mode requires the file to be open for reading, and in exclusive
+///   mode for writing, on Unix.
+///
+/// Example:
+/// ```
+/// use std::fs::OpenOptions;
+/// use advisory_lock::{AdvisoryRangeLock, FileLockMode};
+///
+/// let file = OpenOptions::new().read(true).write(true).create(true).open("pages.db")?;
+/// // Lock the second page of 4 KiB.
+/// file.lock_range(FileLockMode::Exclusive, 4096, 4096)?;
+/// // ... update the page ...
+/// file.unlock_range(4096, 4096)?;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("pages.db")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+pub trait AdvisoryRangeLock {
+    /// Acquire the lock of the `len` bytes starting at `offset`.
+    ///
+    /// `lock_range` is blocking; it will block the current thread until it succeeds or errors.
+    /// `len` must not be zero.
+    fn lock_range(
+        &self,
+        file_lock_mode: FileLockMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), FileLockError>;
+    /// Try to acquire the lock of the `len` bytes starting at `offset`.
+    ///
+    /// `try_lock_range` returns immediately.
+    fn try_lock_range(
+        &self,
+        file_lock_mode: FileLockMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), FileLockError>;
+    /// Release the lock of the `len` bytes starting at `offset`.
+    fn unlock_range(&self, offset: u64, len: u64) -> Result<(), FileLockError>;
+}
+
+impl<T: sys::AsHandle + ?Sized> AdvisoryRangeLock for T {
+    fn lock_range(
+        &self,
+        file_lock_mode: FileLockMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), FileLockError> {
+        check_len(len)?;
+        lock_range_handle(sys::handle(self), file_lock_mode, false, offset, len)
+    }
+
+    fn try_lock_range(
+        &self,
+        file_lock_mode: FileLockMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), FileLockError> {
+        check_len(len)?;
+        lock_range_handle(sys::handle(self), file_lock_mode, true, offset, len)
+    }
+
+    fn unlock_range(&self, offset: u64, len: u64) -> Result<(), FileLockError> {
+        check_len(len)?;
+        unlock_range_handle(sys::handle(self), offset, len)
+    }
+}
+
+/// Reject empty ranges, which `fcntl` extends to the end of the file but `LockFileEx` doesn't.
+pub(crate) fn check_len(len: u64) -> Result<(), FileLockError> {
+    if len == 0 {
+        return Err(FileLockError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a locked range must not be empty",
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn ranges_are_independent() {
+        let mut test_file = temp_dir();
+        test_file.push("range_lock");
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&test_file)
+                .unwrap()
+        };
+        let (first, second) = (open(), open());
+
+        first.lock_range(FileLockMode::Exclusive, 0, 10).unwrap();
+        second
+            .try_lock_range(FileLockMode::Exclusive, 10, 10)
+            .unwrap();
+        if cfg!(any(target_os = "linux", windows)) {
+            assert!(matches!(
+                second.try_lock_range(FileLockMode::Shared, 5, 1),
+                Err(FileLockError::AlreadyLocked)
+            ));
+        }
+        assert!(first.lock_range(FileLockMode::Shared, 0, 0).is_err());
+
+        first.unlock_range(0, 10).unwrap();
+        second.try_lock_range(FileLockMode::Shared, 0, 10).unwrap();
+        second.unlock_range(0, 10).unwrap();
+        second.unlock_range(10, 10).unwrap();
+
+        drop((first, second));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    /// Lock `owned` and `borrowed`, which wrap the same handle, and check that `other` is
+    /// excluded.
+    #[cfg(any(unix, windows))]
+    fn check_io_safety_types<O, B>(owned: &O, borrowed: &B, other: &std::fs::File)
+    where
+        O: crate::AdvisoryFileLock + AdvisoryRangeLock,
+        B: crate::AdvisoryFileLock + AdvisoryRangeLock,
+    {
+        use crate::AdvisoryFileLock;
+
+        AdvisoryFileLock::lock(borrowed, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(other, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        AdvisoryFileLock::unlock(owned).unwrap();
+
+        borrowed.lock_range(FileLockMode::Exclusive, 0, 4).unwrap();
+        if cfg!(any(target_os = "linux", windows)) {
+            assert!(matches!(
+                other.try_lock_range(FileLockMode::Exclusive, 2, 4),
+                Err(FileLockError::AlreadyLocked)
+            ));
+        }
+        owned.unlock_range(0, 4).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn io_safety_fds_are_lockable() {
+        use std::os::unix::io::{AsFd, OwnedFd};
+
+        let mut test_file = temp_dir();
+        test_file.push("range_io_safety_fds");
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&test_file)
+                .unwrap()
+        };
+        let owned = OwnedFd::from(open());
+        let other = open();
+        check_io_safety_types(&owned, &owned.as_fd(), &other);
+
+        drop((owned, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn io_safety_handles_are_lockable() {
+        use std::os::windows::io::{AsHandle, OwnedHandle};
+
+        let mut test_file = temp_dir();
+        test_file.push("range_io_safety_handles");
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&test_file)
+                .unwrap()
+        };
+        let owned = OwnedHandle::from(open());
+        let other = open();
+        check_io_safety_types(&owned, &owned.as_handle(), &other);
+
+        drop((owned, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fs::{open_exclusive, read_all, replace_contents};
+use crate::FileLockError;
+
+/// A token bucket stored in a file, sharing a rate limit between the processes of a machine.
+///
+/// The bucket holds up to `capacity` tokens and is refilled evenly, `capacity` tokens every
+/// `period`. Each call takes tokens while holding an exclusive lock on the file, which stores
+/// the number of tokens left and the time they were counted. A file which doesn't exist yet
+/// stands for a full bucket.
+///
+/// Time is measured with the system clock, as the state outlives processes. If the clock goes
+/// back, no tokens are added until it catches up.
+///
+/// Example:
+/// ```
+/// use advisory_lock::FileRateLimiter;
+/// use std::time::Duration;
+///
+/// // At most 2 requests per minute, across processes.
+/// let limiter = FileRateLimiter::new("quota.state", 2, Duration::from_secs(60));
+/// assert!(limiter.try_acquire(1)?);
+/// assert!(limiter.try_acquire(1)?);
+/// assert!(!limiter.try_acquire(1)?);
+/// #
+/// # std::fs::remove_file("quota.state")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct FileRateLimiter {
+    path: PathBuf,
+    capacity: u32,
+    period: Duration,
+}
+
+/// The contents of the state file.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    /// When `tokens` were counted, in microseconds since the Unix epoch.
+    updated: u64,
+}
+
+impl FileRateLimiter {
+    /// Create a limiter allowing `capacity` tokens every `period`, with its state in the file at
+    /// `path`, which is created on first use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` or `period` is zero.
+    pub fn new<P: Into<PathBuf>>(path: P, capacity: u32, period: Duration) -> FileRateLimiter {
+        assert!(
+            capacity > 0,
+            "the capacity of a rate limiter must not be zero"
+        );
+        assert!(
+            !period.is_zero(),
+            "the period of a rate limiter must not be zero"
+        );
+        FileRateLimiter {
+            path: path.into(),
+            capacity,
+            period,
+        }
+    }
+
+    /// Return the path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Take `tokens` tokens if available, returning whether they were taken.
+    ///
+    /// Fails with an error of kind `InvalidInput` if `tokens` exceeds the capacity, as they
+    /// could never be taken.
+    pub fn try_acquire(&self, tokens: u32) -> Result<bool, FileLockError> {
+        self.take(tokens, SystemTime::now())
+            .map(|taken| taken.is_ok())
+    }
+
+    /// Take `tokens` tokens, sleeping until enough of them are available.
+    ///
+    /// The lock is released while sleeping, so other processes may take tokens in the meantime,
+    /// in which case this waits again.
+    pub fn acquire(&self, tokens: u32) -> Result<(), FileLockError> {
+        while let Err(wait) = self.take(tokens, SystemTime::now())? {
+            thread::sleep(wait);
+        }
+        Ok(())
+    }
+
+    /// Take `tokens` tokens at time `now`, or return how long until enough are available.
+    fn take(&self, tokens: u32, now: SystemTime) -> Result<Result<(), Duration>, FileLockError> {
+        if tokens > self.capacity {
+            return Err(FileLockError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "more tokens requested than the rate limiter holds",
+            )));
+        }
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        let file = open_exclusive(&self.path)?;
+        let mut bucket = self.refill(parse(&read_all(&file)?)?, now);
+
+        let missing = f64::from(tokens) - bucket.tokens;
+        if missing > 0.0 {
+            let wait = self.period.mul_f64(missing / f64::from(self.capacity));
+            return Ok(Err(wait.max(Duration::from_millis(1))));
+        }
+        bucket.tokens -= f64::from(tokens);
+        let contents = format!("{}\n{}\n", bucket.tokens, bucket.updated);
+        replace_contents(&file, contents.as_bytes(), false)?;
+        Ok(Ok(()))
+    }
+
+    /// Add the tokens accumulated since the bucket was last updated, a full bucket if it's new.
+    fn refill(&self, bucket: Option<Bucket>, now: u64) -> Bucket {
+        let capacity = f64::from(self.capacity);
+        match bucket {
+            Some(bucket) if bucket.updated <= now => {
+                let elapsed = (now - bucket.updated) as f64 / self.period.as_micros() as f64;
+                Bucket {
+                    tokens: (bucket.tokens + elapsed * capacity).min(capacity),
+                    updated: now,
+                }
+            }
+            Some(bucket) => bucket,
+            None => Bucket {
+                tokens: capacity,
+                updated: now,
+            },
+        }
+    }
+}
+
+fn parse(contents: &[u8]) -> Result<Option<Bucket>, FileLockError> {
+    if contents.is_empty() {
+        return Ok(None);
+    }
+    let mut lines = std::str::from_utf8(contents).unwrap_or("").lines();
+    match (
+        lines.next().and_then(|tokens| tokens.parse().ok()),
+        lines.next().and_then(|updated| updated.parse().ok()),
+    ) {
+        (Some(tokens), Some(updated)) => Ok(Some(Bucket { tokens, updated })),
+        _ => Err(FileLockError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid rate limiter state",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut test_file = temp_dir();
+        test_file.push("rate_limiter");
+        let _ = std::fs::remove_file(&test_file);
+        let limiter = FileRateLimiter::new(&test_file, 4, Duration::from_secs(4));
+        let other = FileRateLimiter::new(&test_file, 4, Duration::from_secs(4));
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert_eq!(limiter.take(3, start).unwrap(), Ok(()));
+        assert_eq!(other.take(2, start).unwrap(), Err(Duration::from_secs(1)));
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(other.take(2, later).unwrap(), Ok(()));
+        assert_eq!(
+            limiter.take(1, later).unwrap(),
+            Err(Duration::from_millis(500))
+        );
+        // Refills stop at the capacity, and a clock going back adds nothing.
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(limiter.take(4, much_later).unwrap(), Ok(()));
+        assert!(limiter.take(1, start).unwrap().is_err());
+        assert!(limiter.take(5, much_later).is_err());
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+//! Recording of lock operations for assertions in tests.
+//!
+//! While a [`LockRecorder`] is alive, every lock, try_lock and unlock performed through the crate
+//! in this process is appended to it together with a global sequence number, which turns
+//! locking discipline into something a test can check.
+//!
+//! This module is only available with the `test-util` feature.
+//!
+//! Example:
+//! ```
+//! use std::fs::File;
+//! use advisory_lock::{recorder::LockRecorder, AdvisoryFileLock, FileLockMode};
+//!
+//! let recorder = LockRecorder::install();
+//! let file = File::create("recorder.txt")?;
+//!
+//! AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+//! assert_eq!(recorder.held_mode(&file)?, Some(FileLockMode::Exclusive));
+//! recorder.note("write config");
+//! AdvisoryFileLock::unlock(&file)?;
+//!
+//! assert_eq!(recorder.held_mode(&file)?, None);
+//! assert_eq!(recorder.events_for(&file)?.len(), 2);
+//! #
+//! # std::fs::remove_file("recorder.txt")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, ThreadId};
+
+use crate::sync::{self, Arc, Mutex};
+use crate::{FileId, FileLockMode, FileLockOperation};
+
+static RECORDERS: std::sync::Mutex<Vec<Arc<Recording>>> = std::sync::Mutex::new(Vec::new());
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A recorded event.
+#[derive(Clone, Debug)]
+pub struct LockEvent {
+    /// The position of the event among every event recorded in this process.
+    pub sequence: u64,
+    /// The thread which performed the operation.
+    pub thread: ThreadId,
+    /// What happened.
+    pub kind: LockEventKind,
+}
+
+/// An enumeration of events which can be recorded.
+#[derive(Clone, Debug)]
+pub enum LockEventKind {
+    /// An operation performed on a file.
+    Operation {
+        /// The operation.
+        operation: FileLockOperation,
+        /// The requested mode, or `None` for an unlock.
+        mode: Option<FileLockMode>,
+        /// The file operated on, if its identity could be determined.
+        file_id: Option<FileId>,
+        /// Whether the operation succeeded.
+        succeeded: bool,
+    },
+    /// A marker added with [`LockRecorder::note`].
+    Note(String),
+}
+
+struct Recording {
+    events: Mutex<Vec<LockEvent>>,
+}
+
+/// A handle to the recording of lock operations, which stops recording when dropped.
+pub struct LockRecorder {
+    recording: Arc<Recording>,
+}
+
+impl LockRecorder {
+    /// Start recording every lock operation performed in this process.
+    pub fn install() -> LockRecorder {
+        let recording = Arc::new(Recording {
+            events: Mutex::new(Vec::new()),
+        });
+        recorders().push(Arc::clone(&recording));
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        LockRecorder { recording }
+    }
+
+    /// Record a marker, e.g. "write config", to assert on the order of other events against.
+    pub fn note(&self, label: impl Into<String>) {
+        sync::lock(&self.recording.events).push(LockEvent {
+            sequence: SEQUENCE.fetch_add(1, Ordering::SeqCst),
+            thread: thread::current().id(),
+            kind: LockEventKind::Note(label.into()),
+        });
+    }
+
+    /// Return every event recorded so far.
+    pub fn events(&self) -> Vec<LockEvent> {
+        sync::lock(&self.recording.events).clone()
+    }
+
+    /// Return the operations recorded so far on the given file.
+    pub fn events_for(&self, file: &File) -> io::Result<Vec<LockEvent>> {
+        let file_id = FileId::of(file)?;
+        Ok(self
+            .events()
+            .into_iter()
+            .filter(|event| {
+                matches!(event.kind, LockEventKind::Operation { file_id: Some(id), .. } if id == file_id)
+            })
+            .collect())
+    }
+
+    /// Return the mode in which the given file is currently held according to the recording.
+    pub fn held_mode(&self, file: &File) -> io::Result<Option<FileLockMode>> {
+        let mut held = None;
+        for event in self.events_for(file)? {
+            if let LockEventKind::Operation {
+                mode,
+                succeeded: true,
+                ..
+            } = event.kind
+            {
+                held = mode;
+            }
+        }
+        Ok(held)
+    }
+}
+
+impl Drop for LockRecorder {
+    fn drop(&mut self) {
+        recorders().retain(|recording| !Arc::ptr_eq(recording, &self.recording));
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn recorders() -> std::sync::MutexGuard<'static, Vec<Arc<Recording>>> {
+    RECORDERS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Record an operation if any recorder is installed.
+pub(crate) fn record(
+    operation: FileLockOperation,
+    mode: Option<FileLockMode>,
+    file_id: impl FnOnce() -> Option<FileId>,
+    succeeded: bool,
+) {
+    if ACTIVE.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+
+    let event = LockEvent {
+        sequence: SEQUENCE.fetch_add(1, Ordering::SeqCst),
+        thread: thread::current().id(),
+        kind: LockEventKind::Operation {
+            operation,
+            mode,
+            file_id: file_id(),
+            succeeded,
+        },
+    };
+    for recording in recorders().iter() {
+        sync::lock(&recording.events).push(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockError};
+    use std::env::temp_dir;
+
+    #[test]
+    fn records_operations_in_order() {
+        let recorder = LockRecorder::install();
+        let mut test_file = temp_dir();
+        test_file.push("recorder_order");
+        let f1 = File::create(&test_file).unwrap();
+        let f2 = File::open(&test_file).unwrap();
+
+        AdvisoryFileLock::lock(&f1, FileLockMode::Exclusive).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&f2, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        recorder.note("write");
+        AdvisoryFileLock::unlock(&f1).unwrap();
+
+        let events = recorder.events_for(&f1).unwrap();
+        let operations: Vec<_> = events
+            .iter()
+            .map(|event| match event.kind {
+                LockEventKind::Operation {
+                    operation,
+                    succeeded,
+                    ..
+                } => (operation, succeeded),
+                LockEventKind::Note(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                (FileLockOperation::Lock, true),
+                (FileLockOperation::TryLock, false),
+                (FileLockOperation::Unlock, true),
+            ]
+        );
+
+        let note = recorder
+            .events()
+            .into_iter()
+            .find(|event| matches!(event.kind, LockEventKind::Note(_)))
+            .unwrap();
+        assert!(events[1].sequence < note.sequence && note.sequence < events[2].sequence);
+        assert_eq!(recorder.held_mode(&f1).unwrap(), None);
+
+        drop((f1, f2));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! Detection of threads waiting for locks they hold themselves through another handle.
+use std::collections::BTreeMap;
+
+use crate::sync::thread::{self, ThreadId};
+use crate::sync::{self, AtomicU8, Mutex, MutexGuard, Ordering};
+use crate::{
+    default_backend, sys, Backend, FileId, FileLockError, FileLockMode, FileLockOperation,
+};
+
+/// What a thread asking for a lock it already holds through another handle gets.
+///
+/// Whole-file locks belong to the handle, so a thread which locks a file through one handle and
+/// then waits for a conflicting lock on the same file through another handle waits for itself,
+/// forever. This typically happens when two components of a program open the same file
+/// independently. Select the policy with [`set_self_deadlock_policy`].
+///
+/// Only the locks the calling thread acquired while a policy other than [`Ignore`] was selected
+/// are considered. Locks held by other threads of the process aren't: waiting for them is
+/// legitimate, as the other thread may release them. Backends whose locks belong to the process
+/// rather than to the handle, such as [`Fcntl`], never conflict within a process and are left
+/// alone.
+///
+/// [`set_self_deadlock_policy`]: fn.set_self_deadlock_policy.html
+/// [`Ignore`]: #variant.Ignore
+/// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[non_exhaustive]
+pub enum SelfDeadlockPolicy {
+    /// Don't track the locks of the thread; a blocking acquisition waits forever, as it always
+    /// did.
+    #[default]
+    Ignore,
+    /// Fail blocking acquisitions with [`FileLockError::WouldSelfDeadlock`].
+    ///
+    /// Non-blocking acquisitions fail with [`FileLockError::AlreadyLocked`] as usual.
+    ///
+    /// [`FileLockError::WouldSelfDeadlock`]: enum.FileLockError.html#variant.WouldSelfDeadlock
+    /// [`FileLockError::AlreadyLocked`]: enum.FileLockError.html#variant.AlreadyLocked
+    Fail,
+    /// Succeed without locking anything, as the thread already holds the lock, like a reentrant
+    /// mutex would.
+    ///
+    /// The handles of the thread are counted: the lock is released once the last of them is
+    /// unlocked, whichever order they are unlocked in. Closing the handle which acquired the
+    /// lock still releases it right away, as closing a handle always does. An exclusive lock
+    /// can't be granted this way while the thread only holds a shared one; such requests fail
+    /// with [`FileLockError::WouldSelfDeadlock`].
+    ///
+    /// [`FileLockError::WouldSelfDeadlock`]: enum.FileLockError.html#variant.WouldSelfDeadlock
+    Reentrant,
+}
+
+sync::statics! {
+    static POLICY: AtomicU8 = AtomicU8::new(0);
+    static HOLDERS: Mutex<BTreeMap<FileId, Vec<Holder>>> = Mutex::new(BTreeMap::new());
+}
+
+/// A handle through which a thread holds the lock of a file.
+#[derive(Copy, Clone, Debug)]
+struct Holder {
+    thread: ThreadId,
+    handle: sys::Handle,
+    mode: FileLockMode,
+    /// Whether the lock was granted by the `Reentrant` policy rather than by the system.
+    reentrant: bool,
+    /// Whether the handle was unlocked while the thread still held the lock through reentrant
+    /// handles, which keep the lock of this one until the last of them is unlocked.
+    deferred: bool,
+}
+
+// The handle is only compared and passed to the operating system, never dereferenced.
+unsafe impl Send for Holder {}
+
+/// Select what happens when a thread asks for a lock it already holds through another handle.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{
+///     set_self_deadlock_policy, AdvisoryFileLock, FileLockError, FileLockMode,
+///     SelfDeadlockPolicy,
+/// };
+///
+/// set_self_deadlock_policy(SelfDeadlockPolicy::Fail);
+/// let config = File::create("self-deadlock.lock")?;
+/// AdvisoryFileLock::lock(&config, FileLockMode::Exclusive)?;
+///
+/// // Another component opens the same file, and would wait forever.
+/// let again = File::open("self-deadlock.lock")?;
+/// assert!(matches!(
+///     AdvisoryFileLock::lock(&again, FileLockMode::Shared),
+///     Err(FileLockError::WouldSelfDeadlock)
+/// ));
+/// #
+/// # AdvisoryFileLock::unlock(&config)?;
+/// # drop((config, again));
+/// # std::fs::remove_file("self-deadlock.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn set_self_deadlock_policy(policy: SelfDeadlockPolicy) {
+    let value = match policy {
+        SelfDeadlockPolicy::Ignore => 0,
+        SelfDeadlockPolicy::Fail => 1,
+        SelfDeadlockPolicy::Reentrant => 2,
+    };
+    POLICY.store(value, Ordering::Relaxed);
+    if policy == SelfDeadlockPolicy::Ignore {
+        holders().clear();
+    }
+}
+
+/// Return what happens when a thread asks for a lock it already holds through another handle.
+pub fn self_deadlock_policy() -> SelfDeadlockPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => SelfDeadlockPolicy::Fail,
+        2 => SelfDeadlockPolicy::Reentrant,
+        _ => SelfDeadlockPolicy::Ignore,
+    }
+}
+
+fn holders() -> MutexGuard<'static, BTreeMap<FileId, Vec<Holder>>> {
+    HOLDERS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Performs the whole-file lock `operation` on `handle` through `run`, applying the policy.
+pub(crate) fn checked(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let policy = self_deadlock_policy();
+    let per_handle = match default_backend() {
+        Backend::Noop => false,
+        #[cfg(unix)]
+        Backend::Fcntl => false,
+        _ => true,
+    };
+    if policy == SelfDeadlockPolicy::Ignore || !per_handle {
+        return run();
+    }
+    check(policy, handle, operation, file_lock_mode, run)
+}
+
+fn check(
+    policy: SelfDeadlockPolicy,
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let file_id = sys::file_id(handle).map_err(FileLockError::Io)?;
+    let mut this = Holder {
+        thread: thread::current().id(),
+        handle,
+        mode: match file_lock_mode {
+            Some(mode) => mode,
+            None => return release(file_id, handle, run),
+        },
+        reentrant: false,
+        deferred: false,
+    };
+
+    let conflict = holders().get_mut(&file_id).and_then(|holders| {
+        // Forget the handles which were closed while locked.
+        holders.retain(|holder| sys::file_id(holder.handle).ok() == Some(file_id));
+        holders
+            .iter()
+            .find(|holder| {
+                holder.thread == this.thread
+                    && holder.handle != handle
+                    && (this.mode == FileLockMode::Exclusive
+                        || holder.mode == FileLockMode::Exclusive)
+            })
+            .copied()
+    });
+    match conflict {
+        Some(holder)
+            if policy == SelfDeadlockPolicy::Reentrant
+                && (this.mode == FileLockMode::Shared
+                    || holder.mode == FileLockMode::Exclusive) =>
+        {
+            this.reentrant = true;
+        }
+        Some(_) if operation == FileLockOperation::Lock => {
+            return Err(FileLockError::WouldSelfDeadlock)
+        }
+        _ => run()?,
+    }
+
+    let mut holders = holders();
+    let holders = holders.entry(file_id).or_default();
+    holders.retain(|holder| holder.thread != this.thread || holder.handle != handle);
+    holders.push(this);
+    Ok(())
+}
+
+/// What releasing a handle involves.
+enum Release {
+    /// Unlocking the handle.
+    Handle,
+    /// Nothing, as the lock is granted or relied on by other handles.
+    Nothing,
+    /// Unlocking the handle which acquired the lock the last reentrant handle relied on.
+    Deferred(sys::Handle),
+}
+
+fn release(
+    file_id: FileId,
+    handle: sys::Handle,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    match forget(file_id, handle) {
+        Release::Handle => run(),
+        Release::Nothing => Ok(()),
+        // A handle closed in the meantime released its lock already.
+        Release::Deferred(deferred) if sys::file_id(deferred).ok() == Some(file_id) => {
+            crate::unlock_handle(deferred)
+        }
+        Release::Deferred(_) => Ok(()),
+    }
+}
+
+/// Forget that the lock of `file_id` is held through `handle`, and return what releasing it
+/// involves.
+fn forget(file_id: FileId, handle: sys::Handle) -> Release {
+    let thread = thread::current().id();
+    let mut state = holders();
+    let holders = match state.get_mut(&file_id) {
+        Some(holders) => holders,
+        None => return Release::Handle,
+    };
+    // The handle may have been locked by another thread, which shares it with this one.
+    let index = holders
+        .iter()
+        .position(|holder| holder.thread == thread && holder.handle == handle)
+        .or_else(|| holders.iter().position(|holder| holder.handle == handle));
+    let holder = match index {
+        Some(index) => holders.remove(index),
+        None => return Release::Handle,
+    };
+
+    let reentrant_left = holders
+        .iter()
+        .any(|other| other.thread == holder.thread && other.reentrant);
+    let release = match (holder.reentrant, reentrant_left) {
+        (false, true) => {
+            // Reentrant handles of the thread still rely on the lock of this one.
+            holders.push(Holder {
+                deferred: true,
+                ..holder
+            });
+            Release::Nothing
+        }
+        (false, false) => Release::Handle,
+        (true, true) => Release::Nothing,
+        // The last reentrant handle is gone, so the lock they relied on can be released.
+        (true, false) => holders
+            .iter()
+            .position(|other| other.thread == holder.thread && other.deferred)
+            .map_or(Release::Nothing, |index| {
+                Release::Deferred(holders.remove(index).handle)
+            }),
+    };
+    if holders.is_empty() {
+        state.remove(&file_id);
+    }
+    release
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn threads_are_told_when_waiting_for_themselves() {
+        let mut test_file = temp_dir();
+        test_file.push("reentrancy_self_deadlock");
+        let first = File::create(&test_file).unwrap();
+        let second = File::open(&test_file).unwrap();
+
+        // Exercise the layer without enabling it for the tests running concurrently.
+        let op = |policy, file: &File, operation, mode: Option<FileLockMode>| {
+            let handle = sys::handle(file);
+            check(policy, handle, operation, mode, || match mode {
+                Some(mode) => sys::lock_file(handle, mode, operation == FileLockOperation::TryLock),
+                None => sys::unlock_file(handle),
+            })
+        };
+        let (lock, try_lock, unlock) = (
+            FileLockOperation::Lock,
+            FileLockOperation::TryLock,
+            FileLockOperation::Unlock,
+        );
+        let (shared, exclusive) = (Some(FileLockMode::Shared), Some(FileLockMode::Exclusive));
+        let held_elsewhere = || {
+            let probe = File::open(&test_file).unwrap();
+            std::thread::spawn(move || {
+                sys::lock_file(sys::handle(&probe), FileLockMode::Shared, true).is_err()
+            })
+            .join()
+            .unwrap()
+        };
+
+        let fail = SelfDeadlockPolicy::Fail;
+        op(fail, &first, lock, exclusive).unwrap();
+        assert!(matches!(
+            op(fail, &second, lock, shared),
+            Err(FileLockError::WouldSelfDeadlock)
+        ));
+        assert!(matches!(
+            op(fail, &second, try_lock, shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+
+        let reentrant = SelfDeadlockPolicy::Reentrant;
+        op(reentrant, &second, lock, exclusive).unwrap();
+        op(reentrant, &second, unlock, None).unwrap();
+        assert!(held_elsewhere());
+        op(reentrant, &first, unlock, None).unwrap();
+        assert!(!held_elsewhere());
+
+        // The lock is held until the last handle is unlocked, whichever it is.
+        op(reentrant, &first, lock, exclusive).unwrap();
+        op(reentrant, &second, lock, exclusive).unwrap();
+        op(reentrant, &first, unlock, None).unwrap();
+        assert!(held_elsewhere());
+        op(reentrant, &second, unlock, None).unwrap();
+        assert!(!held_elsewhere());
+
+        // A shared lock can't be made exclusive through another handle.
+        op(reentrant, &first, lock, shared).unwrap();
+        op(reentrant, &second, lock, shared).unwrap();
+        assert!(matches!(
+            op(reentrant, &second, lock, exclusive),
+            Err(FileLockError::WouldSelfDeadlock)
+        ));
+        op(reentrant, &second, unlock, None).unwrap();
+        op(reentrant, &first, unlock, None).unwrap();
+        assert!(holders().get(&FileId::of(&first).unwrap()).is_none());
+
+        drop((first, second));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn holders_of_other_threads_are_kept() {
+        let mut test_file = temp_dir();
+        test_file.push("reentrancy_shared_handle");
+        let file = File::create(&test_file).unwrap();
+        let file_id = FileId::of(&file).unwrap();
+        // Handles are stored as integers to be moved to another thread.
+        let handle = sys::handle(&file) as usize;
+        let (fail, lock) = (SelfDeadlockPolicy::Fail, FileLockOperation::Lock);
+        let shared = Some(FileLockMode::Shared);
+
+        std::thread::spawn(move || {
+            check(fail, handle as sys::Handle, lock, shared, || Ok(())).unwrap()
+        })
+        .join()
+        .unwrap();
+        check(fail, handle as sys::Handle, lock, shared, || Ok(())).unwrap();
+        assert_eq!(holders()[&file_id].len(), 2);
+
+        holders().remove(&file_id);
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn loom_threads_only_see_their_own_holders() {
+        let mut test_file = temp_dir();
+        test_file.push("reentrancy_loom");
+        let files = [
+            File::create(&test_file).unwrap(),
+            File::open(&test_file).unwrap(),
+        ];
+        // Handles are stored as integers to be captured by the model.
+        let handles = [
+            sys::handle(&files[0]) as usize,
+            sys::handle(&files[1]) as usize,
+        ];
+        let file_id = FileId::of(&files[0]).unwrap();
+
+        loom::model(move || {
+            let workers: Vec<_> = (0..2)
+                .map(|index| {
+                    thread::spawn(move || {
+                        let own = handles[index] as sys::Handle;
+                        let other = handles[1 - index] as sys::Handle;
+                        let fail = SelfDeadlockPolicy::Fail;
+                        let (lock, unlock) = (FileLockOperation::Lock, FileLockOperation::Unlock);
+                        let shared = Some(FileLockMode::Shared);
+                        check(fail, own, lock, Some(FileLockMode::Exclusive), || Ok(())).unwrap();
+                        // The other thread's lock never counts, only this thread's.
+                        assert!(matches!(
+                            check(fail, other, lock, shared, || Ok(())),
+                            Err(FileLockError::WouldSelfDeadlock)
+                        ));
+                        check(fail, own, unlock, None, || Ok(())).unwrap();
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            assert!(holders().get(&file_id).is_none());
+        });
+
+        drop(files);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
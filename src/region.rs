@@ -0,0 +1,89 @@
+//! The region of the file which whole-file locks cover on Windows.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+static REGION: AtomicU8 = AtomicU8::new(LockRegion::Whole as u8);
+
+/// The bytes `LockFileEx` locks to lock a whole file on Windows.
+///
+/// Windows has no whole-file locks, only byte-range locks, which are *mandatory*: reads and
+/// writes through other handles fail within a region locked by someone else. Unix systems lock
+/// files as a whole and ignore this setting.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[non_exhaustive]
+pub enum LockRegion {
+    /// Lock every byte of the file, however large it grows. This interoperates with other
+    /// programs locking the whole file, but makes the contents unreadable to other handles
+    /// while the file is locked exclusively.
+    Whole = 0,
+    /// Lock the last byte of the largest possible file, which no file ever reaches. Other
+    /// handles can read and write the contents while the file is locked, but programs locking
+    /// the file as a whole don't conflict with the lock.
+    LastByte = 1,
+}
+
+/// Choose the region whole-file locks cover on Windows, for every lock of this process.
+///
+/// The default is [`Whole`]. Every process locking a file must use the same region. Changing
+/// the region only affects the locks acquired afterwards: the locks held meanwhile are released
+/// with the region they were acquired with.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{lock_region, set_lock_region, LockRegion};
+///
+/// // Let other handles read the file while it is locked.
+/// set_lock_region(LockRegion::LastByte);
+/// assert_eq!(lock_region(), LockRegion::LastByte);
+/// # set_lock_region(LockRegion::Whole);
+/// ```
+///
+/// [`Whole`]: enum.LockRegion.html#variant.Whole
+pub fn set_lock_region(region: LockRegion) {
+    REGION.store(region as u8, Ordering::Relaxed);
+}
+
+/// Return the region whole-file locks cover on Windows.
+pub fn lock_region() -> LockRegion {
+    match REGION.load(Ordering::Relaxed) {
+        1 => LockRegion::LastByte,
+        _ => LockRegion::Whole,
+    }
+}
+
+/// Acquire the whole-file lock of `handle` through `region`, whatever the configured region.
+///
+/// Backends other than the native one have no regions, and lock as usual.
+#[cfg(windows)]
+pub(crate) fn lock_handle_in(
+    region: LockRegion,
+    handle: crate::sys::Handle,
+    file_lock_mode: crate::FileLockMode,
+    immediate: bool,
+) -> Result<(), crate::FileLockError> {
+    use crate::{backend, default_backend, sys, Backend, FileLockOperation};
+
+    let operation = if immediate {
+        FileLockOperation::TryLock
+    } else {
+        FileLockOperation::Lock
+    };
+    let backend = default_backend();
+    crate::whole_file_operation(handle, operation, Some(file_lock_mode), || match backend {
+        Backend::Native => {
+            let prepared = sys::PreparedLock::for_region(file_lock_mode, region);
+            sys::lock_prepared(handle, &prepared, immediate)
+        }
+        backend => backend::lock_file(backend, handle, file_lock_mode, immediate),
+    })
+}
+
+impl LockRegion {
+    /// Return the offset and length of the region.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn bounds(self) -> (u64, u64) {
+        match self {
+            LockRegion::Whole => (0, u64::MAX),
+            LockRegion::LastByte => (u64::MAX, 1),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! A registry of the locks held by this process, for diagnostics.
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::sync::{self, thread, Arc, Mutex, MutexGuard};
+use crate::{sys, FileId, FileLockMode, FileLockOperation};
+
+sync::statics! {
+    /// The locks held by this process, keyed by the handle they were acquired through.
+    ///
+    /// Handles are stored as integers: they are only compared, never dereferenced.
+    static HELD: Mutex<BTreeMap<usize, HeldLock>> = Mutex::new(BTreeMap::new());
+}
+
+fn held() -> MutexGuard<'static, BTreeMap<usize, HeldLock>> {
+    HELD.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// A whole-file lock held by this process, as recorded when it was acquired.
+///
+/// It is returned by [`held_locks`].
+///
+/// [`held_locks`]: fn.held_locks.html
+#[derive(Clone, Debug)]
+pub struct HeldLock {
+    path: Option<PathBuf>,
+    file_id: Option<FileId>,
+    mode: FileLockMode,
+    acquired: SystemTime,
+    thread: String,
+    backtrace: Arc<Backtrace>,
+}
+
+impl HeldLock {
+    /// Return the path of the locked file, on systems which can tell it from the handle (Linux,
+    /// Android, Apple systems and Windows).
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Return the identity of the locked file.
+    pub fn file_id(&self) -> Option<FileId> {
+        self.file_id
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.mode
+    }
+
+    /// Return when the lock was acquired, or converted to its current mode.
+    pub fn acquired(&self) -> SystemTime {
+        self.acquired
+    }
+
+    /// Return the name of the thread which acquired the lock, or its id if it has no name.
+    pub fn thread(&self) -> &str {
+        &self.thread
+    }
+
+    /// Return the stack of the thread when it acquired the lock.
+    ///
+    /// Backtraces are captured as [`Backtrace::capture`] does, so they are only filled in when
+    /// the `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` environment variables enable them.
+    ///
+    /// [`Backtrace::capture`]: https://doc.rust-lang.org/stable/std/backtrace/struct.Backtrace.html#method.capture
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+impl fmt::Display for HeldLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            FileLockMode::Shared => "shared",
+            FileLockMode::Exclusive => "exclusive",
+        };
+        match (&self.path, self.file_id) {
+            (Some(path), _) => write!(f, "`{}`", path.display())?,
+            (None, Some(file_id)) => write!(f, "{:?}", file_id)?,
+            (None, None) => f.write_str("unknown file")?,
+        }
+        let held_for = self.acquired.elapsed().unwrap_or_default();
+        write!(
+            f,
+            ": {} lock held by thread `{}` for {:.3?}",
+            mode, self.thread, held_for
+        )
+    }
+}
+
+/// Return the whole-file locks currently held by this process, oldest first.
+///
+/// Every lock acquired through the crate with the `diagnostics` feature enabled is recorded
+/// until it is unlocked, so that a service stuck waiting for a lock can report which of its own
+/// threads holds it, and since when. Locks released by closing their handle are recorded until
+/// the handle is reused for another file; range locks and [`NamedLock`]s aren't recorded.
+///
+/// This is only available with the `diagnostics` feature.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{held_locks, AdvisoryFileLock, FileLockMode, FileId};
+///
+/// let file = File::create("registry.lock")?;
+/// AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+///
+/// let file_id = FileId::of(&file)?;
+/// let held = held_locks();
+/// let lock = held.iter().find(|lock| lock.file_id() == Some(file_id)).unwrap();
+/// assert_eq!(lock.mode(), FileLockMode::Exclusive);
+/// eprintln!("{}\n{}", lock, lock.backtrace());
+/// #
+/// # AdvisoryFileLock::unlock(&file)?;
+/// # drop(file);
+/// # std::fs::remove_file("registry.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`NamedLock`]: struct.NamedLock.html
+pub fn held_locks() -> Vec<HeldLock> {
+    let mut held = held();
+    // Forget the handles which were closed, and possibly reused for other files, since.
+    held.retain(|&handle, lock| sys::file_id(handle as sys::Handle).ok() == lock.file_id);
+    let mut locks: Vec<_> = held.values().cloned().collect();
+    locks.sort_by_key(|lock| lock.acquired);
+    locks
+}
+
+/// Describe the whole-file locks currently held by this process, one per line followed by the
+/// backtrace of its acquisition, if captured.
+///
+/// See [`held_locks`]. This is only available with the `diagnostics` feature.
+///
+/// [`held_locks`]: fn.held_locks.html
+pub fn dump_held_locks() -> String {
+    let mut dump = String::new();
+    for lock in held_locks() {
+        dump.push_str(&format!("{}\n", lock));
+        if let std::backtrace::BacktraceStatus::Captured = lock.backtrace.status() {
+            dump.push_str(&format!("{}\n", lock.backtrace));
+        }
+    }
+    dump
+}
+
+/// Records the successful whole-file lock `operation` on `handle`.
+pub(crate) fn record(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+) {
+    let key = handle as usize;
+    let file_lock_mode = match (operation, file_lock_mode) {
+        (FileLockOperation::Lock | FileLockOperation::TryLock, Some(mode)) => mode,
+        _ => {
+            held().remove(&key);
+            return;
+        }
+    };
+
+    let current = thread::current();
+    let lock = HeldLock {
+        path: sys::path(handle).ok(),
+        file_id: sys::file_id(handle).ok(),
+        mode: file_lock_mode,
+        acquired: SystemTime::now(),
+        thread: current
+            .name()
+            .map_or_else(|| format!("{:?}", current.id()), str::to_owned),
+        backtrace: Arc::new(Backtrace::capture()),
+    };
+    held().insert(key, lock);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AdvisoryFileLock;
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn held_locks_are_listed_until_unlocked() {
+        let mut test_file = temp_dir();
+        test_file.push("registry_held");
+        let file = File::create(&test_file).unwrap();
+        let file_id = FileId::of(&file).unwrap();
+        let find = || {
+            held_locks()
+                .into_iter()
+                .find(|lock| lock.file_id() == Some(file_id))
+        };
+
+        AdvisoryFileLock::lock(&file, FileLockMode::Shared).unwrap();
+        AdvisoryFileLock::lock(&file, FileLockMode::Exclusive).unwrap();
+        let lock = find().unwrap();
+        assert_eq!(lock.mode(), FileLockMode::Exclusive);
+        assert_eq!(lock.thread(), thread::current().name().unwrap());
+        if cfg!(any(target_os = "linux", target_os = "macos", windows)) {
+            let path = lock.path().unwrap().to_owned();
+            assert_eq!(path.file_name(), test_file.file_name());
+            assert!(dump_held_locks().contains(&*path.to_string_lossy()));
+        }
+
+        AdvisoryFileLock::unlock(&file).unwrap();
+        assert!(find().is_none());
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::Clock;
+use crate::rng::Rng;
+use crate::{process_id, AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// A policy deciding how long to wait between attempts to acquire a contended lock.
+///
+/// Closures taking the number of failed attempts so far and returning the delay, or `None` to
+/// give up, are policies too.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::time::Duration;
+/// use advisory_lock::{AdvisoryFileLock, ExponentialBackoff, FileLockMode};
+///
+/// let file = File::create("retry.txt")?;
+/// let backoff = ExponentialBackoff::new(Duration::from_millis(1), Duration::from_millis(100))
+///     .max_attempts(20);
+/// file.lock_with_retry(FileLockMode::Exclusive, backoff)?;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("retry.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub trait RetryPolicy {
+    /// Return how long to wait before the next attempt, given the number of attempts which
+    /// failed so far, or `None` to give up.
+    fn next_delay(&mut self, failed_attempts: u32) -> Option<Duration>;
+}
+
+impl<F> RetryPolicy for F
+where
+    F: FnMut(u32) -> Option<Duration>,
+{
+    fn next_delay(&mut self, failed_attempts: u32) -> Option<Duration> {
+        self(failed_attempts)
+    }
+}
+
+/// Retry at a fixed interval.
+#[derive(Copy, Clone, Debug)]
+pub struct FixedInterval {
+    interval: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl FixedInterval {
+    /// Retry every `interval`, indefinitely.
+    pub fn new(interval: Duration) -> FixedInterval {
+        FixedInterval {
+            interval,
+            max_attempts: None,
+        }
+    }
+
+    /// Give up after `max_attempts` attempts in total.
+    pub fn max_attempts(mut self, max_attempts: u32) -> FixedInterval {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+}
+
+impl RetryPolicy for FixedInterval {
+    fn next_delay(&mut self, failed_attempts: u32) -> Option<Duration> {
+        if exhausted(failed_attempts, self.max_attempts) {
+            return None;
+        }
+        Some(self.interval)
+    }
+}
+
+/// Retry after delays doubling from an initial one up to a maximum, with random jitter.
+///
+/// By default, each delay is drawn uniformly between zero and the current bound ("full
+/// jitter"), so processes contending for the same lock spread their attempts out instead of
+/// retrying in lockstep.
+#[derive(Clone, Debug)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+    jitter: bool,
+    max_attempts: Option<u32>,
+    rng: Rng,
+}
+
+impl ExponentialBackoff {
+    /// Retry after delays doubling from `initial` up to `max`, indefinitely.
+    pub fn new(initial: Duration, max: Duration) -> ExponentialBackoff {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+            ^ u64::from(process_id()) << 32;
+        ExponentialBackoff {
+            initial,
+            max,
+            jitter: true,
+            max_attempts: None,
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Set whether delays are randomized; they are by default.
+    pub fn jitter(mut self, jitter: bool) -> ExponentialBackoff {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Give up after `max_attempts` attempts in total.
+    pub fn max_attempts(mut self, max_attempts: u32) -> ExponentialBackoff {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    fn bound(&self, failed_attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failed_attempts.saturating_sub(1))
+            .unwrap_or(0);
+        match self.initial.checked_mul(factor) {
+            Some(delay) if factor > 0 => delay.min(self.max),
+            _ => self.max,
+        }
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&mut self, failed_attempts: u32) -> Option<Duration> {
+        if exhausted(failed_attempts, self.max_attempts) {
+            return None;
+        }
+        let bound = self.bound(failed_attempts);
+        Some(if self.jitter {
+            self.rng.delay(bound)
+        } else {
+            bound
+        })
+    }
+}
+
+fn exhausted(failed_attempts: u32, max_attempts: Option<u32>) -> bool {
+    max_attempts.is_some_and(|max_attempts| failed_attempts >= max_attempts)
+}
+
+/// Retry `try_lock` as long as the lock is held elsewhere and `policy` allows.
+pub(crate) fn lock_with_retry<L>(
+    lock: &L,
+    file_lock_mode: FileLockMode,
+    policy: &mut dyn RetryPolicy,
+    clock: &dyn Clock,
+) -> Result<(), FileLockError>
+where
+    L: AdvisoryFileLock + ?Sized,
+{
+    let mut failed_attempts = 0;
+    loop {
+        match lock.try_lock(file_lock_mode) {
+            Err(FileLockError::AlreadyLocked) => failed_attempts += 1,
+            result => return result,
+        }
+
+        match policy.next_delay(failed_attempts) {
+            Some(delay) => clock.sleep(delay),
+            None => return Err(FileLockError::AlreadyLocked),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn retries_as_the_policy_says() {
+        let mut test_file = temp_dir();
+        test_file.push("lock_with_retry");
+        let holder = File::create(&test_file).unwrap();
+        let waiter = File::open(&test_file).unwrap();
+        AdvisoryFileLock::lock(&holder, FileLockMode::Exclusive).unwrap();
+
+        let clock = ManualClock::new();
+        let backoff = ExponentialBackoff::new(Duration::from_millis(1), Duration::from_millis(5))
+            .jitter(false)
+            .max_attempts(5);
+        assert!(matches!(
+            waiter.lock_with_retry_with_clock(FileLockMode::Shared, backoff, &clock),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        // 1 + 2 + 4 + 5 milliseconds between the five attempts.
+        assert_eq!(clock.elapsed(), Duration::from_millis(12));
+
+        let mut jittered =
+            ExponentialBackoff::new(Duration::from_millis(1), Duration::from_millis(5));
+        for failed_attempts in 1..100 {
+            assert!(jittered.next_delay(failed_attempts).unwrap() <= Duration::from_millis(5));
+        }
+
+        // Release the lock from a custom policy.
+        let mut release = |failed_attempts| {
+            AdvisoryFileLock::unlock(&holder).unwrap();
+            Some(Duration::from_millis(u64::from(failed_attempts)))
+        };
+        lock_with_retry(&waiter, FileLockMode::Shared, &mut release, &clock).unwrap();
+        let mut fixed = FixedInterval::new(Duration::from_secs(1)).max_attempts(2);
+        assert_eq!(fixed.next_delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(fixed.next_delay(2), None);
+
+        drop((holder, waiter));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+//! A small pseudo-random generator for delays.
+use std::time::Duration;
+
+/// A xorshift64* generator; good enough for jitter and free of dependencies.
+#[derive(Clone, Debug)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        // Zero is a fixed point of xorshift; every other seed is used as is, so distinct seeds
+        // draw distinct sequences.
+        Rng(if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        })
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Return a duration drawn uniformly up to `max_delay`.
+    pub(crate) fn delay(&mut self, max_delay: Duration) -> Duration {
+        let max_nanos = max_delay.as_nanos().min(u128::from(u64::MAX)) as u64;
+        if max_nanos == 0 {
+            return Duration::from_nanos(0);
+        }
+        Duration::from_nanos(self.next() % max_nanos.saturating_add(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_are_reproducible_and_bounded() {
+        let max_delay = Duration::from_micros(500);
+        let mut first = Rng::new(42);
+        let mut second = Rng::new(42);
+        for _ in 0..100 {
+            let delay = first.delay(max_delay);
+            assert_eq!(delay, second.delay(max_delay));
+            assert!(delay <= max_delay);
+        }
+        assert_eq!(
+            Rng::new(0).delay(Duration::from_secs(0)),
+            Duration::from_secs(0)
+        );
+        Rng::new(7).delay(Duration::MAX);
+    }
+
+    #[test]
+    fn neighbouring_seeds_draw_different_delays() {
+        for seed in [0, 2, 42] {
+            assert_ne!(Rng::new(seed).next(), Rng::new(seed + 1).next());
+        }
+    }
+}
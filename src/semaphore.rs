@@ -0,0 +1,91 @@
+//! POSIX named semaphores, an alternative backend of `NamedLock` on Unix.
+use std::ffi::CString;
+use std::io;
+
+use crate::{retry_on_interrupt, sys, FileLockError};
+
+/// A handle to a named semaphore with a single token, which is closed when dropped.
+///
+/// Unlike locks, semaphores don't belong to anyone: whoever took the token gives it back, and a
+/// process which exits holding it doesn't.
+#[derive(Debug)]
+pub(crate) struct NamedSemaphore {
+    /// Stored as an integer, as it is only passed to the operating system.
+    sem: usize,
+}
+
+impl NamedSemaphore {
+    /// Open the semaphore called `name`, creating it with its token available if needed.
+    pub(crate) fn open(name: &str) -> io::Result<NamedSemaphore> {
+        let name = c_name(name)?;
+        let sem = unsafe {
+            libc::sem_open(
+                name.as_ptr(),
+                libc::O_CREAT,
+                0o600 as libc::c_uint,
+                1 as libc::c_uint,
+            )
+        };
+        if sem == libc::SEM_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NamedSemaphore { sem: sem as usize })
+    }
+
+    /// Take the token, waiting for it unless `immediate`.
+    pub(crate) fn acquire(&self, immediate: bool) -> Result<(), FileLockError> {
+        let sem = self.sem as *mut libc::sem_t;
+        loop {
+            let result = unsafe {
+                if immediate {
+                    libc::sem_trywait(sem)
+                } else {
+                    libc::sem_wait(sem)
+                }
+            };
+            if result == 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) => return Err(FileLockError::AlreadyLocked),
+                Some(libc::EINTR) if retry_on_interrupt() => continue,
+                Some(code) => return Err(sys::os_error(code)),
+                None => return Err(FileLockError::Io(err)),
+            }
+        }
+    }
+
+    /// Give the token back.
+    pub(crate) fn release(&self) -> Result<(), FileLockError> {
+        if unsafe { libc::sem_post(self.sem as *mut libc::sem_t) } != 0 {
+            return Err(FileLockError::Io(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Remove the semaphore called `name`, returning whether it existed.
+    ///
+    /// Processes which have it open keep using it, while later ones get a new semaphore.
+    pub(crate) fn unlink(name: &str) -> io::Result<bool> {
+        let name = c_name(name)?;
+        if unsafe { libc::sem_unlink(name.as_ptr()) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::NotFound => Ok(false),
+            _ => Err(err),
+        }
+    }
+}
+
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        unsafe { libc::sem_close(self.sem as *mut libc::sem_t) };
+    }
+}
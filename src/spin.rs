@@ -0,0 +1,80 @@
+//! Adaptive spinning before falling back to a blocking acquisition.
+use std::hint;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// The bounds of the number of `try_lock` attempts made before blocking.
+const MIN_SPINS: u32 = 4;
+const MAX_SPINS: u32 = 128;
+/// Attempts after which spinning yields the thread instead of busy-waiting.
+const BUSY_SPINS: u32 = 8;
+
+/// The current spin budget, shared by the whole process.
+///
+/// It grows when spinning pays off and shrinks when it ends in a blocking call anyway, in the
+/// manner of glibc's adaptive mutexes.
+static SPIN_BUDGET: AtomicU32 = AtomicU32::new(MIN_SPINS * 4);
+
+pub(crate) fn lock_adaptive<L>(lock: &L, file_lock_mode: FileLockMode) -> Result<(), FileLockError>
+where
+    L: AdvisoryFileLock + ?Sized,
+{
+    let budget = SPIN_BUDGET.load(Ordering::Relaxed);
+    for attempt in 0..budget {
+        match lock.try_lock(file_lock_mode) {
+            Err(FileLockError::AlreadyLocked) => {}
+            result => {
+                if result.is_ok() && attempt > 0 {
+                    SPIN_BUDGET.store((budget * 2).min(MAX_SPINS), Ordering::Relaxed);
+                }
+                return result;
+            }
+        }
+
+        if attempt < BUSY_SPINS {
+            for _ in 0..1 << attempt {
+                hint::spin_loop();
+            }
+        } else {
+            thread::yield_now();
+        }
+    }
+
+    SPIN_BUDGET.store((budget / 2).max(MIN_SPINS), Ordering::Relaxed);
+    lock.lock(file_lock_mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn spins_then_blocks() {
+        let mut test_file = temp_dir();
+        test_file.push("spin_then_block");
+        let holder = File::create(&test_file).unwrap();
+        let waiter = File::open(&test_file).unwrap();
+
+        waiter.lock_adaptive(FileLockMode::Shared).unwrap();
+        AdvisoryFileLock::unlock(&waiter).unwrap();
+
+        AdvisoryFileLock::lock(&holder, FileLockMode::Exclusive).unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            AdvisoryFileLock::unlock(&holder).unwrap();
+        });
+        waiter.lock_adaptive(FileLockMode::Exclusive).unwrap();
+        releaser.join().unwrap();
+
+        let budget = SPIN_BUDGET.load(Ordering::Relaxed);
+        assert!((MIN_SPINS..=MAX_SPINS).contains(&budget));
+
+        drop(waiter);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//! Strict mode, which refuses to manipulate a lock through a duplicate of the handle holding it.
+use std::collections::BTreeMap;
+
+use crate::sync::{self, AtomicBool, Mutex, MutexGuard, Ordering};
+use crate::{process_id, sys, FileId, FileLockError, FileLockOperation};
+
+sync::statics! {
+    static STRICT: AtomicBool = AtomicBool::new(false);
+    static HOLDERS: Mutex<BTreeMap<FileId, Vec<Holder>>> = Mutex::new(BTreeMap::new());
+}
+
+/// A handle through which a process acquired the lock of a file.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Holder {
+    pid: u32,
+    handle: sys::Handle,
+}
+
+// The handle is only compared and passed to the operating system, never dereferenced.
+unsafe impl Send for Holder {}
+
+/// Enable or disable strict mode for every lock operation of this process.
+///
+/// Whole-file locks belong to the open file rather than to the handle, so every duplicate of a
+/// handle (made by `dup`, [`File::try_clone`], `fork` or handle inheritance) shares the lock of
+/// the original: unlocking a duplicate releases the lock of the original, and locking one
+/// "succeeds" without excluding anything. In strict mode, the crate remembers the handle through
+/// which each lock was acquired and fails with [`FileLockError::DuplicatedHandle`] when the lock
+/// is then manipulated through a duplicate of it, including from a forked child.
+///
+/// Strict mode costs a few system calls per operation, and is meant to be enabled in tests and
+/// debug builds. It only knows about the locks acquired while it was enabled. Only Linux can tell
+/// the duplicates made within the process apart from other handles, so elsewhere only inherited
+/// handles are detected.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{set_strict_mode, AdvisoryFileLock, FileLockError, FileLockMode};
+///
+/// set_strict_mode(true);
+/// let file = File::create("strict.lock")?;
+/// AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+///
+/// let clone = file.try_clone()?;
+/// # #[cfg(target_os = "linux")]
+/// assert!(matches!(
+///     AdvisoryFileLock::unlock(&clone),
+///     Err(FileLockError::DuplicatedHandle)
+/// ));
+/// AdvisoryFileLock::unlock(&file)?;
+/// # set_strict_mode(false);
+/// #
+/// # drop((file, clone));
+/// # std::fs::remove_file("strict.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`File::try_clone`]: https://doc.rust-lang.org/stable/std/fs/struct.File.html#method.try_clone
+/// [`FileLockError::DuplicatedHandle`]: enum.FileLockError.html#variant.DuplicatedHandle
+pub fn set_strict_mode(enabled: bool) {
+    STRICT.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        holders().clear();
+    }
+}
+
+/// Return whether strict mode is enabled.
+pub fn strict_mode() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+fn holders() -> MutexGuard<'static, BTreeMap<FileId, Vec<Holder>>> {
+    HOLDERS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Performs the whole-file lock `operation` on `handle` through `run`, enforcing strict mode.
+pub(crate) fn checked(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    if !strict_mode() {
+        return run();
+    }
+    check(handle, operation, run)
+}
+
+fn check(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let file_id = sys::file_id(handle).map_err(FileLockError::Io)?;
+    let this = Holder {
+        pid: process_id(),
+        handle,
+    };
+    let duplicated = holders().get(&file_id).is_some_and(|holders| {
+        holders
+            .iter()
+            .filter(|holder| **holder != this)
+            .any(|holder| {
+                if holder.pid != this.pid {
+                    // The handle was inherited from the process which acquired the lock.
+                    holder.handle == handle
+                } else {
+                    // Skip handles which were closed and reused for another file since.
+                    sys::file_id(holder.handle).ok() == Some(file_id)
+                        && sys::same_description(holder.handle, handle).unwrap_or(false)
+                }
+            })
+    });
+    if duplicated {
+        return Err(FileLockError::DuplicatedHandle);
+    }
+
+    // The registry isn't held while blocking on the lock.
+    run()?;
+    let mut holders = holders();
+    match operation {
+        FileLockOperation::Lock | FileLockOperation::TryLock => {
+            let entry = holders.entry(file_id).or_default();
+            if !entry.contains(&this) {
+                entry.push(this);
+            }
+        }
+        FileLockOperation::Unlock => {
+            if let Some(entry) = holders.get_mut(&file_id) {
+                entry.retain(|holder| *holder != this);
+                if entry.is_empty() {
+                    holders.remove(&file_id);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockMode};
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn duplicates_are_refused() {
+        let mut test_file = temp_dir();
+        test_file.push("strict_duplicates");
+        let file = File::create(&test_file).unwrap();
+        let other = File::open(&test_file).unwrap();
+        let clone = file.try_clone().unwrap();
+        // Strict mode is checked explicitly rather than enabled for the whole process.
+        let lock = |file: &File, immediate| {
+            let operation = match immediate {
+                true => FileLockOperation::TryLock,
+                false => FileLockOperation::Lock,
+            };
+            check(sys::handle(file), operation, || match immediate {
+                true => AdvisoryFileLock::try_lock(file, FileLockMode::Exclusive),
+                false => AdvisoryFileLock::lock(file, FileLockMode::Exclusive),
+            })
+        };
+        let unlock = |file: &File| {
+            check(sys::handle(file), FileLockOperation::Unlock, || {
+                AdvisoryFileLock::unlock(file)
+            })
+        };
+
+        lock(&file, false).unwrap();
+        assert!(matches!(
+            lock(&clone, true),
+            Err(FileLockError::DuplicatedHandle)
+        ));
+        assert!(matches!(
+            lock(&other, true),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            unlock(&clone),
+            Err(FileLockError::DuplicatedHandle)
+        ));
+        unlock(&file).unwrap();
+        lock(&clone, false).unwrap();
+        unlock(&clone).unwrap();
+        assert!(holders().get(&FileId::of(&file).unwrap()).is_none());
+
+        drop((file, other, clone));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+
+use crate::{lock_range_handle, sys, unlock_range_handle, FileLockError, FileLockMode};
+
+/// A lock file split into independently lockable stripes.
+///
+/// Each stripe is a one-byte range of the file, and callers lock the stripe their key hashes to,
+/// so unrelated keys no longer contend on a single whole-file lock. Keys are hashed with FNV-1a,
+/// which is stable across processes and compiler versions, as long as their [`Hash`]
+/// implementation is (integers hash differently on platforms of different endianness or width).
+///
+/// Stripes are byte-range locks, which don't interact with whole-file locks on most platforms.
+/// On Linux they are open file description locks which also exclude other `StripedLock`s in the
+/// same process; on other Unix systems they are classic record locks, which are owned by the
+/// process and released when *any* descriptor of the file in this process is closed.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{FileLockMode, StripedLock};
+///
+/// let lock = StripedLock::new("striped.lock", 16)?;
+/// lock.lock(&"user:42", FileLockMode::Exclusive)?;
+/// // ... update the record of user 42 ...
+/// lock.unlock(&"user:42")?;
+/// #
+/// # drop(lock);
+/// # std::fs::remove_file("striped.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct StripedLock {
+    file: File,
+    stripes: u64,
+}
+
+impl StripedLock {
+    /// Open or create the lock file at `path`, split into `stripes` stripes.
+    ///
+    /// Every cooperating process must use the same number of stripes.
+    pub fn new<P: AsRef<Path>>(path: P, stripes: u64) -> io::Result<StripedLock> {
+        if stripes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a striped lock needs at least one stripe",
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(StripedLock { file, stripes })
+    }
+
+    /// Return the number of stripes.
+    pub fn stripes(&self) -> u64 {
+        self.stripes
+    }
+
+    /// Return the stripe `key` maps to.
+    pub fn stripe_for<K: Hash + ?Sized>(&self, key: &K) -> u64 {
+        let mut hasher = Fnv1a::default();
+        key.hash(&mut hasher);
+        hasher.finish() % self.stripes
+    }
+
+    /// Acquire the stripe of `key`, blocking until it succeeds or errors.
+    pub fn lock<K: Hash + ?Sized>(
+        &self,
+        key: &K,
+        file_lock_mode: FileLockMode,
+    ) -> Result<(), FileLockError> {
+        self.lock_stripe(self.stripe_for(key), file_lock_mode)
+    }
+
+    /// Try to acquire the stripe of `key`, returning immediately.
+    pub fn try_lock<K: Hash + ?Sized>(
+        &self,
+        key: &K,
+        file_lock_mode: FileLockMode,
+    ) -> Result<(), FileLockError> {
+        self.try_lock_stripe(self.stripe_for(key), file_lock_mode)
+    }
+
+    /// Release the stripe of `key`.
+    pub fn unlock<K: Hash + ?Sized>(&self, key: &K) -> Result<(), FileLockError> {
+        self.unlock_stripe(self.stripe_for(key))
+    }
+
+    /// Acquire the given stripe, blocking until it succeeds or errors.
+    pub fn lock_stripe(
+        &self,
+        stripe: u64,
+        file_lock_mode: FileLockMode,
+    ) -> Result<(), FileLockError> {
+        lock_range_handle(self.handle(), file_lock_mode, false, self.check(stripe)?, 1)
+    }
+
+    /// Try to acquire the given stripe, returning immediately.
+    pub fn try_lock_stripe(
+        &self,
+        stripe: u64,
+        file_lock_mode: FileLockMode,
+    ) -> Result<(), FileLockError> {
+        lock_range_handle(self.handle(), file_lock_mode, true, self.check(stripe)?, 1)
+    }
+
+    /// Release the given stripe.
+    pub fn unlock_stripe(&self, stripe: u64) -> Result<(), FileLockError> {
+        unlock_range_handle(self.handle(), self.check(stripe)?, 1)
+    }
+
+    fn handle(&self) -> sys::Handle {
+        sys::handle(&self.file)
+    }
+
+    fn check(&self, stripe: u64) -> Result<u64, FileLockError> {
+        if stripe < self.stripes {
+            Ok(stripe)
+        } else {
+            Err(FileLockError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stripe out of range",
+            )))
+        }
+    }
+}
+
+/// The 64-bit FNV-1a hash function.
+///
+/// Unlike the hashers of the standard library, it gives the same hash in every process,
+/// whichever compiler built it, so it can name objects shared between processes.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    /// Return the hash of `bytes`.
+    pub(crate) fn hash(bytes: &[u8]) -> u64 {
+        let mut hasher = Fnv1a::default();
+        hasher.write(bytes);
+        hasher.finish()
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn stripes_are_independent() {
+        let mut test_file = temp_dir();
+        test_file.push("striped_independent");
+        let first = StripedLock::new(&test_file, 4).unwrap();
+        let second = StripedLock::new(&test_file, 4).unwrap();
+        assert!(StripedLock::new(&test_file, 0).is_err());
+        assert_eq!(first.stripe_for("key"), second.stripe_for("key"));
+
+        first.lock_stripe(0, FileLockMode::Exclusive).unwrap();
+        second.try_lock_stripe(1, FileLockMode::Exclusive).unwrap();
+        assert!(second.try_lock_stripe(4, FileLockMode::Shared).is_err());
+        if cfg!(any(windows, target_os = "linux")) {
+            assert!(matches!(
+                second.try_lock_stripe(0, FileLockMode::Shared),
+                Err(FileLockError::AlreadyLocked)
+            ));
+        }
+        first.unlock_stripe(0).unwrap();
+        second.try_lock_stripe(0, FileLockMode::Shared).unwrap();
+
+        drop((first, second));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
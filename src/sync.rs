@@ -0,0 +1,65 @@
+//! Synchronization primitives used by the in-process coordination layers.
+//!
+//! The layers that coordinate the threads of one process (thread awareness, strict mode, the
+//! reentrancy check, the diagnostics registry, named mutexes, the emulated backend, the fair
+//! queue, watchdogs, chaos injection, the panic hook and [`ProcessAndThreadLock`]) take their
+//! primitives from this module, so building with `RUSTFLAGS="--cfg loom"` swaps the standard
+//! primitives for [loom]'s models and lets those layers be model-checked for races, both here and
+//! in downstream crates. The crate models thread awareness and the reentrancy check:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+//!
+//! Loom's primitives can't be created in constants, so the process-wide registries declare
+//! their statics with [`statics!`], which makes them lazily created under loom, anew for every
+//! execution of a model. Only [`Once`] has no model, and always comes from `std`. Process-wide
+//! settings, such as the default backend, are plain `std` atomics and aren't modelled.
+//!
+//! [`ProcessAndThreadLock`]: ../struct.ProcessAndThreadLock.html
+//! [loom]: https://docs.rs/loom
+#![allow(dead_code, unused_imports)] // Not every primitive is used by every feature combination.
+
+#[cfg(loom)]
+pub(crate) use loom::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+};
+
+#[cfg(not(loom))]
+pub(crate) use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+};
+
+pub(crate) use std::sync::Once;
+
+/// Declare statics holding synchronization primitives, which are lazily created under loom.
+macro_rules! statics {
+    ($($(#[$attr:meta])* static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            #[cfg(not(loom))]
+            $(#[$attr])*
+            static $name: $ty = $init;
+        )*
+        #[cfg(loom)]
+        loom::lazy_static! {
+            $($(#[$attr])* static ref $name: $ty = $init;)*
+        }
+    };
+}
+pub(crate) use statics;
+
+/// Lock `mutex`, ignoring poisoning.
+///
+/// The state guarded by the crate's mutexes is only updated by code which cannot panic halfway,
+/// so a poisoned mutex still holds consistent data.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
@@ -0,0 +1,383 @@
+//! Thread-aware mode, which makes threads of one process exclude each other like processes do.
+use std::collections::BTreeMap;
+
+use crate::sync::thread::{self, ThreadId};
+use crate::sync::{self, AtomicBool, Condvar, Mutex, MutexGuard, Ordering};
+use crate::{sys, FileId, FileLockError, FileLockMode, FileLockOperation};
+
+sync::statics! {
+    static THREAD_AWARE: AtomicBool = AtomicBool::new(false);
+    static HOLDERS: Mutex<BTreeMap<FileId, Vec<Holder>>> = Mutex::new(BTreeMap::new());
+    static RELEASED: Condvar = Condvar::new();
+}
+
+/// A thread holding the lock of a file through a handle.
+#[derive(Copy, Clone, Debug)]
+struct Holder {
+    thread: ThreadId,
+    handle: sys::Handle,
+    mode: FileLockMode,
+    /// Whether the system lock is held yet, or still being waited for.
+    acquired: bool,
+}
+
+// The handle is only compared and passed to the operating system, never dereferenced.
+unsafe impl Send for Holder {}
+
+impl Holder {
+    fn is(&self, thread: ThreadId, handle: sys::Handle) -> bool {
+        self.thread == thread && self.handle == handle
+    }
+}
+
+/// Enable or disable thread-aware mode for every lock operation of this process.
+///
+/// Whole-file locks are owned by open files rather than by threads, so threads sharing a handle
+/// all "hold" an exclusive lock at once, and the first one to unlock releases it for everybody.
+/// In thread-aware mode, every whole-file lock is paired with an in-process readers-writer lock
+/// on the file, and each thread is treated as a separate owner, just like a separate process
+/// would be: an exclusive lock excludes every other thread, through any handle, and the lock of a
+/// handle shared by several threads is only released with its last holder.
+///
+/// On Linux, locking or unlocking through a duplicate of a handle (made by `dup` or
+/// [`File::try_clone`]) is forwarded to the handle the lock was acquired through, since they
+/// share the lock; other systems can't tell duplicates apart, so they count as separate handles.
+///
+/// Only the locks acquired while the mode is enabled are known to it. Locks must be released
+/// before closing their handles, as a handle closed while locked is only forgotten once its
+/// descriptor refers to another file.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::thread;
+/// use advisory_lock::{set_thread_aware, AdvisoryFileLock, FileLockError, FileLockMode};
+///
+/// set_thread_aware(true);
+/// let file = File::create("thread-aware.lock")?;
+/// AdvisoryFileLock::lock(&file, FileLockMode::Exclusive)?;
+///
+/// thread::scope(|scope| {
+///     let contender = scope.spawn(|| AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive));
+///     assert!(matches!(contender.join().unwrap(), Err(FileLockError::AlreadyLocked)));
+/// });
+/// AdvisoryFileLock::unlock(&file)?;
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("thread-aware.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`File::try_clone`]: https://doc.rust-lang.org/stable/std/fs/struct.File.html#method.try_clone
+pub fn set_thread_aware(enabled: bool) {
+    THREAD_AWARE.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        holders().clear();
+        RELEASED.notify_all();
+    }
+}
+
+/// Return whether thread-aware mode is enabled.
+pub fn thread_aware() -> bool {
+    THREAD_AWARE.load(Ordering::Relaxed)
+}
+
+fn holders() -> MutexGuard<'static, BTreeMap<FileId, Vec<Holder>>> {
+    HOLDERS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Performs the whole-file lock `operation` on `handle` through `run`, coordinating with the
+/// other threads of the process if thread-aware mode is enabled.
+pub(crate) fn coordinated(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    if !thread_aware() {
+        return run();
+    }
+    coordinate(handle, operation, file_lock_mode, run)
+}
+
+fn coordinate(
+    handle: sys::Handle,
+    operation: FileLockOperation,
+    file_lock_mode: Option<FileLockMode>,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let file_id = sys::file_id(handle).map_err(FileLockError::Io)?;
+    let thread = thread::current().id();
+    match file_lock_mode {
+        Some(mode) => acquire(
+            file_id,
+            Holder {
+                thread,
+                handle,
+                mode,
+                acquired: false,
+            },
+            operation == FileLockOperation::TryLock,
+            run,
+        ),
+        None => release(file_id, thread, handle, run),
+    }
+}
+
+fn acquire(
+    file_id: FileId,
+    mut this: Holder,
+    immediate: bool,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let mut state = holders();
+    loop {
+        let holders = state.entry(file_id).or_default();
+        // Forget the handles which were closed while locked.
+        holders.retain(|holder| sys::file_id(holder.handle).ok() == Some(file_id));
+        this.handle = canonical(holders, this.handle);
+        let conflict = holders.iter().any(|holder| {
+            !holder.is(this.thread, this.handle)
+                && (this.mode == FileLockMode::Exclusive
+                    || holder.mode == FileLockMode::Exclusive
+                    || (holder.handle == this.handle && !holder.acquired))
+        });
+        if !conflict {
+            break;
+        }
+        if immediate {
+            if holders.is_empty() {
+                state.remove(&file_id);
+            }
+            return Err(FileLockError::AlreadyLocked);
+        }
+        state = RELEASED.wait(state).unwrap_or_else(|err| err.into_inner());
+    }
+
+    // Claim the file in-process before waiting for other processes, so the registry isn't held
+    // while blocking.
+    let claimed = state.entry(file_id).or_default();
+    let previous = claimed
+        .iter()
+        .position(|holder| holder.is(this.thread, this.handle))
+        .map(|index| claimed.remove(index));
+    // Threads sharing a handle share its lock, which is only acquired once: `LockFileEx` would
+    // stack a second lock which the last unlock leaves behind.
+    let shared = claimed.iter().any(|holder| holder.handle == this.handle);
+    claimed.push(this);
+    drop(state);
+
+    let result = if shared { Ok(()) } else { run() };
+    let mut state = holders();
+    let holders = state.entry(file_id).or_default();
+    if result.is_ok() {
+        for holder in holders.iter_mut() {
+            if holder.is(this.thread, this.handle) {
+                holder.acquired = true;
+            }
+        }
+    } else {
+        holders.retain(|holder| !holder.is(this.thread, this.handle));
+        holders.extend(previous);
+        if holders.is_empty() {
+            state.remove(&file_id);
+        }
+    }
+    drop(state);
+    RELEASED.notify_all();
+    result
+}
+
+/// Return the handle through which `handle`'s lock is registered in `holders`.
+///
+/// A duplicate of a handle (e.g. made by [`File::try_clone`]) shares the lock of the original, so
+/// operations through it are forwarded to the registered handle rather than being taken for a
+/// separate owner.
+///
+/// [`File::try_clone`]: https://doc.rust-lang.org/stable/std/fs/struct.File.html#method.try_clone
+fn canonical(holders: &[Holder], handle: sys::Handle) -> sys::Handle {
+    if holders.iter().any(|holder| holder.handle == handle) {
+        return handle;
+    }
+    holders
+        .iter()
+        .map(|holder| holder.handle)
+        .find(|registered| sys::same_description(*registered, handle).unwrap_or(false))
+        .unwrap_or(handle)
+}
+
+fn release(
+    file_id: FileId,
+    thread: ThreadId,
+    handle: sys::Handle,
+    run: impl FnOnce() -> Result<(), FileLockError>,
+) -> Result<(), FileLockError> {
+    let mut state = holders();
+    let holders = match state.get_mut(&file_id) {
+        Some(holders) => holders,
+        None => return run(),
+    };
+
+    // A lock moved to another thread along with its handle is released by that thread.
+    let handle = canonical(holders, handle);
+    let index = holders
+        .iter()
+        .position(|holder| holder.is(thread, handle))
+        .or_else(|| holders.iter().position(|holder| holder.handle == handle));
+    let holder = match index {
+        Some(index) => holders.remove(index),
+        None => return run(),
+    };
+
+    let result = if holders.iter().any(|other| other.handle == handle) {
+        // Other threads still hold the lock through the same handle.
+        Ok(())
+    } else {
+        run()
+    };
+    if result.is_err() {
+        holders.push(holder);
+    } else if holders.is_empty() {
+        state.remove(&file_id);
+    }
+    drop(state);
+    RELEASED.notify_all();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn threads_exclude_each_other_through_one_handle() {
+        let mut test_file = temp_dir();
+        test_file.push("threads_one_handle");
+        let file = File::create(&test_file).unwrap();
+        let outsider = File::open(&test_file).unwrap();
+
+        // Exercise the layer without enabling it for the tests running concurrently.
+        let lock = |mode, immediate| {
+            let handle = sys::handle(&file);
+            let operation = if immediate {
+                FileLockOperation::TryLock
+            } else {
+                FileLockOperation::Lock
+            };
+            coordinate(handle, operation, Some(mode), || {
+                sys::lock_file(handle, mode, immediate)
+            })
+        };
+        let unlock = || {
+            let handle = sys::handle(&file);
+            coordinate(handle, FileLockOperation::Unlock, None, || {
+                sys::unlock_file(handle)
+            })
+        };
+
+        lock(FileLockMode::Exclusive, false).unwrap();
+        std::thread::scope(|scope| {
+            let contender = scope.spawn(|| lock(FileLockMode::Shared, true));
+            assert!(matches!(
+                contender.join().unwrap(),
+                Err(FileLockError::AlreadyLocked)
+            ));
+        });
+        unlock().unwrap();
+
+        lock(FileLockMode::Shared, false).unwrap();
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    lock(FileLockMode::Shared, true).unwrap();
+                    unlock().unwrap();
+                })
+                .join()
+                .unwrap();
+        });
+        // The other thread's unlock left the lock of this thread in place.
+        assert!(matches!(
+            crate::AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        unlock().unwrap();
+        assert!(holders().get(&FileId::of(&file).unwrap()).is_none());
+
+        drop((file, outsider));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn clones_are_forwarded_to_the_registered_handle() {
+        let mut test_file = temp_dir();
+        test_file.push("threads_clones");
+        let file = File::create(&test_file).unwrap();
+        let clone = file.try_clone().unwrap();
+        let (handle, clone_handle) = (sys::handle(&file), sys::handle(&clone));
+        let file_id = FileId::of(&file).unwrap();
+
+        coordinate(
+            handle,
+            FileLockOperation::Lock,
+            Some(FileLockMode::Exclusive),
+            || sys::lock_file(handle, FileLockMode::Exclusive, false),
+        )
+        .unwrap();
+        // Relocking through the clone is the same owner relocking, not a contender.
+        coordinate(
+            clone_handle,
+            FileLockOperation::TryLock,
+            Some(FileLockMode::Exclusive),
+            || sys::lock_file(clone_handle, FileLockMode::Exclusive, true),
+        )
+        .unwrap();
+        assert_eq!(holders()[&file_id].len(), 1);
+
+        coordinate(clone_handle, FileLockOperation::Unlock, None, || {
+            sys::unlock_file(clone_handle)
+        })
+        .unwrap();
+        assert!(holders().get(&file_id).is_none());
+
+        drop((file, clone));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn loom_threads_sharing_a_handle_exclude_each_other() {
+        use crate::sync::{Arc, AtomicUsize};
+
+        let mut test_file = temp_dir();
+        test_file.push("threads_loom");
+        let file = File::create(&test_file).unwrap();
+        // Handles are stored as integers to be captured by the model.
+        let handle = sys::handle(&file) as usize;
+
+        loom::model(move || {
+            let inside = Arc::new(AtomicUsize::new(0));
+            let workers: Vec<_> = (0..2)
+                .map(|_| {
+                    let inside = Arc::clone(&inside);
+                    thread::spawn(move || {
+                        let handle = handle as sys::Handle;
+                        let exclusive = Some(FileLockMode::Exclusive);
+                        coordinate(handle, FileLockOperation::Lock, exclusive, || Ok(())).unwrap();
+                        assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                        inside.fetch_sub(1, Ordering::SeqCst);
+                        coordinate(handle, FileLockOperation::Unlock, None, || Ok(())).unwrap();
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        });
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs::File;
+
+use crate::sync::{self, Mutex};
+use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// An advisory lock which remembers its current state to skip redundant system calls.
+///
+/// Unlocking an unlocked `TrackedLock` and locking it again in the mode it is already held in
+/// both return immediately without reaching the operating system.
+///
+/// The state is only accurate as long as the lock is exclusively manipulated through the
+/// `TrackedLock`; locking or unlocking the inner handle directly bypasses the bookkeeping.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{AdvisoryFileLock, FileLockMode, TrackedLock};
+///
+/// let file = TrackedLock::new(File::create("tracked.txt")?);
+/// file.lock(FileLockMode::Exclusive)?;
+/// file.lock(FileLockMode::Exclusive)?; // No system call.
+/// assert_eq!(file.state(), Some(FileLockMode::Exclusive));
+/// file.unlock()?;
+/// file.unlock()?; // No system call.
+/// #
+/// # std::fs::remove_file("tracked.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct TrackedLock<T = File> {
+    inner: T,
+    state: Mutex<Option<FileLockMode>>,
+}
+
+impl<T: AdvisoryFileLock> TrackedLock<T> {
+    /// Wrap the given handle, which must not be locked yet.
+    pub fn new(inner: T) -> TrackedLock<T> {
+        TrackedLock {
+            inner,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Return the mode the lock is currently held in, or `None` if it is not held.
+    pub fn state(&self) -> Option<FileLockMode> {
+        *sync::lock(&self.state)
+    }
+
+    /// Return a reference to the inner handle.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Unwrap the inner handle, leaving its lock state as it is.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn acquire(&self, file_lock_mode: FileLockMode, immediate: bool) -> Result<(), FileLockError> {
+        let mut state = sync::lock(&self.state);
+        let previous = *state;
+        match previous {
+            Some(mode) if mode == file_lock_mode => return Ok(()),
+            // `LockFileEx` doesn't convert a held lock but stacks another one on top of it.
+            #[cfg(windows)]
+            Some(_) => {
+                self.inner.unlock()?;
+                *state = None;
+            }
+            _ => {}
+        }
+
+        let result = if immediate {
+            self.inner.try_lock(file_lock_mode)
+        } else {
+            self.inner.lock(file_lock_mode)
+        };
+        match (&result, previous) {
+            (Ok(()), _) => *state = Some(file_lock_mode),
+            // A failed conversion may have released the lock held before, as `flock` does, so it
+            // is taken back.
+            (Err(_), Some(previous)) => {
+                *state = self.inner.try_lock(previous).ok().map(|()| previous);
+            }
+            (Err(_), None) => {}
+        }
+        result
+    }
+}
+
+impl<T: AdvisoryFileLock> AdvisoryFileLock for TrackedLock<T> {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        self.acquire(file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        let mut state = sync::lock(&self.state);
+        if state.is_none() {
+            return Ok(());
+        }
+
+        self.inner.unlock()?;
+        *state = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::LockRecorder;
+    use std::env::temp_dir;
+
+    #[test]
+    fn redundant_operations_skip_the_os() {
+        let recorder = LockRecorder::install();
+        let mut test_file = temp_dir();
+        test_file.push("tracked_redundant");
+        let file = TrackedLock::new(File::create(&test_file).unwrap());
+
+        file.unlock().unwrap();
+        file.lock(FileLockMode::Shared).unwrap();
+        file.try_lock(FileLockMode::Shared).unwrap();
+        assert_eq!(file.state(), Some(FileLockMode::Shared));
+        file.lock(FileLockMode::Exclusive).unwrap();
+        assert_eq!(file.state(), Some(FileLockMode::Exclusive));
+        file.unlock().unwrap();
+        file.unlock().unwrap();
+        assert_eq!(file.state(), None);
+
+        let syscalls = recorder.events_for(file.get_ref()).unwrap().len();
+        assert_eq!(syscalls, if cfg!(windows) { 4 } else { 3 });
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn failed_conversions_keep_the_state_accurate() {
+        let mut test_file = temp_dir();
+        test_file.push("tracked_conversion");
+        let file = TrackedLock::new(File::create(&test_file).unwrap());
+        let reader = File::open(&test_file).unwrap();
+        let outsider = File::open(&test_file).unwrap();
+
+        file.lock(FileLockMode::Shared).unwrap();
+        AdvisoryFileLock::lock(&reader, FileLockMode::Shared).unwrap();
+        assert!(matches!(
+            file.try_lock(FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert_eq!(file.state(), Some(FileLockMode::Shared));
+
+        // The shared lock is still held by the tracked handle.
+        AdvisoryFileLock::unlock(&reader).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        file.unlock().unwrap();
+        AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).unwrap();
+        AdvisoryFileLock::unlock(&outsider).unwrap();
+
+        drop((file, reader, outsider));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+use crate::ownership::check_per_handle;
+use crate::{sys, AdvisoryFileLock, FileLockError, FileLockMode};
+
+/// A held lock which can be handed to another process over a Unix domain socket.
+///
+/// The descriptor is passed with `SCM_RIGHTS`, so the receiving process gets a descriptor of the
+/// same open file description, which already holds the lock: there is no window in which the lock
+/// is released. This lets a supervisor acquire a lock before spawning the worker which is going to
+/// use it, or move it between long-running workers.
+///
+/// Dropping the lock on either end releases it, unless it has been sent. Only locks held by the
+/// open file description can travel, i.e. those of [`AdvisoryFileLock`]; per-process locks stay
+/// with the process which acquired them, so locking fails while [`Fcntl`] is the default
+/// backend.
+///
+/// ## Windows
+///
+/// Windows has no equivalent of `SCM_RIGHTS`. The lock of `LockFileEx` belongs to the handle it
+/// was acquired through and its duplicates, so the sender calls `DuplicateHandle` with the
+/// worker's process handle as target, passes the resulting value to the worker by any means (a
+/// pipe, a command line argument), and closes its own handle without unlocking it.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use std::os::unix::net::UnixStream;
+/// use advisory_lock::{FileLockMode, TransferableLock};
+///
+/// let (supervisor, worker) = UnixStream::pair()?;
+/// let lock = TransferableLock::lock(File::create("handoff.lock")?, FileLockMode::Exclusive)?;
+/// lock.send(&supervisor).map_err(|(_, err)| err)?;
+///
+/// // In the worker:
+/// let lock = TransferableLock::receive(&worker)?;
+/// assert_eq!(lock.mode(), FileLockMode::Exclusive);
+/// #
+/// # drop(lock);
+/// # std::fs::remove_file("handoff.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`AdvisoryFileLock`]: trait.AdvisoryFileLock.html
+/// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct TransferableLock {
+    /// `None` once the lock has been sent.
+    file: Option<File>,
+    file_lock_mode: FileLockMode,
+}
+
+impl TransferableLock {
+    /// Acquire the lock of `file`, blocking until it is available.
+    pub fn lock(
+        file: File,
+        file_lock_mode: FileLockMode,
+    ) -> Result<TransferableLock, FileLockError> {
+        check_per_handle()?;
+        AdvisoryFileLock::lock(&file, file_lock_mode)?;
+        Ok(TransferableLock::new(file, file_lock_mode))
+    }
+
+    /// Try to acquire the lock of `file` without blocking.
+    pub fn try_lock(
+        file: File,
+        file_lock_mode: FileLockMode,
+    ) -> Result<TransferableLock, FileLockError> {
+        check_per_handle()?;
+        AdvisoryFileLock::try_lock(&file, file_lock_mode)?;
+        Ok(TransferableLock::new(file, file_lock_mode))
+    }
+
+    /// Receive a lock sent with [`send`] over `socket`, blocking until it arrives.
+    ///
+    /// [`send`]: #method.send
+    pub fn receive(socket: &UnixStream) -> io::Result<TransferableLock> {
+        let (file, mode) = sys::receive_fd(socket.as_raw_fd())?;
+        let file_lock_mode = match mode {
+            b'E' => FileLockMode::Exclusive,
+            b'S' => FileLockMode::Shared,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the message doesn't describe a lock",
+                ))
+            }
+        };
+        Ok(TransferableLock::new(file, file_lock_mode))
+    }
+
+    fn new(file: File, file_lock_mode: FileLockMode) -> TransferableLock {
+        TransferableLock {
+            file: Some(file),
+            file_lock_mode,
+        }
+    }
+
+    /// Return the locked file.
+    pub fn file(&self) -> &File {
+        self.file.as_ref().expect("the lock has been sent")
+    }
+
+    /// Return the mode the lock is held in.
+    pub fn mode(&self) -> FileLockMode {
+        self.file_lock_mode
+    }
+
+    /// Send the lock over `socket` to the process at the other end.
+    ///
+    /// On success, the local descriptor is closed without unlocking, since that would release the
+    /// lock for the receiver too. On failure, the lock is returned along with the error, still
+    /// held.
+    pub fn send(mut self, socket: &UnixStream) -> Result<(), (TransferableLock, io::Error)> {
+        let mode = match self.file_lock_mode {
+            FileLockMode::Exclusive => b'E',
+            FileLockMode::Shared => b'S',
+        };
+        match sys::send_fd(socket.as_raw_fd(), self.file().as_raw_fd(), mode) {
+            Ok(()) => {
+                self.file = None;
+                Ok(())
+            }
+            Err(err) => Err((self, err)),
+        }
+    }
+
+    /// Release the lock, returning any error which occurs.
+    pub fn unlock(mut self) -> Result<(), FileLockError> {
+        match self.file.take() {
+            Some(file) => AdvisoryFileLock::unlock(&file),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TransferableLock {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = AdvisoryFileLock::unlock(&file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::net::Shutdown;
+
+    #[test]
+    fn locks_are_handed_over_without_a_gap() {
+        let mut test_file = temp_dir();
+        test_file.push("transfer_handoff");
+        let (supervisor, worker) = UnixStream::pair().unwrap();
+        let outsider = File::create(&test_file).unwrap();
+
+        let lock =
+            TransferableLock::lock(File::open(&test_file).unwrap(), FileLockMode::Shared).unwrap();
+        lock.send(&supervisor).map_err(|(_, err)| err).unwrap();
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive),
+            Err(FileLockError::AlreadyLocked)
+        ));
+
+        let received = TransferableLock::receive(&worker).unwrap();
+        assert_eq!(received.mode(), FileLockMode::Shared);
+        assert!(AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).is_err());
+        drop(received);
+        AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).unwrap();
+        AdvisoryFileLock::unlock(&outsider).unwrap();
+
+        // A failed send keeps the lock.
+        worker.shutdown(Shutdown::Both).unwrap();
+        let lock =
+            TransferableLock::lock(File::open(&test_file).unwrap(), FileLockMode::Shared).unwrap();
+        let (lock, _) = lock.send(&supervisor).unwrap_err();
+        assert!(AdvisoryFileLock::try_lock(&outsider, FileLockMode::Exclusive).is_err());
+        lock.unlock().unwrap();
+        assert!(matches!(
+            TransferableLock::receive(&worker).map(drop),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        drop(outsider);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
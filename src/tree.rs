@@ -0,0 +1,289 @@
+use std::fs::File;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::fs::{open_locked_with, PathFile};
+use crate::{FileLockError, FileLockMode};
+
+/// The name of the marker file whose lock guards a whole tree.
+const MARKER: &str = ".tree.lock";
+
+/// A two-level locking protocol over a directory tree, such as a package cache.
+///
+/// Operations on single files lock the root of the tree in shared mode, then the file itself;
+/// operations on the whole tree (garbage collection, migrations, wiping the cache) lock the root
+/// exclusively, which waits for every file operation to finish and keeps new ones out. Since
+/// every process locks the root first, the protocol can't deadlock.
+///
+/// The lock of the root is held on a marker file, `.tree.lock`, in the root directory, which is
+/// created as needed.
+///
+/// Trees nest: a [`subtree`] has a marker of its own, so a tool syncing one directory can lock
+/// it exclusively while the rest of the tree stays available, and every lock of the subtree
+/// also holds the markers of the trees enclosing it in shared mode, outermost first. Locking
+/// the outer tree therefore quiesces its subtrees too, as a backup of the whole data directory
+/// requires.
+///
+/// Where open files can't be identified, the markers and entries are [emulated] on their paths,
+/// so an entry reached through a link isn't excluded by a lock taken through its real path.
+///
+/// Example:
+/// ```
+/// use std::io::Write;
+/// use advisory_lock::{FileLockMode, TreeLock};
+///
+/// std::fs::create_dir_all("cache")?;
+/// let tree = TreeLock::new("cache");
+///
+/// let entry = tree.lock_entry("cache/package.tar", FileLockMode::Exclusive)?;
+/// entry.file().write_all(b"contents")?;
+/// drop(entry);
+///
+/// let whole = tree.lock_tree()?;
+/// // ... prune the cache ...
+/// drop(whole);
+/// #
+/// # std::fs::remove_dir_all("cache")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`subtree`]: #method.subtree
+/// [emulated]: fn.set_emulation_dir.html
+#[derive(Clone, Debug)]
+pub struct TreeLock {
+    root: PathBuf,
+    /// The roots of the enclosing trees, outermost first.
+    ancestors: Vec<PathBuf>,
+}
+
+/// The lock of a whole tree, released when dropped.
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct TreeGuard {
+    _markers: Vec<PathFile>,
+}
+
+/// The lock of a file in a tree, released when dropped.
+#[must_use = "if unused the lock is immediately released"]
+#[derive(Debug)]
+pub struct EntryGuard {
+    file: PathFile,
+    _markers: Vec<PathFile>,
+}
+
+impl EntryGuard {
+    /// Return the locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Deref for EntryGuard {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl TreeLock {
+    /// Create the protocol over the tree rooted at `root`, which must be an existing directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> TreeLock {
+        TreeLock {
+            root: root.into(),
+            ancestors: Vec::new(),
+        }
+    }
+
+    /// Create the protocol over the subtree rooted at `path`, relative to the root of this tree,
+    /// which must be an existing directory.
+    ///
+    /// Example:
+    /// ```
+    /// use advisory_lock::{FileLockError, TreeLock};
+    ///
+    /// std::fs::create_dir_all("data/users")?;
+    /// let data = TreeLock::new("data");
+    /// let users = data.subtree("users");
+    ///
+    /// let syncing = users.lock_tree()?;
+    /// // The backup of the whole directory waits for the sync to finish.
+    /// assert!(matches!(data.try_lock_tree(), Err(FileLockError::AlreadyLocked)));
+    /// drop(syncing);
+    ///
+    /// let backup = data.lock_tree()?;
+    /// assert!(matches!(users.try_lock_tree(), Err(FileLockError::AlreadyLocked)));
+    /// drop(backup);
+    /// #
+    /// # std::fs::remove_dir_all("data")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn subtree<P: AsRef<Path>>(&self, path: P) -> TreeLock {
+        let mut ancestors = self.ancestors.clone();
+        ancestors.push(self.root.clone());
+        TreeLock {
+            root: self.root.join(path),
+            ancestors,
+        }
+    }
+
+    /// Return the root of the tree.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Lock the whole tree exclusively, blocking until every file operation is finished.
+    pub fn lock_tree(&self) -> Result<TreeGuard, FileLockError> {
+        self.markers(FileLockMode::Exclusive, false)
+            .map(|markers| TreeGuard { _markers: markers })
+    }
+
+    /// Try to lock the whole tree exclusively, returning immediately.
+    pub fn try_lock_tree(&self) -> Result<TreeGuard, FileLockError> {
+        self.markers(FileLockMode::Exclusive, true)
+            .map(|markers| TreeGuard { _markers: markers })
+    }
+
+    /// Lock the file at `path`, which should be inside the tree, blocking until it succeeds.
+    ///
+    /// An exclusive lock creates the file if it doesn't exist and opens it for writing; a shared
+    /// lock opens it for reading, failing if it doesn't exist.
+    pub fn lock_entry<P: AsRef<Path>>(
+        &self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> Result<EntryGuard, FileLockError> {
+        self.entry(path.as_ref(), file_lock_mode, false)
+    }
+
+    /// Try to lock the file at `path`, returning immediately.
+    ///
+    /// See [`lock_entry`] for details.
+    ///
+    /// [`lock_entry`]: #method.lock_entry
+    pub fn try_lock_entry<P: AsRef<Path>>(
+        &self,
+        path: P,
+        file_lock_mode: FileLockMode,
+    ) -> Result<EntryGuard, FileLockError> {
+        self.entry(path.as_ref(), file_lock_mode, true)
+    }
+
+    /// Lock the markers of the enclosing trees in shared mode, then the marker of this one in
+    /// `file_lock_mode`.
+    fn markers(
+        &self,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<Vec<PathFile>, FileLockError> {
+        let mut markers = Vec::with_capacity(self.ancestors.len() + 1);
+        for ancestor in &self.ancestors {
+            markers.push(open_locked_with(
+                &ancestor.join(MARKER),
+                FileLockMode::Shared,
+                true,
+                immediate,
+            )?);
+        }
+        markers.push(open_locked_with(
+            &self.root.join(MARKER),
+            file_lock_mode,
+            true,
+            immediate,
+        )?);
+        Ok(markers)
+    }
+
+    fn entry(
+        &self,
+        path: &Path,
+        file_lock_mode: FileLockMode,
+        immediate: bool,
+    ) -> Result<EntryGuard, FileLockError> {
+        let markers = self.markers(FileLockMode::Shared, immediate)?;
+        let create = file_lock_mode == FileLockMode::Exclusive;
+        let file = open_locked_with(path, file_lock_mode, create, immediate)?;
+        Ok(EntryGuard {
+            file,
+            _markers: markers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn tree_and_entry_locks_exclude_each_other() {
+        let mut root = temp_dir();
+        root.push("tree_lock_root");
+        std::fs::create_dir_all(&root).unwrap();
+        let tree = TreeLock::new(&root);
+        let entry_path = root.join("entry");
+
+        let entry = tree
+            .lock_entry(&entry_path, FileLockMode::Exclusive)
+            .unwrap();
+        let other = tree
+            .try_lock_entry(root.join("other"), FileLockMode::Exclusive)
+            .unwrap();
+        assert!(matches!(
+            tree.try_lock_entry(&entry_path, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            tree.try_lock_tree(),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop((entry, other));
+
+        let whole = tree.try_lock_tree().unwrap();
+        assert!(matches!(
+            tree.try_lock_entry(&entry_path, FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop(whole);
+        drop(
+            tree.try_lock_entry(&entry_path, FileLockMode::Shared)
+                .unwrap(),
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn subtrees_are_quiesced_with_their_ancestors() {
+        let mut root = temp_dir();
+        root.push("tree_lock_nested");
+        std::fs::create_dir_all(root.join("left")).unwrap();
+        std::fs::create_dir_all(root.join("right")).unwrap();
+        let tree = TreeLock::new(&root);
+        let (left, right) = (tree.subtree("left"), tree.subtree("right"));
+
+        // Siblings are independent, but both keep the whole tree out.
+        let syncing = left.try_lock_tree().unwrap();
+        let entry = right
+            .try_lock_entry(root.join("right/entry"), FileLockMode::Exclusive)
+            .unwrap();
+        assert!(matches!(
+            tree.try_lock_tree(),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop((syncing, entry));
+
+        let whole = tree.try_lock_tree().unwrap();
+        assert!(matches!(
+            left.try_lock_tree(),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        assert!(matches!(
+            right.try_lock_entry(root.join("right/entry"), FileLockMode::Shared),
+            Err(FileLockError::AlreadyLocked)
+        ));
+        drop(whole);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
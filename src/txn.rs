@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::fs::{open_exclusive, read_all, replace_file};
+use crate::FileLockError;
+
+/// A transaction over the contents of a file, holding its exclusive lock until it ends.
+///
+/// Writes are staged in memory and only reach the file when the transaction is committed, by
+/// atomically replacing the file as [`replace_atomically`] does. Rolling back, or dropping the
+/// transaction without committing, discards them. Either way the file goes from one complete
+/// version to the next, even if the process crashes halfway.
+///
+/// Example:
+/// ```
+/// use advisory_lock::{read_locked, FileTxn};
+///
+/// let mut txn = FileTxn::begin("ledger.txt")?;
+/// txn.append(b"debit 10\n");
+/// txn.append(b"credit 10\n");
+/// txn.commit()?;
+///
+/// let mut txn = FileTxn::begin("ledger.txt")?;
+/// txn.write(b"oops");
+/// txn.rollback();
+///
+/// assert_eq!(read_locked("ledger.txt")?, b"debit 10\ncredit 10\n");
+/// #
+/// # std::fs::remove_file("ledger.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`replace_atomically`]: fn.replace_atomically.html
+#[derive(Debug)]
+pub struct FileTxn {
+    path: PathBuf,
+    _file: File,
+    original: Vec<u8>,
+    staged: Option<Vec<u8>>,
+}
+
+impl FileTxn {
+    /// Lock the file at `path` exclusively, creating it if needed, and start a transaction.
+    pub fn begin<P: Into<PathBuf>>(path: P) -> Result<FileTxn, FileLockError> {
+        let path = path.into();
+        let file = open_exclusive(&path)?;
+        let original = read_all(&file)?;
+        Ok(FileTxn {
+            path,
+            _file: file,
+            original,
+            staged: None,
+        })
+    }
+
+    /// Return the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the contents as seen by the transaction, including staged writes.
+    pub fn read(&self) -> &[u8] {
+        self.staged.as_deref().unwrap_or(&self.original)
+    }
+
+    /// Return the contents of the file when the transaction began.
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    /// Stage new contents, replacing the whole file on commit.
+    pub fn write<C: Into<Vec<u8>>>(&mut self, contents: C) {
+        self.staged = Some(contents.into());
+    }
+
+    /// Stage bytes to be appended to the current contents.
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.contents_mut().extend_from_slice(bytes);
+    }
+
+    /// Return the staged contents for in-place modification.
+    pub fn contents_mut(&mut self) -> &mut Vec<u8> {
+        let original = &self.original;
+        self.staged.get_or_insert_with(|| original.clone())
+    }
+
+    /// Return whether any write was staged.
+    pub fn is_dirty(&self) -> bool {
+        self.staged.is_some()
+    }
+
+    /// Write the staged contents to the file and release the lock.
+    pub fn commit(self) -> Result<(), FileLockError> {
+        match &self.staged {
+            Some(staged) => replace_file(&self.path, staged).map_err(FileLockError::Io),
+            None => Ok(()),
+        }
+    }
+
+    /// Discard the staged contents and release the lock.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn transactions_are_isolated() {
+        let mut test_file = temp_dir();
+        test_file.push("txn_isolated");
+        let _ = std::fs::remove_file(&test_file);
+
+        let mut txn = FileTxn::begin(&test_file).unwrap();
+        assert!(!txn.is_dirty());
+        txn.contents_mut().extend_from_slice(b"staged");
+        assert_eq!(txn.read(), b"staged");
+        assert_eq!(txn.original(), b"");
+
+        let waiter = {
+            let path = test_file.clone();
+            std::thread::spawn(move || {
+                let txn = FileTxn::begin(path).unwrap();
+                txn.read().to_vec()
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(std::fs::read(&test_file).unwrap(), b"");
+        txn.commit().unwrap();
+        assert_eq!(waiter.join().unwrap(), b"staged");
+
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
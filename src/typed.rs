@@ -0,0 +1,240 @@
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::codec::{Codec, Json};
+use crate::fs::{open_exclusive, open_locked, read_all, replace_contents};
+use crate::{FileLockError, FileLockMode};
+
+/// A file holding a serialized value, which is read under a shared lock and updated under an
+/// exclusive lock.
+///
+/// This packages the read/modify/write boilerplate of state files shared by several processes:
+/// [`read`] never observes a half-written document, and concurrent [`update`]s are serialized
+/// instead of losing each other's changes.
+///
+/// The value is stored as JSON unless another [`Codec`] is given to [`with_codec`], which lets
+/// files in an existing format adopt the locked API as they are.
+///
+/// This type is only available with the `json` feature.
+///
+/// Example:
+/// ```
+/// use std::collections::BTreeMap;
+/// use advisory_lock::TypedLockFile;
+///
+/// let state = TypedLockFile::<BTreeMap<String, u64>>::new("state.json");
+/// state.update(|counters| *counters.entry("runs".to_owned()).or_default() += 1)?;
+/// assert_eq!(state.read()?["runs"], 1);
+/// #
+/// # std::fs::remove_file("state.json")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`read`]: #method.read
+/// [`update`]: #method.update
+/// [`Codec`]: codec/trait.Codec.html
+/// [`with_codec`]: #method.with_codec
+#[derive(Debug)]
+pub struct TypedLockFile<T, C = Json> {
+    path: PathBuf,
+    codec: C,
+    checksum: bool,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedLockFile<T>
+where
+    Json: Codec<T>,
+{
+    /// Create a handle to the JSON file at `path`, which is created by the first update.
+    pub fn new<P: Into<PathBuf>>(path: P) -> TypedLockFile<T> {
+        TypedLockFile::with_codec(path, Json)
+    }
+}
+
+impl<T, C> TypedLockFile<T, C>
+where
+    C: Codec<T>,
+{
+    /// Create a handle to the file at `path` stored with `codec`, which is created by the first
+    /// update.
+    ///
+    /// Example:
+    /// ```
+    /// use advisory_lock::codec::Codec;
+    /// use advisory_lock::TypedLockFile;
+    /// use std::io;
+    ///
+    /// /// A counter stored as plain decimal text.
+    /// struct Decimal;
+    ///
+    /// impl Codec<u64> for Decimal {
+    ///     fn encode(&self, value: &u64) -> io::Result<Vec<u8>> {
+    ///         Ok(value.to_string().into_bytes())
+    ///     }
+    ///
+    ///     fn decode(&self, bytes: &[u8]) -> io::Result<u64> {
+    ///         std::str::from_utf8(bytes)
+    ///             .ok()
+    ///             .and_then(|text| text.trim().parse().ok())
+    ///             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a number"))
+    ///     }
+    /// }
+    ///
+    /// let counter = TypedLockFile::with_codec("counter.txt", Decimal);
+    /// counter.update(|count| *count += 41)?;
+    /// counter.update(|count| *count += 1)?;
+    /// assert_eq!(std::fs::read_to_string("counter.txt")?, "42");
+    /// #
+    /// # std::fs::remove_file("counter.txt")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn with_codec<P: Into<PathBuf>>(path: P, codec: C) -> TypedLockFile<T, C> {
+        TypedLockFile {
+            path: path.into(),
+            codec,
+            checksum: false,
+            value: PhantomData,
+        }
+    }
+
+    /// Append a checksum to the serialized value and verify it when reading.
+    ///
+    /// Reading a file whose checksum doesn't match, e.g. because it was modified by a process
+    /// which doesn't cooperate, fails with [`FileLockError::Corrupted`]. Every process must
+    /// agree on whether the file is checksummed.
+    ///
+    /// [`FileLockError::Corrupted`]: enum.FileLockError.html#variant.Corrupted
+    pub fn checksummed(mut self) -> TypedLockFile<T, C> {
+        self.checksum = true;
+        self
+    }
+
+    /// Return the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the codec of the file.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Read and deserialize the value under a shared lock.
+    pub fn read(&self) -> Result<T, FileLockError> {
+        let file = open_locked(&self.path, FileLockMode::Shared, false)?;
+        self.decode(read_all(&file)?)
+    }
+
+    /// Serialize and write the value under an exclusive lock, replacing the current one.
+    pub fn write(&self, value: &T) -> Result<(), FileLockError> {
+        let file = open_exclusive(&self.path)?;
+        replace_contents(&file, &self.encode(value)?, true)
+    }
+
+    /// Read the value, apply `f` to it and write it back, all under one exclusive lock.
+    ///
+    /// A missing or empty file starts out as `T::default()`. The file is synced to disk before
+    /// the lock is released.
+    pub fn update<F, R>(&self, f: F) -> Result<R, FileLockError>
+    where
+        T: Default,
+        F: FnOnce(&mut T) -> R,
+    {
+        let file = open_exclusive(&self.path)?;
+        let bytes = read_all(&file)?;
+        let mut value = if bytes.is_empty() {
+            T::default()
+        } else {
+            self.decode(bytes)?
+        };
+
+        let result = f(&mut value);
+        replace_contents(&file, &self.encode(&value)?, true)?;
+        Ok(result)
+    }
+
+    fn decode(&self, mut bytes: Vec<u8>) -> Result<T, FileLockError> {
+        if self.checksum {
+            bytes = checksum::unseal(bytes)?;
+        }
+        self.codec.decode(&bytes).map_err(FileLockError::Io)
+    }
+
+    fn encode(&self, value: &T) -> Result<Vec<u8>, FileLockError> {
+        let bytes = self.codec.encode(value).map_err(FileLockError::Io)?;
+        Ok(if self.checksum {
+            checksum::seal(&bytes)
+        } else {
+            bytes
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::thread;
+
+    #[test]
+    fn concurrent_updates_are_serialized() {
+        let mut test_file = temp_dir();
+        test_file.push("typed_concurrent_updates.json");
+        let _ = std::fs::remove_file(&test_file);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let path = test_file.clone();
+                thread::spawn(move || {
+                    let file = TypedLockFile::<u64>::new(path);
+                    for _ in 0..25 {
+                        file.update(|count| *count += 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(TypedLockFile::<u64>::new(&test_file).read().unwrap(), 100);
+        std::fs::write(&test_file, "not json").unwrap();
+        assert!(TypedLockFile::<u64>::new(&test_file).read().is_err());
+
+        let checked = TypedLockFile::<u64>::new(&test_file).checksummed();
+        checked.write(&7).unwrap();
+        assert_eq!(checked.read().unwrap(), 7);
+        assert!(TypedLockFile::<u64>::new(&test_file).read().is_err());
+        std::fs::write(&test_file, "8").unwrap();
+        assert!(matches!(checked.read(), Err(FileLockError::Corrupted)));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(all(feature = "toml", feature = "bincode"))]
+    #[test]
+    fn codecs_round_trip() {
+        use crate::codec::{Bincode, Toml};
+        use std::collections::BTreeMap;
+
+        let mut test_file = temp_dir();
+        test_file.push("typed_codecs");
+        let _ = std::fs::remove_file(&test_file);
+
+        let toml = TypedLockFile::<BTreeMap<String, u64>, _>::with_codec(&test_file, Toml);
+        toml.update(|table| table.insert("retries".to_owned(), 3))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&test_file).unwrap(),
+            "retries = 3\n"
+        );
+        assert_eq!(toml.read().unwrap()["retries"], 3);
+
+        let bincode = TypedLockFile::with_codec(&test_file, Bincode).checksummed();
+        bincode.write(&(1u8, 2u8)).unwrap();
+        assert_eq!(bincode.read().unwrap(), (1, 2));
+        assert!(toml.read().is_err());
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -1,67 +1,771 @@
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::Error;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
 
-use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+use crate::{
+    lock_handle, retry_on_interrupt, unlock_handle, AdvisoryFileLock, FileId, FileLockError,
+    FileLockMode, LockMechanism,
+};
 
-impl AdvisoryFileLock for File {
+pub(crate) type Handle = RawFd;
+
+/// The types which expose a [`Handle`] safely.
+pub(crate) use std::os::unix::io::AsFd as AsHandle;
+
+pub(crate) fn handle<T: AsHandle + ?Sized>(file: &T) -> Handle {
+    file.as_fd().as_raw_fd()
+}
+
+impl<T: AsHandle + ?Sized> AdvisoryFileLock for T {
     fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        self.as_raw_fd().lock(file_lock_mode)
+        lock_handle(handle(self), file_lock_mode, false)
     }
 
     fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        self.as_raw_fd().try_lock(file_lock_mode)
+        lock_handle(handle(self), file_lock_mode, true)
     }
 
     fn unlock(&self) -> Result<(), FileLockError> {
-        self.as_raw_fd().unlock()
+        unlock_handle(handle(self))
     }
 }
 
-impl AdvisoryFileLock for RawFd {
-    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        lock_file(*self, file_lock_mode, false)
+/// The arguments of `flock`, built once and reused for every call.
+#[derive(Copy, Clone)]
+pub(crate) struct PreparedLock {
+    flags: libc::c_int,
+}
+
+impl PreparedLock {
+    pub(crate) fn new(file_lock_mode: FileLockMode) -> PreparedLock {
+        let flags = match file_lock_mode {
+            FileLockMode::Shared => libc::LOCK_SH,
+            FileLockMode::Exclusive => libc::LOCK_EX,
+        };
+        PreparedLock { flags }
     }
+}
 
-    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        lock_file(*self, file_lock_mode, true)
+/// Map the error code of a failed lock call.
+///
+/// Contention is the common failure when polling, so the error code is inspected first and an
+/// `io::Error` is only built for genuine I/O failures.
+fn lock_error(code: libc::c_int, contended: &[libc::c_int]) -> FileLockError {
+    if contended.contains(&code) {
+        FileLockError::AlreadyLocked
+    } else {
+        os_error(code)
     }
+}
 
-    fn unlock(&self) -> Result<(), FileLockError> {
-        unlock_file(*self)
+/// Map the error code of a failed lock or unlock call.
+pub(crate) fn os_error(code: libc::c_int) -> FileLockError {
+    match code {
+        libc::EINTR => FileLockError::Interrupted,
+        libc::ENOLCK => FileLockError::NoLockResources,
+        libc::EDEADLK => FileLockError::DeadlockDetected,
+        libc::EBADF => FileLockError::InvalidHandle,
+        // `ENOTSUP` and `EOPNOTSUPP` are the same code on some systems only.
+        code if code == libc::EOPNOTSUPP || code == libc::ENOTSUP => FileLockError::Unsupported,
+        _ => FileLockError::Io(Error::from_raw_os_error(code)),
     }
 }
 
-fn lock_file(
+fn errno() -> libc::c_int {
+    Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Make the lock call `call`, again as long as it is interrupted and retrying is enabled.
+fn retry_interrupted<F>(contended: &[libc::c_int], mut call: F) -> Result<(), FileLockError>
+where
+    F: FnMut() -> Result<(), libc::c_int>,
+{
+    loop {
+        match call() {
+            Ok(()) => return Ok(()),
+            Err(code) => match lock_error(code, contended) {
+                FileLockError::Interrupted if retry_on_interrupt() => {}
+                err => return Err(err),
+            },
+        }
+    }
+}
+
+/// Call `flock`, returning the error code on failure.
+#[cfg(not(feature = "rustix"))]
+fn flock(raw_fd: RawFd, operation: libc::c_int) -> Result<(), libc::c_int> {
+    if unsafe { libc::flock(raw_fd, operation) } == 0 {
+        Ok(())
+    } else {
+        Err(errno())
+    }
+}
+
+/// Call `flock`, returning the error code on failure.
+#[cfg(feature = "rustix")]
+fn flock(raw_fd: RawFd, operation: libc::c_int) -> Result<(), libc::c_int> {
+    use rustix::fs::FlockOperation;
+
+    let nonblocking = operation & libc::LOCK_NB != 0;
+    let operation = match operation & !libc::LOCK_NB {
+        libc::LOCK_SH if nonblocking => FlockOperation::NonBlockingLockShared,
+        libc::LOCK_SH => FlockOperation::LockShared,
+        libc::LOCK_EX if nonblocking => FlockOperation::NonBlockingLockExclusive,
+        libc::LOCK_EX => FlockOperation::LockExclusive,
+        _ => FlockOperation::Unlock,
+    };
+    rustix::fs::flock(borrow(raw_fd)?, operation).map_err(|err| err.raw_os_error())
+}
+
+/// Borrow `raw_fd` for the duration of a call, failing like the call would if it is invalid.
+#[cfg(feature = "rustix")]
+fn borrow<'a>(raw_fd: RawFd) -> Result<std::os::unix::io::BorrowedFd<'a>, libc::c_int> {
+    if raw_fd < 0 {
+        return Err(libc::EBADF);
+    }
+    // The descriptor is only used by the call, during which the caller keeps it open.
+    Ok(unsafe { std::os::unix::io::BorrowedFd::borrow_raw(raw_fd) })
+}
+
+/// Open the file at `path` with `options`, locking it with `flock` semantics in the same system
+/// call through `O_SHLOCK` or `O_EXLOCK`.
+///
+/// `custom_flags` replaces the custom flags of `options`, which can't be read back.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) fn open_locked(
+    options: &std::fs::OpenOptions,
+    path: &std::path::Path,
+    custom_flags: i32,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<File, FileLockError> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let lock_flag = match file_lock_mode {
+        FileLockMode::Shared => libc::O_SHLOCK,
+        FileLockMode::Exclusive => libc::O_EXLOCK,
+    };
+    // Without `O_NONBLOCK`, the call blocks until the lock is acquired.
+    let nonblocking = immediate && custom_flags & libc::O_NONBLOCK == 0;
+    let flags = custom_flags | lock_flag | if nonblocking { libc::O_NONBLOCK } else { 0 };
+
+    let file = match options.clone().custom_flags(flags).open(path) {
+        Ok(file) => file,
+        Err(err) if err.raw_os_error() == Some(libc::EWOULDBLOCK) => {
+            return Err(FileLockError::AlreadyLocked)
+        }
+        Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            return Err(FileLockError::Unsupported)
+        }
+        Err(err) => return Err(FileLockError::Io(err)),
+    };
+    if nonblocking {
+        let raw_fd = file.as_raw_fd();
+        let status = unsafe { libc::fcntl(raw_fd, libc::F_GETFL) };
+        if status == -1
+            || unsafe { libc::fcntl(raw_fd, libc::F_SETFL, status & !libc::O_NONBLOCK) } == -1
+        {
+            return Err(FileLockError::Io(Error::last_os_error()));
+        }
+    }
+    Ok(file)
+}
+
+pub(crate) fn lock_file(
     raw_fd: RawFd,
     file_lock_mode: FileLockMode,
     immediate: bool,
 ) -> Result<(), FileLockError> {
-    let mut flags = match file_lock_mode {
-        FileLockMode::Shared => libc::LOCK_SH,
-        FileLockMode::Exclusive => libc::LOCK_EX,
-    };
+    lock_prepared(raw_fd, &PreparedLock::new(file_lock_mode), immediate)
+}
+
+pub(crate) fn lock_prepared(
+    raw_fd: RawFd,
+    prepared: &PreparedLock,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let mut flags = prepared.flags;
     if immediate {
         flags |= libc::LOCK_NB;
     }
 
-    let result = unsafe { libc::flock(raw_fd, flags) };
+    retry_interrupted(&[libc::EWOULDBLOCK], || flock(raw_fd, flags))
+}
+
+pub(crate) fn unlock_prepared(raw_fd: RawFd, _: &PreparedLock) -> Result<(), FileLockError> {
+    unlock_file(raw_fd)
+}
+
+pub(crate) fn unlock_file(raw_fd: RawFd) -> Result<(), FileLockError> {
+    flock(raw_fd, libc::LOCK_UN).map_err(os_error)
+}
+
+/// Return the mechanism the lock of `raw_fd` is held with.
+pub(crate) fn lock_mechanism(_: RawFd) -> LockMechanism {
+    LockMechanism::FileLock
+}
+
+pub(crate) fn file_id(raw_fd: RawFd) -> Result<FileId, Error> {
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    let result = unsafe { libc::fstat(raw_fd, &mut stat) };
     if result != 0 {
-        let last_os_error = Error::last_os_error();
-        return Err(match last_os_error.raw_os_error() {
-            Some(code) if code == libc::EWOULDBLOCK => FileLockError::AlreadyLocked,
-            _ => FileLockError::Io(last_os_error),
-        });
+        return Err(Error::last_os_error());
     }
 
+    Ok(FileId {
+        device: stat.st_dev as u64,
+        index: stat.st_ino as u64,
+    })
+}
+
+/// Return the path of the file `raw_fd` refers to, on systems which keep track of it.
+#[cfg(feature = "diagnostics")]
+pub(crate) fn path(raw_fd: RawFd) -> Result<std::path::PathBuf, Error> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        std::fs::read_link(format!("/proc/self/fd/{}", raw_fd))
+    }
+    #[cfg(target_vendor = "apple")]
+    {
+        use std::ffi::{CStr, OsStr};
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut path = vec![0 as libc::c_char; libc::PATH_MAX as usize];
+        if unsafe { libc::fcntl(raw_fd, libc::F_GETPATH, path.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let path = unsafe { CStr::from_ptr(path.as_ptr()) };
+        Ok(OsStr::from_bytes(path.to_bytes()).into())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+    {
+        let _ = raw_fd;
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Return whether `raw_fd` is a regular file or a directory, which `flock` can lock.
+pub(crate) fn is_lockable(raw_fd: RawFd) -> Result<bool, Error> {
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe { libc::fstat(raw_fd, &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let file_type = stat.st_mode & libc::S_IFMT;
+    Ok(file_type == libc::S_IFREG || file_type == libc::S_IFDIR)
+}
+
+/// Return whether the process with the given id is running.
+pub(crate) fn process_alive(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // Signal 0 only checks whether the process exists; `EPERM` means it does, as someone else's.
+    unsafe { libc::kill(pid, 0) == 0 || errno() == libc::EPERM }
+}
+
+/// Run `callback` when the process exits normally.
+pub(crate) fn at_exit(callback: extern "C" fn()) {
+    unsafe { libc::atexit(callback) };
+}
+
+/// Send `raw_fd` and a one-byte payload over the Unix domain socket `socket` with `SCM_RIGHTS`.
+pub(crate) fn send_fd(socket: RawFd, raw_fd: RawFd, payload: u8) -> Result<(), Error> {
+    let mut payload = [payload];
+    let mut iov = iovec(&mut payload);
+    let mut control = control_buffer();
+    let msg = message(&mut iov, &mut control);
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, raw_fd);
+    }
+
+    // A closed peer is reported as `EPIPE` rather than by killing the process.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_NOSIGNAL;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+    if unsafe { libc::sendmsg(socket, &msg, flags) } < 0 {
+        return Err(Error::last_os_error());
+    }
     Ok(())
 }
 
-fn unlock_file(raw_fd: RawFd) -> Result<(), FileLockError> {
-    let result = unsafe { libc::flock(raw_fd, libc::LOCK_UN) };
+/// Receive a descriptor and its one-byte payload sent by [`send_fd`] over `socket`.
+///
+/// The received descriptor is close-on-exec.
+pub(crate) fn receive_fd(socket: RawFd) -> Result<(File, u8), Error> {
+    let mut payload = [0];
+    let mut iov = iovec(&mut payload);
+    let mut control = control_buffer();
+    let mut msg = message(&mut iov, &mut control);
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+    let received = unsafe { libc::recvmsg(socket, &mut msg, flags) };
+    if received < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    let file = if cmsg.is_null()
+        || unsafe {
+            (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        } {
+        None
+    } else {
+        let raw_fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) };
+        Some(unsafe { File::from_raw_fd(raw_fd) })
+    };
+    match file {
+        Some(file) if received == 1 && msg.msg_flags & libc::MSG_CTRUNC == 0 => {
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            set_inheritable(file.as_raw_fd(), false)?;
+            Ok((file, payload[0]))
+        }
+        _ if received == 0 => Err(Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "the socket was closed before a descriptor was received",
+        )),
+        _ => Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the message doesn't carry exactly one descriptor",
+        )),
+    }
+}
+
+/// The control buffer of a message carrying one descriptor, suitably aligned for `cmsghdr`.
+fn control_buffer() -> Vec<u64> {
+    vec![0; control_len().div_ceil(8)]
+}
+
+fn control_len() -> usize {
+    unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as usize }
+}
+
+fn message(iov: &mut libc::iovec, control: &mut [u64]) -> libc::msghdr {
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control_len() as _;
+    msg
+}
+
+fn iovec(payload: &mut [u8; 1]) -> libc::iovec {
+    libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    }
+}
+
+pub(crate) fn set_inheritable(raw_fd: RawFd, inheritable: bool) -> Result<(), Error> {
+    let flags = unsafe { libc::fcntl(raw_fd, libc::F_GETFD) };
+    if flags == -1 {
+        return Err(Error::last_os_error());
+    }
+    let flags = if inheritable {
+        flags & !libc::FD_CLOEXEC
+    } else {
+        flags | libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(raw_fd, libc::F_SETFD, flags) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Spawn `command` with an inherited copy of `raw_fd`, whose number is passed in the environment
+/// variable `env`.
+pub(crate) fn spawn_inheriting(
+    mut command: Command,
+    raw_fd: RawFd,
+    env: &str,
+) -> Result<Child, Error> {
+    command.env(env, raw_fd.to_string());
+    // Only the child's copy of the descriptor is made inheritable, so the children spawned
+    // concurrently by other threads don't get it. `fcntl` is async-signal-safe.
+    unsafe { command.pre_exec(move || set_inheritable(raw_fd, true)) };
+    command.spawn()
+}
+
+pub(crate) fn is_inheritable(raw_fd: RawFd) -> Result<bool, Error> {
+    match unsafe { libc::fcntl(raw_fd, libc::F_GETFD) } {
+        -1 => Err(Error::last_os_error()),
+        flags => Ok(flags & libc::FD_CLOEXEC == 0),
+    }
+}
+
+/// Return whether two descriptors share their open file description, e.g. because one is a `dup`
+/// of the other.
+///
+/// Linux compares the descriptions with `kcmp`. Other systems can't tell without changing the
+/// state of the description, so distinct descriptors are assumed to be distinct descriptions.
+pub(crate) fn same_description(a: RawFd, b: RawFd) -> Result<bool, Error> {
+    if a == b {
+        return Ok(true);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        const KCMP_FILE: libc::c_int = 0;
+        let pid = std::process::id() as libc::pid_t;
+        match unsafe { libc::syscall(libc::SYS_kcmp, pid, pid, KCMP_FILE, a, b) } {
+            -1 => Err(Error::last_os_error()),
+            order => Ok(order == 0),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    Ok(false)
+}
+
+/// The `fcntl` commands used for byte-range locks.
+///
+/// Linux supports open file description locks, which are owned by the open file rather than by
+/// the process, and thus exclude threads of the same process from each other like `flock` does.
+/// Other systems fall back to classic per-process record locks.
+fn range_commands() -> (libc::c_int, libc::c_int) {
+    #[cfg(target_os = "linux")]
+    return (libc::F_OFD_SETLK, libc::F_OFD_SETLKW);
+    #[cfg(not(target_os = "linux"))]
+    return (libc::F_SETLK, libc::F_SETLKW);
+}
+
+/// Return the record lock which conflicts with locking `len` bytes from `offset` in
+/// `file_lock_mode`, as `(pid, mode, start, len)`, using `F_GETLK`.
+///
+/// The pid is `-1` for open file description locks.
+pub(crate) fn record_holder(
+    raw_fd: RawFd,
+    file_lock_mode: FileLockMode,
+    offset: u64,
+    len: u64,
+) -> Result<Option<(libc::pid_t, FileLockMode, u64, u64)>, FileLockError> {
+    let l_type = match file_lock_mode {
+        FileLockMode::Shared => libc::F_RDLCK as libc::c_short,
+        FileLockMode::Exclusive => libc::F_WRLCK as libc::c_short,
+    };
+    let mut flock = flock_struct(l_type, offset, len)?;
+    if unsafe { libc::fcntl(raw_fd, libc::F_GETLK, &mut flock) } != 0 {
+        return Err(FileLockError::Io(Error::last_os_error()));
+    }
+
+    // The constants are of different types on different systems.
+    let l_type = flock.l_type as libc::c_int;
+    let mode = if l_type == libc::F_UNLCK as libc::c_int {
+        return Ok(None);
+    } else if l_type == libc::F_RDLCK as libc::c_int {
+        FileLockMode::Shared
+    } else {
+        FileLockMode::Exclusive
+    };
+    Ok(Some((
+        flock.l_pid,
+        mode,
+        flock.l_start as u64,
+        flock.l_len as u64,
+    )))
+}
+
+/// Return the `flock` lock which conflicts with locking the file of `raw_fd` in
+/// `file_lock_mode`, as `(pid, mode)`, from `/proc/locks`.
+///
+/// Locks held through other descriptions in this process are reported as well, but so is the
+/// lock of `raw_fd` itself, which can't be told apart.
+#[cfg(target_os = "linux")]
+pub(crate) fn flock_holder(
+    raw_fd: RawFd,
+    file_lock_mode: FileLockMode,
+) -> Result<Option<(u32, FileLockMode)>, FileLockError> {
+    let file_id = file_id(raw_fd).map_err(FileLockError::Io)?;
+    let holder = proc_locks(file_id)?
+        .into_iter()
+        .find_map(|(kind, mode, pid)| {
+            let conflicts =
+                mode == FileLockMode::Exclusive || file_lock_mode == FileLockMode::Exclusive;
+            Some((pid?, mode)).filter(|_| kind == "FLOCK" && conflicts)
+        });
+    Ok(holder)
+}
+
+/// Return the number of shared locks of any kind held on the file `file_id`, from
+/// `/proc/locks`.
+#[cfg(target_os = "linux")]
+pub(crate) fn count_shared(file_id: FileId) -> Result<usize, FileLockError> {
+    let locks = proc_locks(file_id)?;
+    Ok(locks
+        .iter()
+        .filter(|(_, mode, _)| *mode == FileLockMode::Shared)
+        .count())
+}
+
+/// Return the locks held on the file `file_id`, as `(kind, mode, pid)` where `kind` is `FLOCK`,
+/// `POSIX` or `OFDLCK`, from `/proc/locks`.
+#[cfg(target_os = "linux")]
+fn proc_locks(file_id: FileId) -> Result<Vec<(String, FileLockMode, Option<u32>)>, FileLockError> {
+    let device = format!(
+        "{:02x}:{:02x}:{}",
+        libc::major(file_id.device as libc::dev_t),
+        libc::minor(file_id.device as libc::dev_t),
+        file_id.index
+    );
+    let locks = std::fs::read_to_string("/proc/locks").map_err(FileLockError::Io)?;
+    // Lines look like `1: FLOCK  ADVISORY  WRITE 1234 fe:00:1220653 0 EOF`; waiters have a `->`
+    // after the id, and open file description locks a pid of -1.
+    let locks = locks
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            match fields[..] {
+                [_, kind, _, access, pid, id, ..] if kind != "->" && id == device => {
+                    let mode = match access {
+                        "READ" => FileLockMode::Shared,
+                        _ => FileLockMode::Exclusive,
+                    };
+                    Some((kind.to_owned(), mode, pid.parse().ok()))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    Ok(locks)
+}
+
+fn flock_struct(
+    l_type: libc::c_short,
+    offset: u64,
+    len: u64,
+) -> Result<libc::flock, FileLockError> {
+    let invalid = |_| {
+        FileLockError::Io(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "lock range does not fit in off_t",
+        ))
+    };
+
+    let mut flock = unsafe { std::mem::zeroed::<libc::flock>() };
+    flock.l_type = l_type as _;
+    flock.l_whence = libc::SEEK_SET as _;
+    flock.l_start = libc::off_t::try_from(offset).map_err(invalid)?;
+    flock.l_len = libc::off_t::try_from(len).map_err(invalid)?;
+    Ok(flock)
+}
+
+pub(crate) fn lock_range(
+    raw_fd: RawFd,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    lock_record(
+        raw_fd,
+        range_commands(),
+        file_lock_mode,
+        immediate,
+        offset,
+        len,
+    )
+}
+
+/// The `fcntl` arguments locking and unlocking a byte range, built once and reused for every
+/// call.
+#[derive(Copy, Clone)]
+pub(crate) struct PreparedRange {
+    file_lock_mode: FileLockMode,
+    lock: libc::flock,
+    unlock: libc::flock,
+}
+
+impl PreparedRange {
+    pub(crate) fn new(
+        file_lock_mode: FileLockMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<PreparedRange, FileLockError> {
+        let l_type = match file_lock_mode {
+            FileLockMode::Shared => libc::F_RDLCK as libc::c_short,
+            FileLockMode::Exclusive => libc::F_WRLCK as libc::c_short,
+        };
+        Ok(PreparedRange {
+            file_lock_mode,
+            lock: flock_struct(l_type, offset, len)?,
+            unlock: flock_struct(libc::F_UNLCK as libc::c_short, offset, len)?,
+        })
+    }
+}
+
+pub(crate) fn lock_range_prepared(
+    raw_fd: RawFd,
+    prepared: &PreparedRange,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    check_access(raw_fd, prepared.file_lock_mode)?;
+    set_record(raw_fd, range_commands(), &prepared.lock, immediate)
+}
+
+pub(crate) fn unlock_range_prepared(
+    raw_fd: RawFd,
+    prepared: &PreparedRange,
+) -> Result<(), FileLockError> {
+    clear_record(raw_fd, range_commands().0, &prepared.unlock)
+}
+
+pub(crate) fn unlock_range(raw_fd: RawFd, offset: u64, len: u64) -> Result<(), FileLockError> {
+    unlock_record(raw_fd, range_commands(), offset, len)
+}
+
+/// The commands of classic record locks, which are owned by the process.
+#[cfg(not(feature = "rustix"))]
+const PROCESS_COMMANDS: (libc::c_int, libc::c_int) = (libc::F_SETLK, libc::F_SETLKW);
+
+/// Acquires a classic record lock over the whole file, owned by the calling process.
+pub(crate) fn lock_process(
+    raw_fd: RawFd,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    #[cfg(feature = "rustix")]
+    {
+        use rustix::fs::FlockOperation;
+
+        check_access(raw_fd, file_lock_mode)?;
+        let operation = match (file_lock_mode, immediate) {
+            (FileLockMode::Shared, false) => FlockOperation::LockShared,
+            (FileLockMode::Shared, true) => FlockOperation::NonBlockingLockShared,
+            (FileLockMode::Exclusive, false) => FlockOperation::LockExclusive,
+            (FileLockMode::Exclusive, true) => FlockOperation::NonBlockingLockExclusive,
+        };
+        retry_interrupted(&[libc::EAGAIN, libc::EACCES], || {
+            rustix::fs::fcntl_lock(borrow(raw_fd)?, operation).map_err(|err| err.raw_os_error())
+        })
+    }
+    // A length of zero extends the lock to the end of the file, however far it grows.
+    #[cfg(not(feature = "rustix"))]
+    lock_record(raw_fd, PROCESS_COMMANDS, file_lock_mode, immediate, 0, 0)
+}
+
+/// Releases the classic record lock of the calling process over the whole file.
+pub(crate) fn unlock_process(raw_fd: RawFd) -> Result<(), FileLockError> {
+    #[cfg(feature = "rustix")]
+    return borrow(raw_fd)
+        .and_then(|fd| {
+            let operation = rustix::fs::FlockOperation::NonBlockingUnlock;
+            rustix::fs::fcntl_lock(fd, operation).map_err(|err| err.raw_os_error())
+        })
+        .map_err(os_error);
+    #[cfg(not(feature = "rustix"))]
+    unlock_record(raw_fd, PROCESS_COMMANDS, 0, 0)
+}
+
+/// Acquires an open file description lock over the whole file.
+#[cfg(target_os = "linux")]
+pub(crate) fn lock_description(
+    raw_fd: RawFd,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let commands = (libc::F_OFD_SETLK, libc::F_OFD_SETLKW);
+    lock_record(raw_fd, commands, file_lock_mode, immediate, 0, 0)
+}
+
+/// Releases the open file description lock over the whole file.
+#[cfg(target_os = "linux")]
+pub(crate) fn unlock_description(raw_fd: RawFd) -> Result<(), FileLockError> {
+    unlock_record(raw_fd, (libc::F_OFD_SETLK, libc::F_OFD_SETLKW), 0, 0)
+}
+
+fn lock_record(
+    raw_fd: RawFd,
+    (set, set_wait): (libc::c_int, libc::c_int),
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    check_access(raw_fd, file_lock_mode)?;
+    let l_type = match file_lock_mode {
+        FileLockMode::Shared => libc::F_RDLCK as libc::c_short,
+        FileLockMode::Exclusive => libc::F_WRLCK as libc::c_short,
+    };
+    let flock = flock_struct(l_type, offset, len)?;
+    set_record(raw_fd, (set, set_wait), &flock, immediate)
+}
+
+/// Take the record lock described by `flock`, whose access must have been checked.
+fn set_record(
+    raw_fd: RawFd,
+    (set, set_wait): (libc::c_int, libc::c_int),
+    flock: &libc::flock,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let command = if immediate { set } else { set_wait };
+    retry_interrupted(&[libc::EAGAIN, libc::EACCES], || {
+        if unsafe { libc::fcntl(raw_fd, command, flock) } == 0 {
+            Ok(())
+        } else {
+            Err(errno())
+        }
+    })
+}
+
+/// Check that `raw_fd` was opened with the access record locks in `file_lock_mode` require.
+///
+/// `fcntl` fails with a bare `EBADF` otherwise, which is easily mistaken for a closed descriptor.
+fn check_access(raw_fd: RawFd, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+    let flags = unsafe { libc::fcntl(raw_fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(os_error(errno()));
+    }
+
+    let access = flags & libc::O_ACCMODE;
+    let message = match file_lock_mode {
+        FileLockMode::Shared if access == libc::O_WRONLY => {
+            "a shared record lock requires the file to be open for reading"
+        }
+        FileLockMode::Exclusive if access == libc::O_RDONLY => {
+            "an exclusive record lock requires the file to be open for writing"
+        }
+        _ => return Ok(()),
+    };
+    Err(FileLockError::Io(Error::new(
+        std::io::ErrorKind::InvalidInput,
+        message,
+    )))
+}
+
+fn unlock_record(
+    raw_fd: RawFd,
+    (set, _): (libc::c_int, libc::c_int),
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    let flock = flock_struct(libc::F_UNLCK as libc::c_short, offset, len)?;
+    clear_record(raw_fd, set, &flock)
+}
+
+/// Release the record lock described by `flock`.
+fn clear_record(raw_fd: RawFd, set: libc::c_int, flock: &libc::flock) -> Result<(), FileLockError> {
+    let result = unsafe { libc::fcntl(raw_fd, set, flock) };
     if result == 0 {
         Ok(())
     } else {
-        Err(FileLockError::Io(Error::last_os_error()))
+        Err(os_error(errno()))
     }
 }
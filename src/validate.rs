@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{sys, FileLockError};
+
+static CHECK_FILE_TYPE: AtomicBool = AtomicBool::new(true);
+
+/// Enable or disable checking the type of the files locked by this process.
+///
+/// Whole-file locks make sense on regular files, and on directories where the platform can lock
+/// them (Unix). On pipes, sockets and device nodes they range from useless (every opener of a
+/// FIFO gets its own, unrelated lock on some systems) to misleading, so acquiring one fails with
+/// [`FileLockError::UnsupportedFileType`] by default. The check costs a system call per
+/// acquisition; disable it to lock such files anyway, or to shave that call off hot paths.
+///
+/// [`FileLockError::UnsupportedFileType`]: enum.FileLockError.html#variant.UnsupportedFileType
+pub fn set_file_type_check(enabled: bool) {
+    CHECK_FILE_TYPE.store(enabled, Ordering::Relaxed);
+}
+
+/// Return whether the type of locked files is checked.
+pub fn file_type_check() -> bool {
+    CHECK_FILE_TYPE.load(Ordering::Relaxed)
+}
+
+/// Fails unless `handle` is of a type whole-file locks make sense on, if the check is enabled.
+pub(crate) fn check_file_type(handle: sys::Handle) -> Result<(), FileLockError> {
+    if !file_type_check() {
+        return Ok(());
+    }
+
+    match sys::is_lockable(handle) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(FileLockError::UnsupportedFileType),
+        Err(err) => Err(FileLockError::from_io(err)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockMode};
+    use std::fs::File;
+
+    #[test]
+    fn pipes_are_rejected() {
+        use std::os::unix::io::{FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (read, _write) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        assert!(matches!(
+            AdvisoryFileLock::try_lock(&read, FileLockMode::Exclusive),
+            Err(FileLockError::UnsupportedFileType)
+        ));
+
+        let directory = File::open(std::env::temp_dir()).unwrap();
+        AdvisoryFileLock::try_lock(&directory, FileLockMode::Shared).unwrap();
+        AdvisoryFileLock::unlock(&directory).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::fs::{open_locked_by, open_locked_with};
+use crate::{
+    lock_handle_with, unlock_handle_with, AdvisoryFileLock, Backend, FileId, FileLockError,
+    FileLockMode,
+};
+
+/// How often the lock is probed while waiting for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Block until the file at `path` could be locked exclusively, without locking it, or until
+/// `timeout` elapses.
+///
+/// Returns whether the lock became available. A coordinator can call this to report that it is
+/// waiting for readers to finish, and how many with [`count_readers`], before it actually fences
+/// anyone out, then lock the file with [`lock_when_exclusive_available`]. The lock may of course
+/// be taken by someone else in between.
+///
+/// The availability is probed by briefly acquiring the lock and releasing it right away, so a
+/// process trying to lock the file at that very moment may fail to. A file which doesn't exist
+/// is available.
+///
+/// Example:
+/// ```
+/// use std::time::Duration;
+/// use advisory_lock::{
+///     count_readers, lock_when_exclusive_available, wait_until_exclusive_available,
+/// };
+///
+/// # std::fs::write("coordinated.lock", b"")?;
+/// if !wait_until_exclusive_available("coordinated.lock", Duration::from_millis(100))? {
+///     match count_readers("coordinated.lock")? {
+///         Some(readers) => eprintln!("waiting for {} readers of coordinated.lock", readers),
+///         None => eprintln!("waiting for the readers of coordinated.lock to finish"),
+///     }
+/// }
+/// let file = lock_when_exclusive_available("coordinated.lock", Duration::from_secs(60))?;
+/// assert!(file.is_some());
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("coordinated.lock")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`count_readers`]: fn.count_readers.html
+/// [`lock_when_exclusive_available`]: fn.lock_when_exclusive_available.html
+pub fn wait_until_exclusive_available<P: AsRef<Path>>(
+    path: P,
+    timeout: Duration,
+) -> Result<bool, FileLockError> {
+    let path = path.as_ref();
+    let available = poll(timeout, &SystemClock, || probe(path))?;
+    Ok(available.is_some())
+}
+
+/// Wait until the file at `path` can be locked exclusively, and lock it, or until `timeout`
+/// elapses.
+///
+/// Returns the locked file, which is created if needed, or `None` if the timeout elapsed. See
+/// [`wait_until_exclusive_available`]. Where open files can't be identified, a `File` can't
+/// carry its lock, so this fails with `Unsupported`.
+///
+/// [`wait_until_exclusive_available`]: fn.wait_until_exclusive_available.html
+pub fn lock_when_exclusive_available<P: AsRef<Path>>(
+    path: P,
+    timeout: Duration,
+) -> Result<Option<File>, FileLockError> {
+    let path = path.as_ref();
+    poll(timeout, &SystemClock, || {
+        open_locked_by(path, true, |file| {
+            AdvisoryFileLock::try_lock(file, FileLockMode::Exclusive)
+        })
+        .map(Some)
+    })
+}
+
+/// Return the number of shared locks held on the file at `path`, or `None` if the system can't
+/// tell.
+///
+/// Only Linux can enumerate the locks of a file, through `/proc/locks`: there every shared
+/// `flock`, record and open file description lock counts, including those of this process. A
+/// lock shared by several handles, through `dup` or `fork`, counts once. Other systems, and the
+/// [`Emulated`] backend, return `None`. The file isn't opened, so this releases no record lock.
+///
+/// See [`wait_until_exclusive_available`] for an example.
+///
+/// [`Emulated`]: enum.Backend.html#variant.Emulated
+/// [`wait_until_exclusive_available`]: fn.wait_until_exclusive_available.html
+pub fn count_readers<P: AsRef<Path>>(path: P) -> Result<Option<usize>, FileLockError> {
+    let file_id = FileId::of_path(path).map_err(FileLockError::Io)?;
+    #[cfg(target_os = "linux")]
+    if crate::default_backend() != crate::Backend::Emulated {
+        return crate::sys::count_shared(file_id).map(Some);
+    }
+    let _ = file_id;
+    Ok(None)
+}
+
+/// Return whether the file at `path` is locked in a way which keeps it from being locked in
+/// `file_lock_mode` right now.
+///
+/// This is meant for status commands and health checks; the answer may of course be outdated
+/// as soon as it is returned. The file is queried through a handle of its own, so a lock held by
+/// this process through another handle counts as well.
+///
+/// On Linux, nothing is acquired: locks are queried with `fcntl(F_GETLK)` and `/proc/locks`,
+/// see [`lock_holder`]. Other systems can't query `flock` and `LockFileEx` locks, so the lock is
+/// briefly acquired and released right away instead, which may make a process trying to lock
+/// the file at that very moment fail to. The same goes for the [`Emulated`] backend everywhere,
+/// as the kernel doesn't know about its locks.
+///
+/// ## Notes
+///
+/// With the [`Fcntl`] backend, closing the handle used for the query releases the record locks
+/// of this process on the file, as closing any handle to it does.
+///
+/// Example:
+/// ```
+/// use std::fs::File;
+/// use advisory_lock::{is_locked, AdvisoryFileLock, FileLockMode};
+///
+/// let file = File::create("status.txt")?;
+/// AdvisoryFileLock::lock(&file, FileLockMode::Shared)?;
+/// assert!(!is_locked("status.txt", FileLockMode::Shared)?);
+/// assert!(is_locked("status.txt", FileLockMode::Exclusive)?);
+/// #
+/// # drop(file);
+/// # std::fs::remove_file("status.txt")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`lock_holder`]: fn.lock_holder.html
+/// [`Emulated`]: enum.Backend.html#variant.Emulated
+/// [`Fcntl`]: enum.Backend.html#variant.Fcntl
+pub fn is_locked<P: AsRef<Path>>(
+    path: P,
+    file_lock_mode: FileLockMode,
+) -> Result<bool, FileLockError> {
+    is_locked_with(crate::default_backend(), path.as_ref(), file_lock_mode)
+}
+
+fn is_locked_with(
+    backend: Backend,
+    path: &Path,
+    file_lock_mode: FileLockMode,
+) -> Result<bool, FileLockError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(FileLockError::Io)?;
+    let handle = crate::sys::handle(&file);
+
+    // The markers of the emulated backend are invisible to the kernel, so only an attempt can
+    // tell whether one is held.
+    #[cfg(unix)]
+    if backend != Backend::Emulated {
+        if crate::lock_holder(&file, file_lock_mode)?.is_some() {
+            return Ok(true);
+        }
+        // Record locks are all visible to `F_GETLK`, and Linux lists the others.
+        if cfg!(target_os = "linux") || backend != Backend::Native {
+            return Ok(false);
+        }
+    }
+
+    match lock_handle_with(backend, handle, file_lock_mode, true) {
+        Ok(()) => unlock_handle_with(backend, handle).map(|()| false),
+        Err(FileLockError::AlreadyLocked) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+/// Return whether the file at `path` could be locked exclusively right now.
+fn probe(path: &Path) -> Result<Option<()>, FileLockError> {
+    match open_locked_with(path, FileLockMode::Exclusive, false, true) {
+        Ok(file) => AdvisoryFileLock::unlock(&file).map(Some),
+        Err(FileLockError::Io(err)) if err.kind() == io::ErrorKind::NotFound => Ok(Some(())),
+        Err(err) => Err(err),
+    }
+}
+
+/// Call `attempt` until it returns a value or `timeout` elapses, treating `AlreadyLocked` as a
+/// reason to try again.
+fn poll<T>(
+    timeout: Duration,
+    clock: &dyn Clock,
+    mut attempt: impl FnMut() -> Result<Option<T>, FileLockError>,
+) -> Result<Option<T>, FileLockError> {
+    if let Some(deadline) = clock.now().checked_add(timeout) {
+        return poll_until(deadline, clock, attempt);
+    }
+    // A timeout too long to be represented never elapses.
+    loop {
+        match attempt() {
+            Err(FileLockError::AlreadyLocked) => clock.sleep(POLL_INTERVAL),
+            result => return result,
+        }
+    }
+}
+
+/// Call `attempt` until it returns a value or `deadline` passes, treating `AlreadyLocked` as a
+/// reason to try again.
+///
+/// `attempt` is called at least once, even if the deadline has already passed.
+pub(crate) fn poll_until<T>(
+    deadline: Instant,
+    clock: &dyn Clock,
+    mut attempt: impl FnMut() -> Result<Option<T>, FileLockError>,
+) -> Result<Option<T>, FileLockError> {
+    loop {
+        match attempt() {
+            Err(FileLockError::AlreadyLocked) => {}
+            result => return result,
+        }
+
+        let now = clock.now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        clock.sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::env::temp_dir;
+
+    #[test]
+    fn waits_without_acquiring() {
+        let mut test_file = temp_dir();
+        test_file.push("wait_exclusive");
+        let reader = File::create(&test_file).unwrap();
+        AdvisoryFileLock::lock(&reader, FileLockMode::Shared).unwrap();
+
+        let clock = ManualClock::new();
+        let timeout = Duration::from_secs(5);
+        let available = poll(timeout, &clock, || probe(&test_file)).unwrap();
+        assert!(available.is_none());
+        assert_eq!(clock.elapsed(), timeout);
+        let missing = test_file.with_extension("missing");
+        assert!(poll(timeout, &clock, || probe(&missing)).unwrap().is_some());
+        assert!(poll(Duration::MAX, &clock, || probe(&missing))
+            .unwrap()
+            .is_some());
+        if cfg!(target_os = "linux") {
+            assert_eq!(count_readers(&test_file).unwrap(), Some(1));
+        }
+
+        AdvisoryFileLock::unlock(&reader).unwrap();
+        assert!(wait_until_exclusive_available(&test_file, timeout).unwrap());
+        // Waiting didn't take the lock.
+        AdvisoryFileLock::try_lock(&reader, FileLockMode::Shared).unwrap();
+        AdvisoryFileLock::unlock(&reader).unwrap();
+
+        let writer = lock_when_exclusive_available(&test_file, timeout)
+            .unwrap()
+            .unwrap();
+        assert!(AdvisoryFileLock::try_lock(&reader, FileLockMode::Shared).is_err());
+
+        assert!(is_locked(&test_file, FileLockMode::Shared).unwrap());
+        drop(writer);
+        assert!(!is_locked(&test_file, FileLockMode::Exclusive).unwrap());
+
+        drop(reader);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn emulated_locks_are_seen() {
+        let mut test_file = temp_dir();
+        test_file.push("wait_is_locked_emulated");
+        let holder = File::create(&test_file).unwrap();
+        let handle = crate::sys::handle(&holder);
+
+        crate::backend::lock_file(Backend::Emulated, handle, FileLockMode::Exclusive, true)
+            .unwrap();
+        assert!(is_locked_with(Backend::Emulated, &test_file, FileLockMode::Shared).unwrap());
+        // The kernel knows nothing of it.
+        assert!(!is_locked_with(Backend::Native, &test_file, FileLockMode::Shared).unwrap());
+        crate::backend::unlock_file(Backend::Emulated, handle).unwrap();
+        assert!(!is_locked_with(Backend::Emulated, &test_file, FileLockMode::Exclusive).unwrap());
+
+        drop(holder);
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+//! WASI has no file locks, so whole-file locks are emulated with sidecar lock files.
+use std::io::{Error, ErrorKind};
+use std::os::fd::{AsRawFd, RawFd};
+use std::process::{Child, Command};
+
+use crate::emulated;
+use crate::{
+    lock_handle, unlock_handle, AdvisoryFileLock, FileId, FileLockError, FileLockMode,
+    LockMechanism,
+};
+
+pub(crate) type Handle = RawFd;
+
+/// The types which expose a [`Handle`] safely.
+pub(crate) use std::os::fd::AsFd as AsHandle;
+
+pub(crate) fn handle<T: AsHandle + ?Sized>(file: &T) -> Handle {
+    file.as_fd().as_raw_fd()
+}
+
+impl<T: AsHandle + ?Sized> AdvisoryFileLock for T {
+    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_handle(handle(self), file_lock_mode, false)
+    }
+
+    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
+        lock_handle(handle(self), file_lock_mode, true)
+    }
+
+    fn unlock(&self) -> Result<(), FileLockError> {
+        unlock_handle(handle(self))
+    }
+}
+
+/// The arguments of an emulated lock, which are only its mode.
+#[derive(Copy, Clone)]
+pub(crate) struct PreparedLock {
+    file_lock_mode: FileLockMode,
+}
+
+impl PreparedLock {
+    pub(crate) fn new(file_lock_mode: FileLockMode) -> PreparedLock {
+        PreparedLock { file_lock_mode }
+    }
+}
+
+pub(crate) fn os_error(code: libc::c_int) -> FileLockError {
+    match code {
+        libc::EINTR => FileLockError::Interrupted,
+        libc::ENOLCK => FileLockError::NoLockResources,
+        libc::EDEADLK => FileLockError::DeadlockDetected,
+        libc::EBADF => FileLockError::InvalidHandle,
+        libc::ENOTSUP => FileLockError::Unsupported,
+        _ => FileLockError::Io(Error::from_raw_os_error(code)),
+    }
+}
+
+pub(crate) fn lock_file(
+    raw_fd: RawFd,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    emulated::lock(raw_fd, file_lock_mode, immediate)
+}
+
+pub(crate) fn lock_prepared(
+    raw_fd: RawFd,
+    prepared: &PreparedLock,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    emulated::lock(raw_fd, prepared.file_lock_mode, immediate)
+}
+
+pub(crate) fn unlock_prepared(raw_fd: RawFd, _: &PreparedLock) -> Result<(), FileLockError> {
+    emulated::unlock(raw_fd)
+}
+
+pub(crate) fn unlock_file(raw_fd: RawFd) -> Result<(), FileLockError> {
+    emulated::unlock(raw_fd)
+}
+
+/// Byte ranges can't be emulated by sidecar files without knowing which ranges overlap.
+pub(crate) fn lock_range(
+    _: RawFd,
+    _: FileLockMode,
+    _: bool,
+    _: u64,
+    _: u64,
+) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn unlock_range(_: RawFd, _: u64, _: u64) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) struct PreparedRange;
+
+impl PreparedRange {
+    pub(crate) fn new(_: FileLockMode, _: u64, _: u64) -> Result<PreparedRange, FileLockError> {
+        Ok(PreparedRange)
+    }
+}
+
+pub(crate) fn lock_range_prepared(
+    _: RawFd,
+    _: &PreparedRange,
+    _: bool,
+) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn unlock_range_prepared(_: RawFd, _: &PreparedRange) -> Result<(), FileLockError> {
+    Err(FileLockError::Unsupported)
+}
+
+pub(crate) fn lock_mechanism(_: RawFd) -> LockMechanism {
+    LockMechanism::SidecarFile
+}
+
+fn stat(raw_fd: RawFd) -> Result<libc::stat, Error> {
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe { libc::fstat(raw_fd, &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+pub(crate) fn file_id(raw_fd: RawFd) -> Result<FileId, Error> {
+    let stat = stat(raw_fd)?;
+    Ok(FileId {
+        device: stat.st_dev,
+        index: stat.st_ino,
+    })
+}
+
+/// Paths aren't tracked for open files here.
+#[cfg(feature = "diagnostics")]
+pub(crate) fn path(_: RawFd) -> Result<std::path::PathBuf, Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+/// Return whether `raw_fd` is a regular file or a directory, as on Unix.
+pub(crate) fn is_lockable(raw_fd: RawFd) -> Result<bool, Error> {
+    let file_type = stat(raw_fd)?.st_mode & libc::S_IFMT;
+    Ok(file_type == libc::S_IFREG || file_type == libc::S_IFDIR)
+}
+
+/// WASI has no way to look processes up, so every process is assumed to be running.
+pub(crate) fn process_alive(_: u32) -> bool {
+    true
+}
+
+/// Run `callback` when the program exits normally.
+pub(crate) fn at_exit(callback: extern "C" fn()) {
+    unsafe { libc::atexit(callback) };
+}
+
+/// WASI programs can't spawn processes, so there is nothing to inherit descriptors.
+pub(crate) fn set_inheritable(_: RawFd, _: bool) -> Result<(), Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+pub(crate) fn spawn_inheriting(_: Command, _: RawFd, _: &str) -> Result<Child, Error> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+pub(crate) fn is_inheritable(_: RawFd) -> Result<bool, Error> {
+    Ok(false)
+}
+
+/// WASI has no `dup`, so distinct descriptors are distinct descriptions.
+pub(crate) fn same_description(a: RawFd, b: RawFd) -> Result<bool, Error> {
+    Ok(a == b)
+}
@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::sync::thread::{self, JoinHandle};
+use crate::sync::{Arc, AtomicBool, Condvar, Mutex, Ordering};
+use crate::{sys, unlock_handle, FileLockGuard};
+
+/// What a [`Watchdog`] does with the lock once its guard outlived the hold limit.
+///
+/// [`Watchdog`]: struct.Watchdog.html
+#[derive(Clone, Debug)]
+pub enum HoldAction {
+    /// Only run the callback, leaving the lock held.
+    Notify,
+    /// Run the callback and release the lock, letting the other processes proceed.
+    Release,
+    /// Run the callback, write a marker file at the given path, and release the lock.
+    ///
+    /// The marker is written before the lock is released, so the next process to acquire it
+    /// knows that the previous holder was cut off, possibly halfway through an update.
+    Poison(PathBuf),
+}
+
+/// A timer which fires if a guard is held for longer than a limit, created by
+/// [`FileLockGuard::watchdog`].
+///
+/// Dropping the watchdog cancels it. It borrows the guard, so it can't outlive the lock it
+/// watches.
+///
+/// A holder whose lock was forcibly released keeps running, and must not modify the protected
+/// data anymore: check [`expired`] before committing anything, in the manner of a fencing token.
+///
+/// [`FileLockGuard::watchdog`]: struct.FileLockGuard.html#method.watchdog
+/// [`expired`]: #method.expired
+#[must_use = "if unused the watchdog is immediately cancelled"]
+#[derive(Debug)]
+pub struct Watchdog<'g> {
+    state: Arc<State>,
+    timer: Option<JoinHandle<()>>,
+    guard: std::marker::PhantomData<&'g ()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: Mutex<bool>,
+    wakeup: Condvar,
+    expired: AtomicBool,
+}
+
+/// The handle, only passed to the operating system while the watched guard is alive.
+struct SendHandle(sys::Handle);
+
+// The handle is only passed to the operating system, never dereferenced.
+unsafe impl Send for SendHandle {}
+
+impl<'a> FileLockGuard<'a> {
+    /// Start a watchdog which calls `on_expiry` and applies `action` if the guard is still held
+    /// after `limit`, so a hung holder can't wedge every other process indefinitely.
+    ///
+    /// Example:
+    /// ```
+    /// use std::fs::File;
+    /// use std::time::Duration;
+    /// use advisory_lock::{FileLockGuard, FileLockMode, HoldAction};
+    ///
+    /// let file = File::create("watched.lock")?;
+    /// let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive)?;
+    /// let watchdog = guard.watchdog(
+    ///     Duration::from_secs(30),
+    ///     HoldAction::Poison("watched.lock.poisoned".into()),
+    ///     || eprintln!("held watched.lock for 30 seconds, releasing it"),
+    /// );
+    /// // ... do the work ...
+    /// assert!(!watchdog.expired());
+    /// drop(watchdog);
+    /// drop(guard);
+    /// #
+    /// # drop(file);
+    /// # std::fs::remove_file("watched.lock")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn watchdog<F>(&self, limit: Duration, action: HoldAction, on_expiry: F) -> Watchdog<'_>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let state = Arc::new(State::default());
+        let handle = SendHandle(sys::handle(self.file()));
+        let timer = {
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                let handle = handle;
+                // A limit too long to be represented never expires.
+                let deadline = Instant::now().checked_add(limit);
+                let mut cancelled = state.cancelled.lock().unwrap_or_else(|e| e.into_inner());
+                while !*cancelled {
+                    let remaining =
+                        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                    cancelled = match remaining {
+                        Some(Duration::ZERO) => {
+                            state.expired.store(true, Ordering::SeqCst);
+                            on_expiry();
+                            expire(handle.0, &action);
+                            return;
+                        }
+                        Some(remaining) => {
+                            state
+                                .wakeup
+                                .wait_timeout(cancelled, remaining)
+                                .unwrap_or_else(|e| e.into_inner())
+                                .0
+                        }
+                        None => state
+                            .wakeup
+                            .wait(cancelled)
+                            .unwrap_or_else(|e| e.into_inner()),
+                    };
+                }
+            })
+        };
+
+        Watchdog {
+            state,
+            timer: Some(timer),
+            guard: std::marker::PhantomData,
+        }
+    }
+}
+
+fn expire(handle: sys::Handle, action: &HoldAction) {
+    match action {
+        HoldAction::Notify => return,
+        HoldAction::Release => {}
+        HoldAction::Poison(marker) => {
+            if let Err(err) = std::fs::write(marker, "the lock was held for too long") {
+                eprintln!(
+                    "advisory-lock: failed to write poison marker {}: {}",
+                    marker.display(),
+                    err
+                );
+            }
+        }
+    }
+    let _ = unlock_handle(handle);
+}
+
+impl Watchdog<'_> {
+    /// Return whether the hold limit was exceeded.
+    ///
+    /// Once this returns `true`, the lock may have been released by the watchdog.
+    pub fn expired(&self) -> bool {
+        self.state.expired.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Watchdog<'_> {
+    fn drop(&mut self) {
+        *self
+            .state
+            .cancelled
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = true;
+        self.state.wakeup.notify_one();
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AdvisoryFileLock, FileLockMode};
+    use std::env::temp_dir;
+    use std::fs::File;
+
+    #[test]
+    fn hung_holders_are_cut_off() {
+        let mut test_file = temp_dir();
+        test_file.push("watchdog_hung");
+        let marker = test_file.with_extension("poisoned");
+        let _ = std::fs::remove_file(&marker);
+        let file = File::create(&test_file).unwrap();
+        let other = File::open(&test_file).unwrap();
+        let guard = FileLockGuard::lock(&file, FileLockMode::Exclusive).unwrap();
+
+        let patient = guard.watchdog(Duration::from_secs(60), HoldAction::Release, || {
+            panic!("cancelled watchdogs don't fire")
+        });
+        drop(patient);
+        let eternal = guard.watchdog(Duration::MAX, HoldAction::Release, || {
+            panic!("watchdogs without a deadline don't fire")
+        });
+        drop(eternal);
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let watchdog = {
+            let fired = Arc::clone(&fired);
+            guard.watchdog(
+                Duration::from_millis(20),
+                HoldAction::Poison(marker.clone()),
+                move || fired.store(true, Ordering::SeqCst),
+            )
+        };
+        assert!(AdvisoryFileLock::try_lock(&other, FileLockMode::Shared).is_err());
+        AdvisoryFileLock::lock(&other, FileLockMode::Shared).unwrap();
+        assert!(watchdog.expired());
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(marker.exists());
+
+        drop(watchdog);
+        drop(guard);
+        drop((file, other));
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::codec::{Codec, Json};
+use crate::sync::{self, Mutex};
+use crate::{FileId, FileLockError, TypedLockFile};
+
+/// What the watcher knows about the file it last loaded.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct Stamp {
+    file_id: FileId,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Result<Stamp, FileLockError> {
+        let metadata = std::fs::metadata(path).map_err(FileLockError::Io)?;
+        Ok(Stamp {
+            file_id: FileId::of_path(path).map_err(FileLockError::Io)?,
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+}
+
+/// A configuration file which is reloaded whenever it changes.
+///
+/// Changes are detected by polling the file's identity, modification time and length, which
+/// doesn't take any lock. The file is then read under a shared lock, so writers using the
+/// crate's exclusive lock (e.g. [`TypedLockFile::update`] or [`replace_atomically`]) are never
+/// observed halfway through.
+///
+/// A change which keeps the length of the file and happens within the resolution of its
+/// modification time is only noticed at the next change. Writers using [`replace_atomically`]
+/// replace the file on every write and are always noticed.
+///
+/// Like [`TypedLockFile`], the file is JSON unless another codec is given to [`with_codec`].
+///
+/// This type is only available with the `json` feature.
+///
+/// Example:
+/// ```
+/// use std::collections::BTreeMap;
+/// use advisory_lock::{ConfigWatcher, TypedLockFile};
+///
+/// let file = TypedLockFile::<BTreeMap<String, u32>>::new("watched.json");
+/// file.update(|config| config.insert("workers".to_owned(), 4))?;
+///
+/// let watcher = ConfigWatcher::<BTreeMap<String, u32>>::new("watched.json")?;
+/// assert_eq!(watcher.current()["workers"], 4);
+///
+/// file.update(|config| config.insert("workers".to_owned(), 16))?;
+/// watcher.reload_if_changed()?;
+/// assert_eq!(watcher.current()["workers"], 16);
+/// #
+/// # std::fs::remove_file("watched.json")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// [`TypedLockFile`]: struct.TypedLockFile.html
+/// [`TypedLockFile::update`]: struct.TypedLockFile.html#method.update
+/// [`replace_atomically`]: fn.replace_atomically.html
+/// [`with_codec`]: #method.with_codec
+#[derive(Debug)]
+pub struct ConfigWatcher<T, C = Json> {
+    file: TypedLockFile<T, C>,
+    state: Mutex<(Arc<T>, Stamp)>,
+}
+
+impl<T> ConfigWatcher<T>
+where
+    Json: Codec<T>,
+{
+    /// Load the JSON configuration at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<ConfigWatcher<T>, FileLockError> {
+        ConfigWatcher::with_codec(path, Json)
+    }
+}
+
+impl<T, C> ConfigWatcher<T, C>
+where
+    C: Codec<T>,
+{
+    /// Load the configuration at `path`, stored with `codec`.
+    pub fn with_codec<P: Into<PathBuf>>(
+        path: P,
+        codec: C,
+    ) -> Result<ConfigWatcher<T, C>, FileLockError> {
+        let file = TypedLockFile::with_codec(path, codec);
+        let stamp = Stamp::of(file.path())?;
+        let value = file.read()?;
+        Ok(ConfigWatcher {
+            file,
+            state: Mutex::new((Arc::new(value), stamp)),
+        })
+    }
+
+    /// Return the path of the configuration file.
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Return the most recently loaded configuration.
+    pub fn current(&self) -> Arc<T> {
+        Arc::clone(&sync::lock(&self.state).0)
+    }
+
+    /// Reload the configuration if the file changed since it was last loaded.
+    ///
+    /// Returns whether the configuration was reloaded. On error, the previous configuration is
+    /// kept.
+    pub fn reload_if_changed(&self) -> Result<bool, FileLockError> {
+        let stamp = Stamp::of(self.path())?;
+        if sync::lock(&self.state).1 == stamp {
+            return Ok(false);
+        }
+
+        // The stamp is taken before reading, so a change racing with the read is picked up
+        // again by the next poll rather than missed.
+        let value = self.file.read()?;
+        *sync::lock(&self.state) = (Arc::new(value), stamp);
+        Ok(true)
+    }
+}
+
+impl<T, C> ConfigWatcher<T, C>
+where
+    T: Send + Sync + 'static,
+    C: Codec<T> + Send + Sync + 'static,
+{
+    /// Spawn a thread reloading the configuration every `interval`.
+    ///
+    /// The thread exits once every other reference to the watcher has been dropped. Errors are
+    /// ignored; the previous configuration stays current until the file can be loaded again.
+    pub fn spawn_poller(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let watcher: Weak<Self> = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match watcher.upgrade() {
+                Some(watcher) => {
+                    let _ = watcher.reload_if_changed();
+                }
+                None => return,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_locked;
+    use std::env::temp_dir;
+
+    #[test]
+    fn poller_picks_up_changes() {
+        let mut test_file = temp_dir();
+        test_file.push("watcher_poller.json");
+        write_locked(&test_file, "1").unwrap();
+
+        let watcher = Arc::new(ConfigWatcher::<u32>::new(&test_file).unwrap());
+        assert!(!watcher.reload_if_changed().unwrap());
+        let poller = watcher.spawn_poller(Duration::from_millis(5));
+
+        write_locked(&test_file, "22").unwrap();
+        for _ in 0..400 {
+            if *watcher.current() == 22 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*watcher.current(), 22);
+
+        write_locked(&test_file, "not json").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*watcher.current(), 22);
+
+        drop(watcher);
+        poller.join().unwrap();
+        std::fs::remove_file(&test_file).unwrap();
+    }
+}
@@ -1,59 +1,86 @@
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::io;
+use std::os::windows::ffi::OsStringExt;
 use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::path::PathBuf;
+use std::process::{Child, Command};
 
 use winapi::{
     shared::{
-        minwindef::TRUE,
+        minwindef::{DWORD, FALSE, TRUE},
         ntdef::NULL,
-        winerror::{ERROR_LOCK_VIOLATION, ERROR_NOT_LOCKED},
+        winerror::{
+            ERROR_ACCESS_DENIED, ERROR_INVALID_FUNCTION, ERROR_INVALID_HANDLE, ERROR_LOCKED,
+            ERROR_LOCK_VIOLATION, ERROR_NOT_LOCKED, ERROR_NOT_SUPPORTED, ERROR_NO_SYSTEM_RESOURCES,
+            ERROR_OPERATION_ABORTED,
+        },
     },
     um::{
         errhandlingapi::GetLastError,
-        fileapi::{LockFileEx, UnlockFileEx},
+        fileapi::{
+            GetFileInformationByHandle, GetFileType, GetFinalPathNameByHandleW, LockFileEx,
+            UnlockFileEx, BY_HANDLE_FILE_INFORMATION,
+        },
+        handleapi::{CloseHandle, GetHandleInformation, SetHandleInformation},
         minwinbase::{
             OVERLAPPED_u, OVERLAPPED_u_s, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
-            OVERLAPPED,
+            OVERLAPPED, STILL_ACTIVE,
         },
+        processthreadsapi::{GetExitCodeProcess, OpenProcess},
+        winbase::{FILE_TYPE_DISK, FILE_TYPE_UNKNOWN, HANDLE_FLAG_INHERIT},
+        winnt::PROCESS_QUERY_LIMITED_INFORMATION,
     },
 };
 
-use crate::{AdvisoryFileLock, FileLockError, FileLockMode};
+use crate::named_mutex;
+use crate::sync::{self, Mutex, MutexGuard};
+use crate::{
+    lock_handle, lock_region, unlock_handle, AdvisoryFileLock, FileId, FileLockError, FileLockMode,
+    LockMechanism, LockRegion,
+};
 
-impl AdvisoryFileLock for File {
-    fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        lock_file(self.as_raw_handle(), file_lock_mode, false)
-    }
+sync::statics! {
+    /// The region each whole-file lock of this process was acquired with, keyed by file handle,
+    /// so it is released with that region whatever the current setting.
+    ///
+    /// File handles are stored as integers: they are only compared, never dereferenced.
+    static REGIONS: Mutex<BTreeMap<usize, LockRegion>> = Mutex::new(BTreeMap::new());
+}
 
-    fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        lock_file(self.as_raw_handle(), file_lock_mode, true)
-    }
+fn regions() -> MutexGuard<'static, BTreeMap<usize, LockRegion>> {
+    sync::lock(&REGIONS)
+}
 
-    fn unlock(&self) -> Result<(), FileLockError> {
-        unlock_file(self.as_raw_handle())
-    }
+pub(crate) type Handle = RawHandle;
+
+/// The types which expose a [`Handle`] safely.
+pub(crate) use std::os::windows::io::AsHandle;
+
+pub(crate) fn handle<T: AsHandle + ?Sized>(file: &T) -> Handle {
+    file.as_handle().as_raw_handle()
 }
 
-impl AdvisoryFileLock for RawHandle {
+impl<T: AsHandle + ?Sized> AdvisoryFileLock for T {
     fn lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        lock_file(*self, file_lock_mode, false)
+        lock_handle(handle(self), file_lock_mode, false)
     }
 
     fn try_lock(&self, file_lock_mode: FileLockMode) -> Result<(), FileLockError> {
-        lock_file(*self, file_lock_mode, true)
+        lock_handle(handle(self), file_lock_mode, true)
     }
 
     fn unlock(&self) -> Result<(), FileLockError> {
-        unlock_file(*self)
+        unlock_handle(handle(self))
     }
 }
 
-fn create_overlapped() -> OVERLAPPED {
+fn create_overlapped(offset: u64) -> OVERLAPPED {
     let overlapped = unsafe {
         let mut overlapped = std::mem::zeroed::<OVERLAPPED_u>();
         *overlapped.s_mut() = OVERLAPPED_u_s {
-            Offset: u32::MAX,
-            OffsetHigh: u32::MAX,
+            Offset: offset as DWORD,
+            OffsetHigh: (offset >> 32) as DWORD,
         };
         overlapped
     };
@@ -66,17 +93,86 @@ fn create_overlapped() -> OVERLAPPED {
     }
 }
 
-fn lock_file(
+/// The arguments of `LockFileEx` and `UnlockFileEx`, built once and reused for every call.
+#[derive(Copy, Clone)]
+pub(crate) struct PreparedLock {
+    flags: DWORD,
+    overlapped: OVERLAPPED,
+    len_low: DWORD,
+    len_high: DWORD,
+    /// The region standing in for the whole file, or `None` for a byte range.
+    region: Option<LockRegion>,
+}
+
+impl PreparedLock {
+    /// Prepare a lock of the whole file, which is emulated by a lock of the configured region.
+    pub(crate) fn new(file_lock_mode: FileLockMode) -> PreparedLock {
+        PreparedLock::for_region(file_lock_mode, lock_region())
+    }
+
+    /// Prepare a lock of the whole file, which is emulated by a lock of `region`.
+    pub(crate) fn for_region(file_lock_mode: FileLockMode, region: LockRegion) -> PreparedLock {
+        let (offset, len) = region.bounds();
+        PreparedLock {
+            region: Some(region),
+            ..PreparedLock::with_range(file_lock_mode, offset, len)
+        }
+    }
+
+    pub(crate) fn with_range(file_lock_mode: FileLockMode, offset: u64, len: u64) -> PreparedLock {
+        let mut flags = 0;
+        if file_lock_mode == FileLockMode::Exclusive {
+            flags |= LOCKFILE_EXCLUSIVE_LOCK;
+        }
+
+        PreparedLock {
+            flags,
+            overlapped: create_overlapped(offset),
+            len_low: len as DWORD,
+            len_high: (len >> 32) as DWORD,
+            region: None,
+        }
+    }
+}
+
+/// Lock the whole file, falling back to a named mutex on file systems without `LockFileEx`.
+pub(crate) fn lock_file(
     raw_handle: RawHandle,
     file_lock_mode: FileLockMode,
     immediate: bool,
 ) -> Result<(), FileLockError> {
-    let mut overlapped = create_overlapped();
+    lock_prepared(raw_handle, &PreparedLock::new(file_lock_mode), immediate)
+}
 
-    let mut flags = 0;
-    if file_lock_mode == FileLockMode::Exclusive {
-        flags |= LOCKFILE_EXCLUSIVE_LOCK;
+/// Lock the whole file with prepared arguments, falling back to a named mutex like `lock_file`.
+pub(crate) fn lock_prepared(
+    raw_handle: RawHandle,
+    prepared: &PreparedLock,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    if named_mutex::holds(raw_handle) {
+        return named_mutex::lock(raw_handle, immediate);
     }
+    match lock_file_ex(raw_handle, prepared, immediate) {
+        Err(FileLockError::Unsupported) => named_mutex::lock(raw_handle, immediate),
+        Ok(()) => {
+            if let Some(region) = prepared.region {
+                regions().insert(raw_handle as usize, region);
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn lock_file_ex(
+    raw_handle: RawHandle,
+    prepared: &PreparedLock,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    let mut overlapped = prepared.overlapped;
+
+    let mut flags = prepared.flags;
     if immediate {
         flags |= LOCKFILE_FAIL_IMMEDIATELY;
     }
@@ -86,32 +182,81 @@ fn lock_file(
             raw_handle as *mut winapi::ctypes::c_void,
             flags,
             0,
-            1,
-            0,
+            prepared.len_low,
+            prepared.len_high,
             &mut overlapped,
         )
     };
     if result != TRUE {
-        return match unsafe { GetLastError() } {
-            ERROR_LOCK_VIOLATION => Err(FileLockError::AlreadyLocked),
-            raw_error => Err(FileLockError::Io(io::Error::from_raw_os_error(
-                raw_error as i32,
-            ))),
-        };
+        return Err(lock_error(unsafe { GetLastError() }));
     }
 
     Ok(())
 }
 
-fn unlock_file(raw_handle: RawHandle) -> Result<(), FileLockError> {
-    let mut overlapped = create_overlapped();
+/// Map the error code of a failed `LockFileEx` call.
+///
+/// `LOCKFILE_FAIL_IMMEDIATELY` reports contention as `ERROR_LOCK_VIOLATION`, but some
+/// redirectors and file system filters report it as `ERROR_LOCKED`.
+fn lock_error(code: DWORD) -> FileLockError {
+    match code {
+        ERROR_LOCK_VIOLATION | ERROR_LOCKED => FileLockError::AlreadyLocked,
+        ERROR_ACCESS_DENIED => FileLockError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "locking requires the file to be open for reading or writing",
+        )),
+        code => os_error(code),
+    }
+}
+
+/// Map the error code of a failed lock or unlock call.
+pub(crate) fn os_error(code: DWORD) -> FileLockError {
+    match code {
+        ERROR_OPERATION_ABORTED => FileLockError::Interrupted,
+        ERROR_NOT_LOCKED => FileLockError::NotLocked,
+        ERROR_NO_SYSTEM_RESOURCES => FileLockError::NoLockResources,
+        ERROR_INVALID_HANDLE => FileLockError::InvalidHandle,
+        ERROR_INVALID_FUNCTION | ERROR_NOT_SUPPORTED => FileLockError::Unsupported,
+        _ => FileLockError::Io(io::Error::from_raw_os_error(code as i32)),
+    }
+}
+
+pub(crate) fn unlock_file(raw_handle: RawHandle) -> Result<(), FileLockError> {
+    unlock_prepared(raw_handle, &PreparedLock::new(FileLockMode::Shared))
+}
+
+/// Unlock the whole file with prepared arguments, or the named mutex standing in for its lock.
+///
+/// A lock is released with the region it was acquired with, even if the configured region
+/// changed since.
+pub(crate) fn unlock_prepared(
+    raw_handle: RawHandle,
+    prepared: &PreparedLock,
+) -> Result<(), FileLockError> {
+    if let Some(result) = named_mutex::unlock(raw_handle) {
+        return result;
+    }
+    let acquired = regions().get(&(raw_handle as usize)).copied();
+    match acquired {
+        Some(region) if prepared.region != Some(region) => unlock_file_ex(
+            raw_handle,
+            &PreparedLock::for_region(FileLockMode::Shared, region),
+        )?,
+        _ => unlock_file_ex(raw_handle, prepared)?,
+    }
+    regions().remove(&(raw_handle as usize));
+    Ok(())
+}
+
+fn unlock_file_ex(raw_handle: RawHandle, prepared: &PreparedLock) -> Result<(), FileLockError> {
+    let mut overlapped = prepared.overlapped;
 
     let result = unsafe {
         UnlockFileEx(
             raw_handle as *mut winapi::ctypes::c_void,
             0,
-            1,
-            0,
+            prepared.len_low,
+            prepared.len_high,
             &mut overlapped,
         )
     };
@@ -119,13 +264,248 @@ fn unlock_file(raw_handle: RawHandle) -> Result<(), FileLockError> {
     if result == TRUE {
         Ok(())
     } else {
-        let raw_error = unsafe { GetLastError() };
-        if raw_error == ERROR_NOT_LOCKED {
-            Ok(())
-        } else {
-            Err(FileLockError::Io(io::Error::from_raw_os_error(
-                raw_error as i32,
-            )))
+        Err(os_error(unsafe { GetLastError() }))
+    }
+}
+
+pub(crate) fn lock_range(
+    raw_handle: RawHandle,
+    file_lock_mode: FileLockMode,
+    immediate: bool,
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    let prepared = PreparedRange::new(file_lock_mode, offset, len)?;
+    lock_range_prepared(raw_handle, &prepared, immediate)
+}
+
+pub(crate) fn unlock_range(
+    raw_handle: RawHandle,
+    offset: u64,
+    len: u64,
+) -> Result<(), FileLockError> {
+    let prepared = PreparedRange::new(FileLockMode::Shared, offset, len)?;
+    unlock_range_prepared(raw_handle, &prepared)
+}
+
+/// The arguments of `LockFileEx` and `UnlockFileEx` for a byte range, built once and reused for
+/// every call.
+#[derive(Copy, Clone)]
+pub(crate) struct PreparedRange(PreparedLock);
+
+impl PreparedRange {
+    pub(crate) fn new(
+        file_lock_mode: FileLockMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<PreparedRange, FileLockError> {
+        Ok(PreparedRange(PreparedLock::with_range(
+            file_lock_mode,
+            offset,
+            len,
+        )))
+    }
+}
+
+pub(crate) fn lock_range_prepared(
+    raw_handle: RawHandle,
+    prepared: &PreparedRange,
+    immediate: bool,
+) -> Result<(), FileLockError> {
+    lock_file_ex(raw_handle, &prepared.0, immediate)
+}
+
+pub(crate) fn unlock_range_prepared(
+    raw_handle: RawHandle,
+    prepared: &PreparedRange,
+) -> Result<(), FileLockError> {
+    unlock_file_ex(raw_handle, &prepared.0)
+}
+
+/// Return whether `raw_handle` is a file on disk, as opposed to a pipe or a character device.
+pub(crate) fn is_lockable(raw_handle: RawHandle) -> io::Result<bool> {
+    let file_type = unsafe { GetFileType(raw_handle as *mut winapi::ctypes::c_void) };
+    if file_type == FILE_TYPE_UNKNOWN {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(0) {
+            return Err(err);
+        }
+    }
+    Ok(file_type == FILE_TYPE_DISK)
+}
+
+/// Return whether the process with the given id is running.
+pub(crate) fn process_alive(pid: u32) -> bool {
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+    if process.is_null() {
+        // Processes of other users can't be opened, but do exist.
+        return unsafe { GetLastError() } == ERROR_ACCESS_DENIED;
+    }
+
+    let mut exit_code = 0;
+    let result = unsafe { GetExitCodeProcess(process, &mut exit_code) };
+    unsafe { CloseHandle(process) };
+    result == TRUE && exit_code == STILL_ACTIVE
+}
+
+extern "C" {
+    /// Registers a function the C runtime calls when the process exits normally.
+    fn atexit(callback: extern "C" fn()) -> i32;
+}
+
+/// Run `callback` when the process exits normally.
+pub(crate) fn at_exit(callback: extern "C" fn()) {
+    unsafe { atexit(callback) };
+}
+
+pub(crate) fn set_inheritable(raw_handle: RawHandle, inheritable: bool) -> io::Result<()> {
+    let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+    let result = unsafe {
+        SetHandleInformation(
+            raw_handle as *mut winapi::ctypes::c_void,
+            HANDLE_FLAG_INHERIT,
+            flags,
+        )
+    };
+    if result != TRUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Spawn `command` with an inherited copy of `raw_handle`, whose value is passed in the
+/// environment variable `env`.
+///
+/// The handle is inheritable while the child is spawned, so children spawned concurrently by
+/// other threads may inherit it too.
+pub(crate) fn spawn_inheriting(
+    mut command: Command,
+    raw_handle: RawHandle,
+    env: &str,
+) -> io::Result<Child> {
+    command.env(env, (raw_handle as usize).to_string());
+    set_inheritable(raw_handle, true)?;
+    let child = command.spawn();
+    let _ = set_inheritable(raw_handle, false);
+    child
+}
+
+pub(crate) fn is_inheritable(raw_handle: RawHandle) -> io::Result<bool> {
+    let mut flags = 0;
+    let result =
+        unsafe { GetHandleInformation(raw_handle as *mut winapi::ctypes::c_void, &mut flags) };
+    if result != TRUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & HANDLE_FLAG_INHERIT != 0)
+}
+
+/// Return whether two handles refer to the same file object.
+///
+/// Windows only compares file objects from version 10 on, so distinct handles are assumed to
+/// refer to distinct objects.
+pub(crate) fn same_description(a: RawHandle, b: RawHandle) -> io::Result<bool> {
+    Ok(a == b)
+}
+
+/// Return the mechanism the lock of `raw_handle` is held with.
+pub(crate) fn lock_mechanism(raw_handle: RawHandle) -> LockMechanism {
+    if named_mutex::holds(raw_handle) {
+        LockMechanism::NamedMutex
+    } else {
+        LockMechanism::FileLock
+    }
+}
+
+pub(crate) fn file_id(raw_handle: RawHandle) -> io::Result<FileId> {
+    let mut info = unsafe { std::mem::zeroed::<BY_HANDLE_FILE_INFORMATION>() };
+    let result =
+        unsafe { GetFileInformationByHandle(raw_handle as *mut winapi::ctypes::c_void, &mut info) };
+    if result != TRUE {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(FileId {
+        device: info.dwVolumeSerialNumber.into(),
+        index: (u64::from(info.nFileIndexHigh) << 32) | u64::from(info.nFileIndexLow),
+    })
+}
+
+/// Return the final path of the file `raw_handle` refers to, with its `\\?\` prefix.
+pub(crate) fn path(raw_handle: RawHandle) -> io::Result<PathBuf> {
+    let mut path = vec![0u16; 512];
+    loop {
+        let len = unsafe {
+            GetFinalPathNameByHandleW(
+                raw_handle as *mut winapi::ctypes::c_void,
+                path.as_mut_ptr(),
+                path.len() as u32,
+                0,
+            )
+        } as usize;
+        if len == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if len < path.len() {
+            path.truncate(len);
+            return Ok(OsString::from_wide(&path).into());
+        }
+        path.resize(len + 1, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contention_codes_are_already_locked() {
+        for code in [ERROR_LOCK_VIOLATION, ERROR_LOCKED] {
+            assert!(matches!(lock_error(code), FileLockError::AlreadyLocked));
         }
+        assert!(matches!(
+            lock_error(ERROR_NOT_SUPPORTED),
+            FileLockError::Unsupported
+        ));
+    }
+
+    #[test]
+    fn locks_are_released_with_the_region_they_were_acquired_with() {
+        let mut test_file = std::env::temp_dir();
+        test_file.push("windows_region_changed");
+        let file = std::fs::File::create(&test_file).unwrap();
+        let other = std::fs::File::open(&test_file).unwrap();
+
+        // As if the region was changed from `LastByte` to the default while the lock was held.
+        let last_byte = PreparedLock::for_region(FileLockMode::Exclusive, LockRegion::LastByte);
+        let whole = PreparedLock::for_region(FileLockMode::Shared, LockRegion::Whole);
+        lock_prepared(handle(&file), &last_byte, true).unwrap();
+        unlock_prepared(handle(&file), &whole).unwrap();
+        lock_prepared(handle(&other), &last_byte, true).unwrap();
+        unlock_prepared(handle(&other), &last_byte).unwrap();
+
+        drop((file, other));
+        std::fs::remove_file(&test_file).unwrap();
+    }
+
+    #[test]
+    fn unlocking_an_unlocked_file_fails() {
+        let mut test_file = std::env::temp_dir();
+        test_file.push("windows_not_locked");
+        let file = std::fs::File::create(&test_file).unwrap();
+
+        assert!(matches!(
+            unlock_file(handle(&file)),
+            Err(FileLockError::NotLocked)
+        ));
+        lock_file(handle(&file), FileLockMode::Exclusive, true).unwrap();
+        unlock_file(handle(&file)).unwrap();
+        assert!(matches!(
+            unlock_file(handle(&file)),
+            Err(FileLockError::NotLocked)
+        ));
+
+        drop(file);
+        std::fs::remove_file(&test_file).unwrap();
     }
 }
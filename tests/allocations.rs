@@ -0,0 +1,72 @@
+//! Checks that acquiring and releasing locks doesn't allocate, even when contended.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::env::temp_dir;
+use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let outer = COUNTING.with(|counting| counting.replace(true));
+    f();
+    COUNTING.with(|counting| counting.set(outer));
+    ALLOCATIONS.load(Ordering::SeqCst) - before
+}
+
+#[test]
+fn lock_operations_do_not_allocate() {
+    let mut test_file = temp_dir();
+    test_file.push("allocations_lock_operations");
+    let holder = File::create(&test_file).unwrap();
+    let poller = File::open(&test_file).unwrap();
+
+    let mut polling = 0;
+    let allocations = count_allocations(|| {
+        AdvisoryFileLock::try_lock(&holder, FileLockMode::Exclusive).unwrap();
+        polling = count_allocations(|| {
+            for _ in 0..100 {
+                assert!(matches!(
+                    AdvisoryFileLock::try_lock(&poller, FileLockMode::Shared),
+                    Err(FileLockError::AlreadyLocked)
+                ));
+            }
+        });
+        AdvisoryFileLock::unlock(&holder).unwrap();
+        AdvisoryFileLock::lock(&poller, FileLockMode::Shared).unwrap();
+        AdvisoryFileLock::unlock(&poller).unwrap();
+    });
+    assert_eq!(polling, 0);
+    // The diagnostics registry records the locks which are acquired, which allocates.
+    if !cfg!(feature = "diagnostics") {
+        assert_eq!(allocations, 0);
+    }
+
+    drop((holder, poller));
+    std::fs::remove_file(&test_file).unwrap();
+}